@@ -0,0 +1,1084 @@
+mod expr;
+mod macros;
+mod parse;
+
+use crate::image::Image;
+use crate::symbols::SymbolTable;
+use expr::{evaluate, ExprError};
+use macros::{Block, Condition, Macro};
+use parse::{split, statements, string_operand, Statement};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Includes and macro expansions inside each other, before it's taken for a loop.
+const MAX_DEPTH: usize = 64;
+// Bytes shown on each line of a listing. Longer DBs carry on over more lines.
+const LISTING_BYTES: usize = 4;
+
+const DIRECTIVES: [&str; 31] = [
+    "ORG", "EQU", "SET", "DB", "DW", "DS", "DC", "END", "INCLUDE", "MACLIB", "=", "MACRO", "ENDM",
+    "EXITM", "LOCAL", "REPT", "IRP", "IRPC", "IF", "IFE", "IF1", "IF2", "IFDEF", "IFNDEF", "IFB",
+    "IFNB", "IFIDN", "IFDIF", "ELSE", "ENDIF", ".Z80",
+];
+// Directives other CP/M assemblers use, which are taken to mean one of the above.
+const SYNONYMS: [(&str, &str); 9] = [
+    ("DEFB", "DB"),
+    ("DEFM", "DB"),
+    ("DEFW", "DW"),
+    ("DEFS", "DS"),
+    ("DEFL", "SET"),
+    ("COND", "IF"),
+    ("IFT", "IF"),
+    ("IFF", "IFE"),
+    ("ENDC", "ENDIF"),
+];
+// Listing control and linker directives that make no difference to an absolute program.
+const IGNORED: [&str; 18] = [
+    "TITLE", "SUBTTL", "PAGE", "EJECT", "NAME", ".LIST", ".XLIST", ".SFCOND", ".LFCOND", ".TFCOND",
+    ".LALL", ".SALL", ".XALL", ".8080", "ASEG", "PUBLIC", "ENTRY", "GLOBAL",
+];
+// Relocatable code needs a linker, which there isn't one of here.
+const RELOCATABLE: [&str; 5] = ["CSEG", "DSEG", "COMMON", "EXTRN", "EXT"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub file: String,
+    // Counts from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SymbolKind {
+    Label,
+    Equ,
+    Set,
+}
+
+#[derive(Clone, Debug)]
+struct Symbol {
+    name: String,
+    value: u16,
+    kind: SymbolKind,
+    pass: u8,
+}
+
+#[derive(Clone, Debug)]
+struct ListingLine {
+    file: Rc<str>,
+    included: bool,
+    line: usize,
+    addr: Option<u16>,
+    value: Option<u16>,
+    bytes: Vec<u8>,
+    text: String,
+    errors: Vec<String>,
+}
+
+// What a program assembled to.
+pub struct Assembly {
+    bytes: BTreeMap<u16, u8>,
+    start: Option<u16>,
+    symbols: BTreeMap<String, (String, u16)>,
+    labels: Vec<(u16, String)>,
+    listing: Vec<ListingLine>,
+}
+
+impl Assembly {
+    // Every byte the program defines, by address.
+    pub fn bytes(&self) -> &BTreeMap<u16, u8> {
+        &self.bytes
+    }
+
+    // From the END directive, if it gave one.
+    pub fn start(&self) -> Option<u16> {
+        self.start
+    }
+
+    pub fn extent(&self) -> Option<RangeInclusive<u16>> {
+        let first = *self.bytes.keys().next()?;
+        let last = *self.bytes.keys().next_back()?;
+        Some(first..=last)
+    }
+
+    // The bytes from the lowest address used to the highest, with zeros in any gaps.
+    pub fn binary(&self) -> Vec<u8> {
+        self.image().binary()
+    }
+
+    pub fn image(&self) -> Image {
+        let mut image = Image::new();
+        for (addr, byte) in &self.bytes {
+            image
+                .add(u32::from(*addr), &[*byte])
+                .expect("an assembly only sets each address once");
+        }
+        image.set_start(self.start);
+        image
+    }
+
+    // Names as they were written, with their values.
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.symbols
+            .values()
+            .map(|(name, value)| (name.as_str(), *value))
+    }
+
+    pub fn value(&self, name: &str) -> Option<u16> {
+        self.symbols
+            .get(&name.to_uppercase())
+            .map(|(_, value)| *value)
+    }
+
+    // The labels, for naming addresses in the debugger.
+    pub fn symbol_table(&self) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        for (addr, name) in &self.labels {
+            symbols.add(*addr, name);
+        }
+        symbols
+    }
+
+    // Intel HEX, with the start address from END in the end of file record.
+    pub fn write_hex<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.image().write_hex(out)
+    }
+
+    // Line number, address, bytes and source. Lines from included files have a + after the
+    // number. Errors follow the line they're about. The symbols come at the end.
+    pub fn write_listing<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut file = None;
+        for line in &self.listing {
+            if line.included && file.as_ref() != Some(&line.file) {
+                writeln!(out, "; {}", line.file)?;
+            }
+            file = Some(line.file.clone());
+            let number = format!("{}{}", line.line, if line.included { "+" } else { "" });
+            let addr = match (line.value, line.addr) {
+                (Some(value), _) => format!("={:04X}", value),
+                (None, Some(addr)) => format!("{:04X}", addr),
+                (None, None) => String::new(),
+            };
+            let mut chunks = line.bytes.chunks(LISTING_BYTES);
+            let bytes = chunks.next().map(hex_bytes).unwrap_or_default();
+            let text = format!("{:>6}  {:<5}  {:<12} {}", number, addr, bytes, line.text);
+            writeln!(out, "{}", text.trim_end())?;
+            let mut addr = line.addr.unwrap_or(0);
+            for chunk in chunks {
+                addr = addr.wrapping_add(LISTING_BYTES as u16);
+                writeln!(out, "        {:04X}   {}", addr, hex_bytes(chunk))?;
+            }
+            for error in &line.errors {
+                writeln!(out, "***** {}", error)?;
+            }
+        }
+        writeln!(out)?;
+        writeln!(out, "Symbols:")?;
+        for (name, value) in self.symbols() {
+            writeln!(out, "{:<16} {:04X}", name, value)?;
+        }
+        Ok(())
+    }
+
+    // A symbol file the debugger frontends can load, one label a line.
+    pub fn write_symbols<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (addr, name) in &self.labels {
+            writeln!(out, "{:04X} {}", addr, name)?;
+        }
+        Ok(())
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    bytes.join(" ")
+}
+
+// Includes are found next to the file that includes them. name is what errors call the
+// source, and where includes are looked for relative to.
+pub fn assemble(name: &str, source: &str) -> Result<Assembly, Vec<Error>> {
+    let mut assembler = Assembler::new();
+    let path = PathBuf::from(name);
+    assembler.files.insert(path.clone(), Rc::new(lines(source)));
+    assembler.run(&path)
+}
+
+pub fn assemble_file(path: &str) -> Result<Assembly, Vec<Error>> {
+    let source = fs::read_to_string(path).map_err(|e| {
+        vec![Error {
+            file: path.to_string(),
+            line: 0,
+            message: format!("couldn't read it: {}", e),
+        }]
+    })?;
+    assemble(path, &source)
+}
+
+fn lines(source: &str) -> Vec<String> {
+    source.lines().map(String::from).collect()
+}
+
+// A file being read, or a macro being expanded, and how far through it the pass is.
+struct Frame {
+    path: PathBuf,
+    name: Rc<str>,
+    lines: Rc<Vec<String>>,
+    next: usize,
+    // For an expansion, the line that asked for it. Its lines are reported there.
+    expansion: Option<usize>,
+    // How many IFs were open when the expansion started, for EXITM to go back to.
+    conditions: usize,
+}
+
+struct Assembler {
+    files: HashMap<PathBuf, Rc<Vec<String>>>,
+    symbols: HashMap<String, Symbol>,
+    pass: u8,
+    pc: u16,
+    // Code has run past FFFF. Everything after would wrap round over the start.
+    overflowed: bool,
+    // The last label without a leading dot. Labels with one belong to it.
+    scope: String,
+    stack: Vec<Frame>,
+    // The line being assembled, for errors found later about where something started.
+    location: (Rc<str>, usize),
+    macros: HashMap<String, Rc<Macro>>,
+    block: Option<Block>,
+    conditions: Vec<Condition>,
+    // LOCAL names made so far, which are numbered.
+    generated: usize,
+    ended: bool,
+    start: Option<u16>,
+    bytes: BTreeMap<u16, u8>,
+    listing: Vec<ListingLine>,
+    errors: Vec<Error>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Assembler {
+            files: HashMap::new(),
+            symbols: HashMap::new(),
+            pass: 1,
+            pc: 0,
+            overflowed: false,
+            scope: String::new(),
+            stack: Vec::new(),
+            location: (Rc::from(""), 0),
+            macros: HashMap::new(),
+            block: None,
+            conditions: Vec::new(),
+            generated: 0,
+            ended: false,
+            start: None,
+            bytes: BTreeMap::new(),
+            listing: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // Pass 1 finds where every label is. Pass 2 does it all again, now that forward
+    // references have values, and keeps the output.
+    fn run(mut self, path: &Path) -> Result<Assembly, Vec<Error>> {
+        for pass in 1..=2 {
+            self.pass = pass;
+            self.pc = 0;
+            self.overflowed = false;
+            self.scope.clear();
+            self.ended = false;
+            self.start = None;
+            self.macros.clear();
+            self.generated = 0;
+            self.stack = vec![Frame {
+                path: path.to_path_buf(),
+                name: Rc::from(path.to_string_lossy().as_ref()),
+                lines: self.files[path].clone(),
+                next: 0,
+                expansion: None,
+                conditions: 0,
+            }];
+            while !self.ended {
+                let (name, number, text) = match self.next_line() {
+                    Some(line) => line,
+                    None => break,
+                };
+                self.line(name, number, &text);
+            }
+            self.unclosed();
+        }
+        if !self.errors.is_empty() {
+            // Pass 1 finds some and pass 2 the rest. Put them back in source order.
+            self.errors
+                .sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+            return Err(self.errors);
+        }
+        let mut labels: Vec<(u16, String)> = self
+            .symbols
+            .values()
+            .filter(|symbol| symbol.kind == SymbolKind::Label)
+            .map(|symbol| (symbol.value, symbol.name.clone()))
+            .collect();
+        labels.sort();
+        Ok(Assembly {
+            bytes: self.bytes,
+            start: self.start,
+            symbols: self
+                .symbols
+                .into_iter()
+                .map(|(key, symbol)| (key, (symbol.name, symbol.value)))
+                .collect(),
+            labels,
+            listing: self.listing,
+        })
+    }
+
+    fn next_line(&mut self) -> Option<(Rc<str>, usize, String)> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            match frame.lines.get(frame.next) {
+                Some(text) => {
+                    frame.next += 1;
+                    let number = frame.expansion.unwrap_or(frame.next);
+                    return Some((frame.name.clone(), number, text.clone()));
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+
+    // Lines from includes and macro expansions are marked as such in the listing.
+    fn line(&mut self, file: Rc<str>, number: usize, text: &str) {
+        let included = self.stack.len() > 1;
+        let assembling = self.block.is_none() && self.active();
+        let start = self.pc;
+        let mut bytes = Vec::new();
+        let mut value = None;
+        let mut errors = Vec::new();
+        self.location = (file.clone(), number);
+        for text in statements(text) {
+            let mut statement_bytes = Vec::new();
+            if let Err(message) = self.assemble_statement(text, &mut statement_bytes, &mut value) {
+                self.report(&file, number, message.clone());
+                errors.push(message);
+            }
+            bytes.extend(statement_bytes);
+        }
+        if self.pass == 2 {
+            let addr = Some(start).filter(|_| !bytes.is_empty() || value.is_none());
+            self.listing.push(ListingLine {
+                file,
+                included,
+                line: number,
+                addr: addr.filter(|_| assembling && !text.trim().is_empty()),
+                value,
+                bytes,
+                text: text.to_string(),
+                errors,
+            });
+        }
+    }
+
+    fn report(&mut self, file: &Rc<str>, line: usize, message: String) {
+        let error = Error {
+            file: file.to_string(),
+            line,
+            message,
+        };
+        if !self.errors.contains(&error) {
+            self.errors.push(error);
+        }
+    }
+
+    fn assemble_statement(
+        &mut self,
+        text: &str,
+        bytes: &mut Vec<u8>,
+        value: &mut Option<u16>,
+    ) -> Result<(), String> {
+        if self.collect(text)? || self.skip(text)? {
+            return Ok(());
+        }
+        let statement = self.split(text)?;
+        if self.control(&statement)? {
+            return Ok(());
+        }
+        self.statement(&statement, bytes, value)
+    }
+
+    // Macro names count as ops too, so they aren't taken for labels.
+    fn split<'a>(&self, text: &'a str) -> Result<Statement<'a>, String> {
+        let is_op = |word: &str| is_op(word) || self.macros.contains_key(&word.to_uppercase());
+        let names_next = |word: &str| {
+            let upper = word.to_uppercase();
+            ["EQU", "SET", "=", "MACRO"].contains(&canonical(&upper))
+        };
+        split(text, &is_op, &names_next)
+    }
+
+    fn statement(
+        &mut self,
+        statement: &Statement,
+        bytes: &mut Vec<u8>,
+        value: &mut Option<u16>,
+    ) -> Result<(), String> {
+        let op = statement
+            .op
+            .map(|op| canonical(&op.to_uppercase()).to_string());
+        let operands = &statement.operands;
+        match (op.as_deref(), statement.label) {
+            (Some("EQU" | "="), Some(name)) => {
+                one_operand(operands, "EQU")?;
+                // Forward references are fine, as long as they're resolved by pass 2.
+                match self.layout_value(operands[0]) {
+                    Ok(result) => {
+                        *value = Some(result);
+                        self.define(name, result, SymbolKind::Equ)?;
+                    }
+                    Err(_) if self.pass == 1 => {}
+                    Err(e) => return Err(e),
+                }
+                return Ok(());
+            }
+            (Some("SET"), Some(name)) => {
+                one_operand(operands, "SET")?;
+                let result = self.layout_value(operands[0])?;
+                *value = Some(result);
+                return self.define(name, result, SymbolKind::Set);
+            }
+            (Some("EQU" | "SET" | "="), None) => {
+                return Err(format!("{} needs a name", op.unwrap_or_default()))
+            }
+            (_, Some(name)) => self.define(name, self.pc, SymbolKind::Label)?,
+            (_, None) => {}
+        }
+        let op = match op {
+            Some(op) => op,
+            None => return Ok(()),
+        };
+        match op.as_str() {
+            "ORG" => {
+                one_operand(operands, "ORG")?;
+                self.pc = self.layout_value(operands[0])?;
+                self.overflowed = false;
+                *value = Some(self.pc);
+            }
+            // DC is DB with the top bit of each string's last character set.
+            "DB" | "DC" => {
+                if operands.is_empty() {
+                    return Err(format!("{} needs a value", op));
+                }
+                for operand in operands {
+                    match string_operand(operand).filter(|text| text.len() != 1 || op == "DC") {
+                        Some(mut text) => {
+                            if let Some(last) = text.last_mut().filter(|_| op == "DC") {
+                                *last |= 0x80;
+                            }
+                            bytes.extend(text)
+                        }
+                        None => bytes.push(self.byte(operand)?),
+                    }
+                }
+                self.emit(bytes)?;
+            }
+            "DW" => {
+                if operands.is_empty() {
+                    return Err(String::from("DW needs a value"));
+                }
+                for operand in operands {
+                    let word = self.word(operand)?;
+                    bytes.extend([word as u8, (word >> 8) as u8]);
+                }
+                self.emit(bytes)?;
+            }
+            "DS" => {
+                one_operand(operands, "DS")?;
+                let size = self.layout_value(operands[0])?;
+                self.advance(size)?;
+            }
+            "END" => {
+                if let Some(operand) = operands.first() {
+                    self.start = Some(self.word(operand)?);
+                }
+                self.ended = true;
+            }
+            "INCLUDE" => {
+                one_operand(operands, "INCLUDE")?;
+                self.include(operands[0])?;
+            }
+            // MAC's way of including NAME.LIB.
+            "MACLIB" => {
+                one_operand(operands, "MACLIB")?;
+                self.include(&format!("{}.LIB", operands[0]))?;
+            }
+            op if IGNORED.contains(&op) => {}
+            op if RELOCATABLE.contains(&op) => {
+                return Err(format!(
+                    "{} is for relocatable code, which isn't supported. Use ORG instead",
+                    op
+                ))
+            }
+            ".Z80" => return Err(String::from("only 8080 mnemonics are supported")),
+            _ => {
+                let (form, code) =
+                    instruction(&op).ok_or_else(|| format!("unknown instruction {}", op))?;
+                let encoded = self.encode(&op, form, code, operands);
+                // A bad line still takes its room, so the addresses after it stay right.
+                let length = match &encoded {
+                    Ok(encoded) => encoded.len(),
+                    Err(_) => form.length(),
+                };
+                match encoded {
+                    Ok(encoded) => {
+                        bytes.extend(encoded);
+                        self.emit(bytes)?;
+                    }
+                    Err(e) => {
+                        self.advance(length as u16)?;
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, operand: &str) -> Result<(), String> {
+        if self.stack.len() > MAX_DEPTH {
+            return Err(String::from("includes are nested too deeply"));
+        }
+        let name = string_operand(operand)
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .unwrap_or_else(|| operand.to_string());
+        let from = &self.stack.last().ok_or("nothing to include from")?.path;
+        let path = from.parent().unwrap_or(Path::new("")).join(&name);
+        if !self.files.contains_key(&path) {
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("couldn't include {}: {}", path.display(), e))?;
+            self.files.insert(path.clone(), Rc::new(lines(&text)));
+        }
+        self.stack.push(Frame {
+            name: Rc::from(path.to_string_lossy().as_ref()),
+            lines: self.files[&path].clone(),
+            path,
+            next: 0,
+            expansion: None,
+            conditions: self.conditions.len(),
+        });
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, value: u16, kind: SymbolKind) -> Result<(), String> {
+        let full = self.qualify(name)?;
+        // Names made up for LOCAL don't start a new scope either.
+        if kind == SymbolKind::Label && !name.starts_with('.') && !name.starts_with("??") {
+            self.scope = full.clone();
+        }
+        let key = full.to_uppercase();
+        match self.symbols.get_mut(&key) {
+            Some(symbol) if symbol.kind == SymbolKind::Set && kind == SymbolKind::Set => {
+                symbol.value = value;
+                symbol.pass = self.pass;
+            }
+            Some(symbol) if symbol.pass == self.pass => {
+                return Err(format!("{} is already defined", full));
+            }
+            Some(symbol) => {
+                if symbol.value != value {
+                    return Err(format!(
+                        "{} moved from {:04X} to {:04X} between passes",
+                        full, symbol.value, value
+                    ));
+                }
+                symbol.pass = self.pass;
+            }
+            None => {
+                self.symbols.insert(
+                    key,
+                    Symbol {
+                        name: full,
+                        value,
+                        kind,
+                        pass: self.pass,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // .name is short for the last ordinary label's name followed by .name.
+    fn qualify(&self, name: &str) -> Result<String, String> {
+        if !name.starts_with('.') {
+            return Ok(name.to_string());
+        }
+        if self.scope.is_empty() {
+            return Err(format!(
+                "{} comes before any label it could belong to",
+                name
+            ));
+        }
+        Ok(format!("{}{}", self.scope, name))
+    }
+
+    // In pass 1, symbols not defined yet count as 0, so forward references work.
+    fn value(&self, text: &str) -> Result<i32, String> {
+        self.evaluate(text, self.pass == 2)
+    }
+
+    // For values that decide where things go, which have to be known in pass 1.
+    fn layout_value(&self, text: &str) -> Result<u16, String> {
+        let value = self.evaluate(text, true)?;
+        Ok(value as u16)
+    }
+
+    fn evaluate(&self, text: &str, strict: bool) -> Result<i32, String> {
+        let lookup = |name: &str| {
+            let full = self.qualify(name).ok()?;
+            self.symbols
+                .get(&full.to_uppercase())
+                .map(|symbol| symbol.value)
+        };
+        match evaluate(text, self.pc, &lookup) {
+            Ok(value) => Ok(value),
+            Err(ExprError::Undefined(_)) if !strict => Ok(0),
+            Err(ExprError::Undefined(name)) => Err(format!("{} isn't defined", name)),
+            Err(ExprError::Syntax(message)) => Err(message),
+        }
+    }
+
+    // Anything from -256 to 255, or with FF as its high byte, so NOT 80H works.
+    fn byte(&self, text: &str) -> Result<u8, String> {
+        let value = self.value(text)?;
+        if (-256..=255).contains(&value) || (0xFF00..=0xFFFF).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(format!("{} doesn't fit in a byte", text))
+        }
+    }
+
+    fn word(&self, text: &str) -> Result<u16, String> {
+        let value = self.value(text)?;
+        if (-0x10000..=0xFFFF).contains(&value) {
+            Ok(value as u16)
+        } else {
+            Err(format!("{} doesn't fit in a word", text))
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let start = self.pc;
+        self.advance(bytes.len() as u16)?;
+        if self.pass == 1 {
+            return Ok(());
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            let addr = start.wrapping_add(i as u16);
+            if self.bytes.insert(addr, *byte).is_some() {
+                return Err(format!("{:04X} is already used", addr));
+            }
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, length: u16) -> Result<(), String> {
+        if self.overflowed && length > 0 {
+            return Err(String::from("code runs past FFFF"));
+        }
+        let end = u32::from(self.pc) + u32::from(length);
+        self.pc = end as u16;
+        if end > 0x10000 {
+            return Err(String::from("code runs past FFFF"));
+        }
+        self.overflowed = end == 0x10000;
+        Ok(())
+    }
+
+    fn register(&self, text: &str) -> Result<u8, String> {
+        let names = ["B", "C", "D", "E", "H", "L", "M", "A"];
+        match names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(text))
+        {
+            Some(index) => Ok(index as u8),
+            None => match self.value(text)? {
+                value @ 0..=7 => Ok(value as u8),
+                _ => Err(format!("{} isn't a register", text)),
+            },
+        }
+    }
+
+    // B, D, H and SP, or PSW in place of SP for PUSH and POP. BC, DE and HL are accepted too.
+    fn pair(&self, text: &str, last: &str) -> Result<u8, String> {
+        match text.to_uppercase().as_str() {
+            "B" | "BC" => Ok(0),
+            "D" | "DE" => Ok(1),
+            "H" | "HL" => Ok(2),
+            name if name == last => Ok(3),
+            _ => Err(format!("{} isn't a register pair here", text)),
+        }
+    }
+
+    fn encode(&self, op: &str, form: Form, code: u8, operands: &[&str]) -> Result<Vec<u8>, String> {
+        let wanted = form.operands();
+        if operands.len() != wanted {
+            return Err(match wanted {
+                0 => format!("{} doesn't take operands", op),
+                1 => format!("{} takes one operand", op),
+                _ => format!("{} takes {} operands", op, wanted),
+            });
+        }
+        let word = |text: &str| -> Result<Vec<u8>, String> {
+            let word = self.word(text)?;
+            Ok(vec![word as u8, (word >> 8) as u8])
+        };
+        let mut bytes = match form {
+            Form::Implied => vec![code],
+            Form::Destination => vec![code | self.register(operands[0])? << 3],
+            Form::Source => vec![code | self.register(operands[0])?],
+            Form::Move => {
+                let (to, from) = (self.register(operands[0])?, self.register(operands[1])?);
+                if to == 6 && from == 6 {
+                    return Err(String::from(
+                        "MOV M,M isn't an instruction (it would be HLT)",
+                    ));
+                }
+                vec![code | to << 3 | from]
+            }
+            Form::MoveImmediate => vec![
+                code | self.register(operands[0])? << 3,
+                self.byte(operands[1])?,
+            ],
+            Form::LoadPair => {
+                let mut bytes = vec![code | self.pair(operands[0], "SP")? << 4];
+                bytes.extend(word(operands[1])?);
+                bytes
+            }
+            Form::Pair => vec![code | self.pair(operands[0], "SP")? << 4],
+            Form::IndirectPair => match self.pair(operands[0], "")? {
+                pair @ 0..=1 => vec![code | pair << 4],
+                _ => return Err(format!("{} only works with B or D", op)),
+            },
+            Form::StackPair => vec![code | self.pair(operands[0], "PSW")? << 4],
+            Form::Byte => vec![code, self.byte(operands[0])?],
+            Form::Word => {
+                let mut bytes = vec![code];
+                bytes.extend(word(operands[0])?);
+                bytes
+            }
+            Form::Restart => match self.value(operands[0])? {
+                vector @ 0..=7 => vec![code | (vector as u8) << 3],
+                _ => return Err(format!("{} isn't a restart number (0-7)", operands[0])),
+            },
+        };
+        bytes.truncate(form.length());
+        Ok(bytes)
+    }
+}
+
+fn one_operand(operands: &[&str], op: &str) -> Result<(), String> {
+    if operands.len() == 1 {
+        Ok(())
+    } else {
+        Err(format!("{} takes one operand", op))
+    }
+}
+
+fn canonical(op: &str) -> &str {
+    match SYNONYMS.iter().find(|(synonym, _)| *synonym == op) {
+        Some((_, directive)) => directive,
+        None => op,
+    }
+}
+
+fn is_op(word: &str) -> bool {
+    let upper = word.to_uppercase();
+    let op = canonical(&upper);
+    DIRECTIVES.contains(&op)
+        || IGNORED.contains(&op)
+        || RELOCATABLE.contains(&op)
+        || instruction(op).is_some()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Form {
+    Implied,
+    // A register in bits 3-5: INR, DCR.
+    Destination,
+    // A register in bits 0-2: ADD and the rest of the arithmetic group.
+    Source,
+    Move,
+    MoveImmediate,
+    LoadPair,
+    // INX, DCX, DAD.
+    Pair,
+    // STAX, LDAX.
+    IndirectPair,
+    StackPair,
+    Byte,
+    Word,
+    Restart,
+}
+
+impl Form {
+    fn length(&self) -> usize {
+        match self {
+            Form::MoveImmediate | Form::Byte => 2,
+            Form::LoadPair | Form::Word => 3,
+            _ => 1,
+        }
+    }
+
+    fn operands(&self) -> usize {
+        match self {
+            Form::Implied => 0,
+            Form::Move | Form::MoveImmediate | Form::LoadPair => 2,
+            _ => 1,
+        }
+    }
+}
+
+fn instruction(op: &str) -> Option<(Form, u8)> {
+    let implied = [
+        ("NOP", 0x00),
+        ("RLC", 0x07),
+        ("RRC", 0x0F),
+        ("RAL", 0x17),
+        ("RAR", 0x1F),
+        ("DAA", 0x27),
+        ("CMA", 0x2F),
+        ("STC", 0x37),
+        ("CMC", 0x3F),
+        ("HLT", 0x76),
+        ("RET", 0xC9),
+        ("XTHL", 0xE3),
+        ("PCHL", 0xE9),
+        ("XCHG", 0xEB),
+        ("DI", 0xF3),
+        ("SPHL", 0xF9),
+        ("EI", 0xFB),
+    ];
+    let conditions = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+    let arithmetic = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+    let immediate = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+    if let Some((_, code)) = implied.iter().find(|(name, _)| *name == op) {
+        return Some((Form::Implied, *code));
+    }
+    if let Some(i) = arithmetic.iter().position(|name| *name == op) {
+        return Some((Form::Source, 0x80 | (i as u8) << 3));
+    }
+    if let Some(i) = immediate.iter().position(|name| *name == op) {
+        return Some((Form::Byte, 0xC6 | (i as u8) << 3));
+    }
+    let condition = |prefix: char| {
+        let rest = op.strip_prefix(prefix)?;
+        let i = conditions.iter().position(|name| *name == rest)?;
+        Some((i as u8) << 3)
+    };
+    if let Some(bits) = condition('R') {
+        return Some((Form::Implied, 0xC0 | bits));
+    }
+    if let Some(bits) = condition('J') {
+        return Some((Form::Word, 0xC2 | bits));
+    }
+    if let Some(bits) = condition('C') {
+        return Some((Form::Word, 0xC4 | bits));
+    }
+    let found = match op {
+        "INR" => (Form::Destination, 0x04),
+        "DCR" => (Form::Destination, 0x05),
+        "MOV" => (Form::Move, 0x40),
+        "MVI" => (Form::MoveImmediate, 0x06),
+        "LXI" => (Form::LoadPair, 0x01),
+        "INX" => (Form::Pair, 0x03),
+        "DCX" => (Form::Pair, 0x0B),
+        "DAD" => (Form::Pair, 0x09),
+        "STAX" => (Form::IndirectPair, 0x02),
+        "LDAX" => (Form::IndirectPair, 0x0A),
+        "PUSH" => (Form::StackPair, 0xC5),
+        "POP" => (Form::StackPair, 0xC1),
+        "SHLD" => (Form::Word, 0x22),
+        "LHLD" => (Form::Word, 0x2A),
+        "STA" => (Form::Word, 0x32),
+        "LDA" => (Form::Word, 0x3A),
+        "JMP" => (Form::Word, 0xC3),
+        "CALL" => (Form::Word, 0xCD),
+        "IN" => (Form::Byte, 0xDB),
+        "OUT" => (Form::Byte, 0xD3),
+        "RST" => (Form::Restart, 0xC7),
+        _ => return None,
+    };
+    Some(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::analysis::Analysis;
+
+    fn assembled(source: &str) -> Assembly {
+        match assemble("test.asm", source) {
+            Ok(assembly) => assembly,
+            Err(errors) => panic!("{:?}", errors),
+        }
+    }
+
+    #[test]
+    fn test_instructions() {
+        let assembly = assembled(
+            "        ORG 100H
+start:  MVI A,20H
+        LXI H,2400H
+        MOV M,A
+        JNZ start
+        PUSH PSW
+        RST 7
+        LDAX D
+        CPI 'A'
+        DAD SP
+        RZ
+        CC start",
+        );
+        assert_eq!(
+            assembly.binary(),
+            vec![
+                0x3E, 0x20, 0x21, 0x00, 0x24, 0x77, 0xC2, 0x00, 0x01, 0xF5, 0xFF, 0x1A, 0xFE, 0x41,
+                0x39, 0xC8, 0xDC, 0x00, 0x01
+            ]
+        );
+        assert_eq!(assembly.extent(), Some(0x100..=0x112));
+    }
+
+    #[test]
+    fn test_labels_and_directives() {
+        let assembly = assembled(
+            "count   SET 1
+count   SET count + 1
+        ORG 0
+        JMP main
+main:   LXI H,table
+.loop:  DCR M
+        JNZ .loop
+        HLT
+table:  DB 'Hi', 0, count, -1
+        DW main, $
+table_end:
+SIZE    EQU table_end - table
+        DS SIZE
+after:  DB LOW after, HIGH 1234H
+        END main",
+        );
+        assert_eq!(assembly.value("size"), Some(9));
+        assert_eq!(assembly.value("main.loop"), Some(0x0006));
+        assert_eq!(assembly.start(), Some(0x0003));
+        assert_eq!(
+            assembly.binary(),
+            vec![
+                0xC3, 0x03, 0x00, 0x21, 0x0B, 0x00, 0x35, 0xC2, 0x06, 0x00, 0x76, 0x48, 0x69, 0x00,
+                0x02, 0xFF, 0x03, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1D, 0x12
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = assemble(
+            "bad.asm",
+            "        MVI A,300H
+        JMP nowhere
+        MOV M,M
+start:  NOP
+start:  NOP
+        FOO
+        ORG later
+later:  NOP",
+        )
+        .err()
+        .unwrap();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "bad.asm:1: 300H doesn't fit in a byte",
+                "bad.asm:2: nowhere isn't defined",
+                "bad.asm:3: MOV M,M isn't an instruction (it would be HLT)",
+                "bad.asm:5: start is already defined",
+                "bad.asm:6: unknown instruction FOO",
+                "bad.asm:7: later isn't defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let assembly = assembled(
+            "        ORG 0100H
+start:  MVI A,1
+        DB 1,2,3,4,5,6
+        END start",
+        );
+        let mut hex = Vec::new();
+        assembly.write_hex(&mut hex).unwrap();
+        assert_eq!(
+            String::from_utf8(hex).unwrap(),
+            ":080100003E01010203040506A3\n:00010001FE\n"
+        );
+        let mut listing = Vec::new();
+        assembly.write_listing(&mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "     1  =0100                       ORG 0100H");
+        assert_eq!(lines[1], "     2  0100   3E 01        start:  MVI A,1");
+        assert_eq!(lines[3], "        0106   05 06");
+        // Source-level debugging reads this back.
+        let parsed = crate::listing::Listing::parse(&listing);
+        assert_eq!(parsed.address_of_line(2), Some((2, 0x0100)));
+        assert_eq!(parsed.labels(), &[(0x0100, String::from("start"))]);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("emu8080-asm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("defs.inc"), "SCREEN EQU 2400H\n").unwrap();
+        let main = dir.join("main.asm");
+        fs::write(&main, "        INCLUDE 'defs.inc'\n        LXI H,SCREEN\n").unwrap();
+        let assembly = assemble_file(main.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(assembly.ok().unwrap().binary(), vec![0x21, 0x00, 0x24]);
+    }
+
+    #[test]
+    fn test_rebuilds_traced_disassembly() {
+        let mut memory = vec![0u8; 0x2000];
+        for (i, name) in ["h", "g", "f", "e"].iter().enumerate() {
+            let path = format!("src/roms/invaders.{}", name);
+            let rom = fs::read(&path).unwrap();
+            memory[i * 0x800..(i + 1) * 0x800].copy_from_slice(&rom);
+        }
+        let mut analysis = Analysis::new(&memory, 0x0000..=0x1FFF);
+        analysis.add_entry(0);
+        analysis.add_rst_vectors();
+        analysis.run();
+        let mut source = Vec::new();
+        analysis
+            .write_source(&mut source, &SymbolTable::new())
+            .unwrap();
+
+        let assembly = assembled(&String::from_utf8(source).unwrap());
+        assert!(assembly.binary() == memory);
+    }
+}
@@ -0,0 +1,366 @@
+// Intel-style expressions. Numbers are decimal unless they end in H (hex), B (binary), O or Q
+// (octal) or D, or start 0x. $ on its own is the address of the current line. 'A' and 'AB'
+// are character values. Operators, loosest first:
+//   OR XOR | ^    AND &    NOT    EQ NE LT LE GT GE = <> == != < <= > >=
+//   + -    * / MOD SHL SHR % << >>    unary - + ~ HIGH LOW NUL
+// Comparisons give 0FFFFH for true and 0 for false. NUL is true if nothing follows it, which
+// is how a macro finds out an argument was left out.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprError {
+    Undefined(String),
+    Syntax(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i32),
+    Name(String),
+    Text(Vec<u8>),
+    Op(&'static str),
+    Here,
+    Open,
+    Close,
+}
+
+const WORD_OPS: [&str; 16] = [
+    "OR", "XOR", "AND", "NOT", "EQ", "NE", "LT", "LE", "GT", "GE", "MOD", "SHL", "SHR", "HIGH",
+    "LOW", "NUL",
+];
+// Longest first, so << isn't read as <.
+const SYMBOL_OPS: [&str; 19] = [
+    "<<", ">>", "<=", ">=", "<>", "==", "!=", "|", "^", "&", "~", "=", "<", ">", "+", "-", "*",
+    "/", "%",
+];
+
+// lookup gives a symbol's value, and is handed names as written.
+pub fn evaluate(
+    text: &str,
+    here: u16,
+    lookup: &dyn Fn(&str) -> Option<u16>,
+) -> Result<i32, ExprError> {
+    let tokens = tokenize(text).map_err(ExprError::Syntax)?;
+    if tokens.is_empty() {
+        return Err(ExprError::Syntax(String::from("missing expression")));
+    }
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        here,
+        lookup,
+    };
+    let value = parser.or()?;
+    match parser.tokens.get(parser.position) {
+        None => Ok(value),
+        Some(_) => Err(ExprError::Syntax(format!(
+            "can't make sense of {}",
+            text.trim()
+        ))),
+    }
+}
+
+// Names start with a letter or one of _ ? @ . and go on with those and digits.
+pub fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || "_?@.".contains(c)
+}
+
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_?@.".contains(c)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            let (bytes, length) = quoted(&chars[i..]).ok_or("unterminated string")?;
+            tokens.push(Token::Text(bytes));
+            i += length;
+        } else if c.is_ascii_digit()
+            || (c == '$' && chars.get(i + 1).is_some_and(char::is_ascii_hexdigit))
+        {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '$') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(number(&word)?));
+        } else if c == '$' {
+            tokens.push(Token::Here);
+            i += 1;
+        } else if is_name_start(c) {
+            let start = i;
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let upper = word.to_uppercase();
+            match WORD_OPS.iter().find(|op| **op == upper) {
+                Some(op) => tokens.push(Token::Op(op)),
+                None => tokens.push(Token::Name(word)),
+            }
+        } else {
+            let rest: String = chars[i..].iter().collect();
+            let op = SYMBOL_OPS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("unexpected {}", c))?;
+            tokens.push(Token::Op(if *op == "%" { "MOD" } else { op }));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+// A quoted string at the start of chars, with a doubled quote standing for itself. Gives the
+// bytes and how many chars it took up.
+pub fn quoted(chars: &[char]) -> Option<(Vec<u8>, usize)> {
+    let quote = *chars.first()?;
+    let mut bytes = Vec::new();
+    let mut i = 1;
+    loop {
+        let c = *chars.get(i)?;
+        if c == quote {
+            if chars.get(i + 1) == Some(&quote) {
+                bytes.push(c as u8);
+                i += 2;
+                continue;
+            }
+            return Some((bytes, i + 1));
+        }
+        bytes.push(c as u8);
+        i += 1;
+    }
+}
+
+fn number(word: &str) -> Result<i32, String> {
+    let upper = word.to_uppercase();
+    let (digits, radix) = if let Some(hex) = upper.strip_prefix("0X") {
+        (hex, 16)
+    } else if let Some(hex) = upper.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = upper.strip_suffix('H') {
+        (hex, 16)
+    } else if let Some(octal) = upper.strip_suffix(['O', 'Q']) {
+        (octal, 8)
+    } else if let Some(binary) = upper.strip_suffix('B') {
+        (binary, 2)
+    } else if let Some(decimal) = upper.strip_suffix('D') {
+        (decimal, 10)
+    } else {
+        (upper.as_str(), 10)
+    };
+    i32::from_str_radix(digits, radix)
+        .ok()
+        .filter(|value| *value <= 0xFFFF)
+        .ok_or_else(|| format!("bad number {}", word))
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    here: u16,
+    lookup: &'a dyn Fn(&str) -> Option<u16>,
+}
+
+impl Parser<'_> {
+    fn peek_op(&self, ops: &[&str]) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Op(op)) if ops.contains(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn next_op(&mut self, ops: &[&str]) -> Option<&'static str> {
+        let op = self.peek_op(ops)?;
+        self.position += 1;
+        Some(op)
+    }
+
+    fn or(&mut self) -> Result<i32, ExprError> {
+        let mut value = self.and()?;
+        while let Some(op) = self.next_op(&["OR", "XOR", "|", "^"]) {
+            let right = self.and()?;
+            value = if op == "OR" || op == "|" {
+                value | right
+            } else {
+                value ^ right
+            };
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<i32, ExprError> {
+        let mut value = self.not()?;
+        while self.next_op(&["AND", "&"]).is_some() {
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<i32, ExprError> {
+        if self.next_op(&["NOT"]).is_some() {
+            return Ok(!self.not()? & 0xFFFF);
+        }
+        self.relation()
+    }
+
+    fn relation(&mut self) -> Result<i32, ExprError> {
+        let ops = [
+            "EQ", "NE", "LT", "LE", "GT", "GE", "=", "==", "<>", "!=", "<", "<=", ">", ">=",
+        ];
+        let mut value = self.sum()?;
+        while let Some(op) = self.next_op(&ops) {
+            let (left, right) = (value & 0xFFFF, self.sum()? & 0xFFFF);
+            let holds = match op {
+                "EQ" | "=" | "==" => left == right,
+                "NE" | "<>" | "!=" => left != right,
+                "LT" | "<" => left < right,
+                "LE" | "<=" => left <= right,
+                "GT" | ">" => left > right,
+                _ => left >= right,
+            };
+            value = if holds { 0xFFFF } else { 0 };
+        }
+        Ok(value)
+    }
+
+    fn sum(&mut self) -> Result<i32, ExprError> {
+        let mut value = self.product()?;
+        while let Some(op) = self.next_op(&["+", "-"]) {
+            let right = self.product()?;
+            value = if op == "+" {
+                value.wrapping_add(right)
+            } else {
+                value.wrapping_sub(right)
+            };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i32, ExprError> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.next_op(&["*", "/", "MOD", "SHL", "SHR", "<<", ">>"]) {
+            let right = self.unary()?;
+            value = match op {
+                "*" => value.wrapping_mul(right),
+                "/" | "MOD" if right == 0 => {
+                    return Err(ExprError::Syntax(String::from("division by zero")))
+                }
+                "/" => value / right,
+                "MOD" => value % right,
+                "SHL" | "<<" => (value & 0xFFFF) << (right & 0x1F).min(16),
+                _ => (value & 0xFFFF) >> (right & 0x1F).min(16),
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i32, ExprError> {
+        match self.next_op(&["-", "+", "~", "HIGH", "LOW", "NUL"]) {
+            Some("-") => Ok(self.unary()?.wrapping_neg()),
+            Some("NUL") => {
+                let empty = self.position == self.tokens.len();
+                self.position = self.tokens.len();
+                Ok(if empty { 0xFFFF } else { 0 })
+            }
+            Some("~") => Ok(!self.unary()? & 0xFFFF),
+            Some("HIGH") => Ok((self.unary()? >> 8) & 0xFF),
+            Some("LOW") => Ok(self.unary()? & 0xFF),
+            Some(_) => self.unary(),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i32, ExprError> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Here) => Ok(i32::from(self.here)),
+            Some(Token::Name(name)) => (self.lookup)(&name)
+                .map(i32::from)
+                .ok_or(ExprError::Undefined(name)),
+            Some(Token::Text(bytes)) => match bytes[..] {
+                [c] => Ok(i32::from(c)),
+                [high, low] => Ok(i32::from(high) << 8 | i32::from(low)),
+                _ => Err(ExprError::Syntax(String::from(
+                    "character values are one or two characters",
+                ))),
+            },
+            Some(Token::Open) => {
+                let value = self.or()?;
+                match self.tokens.get(self.position) {
+                    Some(Token::Close) => {
+                        self.position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(ExprError::Syntax(String::from("missing )"))),
+                }
+            }
+            Some(Token::Op(op)) => Err(ExprError::Syntax(format!("unexpected {}", op))),
+            Some(Token::Close) => Err(ExprError::Syntax(String::from("unexpected )"))),
+            None => Err(ExprError::Syntax(String::from("expression ends too soon"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Result<i32, ExprError> {
+        let lookup = |name: &str| match name.to_uppercase().as_str() {
+            "SCREEN" => Some(0x2400),
+            "WIDTH" => Some(32),
+            _ => None,
+        };
+        evaluate(text, 0x0100, &lookup)
+    }
+
+    #[test]
+    fn test_numbers_and_operators() {
+        assert_eq!(value("0FFH"), Ok(0xFF));
+        assert_eq!(
+            value("1010B + 17O + 10D + 0x10 + $10"),
+            Ok(10 + 15 + 10 + 16 + 16)
+        );
+        assert_eq!(value("$ + 3"), Ok(0x103));
+        assert_eq!(value("screen + width * 2"), Ok(0x2440));
+        assert_eq!(value("(screen + 1) SHR 8"), Ok(0x24));
+        assert_eq!(value("HIGH screen OR LOW 1234H"), Ok(0x34 | 0x24));
+        assert_eq!(value("'A' + 1"), Ok(0x42));
+        assert_eq!(value("'AB'"), Ok(0x4142));
+        assert_eq!(value("''''"), Ok(0x27));
+        assert_eq!(value("width EQ 32 AND 5"), Ok(5));
+        assert_eq!(value("NOT 0"), Ok(0xFFFF));
+        assert_eq!(value("-1"), Ok(-1));
+        assert_eq!(value("7 MOD 4 + 7 % 4"), Ok(6));
+        assert_eq!(value("NUL"), Ok(0xFFFF));
+        assert_eq!(value("NOT NUL screen + 2"), Ok(0xFFFF));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            value("nowhere + 1"),
+            Err(ExprError::Undefined(String::from("nowhere")))
+        );
+        assert!(matches!(value("(1 + 2"), Err(ExprError::Syntax(_))));
+        assert!(matches!(value("1 / 0"), Err(ExprError::Syntax(_))));
+        assert!(matches!(value("12G"), Err(ExprError::Syntax(_))));
+        assert!(matches!(value("1 2"), Err(ExprError::Syntax(_))));
+        assert!(matches!(value(""), Err(ExprError::Syntax(_))));
+    }
+}
@@ -0,0 +1,577 @@
+use super::expr::{is_name_char, is_name_start};
+use super::parse::{split_arguments, Statement};
+use super::{canonical, Assembler, Frame, SymbolKind, MAX_DEPTH};
+use std::rc::Rc;
+
+// MACRO, REPT, IRP and IRPC collect the lines up to their ENDM. A MACRO keeps them to be expanded
+// wherever it's called. The others expand them straight away.
+const BLOCKS: [&str; 4] = ["MACRO", "REPT", "IRP", "IRPC"];
+// After canonical() has turned COND, IFT and IFF into these.
+const CONDITIONS: [&str; 10] = [
+    "IF", "IFE", "IF1", "IF2", "IFDEF", "IFNDEF", "IFB", "IFNB", "IFIDN", "IFDIF",
+];
+const CONTROLS: [&str; 8] = [
+    "REPT", "IRP", "IRPC", "ENDM", "EXITM", "LOCAL", "ELSE", "ENDIF",
+];
+
+pub(super) struct Macro {
+    // In upper case.
+    params: Vec<String>,
+    lines: Vec<String>,
+}
+
+enum BlockKind {
+    Macro(String, Vec<String>),
+    Rept(u16),
+    Irp(String, Vec<String>),
+    Irpc(String, String),
+}
+
+pub(super) struct Block {
+    kind: BlockKind,
+    lines: Vec<String>,
+    // Blocks inside this one, whose ENDMs aren't this one's.
+    depth: usize,
+    file: Rc<str>,
+    line: usize,
+}
+
+pub(super) struct Condition {
+    // Whether lines are being assembled, which needs the IFs around this one to be true too.
+    active: bool,
+    // A branch has been assembled already, or the IF around this one is false, so an ELSE
+    // mustn't start assembling.
+    done: bool,
+    seen_else: bool,
+    file: Rc<str>,
+    line: usize,
+}
+
+impl Assembler {
+    pub(super) fn active(&self) -> bool {
+        self.conditions
+            .last()
+            .is_none_or(|condition| condition.active)
+    }
+
+    // Inside a block lines are kept rather than assembled. Says whether this one was.
+    pub(super) fn collect(&mut self, text: &str) -> Result<bool, String> {
+        if self.block.is_none() {
+            return Ok(false);
+        }
+        let op = self.op_of(text);
+        let block = match self.block.as_mut() {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        match op.as_deref() {
+            Some(op) if BLOCKS.contains(&op) => block.depth += 1,
+            Some("ENDM") if block.depth == 0 => {
+                if let Some(block) = self.block.take() {
+                    self.finish(block)?;
+                }
+                return Ok(true);
+            }
+            Some("ENDM") => block.depth -= 1,
+            _ => {}
+        }
+        block.lines.push(text.to_string());
+        Ok(true)
+    }
+
+    // Inside a false IF only the lines that end it matter. Says whether this one was skipped.
+    pub(super) fn skip(&mut self, text: &str) -> Result<bool, String> {
+        if self.active() {
+            return Ok(false);
+        }
+        match self.op_of(text).as_deref() {
+            Some(op) if CONDITIONS.contains(&op) => self.push_condition(false),
+            Some("ELSE") => self.flip_condition()?,
+            Some("ENDIF") => self.pop_condition()?,
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    // Blocks, conditions and macro calls. Says whether the statement was one of them.
+    pub(super) fn control(&mut self, statement: &Statement) -> Result<bool, String> {
+        let upper = match statement.op {
+            Some(op) => op.to_uppercase(),
+            None => return Ok(false),
+        };
+        let op = canonical(&upper);
+        let arguments = split_arguments(statement.operand_text);
+        if op == "MACRO" {
+            // Collected even when it's wrong, so its lines aren't assembled.
+            let name = statement.label.map(str::to_uppercase);
+            let params = parameters(&arguments);
+            let kind = BlockKind::Macro(
+                name.clone().unwrap_or_default(),
+                params.clone().unwrap_or_default(),
+            );
+            self.open(kind);
+            name.ok_or("MACRO needs a name")?;
+            return params.map(|_| true);
+        }
+        let call = self.macros.get(op).cloned();
+        if call.is_none() && !CONTROLS.contains(&op) && !CONDITIONS.contains(&op) {
+            return Ok(false);
+        }
+        if let Some(label) = statement.label {
+            self.define(label, self.pc, SymbolKind::Label)?;
+        }
+        if let Some(called) = call {
+            self.call(op, &called, &arguments)?;
+            return Ok(true);
+        }
+        match op {
+            "REPT" => {
+                let count = match &statement.operands[..] {
+                    [count] => self.layout_value(count),
+                    _ => Err(String::from("REPT takes one operand")),
+                };
+                self.open(BlockKind::Rept(*count.as_ref().unwrap_or(&0)));
+                count?;
+            }
+            "IRP" | "IRPC" => {
+                let param = arguments
+                    .first()
+                    .ok_or_else(|| format!("{} needs a parameter", op))
+                    .and_then(|param| parameters(std::slice::from_ref(param)));
+                let name = param.clone().unwrap_or_default().concat();
+                let values = &arguments[arguments.len().min(1)..];
+                if op == "IRPC" {
+                    self.open(BlockKind::Irpc(name, values.join(",")));
+                } else if let [list] = values {
+                    self.open(BlockKind::Irp(name, split_arguments(list)));
+                } else {
+                    self.open(BlockKind::Irp(name, values.to_vec()));
+                }
+                param?;
+            }
+            "ENDM" => return Err(String::from("ENDM without MACRO, REPT or IRP")),
+            "EXITM" => self.exit_macro()?,
+            "LOCAL" => {
+                return Err(String::from(
+                    "LOCAL has to come straight after MACRO, REPT or IRP",
+                ))
+            }
+            "ELSE" => self.flip_condition()?,
+            "ENDIF" => self.pop_condition()?,
+            _ => {
+                // An IF that can't be worked out is taken as false, so its ENDIF still matches.
+                let holds = self.condition(op, statement);
+                self.push_condition(*holds.as_ref().unwrap_or(&false));
+                holds?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Blocks and conditions still open at the end of the source.
+    pub(super) fn unclosed(&mut self) {
+        if let Some(block) = self.block.take() {
+            let op = match block.kind {
+                BlockKind::Macro(..) => "MACRO",
+                BlockKind::Rept(_) => "REPT",
+                BlockKind::Irp(..) => "IRP",
+                BlockKind::Irpc(..) => "IRPC",
+            };
+            self.report(&block.file, block.line, format!("{} without ENDM", op));
+        }
+        for condition in std::mem::take(&mut self.conditions) {
+            self.report(
+                &condition.file,
+                condition.line,
+                String::from("IF without ENDIF"),
+            );
+        }
+    }
+
+    // Just the op, in its canonical form, for lines that are only being looked at to find the
+    // end of a block or condition.
+    fn op_of(&self, text: &str) -> Option<String> {
+        let statement = self.split(text).ok()?;
+        Some(canonical(&statement.op?.to_uppercase()).to_string())
+    }
+
+    fn open(&mut self, kind: BlockKind) {
+        let (file, line) = self.location.clone();
+        self.block = Some(Block {
+            kind,
+            lines: Vec::new(),
+            depth: 0,
+            file,
+            line,
+        });
+    }
+
+    fn finish(&mut self, block: Block) -> Result<(), String> {
+        let each = |param: String, values: Vec<String>| {
+            values
+                .into_iter()
+                .map(|value| vec![(param.clone(), value)])
+                .collect()
+        };
+        match block.kind {
+            BlockKind::Macro(name, params) => {
+                let lines = block.lines;
+                self.macros.insert(name, Rc::new(Macro { params, lines }));
+                Ok(())
+            }
+            BlockKind::Rept(count) => self.expand(&block.lines, vec![Vec::new(); count.into()]),
+            BlockKind::Irp(param, values) => self.expand(&block.lines, each(param, values)),
+            BlockKind::Irpc(param, chars) => {
+                let values = chars.chars().map(String::from).collect();
+                self.expand(&block.lines, each(param, values))
+            }
+        }
+    }
+
+    // Arguments left out are empty. One that starts with % is replaced by its value.
+    fn call(&mut self, name: &str, called: &Macro, arguments: &[String]) -> Result<(), String> {
+        if arguments.len() > called.params.len() {
+            return Err(format!(
+                "{} takes {} arguments, not {}",
+                name,
+                called.params.len(),
+                arguments.len()
+            ));
+        }
+        let mut substitutions = Vec::new();
+        for (i, param) in called.params.iter().enumerate() {
+            let argument = arguments.get(i).cloned().unwrap_or_default();
+            let argument = match argument.strip_prefix('%') {
+                Some(expression) => (self.value(expression)? as u16).to_string(),
+                None => argument,
+            };
+            substitutions.push((param.clone(), argument));
+        }
+        self.expand(&called.lines, vec![substitutions])
+    }
+
+    // Once through the lines for each set of substitutions, each time with new names for the
+    // LOCAL labels. The expansion is read before the rest of the current file.
+    fn expand(
+        &mut self,
+        lines: &[String],
+        iterations: Vec<Vec<(String, String)>>,
+    ) -> Result<(), String> {
+        if self.stack.len() > MAX_DEPTH {
+            return Err(String::from("macros are nested too deeply"));
+        }
+        let mut locals = Vec::new();
+        let mut body = Vec::new();
+        for line in lines {
+            let statement = self.split(line).unwrap_or_default();
+            match statement.op {
+                Some(op) if op.eq_ignore_ascii_case("LOCAL") && body.is_empty() => {
+                    locals.extend(parameters(&split_arguments(statement.operand_text))?)
+                }
+                _ => body.push(line),
+            }
+        }
+        let mut expanded = Vec::new();
+        for mut substitutions in iterations {
+            for local in &locals {
+                self.generated += 1;
+                substitutions.push((local.clone(), format!("??{:04}", self.generated)));
+            }
+            expanded.extend(body.iter().map(|line| substitute(line, &substitutions)));
+        }
+        let frame = self.stack.last().ok_or("nothing to expand into")?;
+        let frame = Frame {
+            path: frame.path.clone(),
+            name: frame.name.clone(),
+            lines: Rc::new(expanded),
+            next: 0,
+            expansion: Some(self.location.1),
+            conditions: self.conditions.len(),
+        };
+        self.stack.push(frame);
+        Ok(())
+    }
+
+    // Drops the rest of the innermost expansion, and any IFs it opened.
+    fn exit_macro(&mut self) -> Result<(), String> {
+        let index = self
+            .stack
+            .iter()
+            .rposition(|frame| frame.expansion.is_some())
+            .ok_or("EXITM outside a macro")?;
+        self.conditions.truncate(self.stack[index].conditions);
+        self.stack.truncate(index);
+        Ok(())
+    }
+
+    fn condition(&self, op: &str, statement: &Statement) -> Result<bool, String> {
+        let operand = statement.operand_text;
+        let defined = |name: &str| -> Result<bool, String> {
+            let key = self.qualify(name)?.to_uppercase();
+            // Only earlier in this pass, so both passes go the same way.
+            Ok(self
+                .symbols
+                .get(&key)
+                .is_some_and(|symbol| symbol.pass == self.pass))
+        };
+        let arguments = split_arguments(operand);
+        Ok(match op {
+            "IF" => self.evaluate(operand, true)? != 0,
+            "IFE" => self.evaluate(operand, true)? == 0,
+            "IF1" => self.pass == 1,
+            "IF2" => self.pass == 2,
+            "IFDEF" => defined(operand)?,
+            "IFNDEF" => !defined(operand)?,
+            "IFB" => arguments.first().is_none_or(|argument| argument.is_empty()),
+            "IFNB" => arguments
+                .first()
+                .is_some_and(|argument| !argument.is_empty()),
+            _ => match &arguments[..] {
+                [first, second] => (first == second) == (op == "IFIDN"),
+                _ => return Err(format!("{} compares two arguments", op)),
+            },
+        })
+    }
+
+    fn push_condition(&mut self, holds: bool) {
+        let outer = self.active();
+        let (file, line) = self.location.clone();
+        self.conditions.push(Condition {
+            active: outer && holds,
+            done: !outer || holds,
+            seen_else: false,
+            file,
+            line,
+        });
+    }
+
+    fn flip_condition(&mut self) -> Result<(), String> {
+        let condition = self.conditions.last_mut().ok_or("ELSE without IF")?;
+        if condition.seen_else {
+            return Err(String::from("a second ELSE for the same IF"));
+        }
+        condition.seen_else = true;
+        condition.active = !condition.done;
+        condition.done = true;
+        Ok(())
+    }
+
+    fn pop_condition(&mut self) -> Result<(), String> {
+        self.conditions.pop().ok_or("ENDIF without IF")?;
+        Ok(())
+    }
+}
+
+fn parameters(arguments: &[String]) -> Result<Vec<String>, String> {
+    arguments
+        .iter()
+        .map(|name| {
+            let valid = name.starts_with(is_name_start) && name.chars().all(is_name_char);
+            if valid {
+                Ok(name.to_uppercase())
+            } else {
+                Err(format!("{} isn't a valid parameter name", name))
+            }
+        })
+        .collect()
+}
+
+// Puts arguments in place of parameter names, which come in upper case. Outside quotes any
+// use of a name is replaced. Inside them only one joined on with & is. The & that joins a name
+// to what's next to it goes. So do ;; comments, which are only for the macro's own source.
+fn substitute(line: &str, substitutions: &[(String, String)]) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut out = String::new();
+    let mut quote = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if quote.is_none() && c == ';' {
+            if chars.get(i + 1) == Some(&';') {
+                return out.trim_end().to_string();
+            }
+            out.extend(&chars[i..]);
+            break;
+        }
+        let starts_name = is_name_start(c) && (i == 0 || !is_name_char(chars[i - 1]));
+        if !starts_name {
+            match quote {
+                Some(q) if c == q => quote = None,
+                None if c == '\'' || c == '"' => quote = Some(c),
+                _ => {}
+            }
+            out.push(c);
+            i += 1;
+            continue;
+        }
+        let end = (i..chars.len())
+            .find(|&j| !is_name_char(chars[j]))
+            .unwrap_or(chars.len());
+        let name: String = chars[i..end].iter().collect();
+        let joined_before = out.ends_with('&');
+        let joined_after = chars.get(end) == Some(&'&');
+        let found = substitutions
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(&name));
+        match found {
+            Some((_, value)) if quote.is_none() || joined_before || joined_after => {
+                if joined_before {
+                    out.pop();
+                }
+                out.push_str(value);
+                i = if joined_after { end + 1 } else { end };
+            }
+            _ => {
+                out.push_str(&name);
+                i = end;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn binary(source: &str) -> Vec<u8> {
+        match assemble("test.asm", source) {
+            Ok(assembly) => assembly.binary(),
+            Err(errors) => panic!("{:?}", errors),
+        }
+    }
+
+    #[test]
+    fn test_substitute() {
+        let substitutions = [
+            (String::from("REG"), String::from("B")),
+            (String::from("N"), String::from("3")),
+        ];
+        assert_eq!(
+            substitute("L&N:  MVI reg,N ; reg", &substitutions),
+            "L3:  MVI B,3 ; reg"
+        );
+        assert_eq!(
+            substitute("  DB 'reg &N', 'N&'  ;; gone", &substitutions),
+            "  DB 'reg 3', '3'"
+        );
+        assert_eq!(
+            substitute("  DW regs, N.x", &substitutions),
+            "  DW regs, N.x"
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "
+; Loads a pair, or clears it when there's no value.
+load    MACRO pair, value
+        LOCAL skip
+        IFB <value>
+        LXI pair,0
+        EXITM
+        ENDIF
+        LXI pair,value
+        JMP skip
+skip:
+        ENDM
+
+        load H, 1234H
+        load D
+        REPT 2
+        NOP
+        ENDM
+        IRP r,<B,C>
+        INR r
+        ENDM
+        IRPC c,AB
+        DB '&c'
+        ENDM";
+        assert_eq!(
+            binary(source),
+            vec![
+                0x21, 0x34, 0x12, 0xC3, 0x06, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x04, 0x0C, 0x41,
+                0x42
+            ]
+        );
+    }
+
+    #[test]
+    fn test_conditions() {
+        let source = "
+DEBUG   EQU 0
+        IF DEBUG
+        HLT
+        IF 1
+        HLT
+        ENDIF
+        ELSE
+        NOP
+        ENDIF
+        IFNDEF later
+        DB 1
+        ENDIF
+        IFIDN <a>,<a>
+        DB 2
+        ENDIF
+        COND NOT DEBUG
+        DEFB 3
+        ENDC
+        IFE DEBUG
+        DB 4
+        ENDIF
+later:  DC 'AB'";
+        assert_eq!(
+            binary(source),
+            vec![0x00, 0x01, 0x02, 0x03, 0x04, 0x41, 0xC2]
+        );
+    }
+
+    #[test]
+    fn test_mac_syntax() {
+        let source = "
+* A MAC-style comment
+        PUSH H! PUSH D! MVI A,'!'
+count   DEFL 1
+count   DEFL count+1
+        TITLE 'ignored'
+        DB count";
+        assert_eq!(binary(source), vec![0xE5, 0xD5, 0x3E, 0x21, 0x02]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = assemble(
+            "bad.asm",
+            "        ENDM
+        ELSE
+        IF 1
+        ELSE
+        ELSE
+        ENDIF
+        EXITM
+twice   MACRO
+        NOP
+        ENDM
+        twice 1
+        IF 1
+        REPT 2",
+        )
+        .err()
+        .unwrap();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "bad.asm:1: ENDM without MACRO, REPT or IRP",
+                "bad.asm:2: ELSE without IF",
+                "bad.asm:5: a second ELSE for the same IF",
+                "bad.asm:7: EXITM outside a macro",
+                "bad.asm:11: TWICE takes 0 arguments, not 1",
+                "bad.asm:12: IF without ENDIF",
+                "bad.asm:13: REPT without ENDM",
+            ]
+        );
+    }
+}
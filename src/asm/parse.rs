@@ -0,0 +1,241 @@
+use super::expr::{is_name_char, is_name_start, quoted};
+
+// One source line taken apart: [label[:]] [op [operand, ...]] [; comment]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Statement<'a> {
+    pub label: Option<&'a str>,
+    pub op: Option<&'a str>,
+    pub operands: Vec<&'a str>,
+    // The operands before they were split, for macro arguments.
+    pub operand_text: &'a str,
+}
+
+// is_op says whether a word is an instruction or directive. A word in the first column is a
+// label unless it's one of those, so labels don't need their colons there. A name in front of
+// EQU, SET and the like is taken as a label wherever it starts, and so is anything with a colon.
+// M80 marks public labels with two colons, which makes no difference here.
+pub fn split<'a>(
+    text: &'a str,
+    is_op: &dyn Fn(&str) -> bool,
+    names_next: &dyn Fn(&str) -> bool,
+) -> Result<Statement<'a>, String> {
+    let code = strip_comment(text)?;
+    let mut statement = Statement::default();
+    let mut rest = code.trim_start();
+    if rest.is_empty() {
+        return Ok(statement);
+    }
+    let first_column = !code.starts_with(char::is_whitespace);
+    let (first, after) = word(rest);
+    let (second, _) = word(after.trim_start());
+    if let Some(label) = first.strip_suffix("::").or_else(|| first.strip_suffix(':')) {
+        statement.label = Some(check_name(label)?);
+        rest = after.trim_start();
+    } else if names_next(second) || (first_column && !is_op(first)) {
+        statement.label = Some(check_name(first)?);
+        rest = after.trim_start();
+    }
+    if rest.is_empty() {
+        return Ok(statement);
+    }
+    let (op, after) = word(rest);
+    statement.op = Some(op);
+    statement.operand_text = after.trim();
+    statement.operands = split_operands(statement.operand_text);
+    Ok(statement)
+}
+
+// The first word, ending at white space or just after a colon or two. Everything after it comes
+// back too.
+fn word(text: &str) -> (&str, &str) {
+    let end = text
+        .char_indices()
+        .find_map(|(i, c)| match c {
+            c if c.is_whitespace() => Some(i),
+            ':' if text[i + 1..].starts_with(':') => None,
+            ':' => Some(i + 1),
+            _ => None,
+        })
+        .unwrap_or(text.len());
+    text.split_at(end)
+}
+
+fn check_name(name: &str) -> Result<&str, String> {
+    let valid = name.starts_with(is_name_start) && name.chars().all(is_name_char);
+    if valid {
+        Ok(name)
+    } else {
+        Err(format!("{} isn't a valid label", name))
+    }
+}
+
+// Everything before a ; that isn't inside quotes. A * in the first column makes the whole line
+// a comment, as in MAC.
+fn strip_comment(text: &str) -> Result<&str, String> {
+    if text.starts_with('*') {
+        return Ok("");
+    }
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i].1 {
+            ';' => return Ok(&text[..chars[i].0]),
+            '\'' | '"' => {
+                let rest: Vec<char> = chars[i..].iter().map(|(_, c)| *c).collect();
+                let (_, length) = quoted(&rest).ok_or("unterminated string")?;
+                i += length;
+            }
+            _ => i += 1,
+        }
+    }
+    Ok(text)
+}
+
+// Splits at commas that aren't inside quotes or brackets.
+pub fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+// Macro arguments split like operands, except that one in <> can have commas in it. The <>
+// come off.
+pub fn split_arguments(text: &str) -> Vec<String> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut arguments = Vec::new();
+    let mut depth = 0;
+    let mut brackets = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        let starting = text[start..i].trim().is_empty();
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '<') if starting || brackets > 0 => brackets += 1,
+            (None, '>') if brackets > 0 => brackets -= 1,
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 && brackets == 0 => {
+                arguments.push(argument(&text[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    arguments.push(argument(&text[start..]));
+    arguments
+}
+
+fn argument(text: &str) -> String {
+    let text = text.trim();
+    match text
+        .strip_prefix('<')
+        .and_then(|text| text.strip_suffix('>'))
+    {
+        Some(inner) => inner.to_string(),
+        None => text.to_string(),
+    }
+}
+
+// MAC puts several statements on one line with ! between them.
+pub fn statements(text: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ';') => break,
+            (None, '!') => {
+                statements.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&text[start..]);
+    statements
+}
+
+// The bytes of an operand that's nothing but a quoted string.
+pub fn string_operand(text: &str) -> Option<Vec<u8>> {
+    let chars: Vec<char> = text.chars().collect();
+    match chars.first() {
+        Some('\'' | '"') => {
+            let (bytes, length) = quoted(&chars)?;
+            Some(bytes).filter(|_| length == chars.len())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Statement<'_> {
+        let is_op = |word: &str| ["MVI", "DB", "NOP"].contains(&word.to_uppercase().as_str());
+        let names_next = |word: &str| ["EQU", "SET"].contains(&word.to_uppercase().as_str());
+        split(text, &is_op, &names_next).unwrap()
+    }
+
+    #[test]
+    fn test_split() {
+        let statement = parse("start:  MVI A,'; ' ; comment");
+        assert_eq!(statement.label, Some("start"));
+        assert_eq!(statement.op, Some("MVI"));
+        assert_eq!(statement.operands, vec!["A", "'; '"]);
+
+        assert_eq!(parse("loop NOP").label, Some("loop"));
+        assert_eq!(parse("NOP").label, None);
+        assert_eq!(parse("  count EQU 5").label, Some("count"));
+        assert_eq!(
+            parse("next:DB 1, (2,3), 'a,b'").operands,
+            vec!["1", "(2,3)", "'a,b'"]
+        );
+        assert_eq!(parse("   ; nothing"), Statement::default());
+        assert_eq!(string_operand("'it''s'"), Some(b"it's".to_vec()));
+        assert_eq!(string_operand("'a'+1"), None);
+        assert_eq!(parse("public:: NOP").label, Some("public"));
+        assert_eq!(parse("* MAC comment"), Statement::default());
+    }
+
+    #[test]
+    fn test_arguments_and_statements() {
+        assert_eq!(
+            split_arguments("<1, 2>, 'a,b', x<3, (4,5), <<6>,7>"),
+            vec!["1, 2", "'a,b'", "x<3", "(4,5)", "<6>,7"]
+        );
+        assert_eq!(split_arguments("a,,"), vec!["a", "", ""]);
+        assert_eq!(
+            statements("PUSH H! MVI A,'!' ! RET ; done!"),
+            vec!["PUSH H", " MVI A,'!' ", " RET ; done!"]
+        );
+    }
+}
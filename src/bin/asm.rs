@@ -0,0 +1,101 @@
+use emu8080::asm;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "\
+usage: asm [options] source.asm
+  -o file       where the binary goes (default: the source with .bin in place of .asm). It runs
+                from the lowest address the program uses to the highest
+  --hex file    write Intel HEX as well
+  --srec file   write Motorola S-records as well
+  --list file   write a listing, which the debugger can use for source-level debugging
+  --sym file    write the labels as a symbol file
+e.g. asm -o game.bin --list game.lst game.asm";
+
+fn main() {
+    let mut output = None;
+    let mut hex = None;
+    let mut srec = None;
+    let mut list = None;
+    let mut sym = None;
+    let mut source = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "--hex" => hex = Some(args.next().unwrap_or_else(|| usage())),
+            "--srec" => srec = Some(args.next().unwrap_or_else(|| usage())),
+            "--list" => list = Some(args.next().unwrap_or_else(|| usage())),
+            "--sym" => sym = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if source.is_none() => source = Some(arg),
+            _ => usage(),
+        }
+    }
+    let source = source.unwrap_or_else(|| usage());
+
+    let assembly = asm::assemble_file(&source).unwrap_or_else(|errors| {
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        fail(&format!("{} errors", errors.len()));
+    });
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension("bin")
+            .to_string_lossy()
+            .into_owned()
+    });
+    write(&output, |out| out.write_all(&assembly.binary()));
+    if let Some(path) = hex {
+        write(&path, |out| assembly.write_hex(out));
+    }
+    if let Some(path) = srec {
+        write(&path, |out| assembly.image().write_srecords(out));
+    }
+    if let Some(path) = list {
+        write(&path, |out| assembly.write_listing(out));
+    }
+    if let Some(path) = sym {
+        write(&path, |out| assembly.write_symbols(out));
+    }
+    if let Some(extent) = assembly.extent() {
+        println!(
+            "{:04X}-{:04X}, {} bytes",
+            extent.start(),
+            extent.end(),
+            assembly.bytes().len()
+        );
+    }
+}
+
+fn write<F>(path: &str, contents: F)
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let result = File::create(path).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        contents(&mut out)?;
+        out.flush()
+    });
+    if let Err(e) = result {
+        fail(&format!("Couldn't write {}: {}", path, e));
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -0,0 +1,34 @@
+use emu8080::dap;
+
+use std::io::{self, BufReader};
+use std::sync::mpsc;
+use std::thread;
+
+// A Debug Adapter Protocol server on stdin and stdout. In VS Code, point a debugger
+// contribution's "program" at this binary and use a launch configuration like:
+//   { "type": "emu8080", "request": "launch", "program": "hello.com", "listing": "hello.lst" }
+// Logging goes to stderr, so RUST_LOG=debug shows the traffic.
+fn main() {
+    env_logger::init();
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(io::stdin());
+        loop {
+            match dap::read_message(&mut input) {
+                Ok(Some(message)) => {
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    break;
+                }
+            }
+        }
+    });
+    if let Err(e) = dap::serve(requests, io::stdout()) {
+        eprintln!("{}", e);
+    }
+}
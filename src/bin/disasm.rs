@@ -0,0 +1,239 @@
+use emu8080::debugger::parse_number;
+use emu8080::disasm::analysis::Analysis;
+use emu8080::disasm::graph::ControlFlow;
+use emu8080::disasm::xref::{Access, CrossReference};
+use emu8080::disasm::{self, Syntax};
+use emu8080::image::Image;
+use emu8080::machine::invaders::{self, Invaders};
+use emu8080::machine::Machine;
+use emu8080::symbols::SymbolTable;
+use emu8080::trace::parse_symbolic_range;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::process;
+
+const USAGE: &str = "\
+usage: disasm [options] rom...
+  --base addr        where the first ROM loads (default 0). Each further ROM follows the last.
+                     Intel HEX (.hex) and S-record (.s19) files go where they say instead
+  --range start-end  addresses to disassemble (default: everything loaded)
+  --sym file         name addresses with labels from a symbol file or listing
+  --zilog            Z80 mnemonics instead of Intel's, in the listing, --trace source and
+                     --cfg graphs. --xref and --calls show no instructions
+  --plain            leave out the address and byte columns
+  --trace            follow the code from the entry points, and write the rest as data. The
+                     output assembles back to the same bytes
+  --entry addr       where code starts, for --trace (default: the start of the range, and the
+                     RST vectors in it). Can be given more than once
+  --data start-end   bytes --trace shouldn't take for code. Can be given more than once
+  --cfg file         trace, and write each routine's control-flow graph to file in Graphviz
+                     DOT form: its basic blocks with the branches, jumps and calls between them
+  --routine addr     only graph the routine starting here, for --cfg. Can be given more than
+                     once
+  --calls file       trace, and write the call graph of every routine to file in DOT form
+  --xref             trace, and list every address the code jumps to, calls, reads, writes or
+                     loads, with where from, and the routines using each I/O port
+  --invaders         also trace from Space Invaders' game object handlers, which are only
+                     reached through RAM. For --xref, name its ports and split RAM from video
+                     RAM
+e.g. disasm src/roms/invaders.h src/roms/invaders.g src/roms/invaders.f src/roms/invaders.e";
+
+fn main() {
+    let mut base = 0;
+    let mut range = None;
+    let mut syntax = Syntax::Intel;
+    let mut plain = false;
+    let mut trace = false;
+    let mut entries = Vec::new();
+    let mut data = Vec::new();
+    let mut cfg = None;
+    let mut routines = Vec::new();
+    let mut calls = None;
+    let mut xref = false;
+    let mut invaders = false;
+    let mut symbols = SymbolTable::new();
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => {
+                base = args
+                    .next()
+                    .and_then(|arg| parse_number(&arg))
+                    .unwrap_or_else(|| usage())
+            }
+            "--range" => range = Some(args.next().unwrap_or_else(|| usage())),
+            "--sym" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                if let Err(e) = symbols.load(&path) {
+                    fail(&format!("Couldn't read {}: {}", path, e));
+                }
+            }
+            "--zilog" => syntax = Syntax::Zilog,
+            "--plain" => plain = true,
+            "--trace" => trace = true,
+            "--entry" => entries.push(args.next().unwrap_or_else(|| usage())),
+            "--data" => data.push(args.next().unwrap_or_else(|| usage())),
+            "--cfg" => cfg = Some(args.next().unwrap_or_else(|| usage())),
+            "--routine" => routines.push(args.next().unwrap_or_else(|| usage())),
+            "--calls" => calls = Some(args.next().unwrap_or_else(|| usage())),
+            "--xref" => xref = true,
+            "--invaders" => invaders = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+
+    let mut image = Image::new();
+    let mut end = u32::from(base);
+    for path in &paths {
+        if end > 0xFFFF {
+            fail(&format!("{} runs past FFFF", path));
+        }
+        let loaded = Image::load(path, end as u16)
+            .and_then(|loaded| image.merge(&loaded).map(|_| loaded))
+            .unwrap_or_else(|e| fail(&format!("Couldn't load {}: {}", path, e)));
+        if let Some(extent) = loaded.extent() {
+            end = u32::from(*extent.end()) + 1;
+        }
+    }
+    let extent = match image.extent() {
+        Some(extent) => extent,
+        None => return,
+    };
+    let mut memory = vec![0u8; 0x10000];
+    for (addr, byte) in image.bytes() {
+        memory[usize::from(*addr)] = *byte;
+    }
+    let range = match range {
+        Some(arg) => parse_symbolic_range(&arg, &symbols)
+            .unwrap_or_else(|| fail(&format!("{} isn't an address range", arg))),
+        None => extent,
+    };
+
+    if trace || xref || cfg.is_some() || calls.is_some() {
+        let mut analysis = Analysis::new(&memory, range.clone());
+        analysis.set_syntax(syntax);
+        if entries.is_empty() {
+            analysis.add_entry(*range.start());
+            analysis.add_rst_vectors();
+        }
+        for arg in &entries {
+            let addr = symbols
+                .resolve(arg)
+                .unwrap_or_else(|| fail(&format!("{} isn't an address", arg)));
+            analysis.add_entry(addr);
+        }
+        if invaders {
+            for addr in invaders::OBJECT_HANDLERS.iter() {
+                analysis.add_entry(*addr);
+            }
+        }
+        for arg in &data {
+            let range = parse_symbolic_range(arg, &symbols)
+                .unwrap_or_else(|| fail(&format!("{} isn't an address range", arg)));
+            analysis.add_data(range);
+        }
+        analysis.run();
+        if cfg.is_some() || calls.is_some() {
+            write_graphs(&analysis, &symbols, cfg, &routines, calls);
+        }
+        if xref {
+            let mut report = CrossReference::new(&analysis, &symbols);
+            if invaders {
+                report.set_memory_map(Invaders::new().memory_map());
+                for (port, name) in invaders::INPUT_PORTS.iter() {
+                    report.name_port(*port, Access::In, name);
+                }
+                for (port, name) in invaders::OUTPUT_PORTS.iter() {
+                    report.name_port(*port, Access::Out, name);
+                }
+            }
+            report
+                .write_report(&mut io::stdout())
+                .unwrap_or_else(|e| fail(&e.to_string()));
+        }
+        if trace {
+            analysis
+                .write_source(&mut io::stdout(), &symbols)
+                .unwrap_or_else(|e| fail(&e.to_string()));
+        }
+        return;
+    }
+
+    // Stops after the instruction that reaches the end of the range, so one straddling the end
+    // is shown whole.
+    let mut addr = u32::from(*range.start());
+    while addr <= u32::from(*range.end()) {
+        let instruction = disasm::disassemble(&memory, addr as u16);
+        if let Some(name) = symbols.name(instruction.address) {
+            println!("{}:", name);
+        }
+        if plain {
+            println!(
+                "        {}",
+                disasm::symbolic_text_in(&instruction, &symbols, syntax)
+            );
+        } else {
+            println!(
+                "{}",
+                disasm::format_symbolic_listing_in(&instruction, &symbols, syntax)
+            );
+        }
+        addr += u32::from(instruction.length);
+    }
+}
+
+fn write_graphs(
+    analysis: &Analysis,
+    symbols: &SymbolTable,
+    cfg: Option<String>,
+    routines: &[String],
+    calls: Option<String>,
+) {
+    let flow = ControlFlow::new(analysis, symbols);
+    let create = |path: &str| {
+        File::create(path)
+            .map(BufWriter::new)
+            .unwrap_or_else(|e| fail(&format!("Couldn't create {}: {}", path, e)))
+    };
+    if let Some(path) = cfg {
+        let heads: Vec<u16> = if routines.is_empty() {
+            flow.routines()
+        } else {
+            routines
+                .iter()
+                .map(|arg| {
+                    flow.resolve(arg)
+                        .or_else(|| symbols.resolve(arg))
+                        .filter(|addr| flow.routine(*addr).is_some())
+                        .unwrap_or_else(|| fail(&format!("No routine starts at {}", arg)))
+                })
+                .collect()
+        };
+        flow.write_dot(&mut create(&path), &heads)
+            .unwrap_or_else(|e| fail(&format!("Couldn't write {}: {}", path, e)));
+    }
+    if let Some(path) = calls {
+        flow.write_call_graph(&mut create(&path))
+            .unwrap_or_else(|e| fail(&format!("Couldn't write {}: {}", path, e)));
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -0,0 +1,105 @@
+use emu8080::debugger::{load_file_arg, Debugger};
+use emu8080::gdb::GdbStub;
+use emu8080::machine::invaders::{self, Invaders};
+use emu8080::machine::Bare;
+use emu8080::romset::{Source, Status};
+use emu8080::Cpu;
+
+use std::env;
+use std::net::TcpListener;
+
+const USAGE: &str = "usage: gdbserver [--port n] [--invaders [--roms path]] [file[@addr]...]";
+
+// Waits for gdb on a TCP port. Connect with:
+//   (gdb) set architecture z80
+//   (gdb) target remote localhost:1234
+// The emulator keeps its state between connections.
+fn main() {
+    env_logger::init();
+    let mut port = 1234;
+    let mut use_invaders = false;
+    let mut roms = String::from("src/roms");
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--invaders" => use_invaders = true,
+            "--roms" => match args.next() {
+                Some(path) => roms = path,
+                None => {
+                    eprintln!("{}", USAGE);
+                    return;
+                }
+            },
+            "--port" => match args.next().and_then(|port| port.parse().ok()) {
+                Some(number) => port = number,
+                None => {
+                    eprintln!("{}", USAGE);
+                    return;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    let mut cpu = Cpu::new();
+    if use_invaders && files.is_empty() {
+        if let Err(message) = load_invaders(&mut cpu, &roms) {
+            eprintln!("{}", message);
+            return;
+        }
+    }
+    for arg in files.iter() {
+        if let Err(message) = load_file_arg(&mut cpu, arg) {
+            eprintln!("{}", message);
+            return;
+        }
+    }
+    let mut debugger = if use_invaders {
+        Debugger::new(cpu, Box::new(Invaders::new()))
+    } else {
+        Debugger::new(cpu, Box::new(Bare))
+    };
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).expect("Couldn't listen for gdb.");
+    println!("Waiting for gdb on port {}", port);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        println!("gdb connected");
+        if let Err(e) = GdbStub::new(&mut debugger, stream).serve() {
+            eprintln!("Connection lost: {}", e);
+        }
+        println!("gdb disconnected");
+    }
+}
+
+// The Invaders set from a directory or zip, MAME style, for --invaders without files. Bad dumps
+// are logged but still load.
+fn load_invaders(cpu: &mut Cpu, path: &str) -> Result<(), String> {
+    let set = invaders::rom_set();
+    let source = Source::open(path, &set.name)
+        .map_err(|e| format!("Couldn't open the ROMs in {}: {}", path, e))?;
+    let statuses = set
+        .verify(&source)
+        .map_err(|e| format!("Couldn't read the ROMs in {}: {}", path, e))?;
+    for (rom, status) in set.roms.iter().zip(statuses) {
+        if status != Status::Good {
+            log::warn!("{}: {}", rom.name, status);
+        }
+    }
+    let image = set
+        .load(&source)
+        .map_err(|e| format!("Couldn't load the ROMs from {}: {}", path, e))?;
+    image.load_into(cpu);
+    Ok(())
+}
@@ -1,116 +1,125 @@
+use emu8080::coverage::Coverage;
+use emu8080::heatmap::Heatmap;
+use emu8080::hexview::{self, font, Cell, HexView, Key, Style};
+use emu8080::machine::invaders::{self, Button, Invaders};
+use emu8080::machine::{self, Machine};
+use emu8080::profile::Profiler;
+use emu8080::romset::{load_manifest, Source, Status};
+use emu8080::strict::Strict;
+use emu8080::symbols::SymbolTable;
+use emu8080::trace::{parse_cycle_window, parse_symbolic_range, Tracer};
 use emu8080::Cpu;
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
 use sdl2::EventPump;
-use std::fs::File;
-use std::io::prelude::*;
+use std::env;
 use std::time::Duration;
 use std::time::Instant;
 
 const HEIGHT: u32 = 224;
 const WIDTH: u32 = 256;
 
-struct Cabinet {
-    p0: u8,
-    p1: u8,
-    p2: u8,
-    p3: u8,
-    p4: u8,
-    p5: u8,
-    p6: u8,
-    p7: u8,
-    shift: u16,
-    offset: u8,
+// The memory window draws its 3x5 glyphs in 4x7 cells, at twice size.
+const VIEW_ROWS: usize = 32;
+const VIEW_COLUMNS: u32 = 72;
+const VIEW_SCALE: u32 = 2;
+const VIEW_MARGIN: u32 = 8;
+const CELL_WIDTH: u32 = (font::WIDTH + 1) * VIEW_SCALE;
+const CELL_HEIGHT: u32 = (font::HEIGHT + 2) * VIEW_SCALE;
+const VIEW_REFRESH: Duration = Duration::from_millis(16);
+
+// The memory window, hidden until F1 shows it.
+struct Viewer {
+    canvas: Canvas<Window>,
+    view: HexView,
+    shown: bool,
+    last_drawn: Instant,
 }
 
-// move game stuff inside of the impl Cabinet block. The game runs as part of the full cabinet implementation.
-
-impl Cabinet {
-    fn new() -> Self {
-        Cabinet {
-            // p0: 0xE,
-            p0: 0,
-            p1: 1,
-            // p1: 0x8,
-            p2: 0,
-            p3: 0,
-            p4: 0,
-            p5: 0,
-            p6: 0,
-            p7: 0,
-            shift: 0,
-            offset: 0,
+impl Viewer {
+    fn toggle(&mut self) {
+        if self.shown {
+            self.hide();
+        } else {
+            self.shown = true;
+            self.canvas.window_mut().show();
+            self.canvas.window_mut().raise();
         }
     }
 
-    fn input(&mut self, port: u8) -> u8 {
-        match port {
-            0 => self.p0,
-            1 => self.p1,
-            2 => self.p2,
-            3 => ((self.shift >> (8 - self.offset)) & 0xFF) as u8,
-            _ => panic!("Invalid port selection"),
-        }
+    fn hide(&mut self) {
+        self.shown = false;
+        self.canvas.window_mut().hide();
     }
+}
 
-    fn output(&mut self, port: u8, value: u8) {
-        match port {
-            2 => self.offset = value & 0x7,
-            3 => {
-                // sound stuff
-            }
-            4 => self.shift = (self.shift >> 8) | ((value as u16) << 8),
-            _ => {
-                // do nothing
-            }
-        }
+fn key_button(key: Keycode) -> Option<Button> {
+    match key {
+        Keycode::A => Some(Button::P1Left),
+        Keycode::Kp4 => Some(Button::P2Left),
+        Keycode::D => Some(Button::P1Right),
+        Keycode::Kp6 => Some(Button::P2Right),
+        Keycode::Space => Some(Button::P1Fire),
+        Keycode::Kp0 => Some(Button::P2Fire),
+        _ => None,
     }
+}
 
-    fn key_down(&mut self, key: Keycode) {
-        match key {
-            Keycode::A => self.p1 |= 0x20,
-            Keycode::Kp4 => self.p2 |= 0x20,
-            Keycode::D => self.p1 |= 0x40,
-            Keycode::Kp6 => self.p2 |= 0x40,
-            Keycode::Space => self.p1 |= 0x10,
-            Keycode::Kp0 => self.p2 |= 0x10,
-            _ => {
-                // do nothing
-            }
-        }
-    }
-
-    fn key_up(&mut self, key: Keycode) {
-        match key {
-            Keycode::A => self.p1 &= 0xDF,
-            Keycode::Kp4 => self.p2 &= 0xDF,
-            Keycode::D => self.p1 &= 0xBF,
-            Keycode::Kp6 => self.p2 &= 0xBF,
-            Keycode::Space => self.p1 &= 0xEF,
-            Keycode::Kp0 => self.p2 &= 0xEF,
-            _ => {
-                // do nothing
-            }
-        }
+// Keys the memory window acts on. Anything typed arrives as text instead.
+fn view_key(key: Keycode, keymod: Mod) -> Option<Key> {
+    match key {
+        Keycode::Up => Some(Key::Up),
+        Keycode::Down => Some(Key::Down),
+        Keycode::Left => Some(Key::Left),
+        Keycode::Right => Some(Key::Right),
+        Keycode::PageUp => Some(Key::PageUp),
+        Keycode::PageDown => Some(Key::PageDown),
+        Keycode::Home => Some(Key::Home),
+        Keycode::End => Some(Key::End),
+        Keycode::Tab => Some(Key::Tab),
+        Keycode::Return | Keycode::KpEnter => Some(Key::Enter),
+        Keycode::Escape => Some(Key::Escape),
+        Keycode::Backspace => Some(Key::Backspace),
+        Keycode::G if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => Some(Key::Goto),
+        _ => None,
     }
 }
 
 fn main() {
-    const NANOS_PER_SECOND: u64 = 1_000_000_000;
-    const CPU_SPEED: u64 = 2_000_000;
-    const NANOS_PER_CYCLE: u64 = NANOS_PER_SECOND / CPU_SPEED;
-    const VIDEO_INTERRUPT_TIMER: Duration = Duration::from_nanos(16667);
     env_logger::init();
+    let symbols = trace_symbols();
+    let mut tracer = trace_from_args(&symbols);
+    // --profile prefix writes prefix.txt and prefix.folded on exit.
+    let profile_prefix = env::args().skip_while(|arg| arg != "--profile").nth(1);
+    let mut profiler = profile_prefix.as_ref().map(|_| Profiler::new());
+    // --coverage prefix adds this session to prefix.cov and lists the ROM in prefix.lst on exit.
+    let coverage_prefix = env::args().skip_while(|arg| arg != "--coverage").nth(1);
+    let mut coverage = coverage_prefix.as_ref().map(|prefix| {
+        Coverage::load(&format!("{}.cov", prefix)).expect("Couldn't read the coverage map.")
+    });
+    // --heatmap prefix writes prefix.png and prefix.txt on exit, and logs writes to code or ROM
+    // as they happen.
+    let heatmap_prefix = env::args().skip_while(|arg| arg != "--heatmap").nth(1);
+    let mut heatmap = heatmap_prefix.as_ref().map(|_| {
+        let mut heatmap = Heatmap::new();
+        heatmap.add_rom(invaders::ROM_AREA);
+        heatmap
+    });
+    // --strict logs stack use in ROM or video RAM, code run from video or unmapped memory, and
+    // pc or sp wrapping round memory, each with a trace and call stack.
+    let strict_on = env::args().any(|arg| arg == "--strict");
     let sdl = sdl2::init().expect("Sdl failed to init. Big mistake.");
 
-    let mut cabinet = Cabinet::new();
+    let mut cabinet = Invaders::new();
+    let mut strict = strict_on.then(|| Strict::new(cabinet.memory_map()));
     let mut cpu = Cpu::new();
     read_space_invaders_into_memory(&mut cpu);
+    cpu.set_call_tracking(profiler.is_some() || strict.is_some());
 
     let mut event_pump = sdl
         .event_pump()
@@ -128,82 +137,232 @@ fn main() {
         .present_vsync()
         .build()
         .expect("window failed to init to canvas");
+    // F1 shows memory live as hex and ASCII, starting at the RAM. Bytes that just changed are
+    // highlighted. Typing hex digits, or text after Tab, edits memory as the game runs, and
+    // Ctrl+G goes to an address or --sym name.
+    let memory_window = video
+        .window(
+            "Memory",
+            VIEW_COLUMNS * CELL_WIDTH + 2 * VIEW_MARGIN,
+            (VIEW_ROWS as u32 + 2) * CELL_HEIGHT + 2 * VIEW_MARGIN,
+        )
+        .hidden()
+        .build()
+        .unwrap();
+    let mut viewer = Viewer {
+        canvas: memory_window
+            .into_canvas()
+            .accelerated()
+            .build()
+            .expect("memory window failed to init to canvas"),
+        view: HexView::new(VIEW_ROWS),
+        shown: false,
+        last_drawn: Instant::now(),
+    };
+    viewer.view.jump(*invaders::RAM.start());
 
-    let mut last_interrupt = Instant::now();
-    let mut last_cycle = Instant::now();
     let mut cycles_elapsed: u64 = 0;
 
-    let mut count = 0;
-
     'running: loop {
-        handle_events(&cpu, &mut cabinet, &mut event_pump);
-
-        // if Instant::now().duration_since(last_interrupt) >= VIDEO_INTERRUPT_TIMER {
-        //     if cpu.interrupts_enabled() {
-        //         last_interrupt = Instant::now();
-        //     }
-        // } else {
-        // let nanos_elapsed = Duration::from_nanos(cycles_elapsed * NANOS_PER_CYCLE);
-        // if Instant::now().duration_since(last_cycle) > nanos_elapsed {
-        let op = cpu.get_current_opcode();
-        match op.code {
-            0xD3 => {
-                let (byte, register) = cpu.output();
-                cabinet.output(byte, register.into());
-                cpu.increment_pc(1);
-            }
-            0xDB => {
-                let input = cabinet.input(cpu.get_next_byte());
-                cpu.input(input);
-                cpu.increment_pc(1);
+        if handle_events(
+            &mut cpu,
+            &mut cabinet,
+            &mut event_pump,
+            &mut viewer,
+            &symbols,
+        ) {
+            break 'running;
+        }
+
+        if let Some(tracer) = tracer.as_mut() {
+            tracer
+                .record(cycles_elapsed, &cpu)
+                .expect("Couldn't write the trace.");
+        }
+        if let Some(profiler) = profiler.as_mut() {
+            profiler.before(&cpu);
+        }
+        if let Some(coverage) = coverage.as_mut() {
+            coverage.record(&cpu);
+        }
+        if let Some(heatmap) = heatmap.as_mut() {
+            heatmap.record(&cpu);
+        }
+        if let Some(strict) = strict.as_mut() {
+            for fault in strict.check(&cpu) {
+                for line in fault.report(cpu.get_memory(), &symbols) {
+                    log::warn!("{}", line);
+                }
             }
-            _ => {
-                cycles_elapsed += cpu.execute_opcode(&count) as u64;
-                last_cycle = Instant::now();
+        }
+        let cycles = machine::step(&mut cpu, &mut cabinet) as u64;
+        if let Some(profiler) = profiler.as_mut() {
+            profiler.after(cycles);
+        }
+        cycles_elapsed += cycles;
+        // RST 1 at mid-screen and RST 2 at the end of it, as the debugger raises them. The
+        // screen is drawn once a frame, at the end, and waiting for vsync paces the game.
+        if let Some(vector) = cabinet.pending_interrupt(cycles_elapsed) {
+            cpu.generate_interrupt(vector);
+            if vector == 2 {
+                draw_to_screen(&mut cpu, &mut canvas);
             }
         }
-        // }
-        // }
-        if count > 50000 {
-            draw_to_screen(&mut cpu, &mut canvas);
+        if viewer.shown && viewer.last_drawn.elapsed() >= VIEW_REFRESH {
+            draw_viewer(&mut viewer, cpu.get_memory(), &symbols);
         }
-        count += 1;
     }
+    if let (Some(profiler), Some(prefix)) = (profiler, profile_prefix) {
+        profiler
+            .save(&prefix, cpu.get_memory(), &symbols)
+            .expect("Couldn't write the profile.");
+    }
+    if let (Some(coverage), Some(prefix)) = (coverage, coverage_prefix) {
+        coverage
+            .save(&prefix, cpu.get_memory(), &symbols, 0x0000..=0x1FFF)
+            .expect("Couldn't write the coverage.");
+    }
+    if let (Some(heatmap), Some(prefix)) = (heatmap, heatmap_prefix) {
+        heatmap
+            .save(&prefix, &symbols)
+            .expect("Couldn't write the heatmap.");
+    }
+}
+
+// --trace file, optionally narrowed with --trace-pc 0A00-0AFF and --trace-cycles 0-500000.
+// --sym file names addresses in the trace, and can be used in --trace-pc.
+fn trace_from_args(symbols: &SymbolTable) -> Option<Tracer> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+    };
+    let mut tracer = Tracer::to_file(value("--trace")?).expect("Couldn't create the trace file.");
+    if let Some(range) = value("--trace-pc") {
+        let range = parse_symbolic_range(range, symbols).expect("Bad --trace-pc range.");
+        tracer.set_address_range(range);
+    }
+    tracer.set_symbols(symbols.clone());
+    if let Some(window) = value("--trace-cycles") {
+        tracer.set_cycle_window(parse_cycle_window(window).expect("Bad --trace-cycles window."));
+    }
+    Some(tracer)
+}
+
+// --sym file names addresses in traces, profiles, coverage listings and heatmap reports.
+fn trace_symbols() -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    if let Some(path) = env::args().skip_while(|arg| arg != "--sym").nth(1) {
+        symbols.load(&path).expect("Couldn't read the symbol file.");
+    }
+    symbols
 }
 
+// --roms path reads the set from a directory or zip, MAME style, instead of src/roms. Bad dumps
+// are logged but still run. --manifest file describes the set instead, with any patches for it.
 fn read_space_invaders_into_memory(cpu: &mut Cpu) {
-    let path1 = String::from("src/roms/invaders.h");
-    let path2 = String::from("src/roms/invaders.g");
-    let path3 = String::from("src/roms/invaders.f");
-    let path4 = String::from("src/roms/invaders.e");
-    let path_list = vec![path1, path2, path3, path4];
-    for (i, path) in path_list.iter().enumerate() {
-        let mut file = File::open(&path).expect("File not found");
-        let mut buffer = [0u8; 0x7FF];
-        file.read(&mut buffer).expect("Error reading file.");
-        cpu.load_rom_into_memory(0x7FF * i + i, &buffer);
+    let path = env::args()
+        .skip_while(|arg| arg != "--roms")
+        .nth(1)
+        .unwrap_or_else(|| "src/roms".to_string());
+    let set = match env::args().skip_while(|arg| arg != "--manifest").nth(1) {
+        Some(manifest) => load_manifest(&manifest)
+            .unwrap_or_else(|e| panic!("Couldn't read {}: {}", manifest, e))
+            .into_iter()
+            .find(|set| set.name == invaders::rom_set().name)
+            .unwrap_or_else(|| panic!("{} has no [invaders] set.", manifest)),
+        None => invaders::rom_set(),
+    };
+    let source = Source::open(&path, &set.name)
+        .unwrap_or_else(|e| panic!("Couldn't open the ROMs in {}: {}", path, e));
+    let statuses = set
+        .verify(&source)
+        .unwrap_or_else(|e| panic!("Couldn't read the ROMs in {}: {}", path, e));
+    for (rom, status) in set.roms.iter().zip(statuses) {
+        if status != Status::Good {
+            log::warn!("{}: {}", rom.name, status);
+        }
     }
+    let image = set
+        .load(&source)
+        .unwrap_or_else(|e| panic!("Couldn't load the ROMs from {}: {}", path, e));
+    image.load_into(cpu);
 }
 
-fn handle_events(cpu: &Cpu, cabinet: &mut Cabinet, event_pump: &mut EventPump) {
+// True when the player asked to quit. Keys pressed in the memory window go to it rather than
+// the game.
+fn handle_events(
+    cpu: &mut Cpu,
+    cabinet: &mut Invaders,
+    event_pump: &mut EventPump,
+    viewer: &mut Viewer,
+    symbols: &SymbolTable,
+) -> bool {
+    let viewer_id = viewer.canvas.window().id();
     for event in event_pump.poll_iter() {
         match event {
+            Event::KeyDown {
+                keycode: Some(Keycode::F1),
+                repeat: false,
+                ..
+            } => viewer.toggle(),
+            Event::Window {
+                window_id,
+                win_event: WindowEvent::Close,
+                ..
+            } if window_id == viewer_id => viewer.hide(),
+            Event::KeyDown {
+                window_id,
+                keycode: Some(key),
+                keymod,
+                ..
+            } if window_id == viewer_id => {
+                if let Some(key) = view_key(key, keymod) {
+                    viewer.view.key(key, cpu, symbols);
+                }
+            }
+            Event::TextInput {
+                window_id, text, ..
+            } if window_id == viewer_id => {
+                for c in text.chars() {
+                    viewer.view.key(Key::Char(c), cpu, symbols);
+                }
+            }
+            Event::MouseWheel { window_id, y, .. } if window_id == viewer_id => {
+                viewer.view.scroll(-3 * y);
+            }
+            // SDL only sends Quit once the last window closes, which the hidden memory window
+            // never does, so closing the game's window quits too.
             Event::Quit { .. }
+            | Event::Window {
+                win_event: WindowEvent::Close,
+                ..
+            }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => ::std::process::exit(0),
+            } => return true,
             Event::KeyDown {
                 keycode: Some(key), ..
-            } => cabinet.key_down(key),
+            } => {
+                if let Some(button) = key_button(key) {
+                    cabinet.press(button);
+                }
+            }
             Event::KeyUp {
                 keycode: Some(key), ..
-            } => cabinet.key_up(key),
+            } => {
+                if let Some(button) = key_button(key) {
+                    cabinet.release(button);
+                }
+            }
             _ => {
                 // do nothing
             }
         }
     }
+    false
 }
 
 fn draw_to_screen(cpu: &mut Cpu, canvas: &mut Canvas<Window>) {
@@ -229,3 +388,75 @@ fn draw_to_screen(cpu: &mut Cpu, canvas: &mut Canvas<Window>) {
     }
     canvas.present()
 }
+
+fn draw_viewer(viewer: &mut Viewer, memory: &[u8], symbols: &SymbolTable) {
+    viewer.view.frame(memory);
+    let mut lines = viewer.view.lines(memory);
+    lines.push(Vec::new());
+    let status = viewer.view.status(memory, symbols);
+    lines.push(
+        status
+            .chars()
+            .map(|ch| Cell {
+                ch,
+                style: Style::Normal,
+            })
+            .collect(),
+    );
+    let canvas = &mut viewer.canvas;
+    canvas.set_draw_color(Color::RGB(16, 16, 24));
+    canvas.clear();
+    for (row, line) in lines.iter().enumerate() {
+        for (column, cell) in line.iter().enumerate() {
+            let x = (VIEW_MARGIN + column as u32 * CELL_WIDTH) as i32;
+            let y = (VIEW_MARGIN + row as u32 * CELL_HEIGHT) as i32;
+            let (foreground, background) = cell_colors(cell.style);
+            if let Some(background) = background {
+                canvas.set_draw_color(background);
+                canvas
+                    .fill_rect(Rect::new(x, y, CELL_WIDTH, CELL_HEIGHT))
+                    .unwrap();
+            }
+            let mut pixels = Vec::new();
+            for py in 0..font::HEIGHT {
+                for px in 0..font::WIDTH {
+                    if font::lit(cell.ch, px, py) {
+                        pixels.push(Rect::new(
+                            x + (px * VIEW_SCALE) as i32,
+                            y + ((py + 1) * VIEW_SCALE) as i32,
+                            VIEW_SCALE,
+                            VIEW_SCALE,
+                        ));
+                    }
+                }
+            }
+            canvas.set_draw_color(foreground);
+            canvas.fill_rects(&pixels).unwrap();
+        }
+    }
+    canvas.present();
+    viewer.last_drawn = Instant::now();
+}
+
+// Text and background colours. A changed byte starts out red and fades back to normal.
+fn cell_colors(style: Style) -> (Color, Option<Color>) {
+    let normal = Color::RGB(200, 200, 200);
+    match style {
+        Style::Normal => (normal, None),
+        Style::Address => (Color::RGB(100, 180, 255), None),
+        Style::Dim => (Color::RGB(90, 90, 90), None),
+        Style::Changed(age) => {
+            let fade = |from: u8, to: u8| {
+                let age = u32::from(age);
+                let fade = u32::from(hexview::FADE_FRAMES);
+                ((u32::from(from) * age + u32::from(to) * (fade - age)) / fade) as u8
+            };
+            (
+                Color::RGB(fade(255, 200), fade(60, 200), fade(60, 200)),
+                None,
+            )
+        }
+        Style::Cursor => (Color::RGB(0, 0, 0), Some(Color::RGB(255, 220, 0))),
+        Style::Mirror => (normal, Some(Color::RGB(60, 60, 120))),
+    }
+}
@@ -0,0 +1,707 @@
+use emu8080::coverage::Coverage;
+use emu8080::debugger::{
+    load_file, load_file_arg, parse_number, Breakpoint, Debugger, StopReason, WatchKind, Watchpoint,
+};
+use emu8080::disasm;
+use emu8080::hash::Crc32;
+use emu8080::heatmap::Heatmap;
+use emu8080::image::Image;
+use emu8080::machine::invaders;
+use emu8080::machine::Bare;
+use emu8080::profile::Profiler;
+use emu8080::search::{Filter, RamSearch};
+use emu8080::strict::{FaultKind, Strict};
+use emu8080::symbols;
+use emu8080::trace::{parse_cycle_window, parse_symbolic_range, Tracer};
+use emu8080::{Cpu, RegisterName};
+
+use std::convert::TryFrom;
+use std::env;
+use std::io::prelude::*;
+use std::io::{self, BufRead};
+
+const HELP: &str = "\
+s [n]              step n instructions (default 1)
+n                  step over a call
+c                  continue until a breakpoint, HLT or Ctrl-C
+rs [n]             step back n instructions (default 1)
+rn                 step back over a call
+rc                 continue backwards to the previous breakpoint or watchpoint hit
+b [addr]           set a breakpoint, or list them
+b addr [if expr] [log text]  stop at addr only when expr holds, or print text and carry on
+b if expr [log text]         stop (or log) whenever expr holds, checked every instruction
+bc addr|#n|*       clear a breakpoint, an address-less one by number, or all of them
+wp [addr [len] [r|w|a]]  watch memory for reads, writes (default) or both, or list watchpoints
+wc addr [len] [r|w|a]    clear a watchpoint
+r                  show registers and flags
+bt                 show the call stack
+r name value       set a register (a b c d e h l f bc de hl psw sp pc) or flag (s z ac p cy)
+u [addr] [n]       disassemble n instructions (default: around pc)
+d addr [len]       hex dump memory
+e addr byte...     edit memory
+i n                fire interrupt n (RST n)
+l file [addr]      load a file into memory. Raw binaries go at addr (default 0). .hex and
+                   .s19 files go where they say
+w file addr len    save memory to a file, as Intel HEX or S-records if the extension says so
+t file [pc-range] [cycle-window]  trace to a file, e.g. t out.log 0A00-0AFF 0-500000
+t off              stop tracing
+p on|off           start (or restart) or stop profiling
+p [n]              show the profile, n lines per section (default 20)
+p save prefix      write prefix.txt and prefix.folded (collapsed stacks for flamegraphs)
+cov on|off         start (or restart) or stop coverage tracking
+cov [range]        show coverage and the labels never reached, e.g. cov 0000-1FFF
+cov save prefix [range]  write prefix.lst (annotated listing) and prefix.cov (binary map)
+heat on [rom-range]  count reads and writes, and report writes to code or to rom-range
+heat off           stop counting
+heat [n]           show writes to code or ROM and the n most written addresses (default 20)
+heat save prefix   write prefix.png (256x256 heatmap) and prefix.txt
+strict on|off      stop in front of instructions that use the stack in ROM or video RAM,
+                   run from video or unmapped memory, or wrap pc or sp round memory
+strict check on|off  turn one check on or off: stack-rom, stack-video, exec-video,
+                   exec-unmapped, pc-wrap or sp-wrap
+ss [range]         start a RAM search (default 2000-23FF, the Invaders work RAM)
+sf eq byte|inc|dec|changed|same|bcd n  keep the addresses that fit since the last ss or sf;
+                   bcd n is decimal, e.g. sf bcd 150 for a score
+sl                 list the addresses still in the search
+sym [file]         load labels from a symbol file or listing, or list them
+label addr [name]  name an address, or remove its name
+; addr [text]      comment an address, or remove the comment
+q                  quit
+Numbers are hex. A 0x or $ prefix is accepted. Addresses can be labels, or label+offset.
+Expressions use registers, pairs, flags.cy etc, m, [addr] (byte), w[addr] (word), cycles,
+hits, labels and C's operators: b 0A30 if a > 10 && [20F8] != 0 && flags.cy
+Log text puts expressions in braces, :d for decimal: log score {w[20F8]} hit {hits:d}
+Labels and comments are kept per ROM in ~/.emu8080/labels.
+Arguments ending .sym, .lst or .prn are loaded as labels, others as files to run.";
+
+fn main() {
+    env_logger::init();
+    let mut debugger = Debugger::new(Cpu::new(), Box::new(Bare));
+    let mut hash = Crc32::new();
+    let mut loaded = false;
+    for arg in env::args().skip(1) {
+        let lower = arg.to_lowercase();
+        if [".sym", ".lst", ".prn"].iter().any(|e| lower.ends_with(e)) {
+            match debugger.symbols.load(&arg) {
+                Ok(count) => println!("Read {} labels from {}", count, arg),
+                Err(e) => println!("Couldn't read {}: {}", arg, e),
+            }
+            continue;
+        }
+        match load_file_arg(&mut debugger.cpu, &arg) {
+            Ok((addr, len)) => {
+                println!("Loaded {} bytes from {} at {:04X}", len, arg, addr);
+                let memory = debugger.cpu.get_memory();
+                let end = (usize::from(addr) + len).min(memory.len());
+                hash.update(&memory[usize::from(addr)..end]);
+                loaded = true;
+            }
+            Err(message) => println!("{}", message),
+        }
+    }
+    if let Some(path) = symbols::user_file(hash.finish()).filter(|_| loaded) {
+        if let Err(e) = debugger.symbols.attach(path) {
+            println!("Couldn't read user labels: {}", e);
+        }
+    }
+    let interrupt = debugger.interrupt_handle();
+    ctrlc::set_handler(move || interrupt.store(true, std::sync::atomic::Ordering::SeqCst))
+        .expect("Couldn't install the Ctrl-C handler.");
+
+    show_current(&debugger);
+    let mut search = None;
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+        if args[0] == "q" {
+            break;
+        }
+        if let Err(message) = run_command(&mut debugger, &mut search, &args) {
+            println!("{}", message);
+        }
+    }
+}
+
+fn run_command(
+    debugger: &mut Debugger,
+    search: &mut Option<RamSearch>,
+    args: &[&str],
+) -> Result<(), String> {
+    match args[0] {
+        "s" => {
+            let count = optional_number(args.get(1), 1)?;
+            for _ in 0..count {
+                let reason = debugger.step();
+                report_mismatches(debugger);
+                report_messages(debugger);
+                report_faults(debugger);
+                if reason != StopReason::Step {
+                    report(reason);
+                    break;
+                }
+                show_current(debugger);
+            }
+        }
+        "n" => {
+            let reason = debugger.step_over();
+            report_mismatches(debugger);
+            report_messages(debugger);
+            report_faults(debugger);
+            report(reason);
+            show_current(debugger);
+        }
+        "c" => {
+            let reason = debugger.cont();
+            report_mismatches(debugger);
+            report_messages(debugger);
+            report_faults(debugger);
+            report(reason);
+            show_current(debugger);
+        }
+        "rs" => {
+            let count = optional_number(args.get(1), 1)?;
+            for _ in 0..count {
+                let reason = debugger.reverse_step();
+                if reason != StopReason::Step {
+                    report(reason);
+                    break;
+                }
+            }
+            show_current(debugger);
+        }
+        "rn" | "rc" => {
+            let reason = if args[0] == "rn" {
+                debugger.reverse_next()
+            } else {
+                debugger.reverse_continue()
+            };
+            report(reason);
+            show_current(debugger);
+        }
+        "b" => match args.get(1) {
+            Some(&"if") => {
+                let breakpoint = Breakpoint::parse(&args[1..].join(" "), &debugger.symbols)?;
+                debugger.add_condition(breakpoint);
+                println!("Breakpoint #{}", debugger.conditions().count());
+            }
+            Some(arg) => {
+                let addr = address(debugger, arg)?;
+                let breakpoint = Breakpoint::parse(&args[2..].join(" "), &debugger.symbols)?;
+                debugger.set_breakpoint(addr, breakpoint);
+                println!("Breakpoint at {:04X}", addr);
+            }
+            None => {
+                for addr in debugger.breakpoints() {
+                    let name = debugger.symbols.name(*addr).unwrap_or_default();
+                    let breakpoint = debugger.breakpoint(*addr).cloned().unwrap_or_default();
+                    println!("{:04X}  {:<16}{}", addr, name, describe(&breakpoint));
+                }
+                for (i, breakpoint) in debugger.conditions().enumerate() {
+                    println!("#{:<21}{}", i + 1, describe(breakpoint));
+                }
+            }
+        },
+        "bc" => match args.get(1) {
+            Some(&"*") => debugger.clear_breakpoints(),
+            Some(arg) if arg.starts_with('#') => {
+                let index: usize = arg[1..]
+                    .parse()
+                    .map_err(|_| format!("{} isn't a breakpoint number", arg))?;
+                if !debugger.remove_condition(index.wrapping_sub(1)) {
+                    return Err(format!("No breakpoint {}", arg));
+                }
+            }
+            Some(arg) => {
+                let addr = address(debugger, arg)?;
+                if !debugger.remove_breakpoint(addr) {
+                    return Err(format!("No breakpoint at {:04X}", addr));
+                }
+            }
+            None => return Err(String::from("bc needs an address")),
+        },
+        "wp" => match args.get(1) {
+            Some(_) => {
+                let watchpoint = watchpoint(debugger, args)?;
+                debugger.add_watchpoint(watchpoint);
+                println!("Watching {:04X}", watchpoint.addr);
+            }
+            None => {
+                for w in debugger.watchpoints() {
+                    println!("{:04X} {:X} {:?}", w.addr, w.len, w.kind);
+                }
+            }
+        },
+        "wc" => {
+            let watchpoint = watchpoint(debugger, args)?;
+            if !debugger.remove_watchpoint(watchpoint) {
+                return Err(format!(
+                    "No watchpoint like that at {:04X}",
+                    watchpoint.addr
+                ));
+            }
+        }
+        "r" => {
+            if args.len() == 3 {
+                let value = address(debugger, args[2])?;
+                set_register(&mut debugger.cpu, args[1], value)?;
+                debugger.state_changed();
+            }
+            show_registers(debugger);
+        }
+        "bt" => {
+            for line in debugger.backtrace() {
+                println!("{}", line);
+            }
+        }
+        "u" => {
+            let memory = debugger.cpu.get_memory();
+            let count = optional_number(args.get(2), 10)? as usize;
+            let start = match args.get(1) {
+                Some(arg) => address(debugger, arg)?,
+                None => disasm::find_previous(memory, debugger.cpu.get_pc(), count / 2),
+            };
+            for instruction in disasm::disassemble_range(memory, start, count) {
+                let marker = if instruction.address == debugger.cpu.get_pc() {
+                    "=>"
+                } else {
+                    "  "
+                };
+                if let Some(name) = debugger.symbols.name(instruction.address) {
+                    println!("{}:", name);
+                }
+                let line = disasm::format_symbolic_listing(&instruction, &debugger.symbols);
+                println!("{} {}", marker, line);
+            }
+        }
+        "d" => {
+            let addr = address(debugger, args.get(1).ok_or("d needs an address")?)?;
+            let len = optional_number(args.get(2), 0x80)?;
+            hex_dump(debugger.cpu.get_memory(), addr, len);
+        }
+        "e" => {
+            let addr = address(debugger, args.get(1).ok_or("e needs an address")?)?;
+            for (i, arg) in args[2..].iter().enumerate() {
+                let value = byte(arg)?;
+                debugger
+                    .cpu
+                    .write_memory(addr.wrapping_add(i as u16), value);
+            }
+            debugger.state_changed();
+        }
+        "i" => {
+            let vector = number(args.get(1).ok_or("i needs an interrupt number")?)?;
+            if vector > 7 {
+                return Err(String::from("Interrupts go from 0 to 7"));
+            }
+            if debugger.fire_interrupt(vector as u8) {
+                show_current(debugger);
+            } else {
+                println!("Interrupts are disabled");
+            }
+        }
+        "l" => {
+            let path = args.get(1).ok_or("l needs a file")?;
+            let addr = match args.get(2) {
+                Some(arg) => address(debugger, arg)?,
+                None => 0,
+            };
+            let (addr, len) =
+                load_file(&mut debugger.cpu, path, addr).map_err(|e| e.to_string())?;
+            debugger.state_changed();
+            println!("Loaded {} bytes at {:04X}", len, addr);
+        }
+        "w" => {
+            let path = args.get(1).ok_or("w needs a file")?;
+            let addr = address(debugger, args.get(2).ok_or("w needs an address")?)? as usize;
+            let len = number(args.get(3).ok_or("w needs a length")?)? as usize;
+            let end = (addr + len).min(0x10000);
+            if end > addr {
+                let range = addr as u16..=(end - 1) as u16;
+                Image::from_memory(debugger.cpu.get_memory(), range)
+                    .save(path)
+                    .map_err(|e| e.to_string())?;
+            }
+            println!("Saved {} bytes", end.saturating_sub(addr));
+        }
+        "t" => match args.get(1) {
+            Some(&"off") | None => debugger.set_tracer(None),
+            Some(path) => {
+                let mut tracer = Tracer::to_file(path).map_err(|e| e.to_string())?;
+                if let Some(arg) = args.get(2) {
+                    let range = parse_symbolic_range(arg, &debugger.symbols)
+                        .ok_or_else(|| format!("{} isn't an address range", arg))?;
+                    tracer.set_address_range(range);
+                }
+                if let Some(arg) = args.get(3) {
+                    let window = parse_cycle_window(arg)
+                        .ok_or_else(|| format!("{} isn't a cycle window", arg))?;
+                    tracer.set_cycle_window(window);
+                }
+                debugger.set_tracer(Some(tracer));
+                println!("Tracing to {}", path);
+            }
+        },
+        "p" => match args.get(1) {
+            Some(&"on") => {
+                debugger.set_profiler(Some(Profiler::new()));
+                println!("Profiling");
+            }
+            Some(&"off") => debugger.set_profiler(None),
+            Some(&"save") => {
+                let prefix = args.get(2).ok_or("p save needs a file prefix")?;
+                let profiler = debugger.profiler().ok_or("Profiling is off")?;
+                profiler
+                    .save(prefix, debugger.cpu.get_memory(), &debugger.symbols)
+                    .map_err(|e| e.to_string())?;
+                println!("Wrote {0}.txt and {0}.folded", prefix);
+            }
+            arg => {
+                let limit = arg.map_or(Ok(20), |arg| {
+                    arg.parse()
+                        .map_err(|_| format!("{} isn't a line count", arg))
+                })?;
+                let profiler = debugger.profiler().ok_or("Profiling is off. Try p on.")?;
+                profiler
+                    .write_report(
+                        &mut io::stdout(),
+                        debugger.cpu.get_memory(),
+                        &debugger.symbols,
+                        limit,
+                    )
+                    .map_err(|e| e.to_string())?;
+            }
+        },
+        "cov" => {
+            let range = |arg: Option<&&str>, coverage: &Coverage| match arg {
+                Some(arg) => parse_symbolic_range(arg, &debugger.symbols)
+                    .ok_or_else(|| format!("{} isn't an address range", arg)),
+                None => coverage
+                    .extent()
+                    .ok_or_else(|| String::from("Nothing covered yet")),
+            };
+            match args.get(1) {
+                Some(&"on") => {
+                    debugger.set_coverage(Some(Coverage::new()));
+                    println!("Tracking coverage");
+                }
+                Some(&"off") => debugger.set_coverage(None),
+                Some(&"save") => {
+                    let prefix = args.get(2).ok_or("cov save needs a file prefix")?;
+                    let coverage = debugger.coverage().ok_or("Coverage is off")?;
+                    let range = range(args.get(3), coverage)?;
+                    coverage
+                        .save(prefix, debugger.cpu.get_memory(), &debugger.symbols, range)
+                        .map_err(|e| e.to_string())?;
+                    println!("Wrote {0}.lst and {0}.cov", prefix);
+                }
+                arg => {
+                    let coverage = debugger.coverage().ok_or("Coverage is off. Try cov on.")?;
+                    let range = range(arg, coverage)?;
+                    let (start, end) = (*range.start(), *range.end());
+                    let summary = coverage.summary(range.clone());
+                    let size = usize::from(end - start) + 1;
+                    let percent = |count: usize| 100.0 * count as f64 / size as f64;
+                    println!("{:04X}-{:04X}:", start, end);
+                    println!("  run as opcodes  {:>6.2}%", percent(summary.executed));
+                    println!("  operands        {:>6.2}%", percent(summary.operand));
+                    println!("  read as data    {:>6.2}%", percent(summary.read));
+                    println!("  written         {:>6.2}%", percent(summary.written));
+                    println!("  never touched   {:>6.2}%", percent(summary.untouched));
+                    for (addr, name) in coverage.unreached(range, &debugger.symbols) {
+                        println!("  never reached   {:04X}  {}", addr, name);
+                    }
+                }
+            }
+        }
+        "heat" => match args.get(1) {
+            Some(&"on") => {
+                let mut heatmap = Heatmap::new();
+                if let Some(arg) = args.get(2) {
+                    let range = parse_symbolic_range(arg, &debugger.symbols)
+                        .ok_or_else(|| format!("{} isn't an address range", arg))?;
+                    heatmap.add_rom(range);
+                }
+                debugger.set_heatmap(Some(heatmap));
+                println!("Counting memory accesses");
+            }
+            Some(&"off") => debugger.set_heatmap(None),
+            Some(&"save") => {
+                let prefix = args.get(2).ok_or("heat save needs a file prefix")?;
+                let heatmap = debugger.heatmap().ok_or("The heatmap is off")?;
+                heatmap
+                    .save(prefix, &debugger.symbols)
+                    .map_err(|e| e.to_string())?;
+                println!("Wrote {0}.png and {0}.txt", prefix);
+            }
+            arg => {
+                let limit = arg.map_or(Ok(20), |arg| {
+                    arg.parse()
+                        .map_err(|_| format!("{} isn't a line count", arg))
+                })?;
+                let heatmap = debugger
+                    .heatmap()
+                    .ok_or("The heatmap is off. Try heat on.")?;
+                heatmap
+                    .write_report(&mut io::stdout(), &debugger.symbols, limit)
+                    .map_err(|e| e.to_string())?;
+            }
+        },
+        "strict" => match args[1..] {
+            ["on"] => {
+                let map = debugger.machine.memory_map();
+                debugger.set_strict(Some(Strict::new(map)));
+                println!("Stopping on stack and execution faults");
+            }
+            ["off"] => debugger.set_strict(None),
+            [name, setting @ ("on" | "off")] => {
+                let kind = FaultKind::from_name(name).ok_or_else(|| {
+                    let names: Vec<&str> = FaultKind::ALL.iter().map(|kind| kind.name()).collect();
+                    format!("{} isn't a check. Try {}.", name, names.join(", "))
+                })?;
+                let strict = debugger
+                    .strict_mut()
+                    .ok_or("Strict mode is off. Try strict on.")?;
+                if setting == "on" {
+                    strict.enable(kind);
+                } else {
+                    strict.ignore(kind);
+                }
+            }
+            _ => return Err(String::from("Try strict on|off, or strict check on|off")),
+        },
+        "ss" => {
+            let range = match args.get(1) {
+                Some(arg) => parse_symbolic_range(arg, &debugger.symbols)
+                    .ok_or_else(|| format!("{} isn't an address range", arg))?,
+                None => invaders::RAM,
+            };
+            let started = RamSearch::new(debugger.cpu.get_memory(), range.clone());
+            println!(
+                "Searching {:04X}-{:04X}, {} candidates",
+                range.start(),
+                range.end(),
+                started.candidates().len()
+            );
+            *search = Some(started);
+        }
+        "sf" => {
+            let search = search.as_mut().ok_or("No search yet. Try ss.")?;
+            let value = || args.get(2).ok_or("That filter needs a value");
+            let filter = match args.get(1) {
+                Some(&"eq") => Filter::Equal(byte(value()?)?),
+                Some(&"inc") => Filter::Increased,
+                Some(&"dec") => Filter::Decreased,
+                Some(&"changed") => Filter::Changed,
+                Some(&"same") => Filter::Unchanged,
+                Some(&"bcd") => {
+                    let value = value()?;
+                    Filter::Bcd(
+                        value
+                            .parse()
+                            .map_err(|_| format!("{} isn't a decimal number", value))?,
+                    )
+                }
+                _ => return Err(String::from("sf eq byte|inc|dec|changed|same|bcd n")),
+            };
+            let left = search.filter(debugger.cpu.get_memory(), filter);
+            println!("{} candidates", left);
+            if left <= 20 {
+                list_candidates(debugger, search);
+            }
+        }
+        "sl" => list_candidates(debugger, search.as_ref().ok_or("No search yet. Try ss.")?),
+        "sym" => match args.get(1) {
+            Some(path) => {
+                let count = debugger.symbols.load(path).map_err(|e| e.to_string())?;
+                println!("Read {} labels", count);
+            }
+            None => {
+                for (addr, name) in debugger.symbols.labels() {
+                    println!("{:04X}  {}", addr, name);
+                }
+            }
+        },
+        "label" => {
+            let addr = address(debugger, args.get(1).ok_or("label needs an address")?)?;
+            let result = match args.get(2) {
+                Some(name) => debugger.symbols.set_label(addr, name),
+                None => debugger.symbols.remove_label(addr).map(|_| ()),
+            };
+            result.map_err(|e| format!("Couldn't save labels: {}", e))?;
+        }
+        ";" => {
+            let addr = address(debugger, args.get(1).ok_or("; needs an address")?)?;
+            let text = args.get(2..).unwrap_or_default().join(" ");
+            debugger
+                .symbols
+                .set_comment(addr, &text)
+                .map_err(|e| format!("Couldn't save comments: {}", e))?;
+        }
+        "h" | "?" => println!("{}", HELP),
+        _ => return Err(format!("Unknown command {}. Try h for help.", args[0])),
+    }
+    Ok(())
+}
+
+fn report(reason: StopReason) {
+    match reason {
+        StopReason::Step => {}
+        StopReason::Breakpoint(addr) => println!("Breakpoint at {:04X}", addr),
+        StopReason::Watchpoint(addr, access) => println!("{:?} of {:04X}", access, addr),
+        StopReason::Halted(addr) => println!("Halted at {:04X}", addr),
+        StopReason::Interrupted => println!("Interrupted"),
+        StopReason::Fault(addr) => println!("Strict mode fault at {:04X}", addr),
+        StopReason::StartOfHistory => println!("No earlier history"),
+    }
+}
+
+fn report_mismatches(debugger: &mut Debugger) {
+    for mismatch in debugger.take_mismatches() {
+        println!("Stack mismatch at {}", mismatch);
+    }
+}
+
+fn list_candidates(debugger: &Debugger, search: &RamSearch) {
+    for addr in search.candidates() {
+        let name = debugger.symbols.name(*addr).unwrap_or_default();
+        println!(
+            "{:04X}  {:02X}  {}",
+            addr,
+            debugger.cpu.read_memory(*addr),
+            name
+        );
+    }
+}
+
+fn report_messages(debugger: &mut Debugger) {
+    for message in debugger.take_messages() {
+        println!("{}", message);
+    }
+}
+
+fn report_faults(debugger: &mut Debugger) {
+    for fault in debugger.take_faults() {
+        for line in fault.report(debugger.cpu.get_memory(), &debugger.symbols) {
+            println!("{}", line);
+        }
+    }
+}
+
+fn describe(breakpoint: &Breakpoint) -> String {
+    let mut text = format!("hits {}", breakpoint.hits);
+    if let Some(condition) = &breakpoint.condition {
+        text.push_str(&format!("  if {}", condition));
+    }
+    if let Some(log) = &breakpoint.log {
+        text.push_str(&format!("  log {}", log));
+    }
+    text
+}
+
+fn show_current(debugger: &Debugger) {
+    let instruction = disasm::disassemble(debugger.cpu.get_memory(), debugger.cpu.get_pc());
+    println!(
+        "{}",
+        disasm::format_symbolic_listing(&instruction, &debugger.symbols)
+    );
+}
+
+fn show_registers(debugger: &Debugger) {
+    let cpu = &debugger.cpu;
+    println!(
+        "A={:02X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} F={} cycles={}",
+        cpu.read_register(RegisterName::A),
+        cpu.read_register(RegisterName::BC),
+        cpu.read_register(RegisterName::DE),
+        cpu.read_register(RegisterName::HL),
+        cpu.read_register(RegisterName::SP),
+        cpu.read_register(RegisterName::PC),
+        cpu.get_flags(),
+        debugger.cycles()
+    );
+}
+
+fn set_register(cpu: &mut Cpu, name: &str, value: u16) -> Result<(), String> {
+    if let Some(reg) = RegisterName::from_name(name) {
+        if !reg.is_pair() && value > 0xFF {
+            return Err(format!("{:X} doesn't fit in {}", value, name));
+        }
+        cpu.write_register(reg, value);
+        return Ok(());
+    }
+    let mut flags = cpu.get_flags();
+    if flags.set_by_name(name, value != 0) {
+        cpu.write_register(RegisterName::F, u16::from(u8::from(flags)));
+        Ok(())
+    } else {
+        Err(format!("Unknown register {}", name))
+    }
+}
+
+fn hex_dump(memory: &[u8], addr: u16, len: u16) {
+    let start = addr as usize;
+    let end = (start + len as usize).min(memory.len());
+    for row in (start..end).step_by(16) {
+        let bytes = &memory[row..(row + 16).min(end)];
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| {
+                if (0x20..0x7F).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("{:04X}  {:<47}  {}", row, hex.join(" "), ascii);
+    }
+}
+
+// wp and wc take addr [len] [r|w|a].
+fn watchpoint(debugger: &Debugger, args: &[&str]) -> Result<Watchpoint, String> {
+    let addr = address(debugger, args.get(1).ok_or("Watchpoints need an address")?)?;
+    let mut len = 1;
+    let mut kind = WatchKind::Write;
+    for arg in &args[2..] {
+        match *arg {
+            "r" => kind = WatchKind::Read,
+            "w" => kind = WatchKind::Write,
+            "a" => kind = WatchKind::Access,
+            _ => len = number(arg)?,
+        }
+    }
+    Ok(Watchpoint { addr, len, kind })
+}
+
+fn number(arg: &str) -> Result<u16, String> {
+    parse_number(arg).ok_or_else(|| format!("{} isn't a hex number", arg))
+}
+
+fn byte(arg: &str) -> Result<u8, String> {
+    let value = number(arg)?;
+    u8::try_from(value).map_err(|_| format!("{} doesn't fit in a byte", arg))
+}
+
+fn address(debugger: &Debugger, arg: &str) -> Result<u16, String> {
+    debugger
+        .symbols
+        .resolve(arg)
+        .ok_or_else(|| format!("{} isn't a label or hex number", arg))
+}
+
+fn optional_number(arg: Option<&&str>, default: u16) -> Result<u16, String> {
+    match arg {
+        Some(arg) => number(arg),
+        None => Ok(default),
+    }
+}
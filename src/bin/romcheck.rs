@@ -0,0 +1,96 @@
+use emu8080::hash::hex_digest;
+use emu8080::machine::invaders;
+use emu8080::romset::{load_manifest, parse_manifest, Source, Status};
+
+use std::env;
+use std::process;
+
+const USAGE: &str = "\
+usage: romcheck [options] path
+  Checks the ROM sets in a manifest against the dumps in path: a set's zip or directory, or a
+  directory holding set.zip or set/ as MAME has them. Exits 1 unless every file is good.
+  --manifest file    the sets to check (default: Space Invaders). Each [set] line is followed
+                     by lines of: name size addr crc32 [sha1], the numbers in hex, and any
+                     patches as: patch name file
+  --set name         only check this set
+e.g. romcheck src/roms";
+
+fn main() {
+    let mut manifest = None;
+    let mut only = None;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--manifest" => manifest = Some(args.next().unwrap_or_else(|| usage())),
+            "--set" => only = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let sets = match &manifest {
+        Some(file) => {
+            load_manifest(file).unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", file, e)))
+        }
+        None => parse_manifest(invaders::MANIFEST).unwrap_or_else(|e| fail(&e.to_string())),
+    };
+    let sets: Vec<_> = sets
+        .into_iter()
+        .filter(|set| only.as_ref().is_none_or(|name| &set.name == name))
+        .collect();
+    if sets.is_empty() {
+        fail("There are no sets to check.");
+    }
+
+    let mut bad = 0;
+    let mut total = 0;
+    let mut unpatched = 0;
+    for set in &sets {
+        println!("[{}]", set.name);
+        let source = Source::open(&path, &set.name)
+            .unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", path, e)));
+        let statuses = set
+            .verify(&source)
+            .unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", path, e)));
+        for (rom, status) in set.roms.iter().zip(statuses) {
+            total += 1;
+            if status != Status::Good {
+                bad += 1;
+            }
+            let sha1 = rom.sha1.map(|sha1| hex_digest(&sha1)).unwrap_or_default();
+            println!(
+                "{:<12} {:04X} {:5} {:08x} {:40}  {}",
+                rom.name, rom.addr, rom.size, rom.crc32, sha1, status
+            );
+        }
+        // Patches are checked by loading the set with them.
+        for patch in &set.patches {
+            println!("patch {} {}", patch.rom, patch.path);
+        }
+        if !set.patches.is_empty() {
+            if let Err(e) = set.load(&source) {
+                println!("the set doesn't load with its patches: {}", e);
+                unpatched += 1;
+            }
+        }
+    }
+    println!("{} of {} files good", total - bad, total);
+    if bad > 0 || unpatched > 0 {
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
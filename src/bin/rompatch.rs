@@ -0,0 +1,51 @@
+use emu8080::patch::{self, Format};
+
+use std::env;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "\
+usage: rompatch create original modified patch
+       rompatch apply patch original output
+  create writes the differences between two dumps as an IPS patch, or BPS if the patch's name
+  ends in .bps. BPS patches check the sizes and CRC-32s of both dumps, so they won't apply to
+  the wrong one. apply takes either kind.
+  To apply a patch whenever a ROM set loads, add a line to the set's manifest:
+    patch invaders.h fix.ips
+e.g. rompatch create src/roms/invaders.h invaders-fixed.h fix.bps";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let read = |path: &str| {
+        fs::read(path).unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", path, e)))
+    };
+    let (output, data) = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["create", original, modified, output] => {
+            let patch = patch::create(Format::from_path(output), &read(original), &read(modified))
+                .unwrap_or_else(|e| fail(&format!("Couldn't make a patch: {}", e)));
+            (output, patch)
+        }
+        ["apply", patch, original, output] => {
+            let patched = patch::apply(&read(patch), &read(original))
+                .unwrap_or_else(|e| fail(&format!("Couldn't apply {}: {}", patch, e)));
+            (output, patched)
+        }
+        _ => usage(),
+    };
+    fs::write(output, &data).unwrap_or_else(|e| fail(&format!("Couldn't write {}: {}", output, e)));
+    println!("{}: {} bytes", output, data.len());
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
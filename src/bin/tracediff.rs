@@ -0,0 +1,139 @@
+use emu8080::trace::diff::{
+    detect_parser, differences, first_divergence, parser_named, DiffOptions, Record,
+};
+
+use std::env;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "\
+usage: tracediff [options] ours.log reference.log
+  --format1 name, --format2 name   trace formats: native or kv (default: detect)
+  --context n                      instructions shown around the divergence (default 5)
+  --flags-mask hex                 flag bits compared (default D5: S Z AC P CY)
+  --cycles                         compare cycle counts too";
+
+struct Trace {
+    path: String,
+    records: Vec<Record>,
+}
+
+fn main() {
+    let mut formats = [None, None];
+    let mut paths = Vec::new();
+    let mut context = 5;
+    let mut options = DiffOptions::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format1" => formats[0] = Some(args.next().unwrap_or_else(|| usage())),
+            "--format2" => formats[1] = Some(args.next().unwrap_or_else(|| usage())),
+            "--context" => {
+                context = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--flags-mask" => {
+                options.flags_mask = args
+                    .next()
+                    .and_then(|mask| u8::from_str_radix(mask.trim_start_matches("0x"), 16).ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--cycles" => options.compare_cycles = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        usage();
+    }
+    let a = read_trace(&paths[0], formats[0].as_deref());
+    let b = read_trace(&paths[1], formats[1].as_deref());
+
+    let index = match first_divergence(&a.records, &b.records, options) {
+        Some(index) => index,
+        None => {
+            let common = a.records.len().min(b.records.len());
+            println!("No divergence in {} instructions.", common);
+            if a.records.len() != b.records.len() {
+                let longer = if a.records.len() > b.records.len() {
+                    &a
+                } else {
+                    &b
+                };
+                println!(
+                    "{} carries on for {} more.",
+                    longer.path,
+                    longer.records.len() - common
+                );
+            }
+            return;
+        }
+    };
+
+    println!("First divergence at instruction {}:", index + 1);
+    for record in &a.records[index.saturating_sub(context)..index] {
+        println!("    {}", record.text);
+    }
+    println!("1 > {}", describe(&a, index));
+    println!("2 > {}", describe(&b, index));
+    for difference in differences(&a.records[index], &b.records[index], options) {
+        println!("    {}", difference);
+    }
+    if index > 0 {
+        // Traces show the state before each instruction, so the one before did the damage.
+        println!(
+            "Most likely caused by the previous instruction, line {} of {}:",
+            a.records[index - 1].line,
+            a.path
+        );
+        println!("    {}", a.records[index - 1].text);
+    }
+    for trace in &[&a, &b] {
+        let after = &trace.records[index + 1..(index + 1 + context).min(trace.records.len())];
+        if !after.is_empty() {
+            println!("Then in {}:", trace.path);
+            for record in after {
+                println!("    {}", record.text);
+            }
+        }
+    }
+    process::exit(1);
+}
+
+fn read_trace(path: &str, format: Option<&str>) -> Trace {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Couldn't read {}: {}", path, e);
+        process::exit(2);
+    });
+    let parser = match format {
+        Some(name) => parser_named(name).unwrap_or_else(|| {
+            eprintln!("Unknown trace format {}", name);
+            process::exit(2);
+        }),
+        None => detect_parser(&text),
+    };
+    Trace {
+        path: path.to_string(),
+        records: parser.parse(&text),
+    }
+}
+
+fn describe(trace: &Trace, index: usize) -> String {
+    let record = &trace.records[index];
+    format!(
+        "{} (line {} of {})",
+        record.text.trim(),
+        record.line,
+        trace.path
+    )
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,703 @@
+use emu8080::coverage::Coverage;
+use emu8080::debugger::{load_file_arg, Breakpoint, Debugger, StopReason};
+use emu8080::disasm;
+use emu8080::hash::Crc32;
+use emu8080::heatmap::Heatmap;
+use emu8080::image::Image;
+use emu8080::machine::invaders::{self, Button, Invaders};
+use emu8080::machine::Bare;
+use emu8080::profile::Profiler;
+use emu8080::romset::{Source, Status};
+use emu8080::strict::Strict;
+use emu8080::symbols;
+use emu8080::trace::{parse_symbolic_range, Tracer};
+use emu8080::{Cpu, RegisterName};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, Stdout, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const FRAME: Duration = Duration::from_millis(16);
+const CYCLES_PER_FRAME: u64 = 2_000_000 / 60;
+// Terminals don't report key releases, so a pressed button is let go after this many frames.
+const HOLD_FRAMES: u32 = 8;
+
+const HELP: &str = "c run  p pause  s step  n over  S N C back  b break@pc  : command  q quit | 5 coin  1 2 start  arrows move  space fire";
+
+struct Pane {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+struct App {
+    debugger: Debugger,
+    invaders: Option<Rc<RefCell<Invaders>>>,
+    held: Vec<(Button, u32)>,
+    running: bool,
+    status: String,
+    memory_addr: u16,
+    command: Option<String>,
+    quit: bool,
+}
+
+fn main() {
+    let mut files = Vec::new();
+    let mut symbol_files = Vec::new();
+    let mut use_invaders = false;
+    let mut roms = String::from("src/roms");
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let lower = arg.to_lowercase();
+        if arg == "--invaders" {
+            use_invaders = true;
+        } else if arg == "--roms" {
+            match args.next() {
+                Some(path) => roms = path,
+                None => {
+                    eprintln!("--roms needs a directory or zip.");
+                    return;
+                }
+            }
+        } else if [".sym", ".lst", ".prn"].iter().any(|e| lower.ends_with(e)) {
+            symbol_files.push(arg);
+        } else {
+            files.push(arg);
+        }
+    }
+
+    let mut cpu = Cpu::new();
+    let mut hash = Crc32::new();
+    let mut status = String::from("Paused");
+    if use_invaders && files.is_empty() {
+        match load_invaders(&roms) {
+            Ok((image, warnings)) => {
+                image.load_into(&mut cpu);
+                hash.update(&image.binary());
+                for warning in warnings {
+                    status = format!("{} | {}", status, warning);
+                }
+            }
+            Err(message) => {
+                eprintln!("{}", message);
+                return;
+            }
+        }
+    }
+    for arg in files.iter() {
+        match load_file_arg(&mut cpu, arg) {
+            Ok((addr, len)) => {
+                let end = (usize::from(addr) + len).min(0x10000);
+                hash.update(&cpu.get_memory()[usize::from(addr)..end]);
+            }
+            Err(message) => {
+                eprintln!("{}", message);
+                return;
+            }
+        }
+    }
+    let (invaders, mut debugger) = if use_invaders {
+        let machine = Rc::new(RefCell::new(Invaders::new()));
+        (Some(machine.clone()), Debugger::new(cpu, Box::new(machine)))
+    } else {
+        (None, Debugger::new(cpu, Box::new(Bare)))
+    };
+    for path in symbol_files.iter() {
+        if let Err(e) = debugger.symbols.load(path) {
+            eprintln!("Couldn't read {}: {}", path, e);
+            return;
+        }
+    }
+    if let Some(path) =
+        symbols::user_file(hash.finish()).filter(|_| !files.is_empty() || use_invaders)
+    {
+        if let Err(e) = debugger.symbols.attach(path) {
+            eprintln!("Couldn't read user labels: {}", e);
+        }
+    }
+    let mut app = App {
+        debugger,
+        invaders,
+        held: Vec::new(),
+        running: false,
+        status,
+        memory_addr: 0x2000,
+        command: None,
+        quit: false,
+    };
+
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode().expect("Couldn't put the terminal in raw mode.");
+    execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All)).unwrap();
+    let result = run(&mut app, &mut stdout);
+    execute!(stdout, Show, LeaveAlternateScreen).unwrap();
+    terminal::disable_raw_mode().unwrap();
+    result.expect("Terminal error");
+}
+
+// The Invaders set from a directory or zip, MAME style, for --invaders without files. Bad dumps
+// still load, with a note for the status line, since the terminal is taken over before a log
+// would be read.
+fn load_invaders(path: &str) -> Result<(Image, Vec<String>), String> {
+    let set = invaders::rom_set();
+    let source = Source::open(path, &set.name)
+        .map_err(|e| format!("Couldn't open the ROMs in {}: {}", path, e))?;
+    let statuses = set
+        .verify(&source)
+        .map_err(|e| format!("Couldn't read the ROMs in {}: {}", path, e))?;
+    let warnings = set
+        .roms
+        .iter()
+        .zip(statuses)
+        .filter(|(_, status)| *status != Status::Good)
+        .map(|(rom, status)| format!("{}: {}", rom.name, status))
+        .collect();
+    let image = set
+        .load(&source)
+        .map_err(|e| format!("Couldn't load the ROMs from {}: {}", path, e))?;
+    Ok((image, warnings))
+}
+
+fn run(app: &mut App, stdout: &mut Stdout) -> io::Result<()> {
+    while !app.quit {
+        let frame_start = Instant::now();
+        if app.running {
+            let reason = app.debugger.run_for(CYCLES_PER_FRAME);
+            if let Some(message) = app.debugger.take_messages().pop() {
+                app.status = message;
+            }
+            if reason != StopReason::Step {
+                app.running = false;
+                app.status = describe(reason);
+            }
+        }
+        // Stepping can stop on one too, so this is picked up here rather than above.
+        if let Some(fault) = app.debugger.take_faults().pop() {
+            app.status = fault.to_string();
+        }
+        release_held(app);
+        draw(app, stdout)?;
+        let timeout = if app.running {
+            FRAME.checked_sub(frame_start.elapsed()).unwrap_or_default()
+        } else {
+            Duration::from_millis(100)
+        };
+        if event::poll(timeout)? {
+            match event::read()? {
+                Event::Key(key) => handle_key(app, key),
+                Event::Resize(_, _) => queue!(stdout, Clear(ClearType::All))?,
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn release_held(app: &mut App) {
+    if let Some(machine) = &app.invaders {
+        for held in app.held.iter_mut() {
+            held.1 -= 1;
+            if held.1 == 0 {
+                machine.borrow_mut().release(held.0);
+            }
+        }
+        app.held.retain(|held| held.1 > 0);
+    }
+}
+
+fn press(app: &mut App, button: Button) {
+    if let Some(machine) = &app.invaders {
+        machine.borrow_mut().press(button);
+        app.held.retain(|held| held.0 != button);
+        app.held.push((button, HOLD_FRAMES));
+    }
+}
+
+fn handle_key(app: &mut App, key: KeyEvent) {
+    if let Some(command) = app.command.as_mut() {
+        match key.code {
+            KeyCode::Char(c) => command.push(c),
+            KeyCode::Backspace => {
+                command.pop();
+            }
+            KeyCode::Enter => {
+                let command = app.command.take().unwrap_or_default();
+                app.status = run_command(app, &command);
+            }
+            KeyCode::Esc => app.command = None,
+            _ => {}
+        }
+        return;
+    }
+    match key.code {
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => app.quit = true,
+        KeyCode::Char('q') => app.quit = true,
+        KeyCode::Char('c') | KeyCode::F(5) => {
+            app.running = true;
+            app.status = String::from("Running");
+        }
+        KeyCode::Char('p') | KeyCode::Esc => {
+            app.running = false;
+            app.status = String::from("Paused");
+        }
+        KeyCode::Char('s') | KeyCode::F(11) if !app.running => {
+            app.status = describe(app.debugger.step());
+        }
+        KeyCode::Char('n') | KeyCode::F(10) if !app.running => {
+            app.status = describe(app.debugger.step_over());
+        }
+        KeyCode::Char('S') if !app.running => {
+            app.status = describe(app.debugger.reverse_step());
+        }
+        KeyCode::Char('N') if !app.running => {
+            app.status = describe(app.debugger.reverse_next());
+        }
+        KeyCode::Char('C') if !app.running => {
+            app.status = describe(app.debugger.reverse_continue());
+        }
+        KeyCode::Char('b') => {
+            let pc = app.debugger.cpu.get_pc();
+            if !app.debugger.remove_breakpoint(pc) {
+                app.debugger.add_breakpoint(pc);
+            }
+        }
+        KeyCode::Char(':') => app.command = Some(String::new()),
+        KeyCode::PageUp => app.memory_addr = app.memory_addr.wrapping_sub(0x80),
+        KeyCode::PageDown => app.memory_addr = app.memory_addr.wrapping_add(0x80),
+        KeyCode::Char('5') => press(app, Button::Coin),
+        KeyCode::Char('1') => press(app, Button::P1Start),
+        KeyCode::Char('2') => press(app, Button::P2Start),
+        KeyCode::Left => press(app, Button::P1Left),
+        KeyCode::Right => press(app, Button::P1Right),
+        KeyCode::Char(' ') | KeyCode::Up => press(app, Button::P1Fire),
+        _ => {}
+    }
+}
+
+// b addr [if expr] [log text], b if expr [log text], bc addr, m addr (memory view), r name value, i n (interrupt), t file|off (trace),
+// sym file, label addr [name], prof on|off|save prefix, cov on|off|save prefix [range],
+// heat on|off|save prefix, strict on|off. Addresses can be labels.
+fn run_command(app: &mut App, command: &str) -> String {
+    let args: Vec<&str> = command.split_whitespace().collect();
+    let symbols = app.debugger.symbols.clone();
+    let number = |i: usize| args.get(i).and_then(|arg| symbols.resolve(arg));
+    match (args.first(), number(1)) {
+        (Some(&"b"), _) if args.get(1) == Some(&"if") => {
+            match Breakpoint::parse(&args[1..].join(" "), &symbols) {
+                Ok(breakpoint) => {
+                    app.debugger.add_condition(breakpoint);
+                    String::from("Breakpoint added")
+                }
+                Err(e) => e,
+            }
+        }
+        (Some(&"b"), Some(addr)) => match Breakpoint::parse(&args[2..].join(" "), &symbols) {
+            Ok(breakpoint) => {
+                app.debugger.set_breakpoint(addr, breakpoint);
+                format!("Breakpoint at {:04X}", addr)
+            }
+            Err(e) => e,
+        },
+        (Some(&"bc"), Some(addr)) => {
+            app.debugger.remove_breakpoint(addr);
+            format!("Cleared breakpoint at {:04X}", addr)
+        }
+        (Some(&"m"), Some(addr)) => {
+            app.memory_addr = addr;
+            format!("Memory at {:04X}", addr)
+        }
+        (Some(&"i"), Some(vector)) => {
+            if app.debugger.fire_interrupt((vector & 0x7) as u8) {
+                format!("Interrupt {}", vector & 0x7)
+            } else {
+                String::from("Interrupts are disabled")
+            }
+        }
+        (Some(&"r"), _) => match (
+            args.get(1).and_then(|n| RegisterName::from_name(n)),
+            number(2),
+        ) {
+            (Some(reg), Some(value)) => {
+                app.debugger.cpu.write_register(reg, value);
+                app.debugger.state_changed();
+                format!("{} = {:X}", args[1], value)
+            }
+            _ => String::from("Usage: r name value"),
+        },
+        (Some(&"sym"), _) => match args.get(1) {
+            Some(path) => match app.debugger.symbols.load(path) {
+                Ok(count) => format!("Read {} labels", count),
+                Err(e) => e.to_string(),
+            },
+            None => String::from("Usage: sym file"),
+        },
+        (Some(&"label"), Some(addr)) => {
+            let result = match args.get(2) {
+                Some(name) => app.debugger.symbols.set_label(addr, name),
+                None => app.debugger.symbols.remove_label(addr).map(|_| ()),
+            };
+            match result {
+                Ok(()) => format!("Labelled {:04X}", addr),
+                Err(e) => format!("Couldn't save labels: {}", e),
+            }
+        }
+        (Some(&"prof"), _) => match (args.get(1), args.get(2)) {
+            (Some(&"on"), _) => {
+                app.debugger.set_profiler(Some(Profiler::new()));
+                String::from("Profiling")
+            }
+            (Some(&"off"), _) => {
+                app.debugger.set_profiler(None);
+                String::from("Profiling off")
+            }
+            (Some(&"save"), Some(prefix)) => match app.debugger.profiler() {
+                Some(profiler) => {
+                    let debugger = &app.debugger;
+                    match profiler.save(prefix, debugger.cpu.get_memory(), &debugger.symbols) {
+                        Ok(()) => format!("Wrote {0}.txt and {0}.folded", prefix),
+                        Err(e) => e.to_string(),
+                    }
+                }
+                None => String::from("Profiling is off"),
+            },
+            _ => String::from("Usage: prof on|off|save prefix"),
+        },
+        (Some(&"cov"), _) => match (args.get(1), args.get(2)) {
+            (Some(&"on"), _) => {
+                app.debugger.set_coverage(Some(Coverage::new()));
+                String::from("Tracking coverage")
+            }
+            (Some(&"off"), _) => {
+                app.debugger.set_coverage(None);
+                String::from("Coverage off")
+            }
+            (Some(&"save"), Some(prefix)) => match app.debugger.coverage() {
+                Some(coverage) => {
+                    let debugger = &app.debugger;
+                    let range = match args.get(3) {
+                        Some(arg) => parse_symbolic_range(arg, &symbols),
+                        None => coverage.extent(),
+                    };
+                    match range {
+                        Some(range) => match coverage.save(
+                            prefix,
+                            debugger.cpu.get_memory(),
+                            &debugger.symbols,
+                            range,
+                        ) {
+                            Ok(()) => format!("Wrote {0}.lst and {0}.cov", prefix),
+                            Err(e) => e.to_string(),
+                        },
+                        None => String::from("Nothing to save in that range"),
+                    }
+                }
+                None => String::from("Coverage is off"),
+            },
+            _ => String::from("Usage: cov on|off|save prefix [range]"),
+        },
+        (Some(&"heat"), _) => match (args.get(1), args.get(2)) {
+            (Some(&"on"), _) => {
+                let mut heatmap = Heatmap::new();
+                if app.invaders.is_some() {
+                    heatmap.add_rom(invaders::ROM_AREA);
+                }
+                app.debugger.set_heatmap(Some(heatmap));
+                String::from("Counting memory accesses")
+            }
+            (Some(&"off"), _) => {
+                app.debugger.set_heatmap(None);
+                String::from("Heatmap off")
+            }
+            (Some(&"save"), Some(prefix)) => match app.debugger.heatmap() {
+                Some(heatmap) => match heatmap.save(prefix, &app.debugger.symbols) {
+                    Ok(()) => format!(
+                        "Wrote {0}.png and {0}.txt, {1} suspicious writes",
+                        prefix,
+                        heatmap.findings().len()
+                    ),
+                    Err(e) => e.to_string(),
+                },
+                None => String::from("The heatmap is off"),
+            },
+            _ => String::from("Usage: heat on|off|save prefix"),
+        },
+        (Some(&"strict"), _) => match args.get(1) {
+            Some(&"on") => {
+                let map = app.debugger.machine.memory_map();
+                app.debugger.set_strict(Some(Strict::new(map)));
+                String::from("Stopping on stack and execution faults")
+            }
+            Some(&"off") => {
+                app.debugger.set_strict(None);
+                String::from("Strict mode off")
+            }
+            _ => String::from("Usage: strict on|off"),
+        },
+        (Some(&"t"), _) => match args.get(1) {
+            Some(&"off") | None => {
+                app.debugger.set_tracer(None);
+                String::from("Tracing off")
+            }
+            Some(path) => match Tracer::to_file(path) {
+                Ok(tracer) => {
+                    app.debugger.set_tracer(Some(tracer));
+                    format!("Tracing to {}", path)
+                }
+                Err(e) => e.to_string(),
+            },
+        },
+        _ => format!("Unknown command: {}", command),
+    }
+}
+
+fn describe(reason: StopReason) -> String {
+    match reason {
+        StopReason::Step => String::from("Paused"),
+        StopReason::Breakpoint(addr) => format!("Breakpoint at {:04X}", addr),
+        StopReason::Watchpoint(addr, access) => format!("{:?} of {:04X}", access, addr),
+        StopReason::Halted(addr) => format!("Halted at {:04X}", addr),
+        StopReason::Interrupted => String::from("Interrupted"),
+        StopReason::Fault(addr) => format!("Strict mode fault at {:04X}", addr),
+        StopReason::StartOfHistory => String::from("No earlier history"),
+    }
+}
+
+fn draw(app: &App, stdout: &mut Stdout) -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let top = height.saturating_sub(12);
+    let left = Pane::new(0, 0, 26, 6);
+    let stack = Pane::new(0, 6, 26, top.saturating_sub(6) / 2);
+    let breakpoints = Pane::new(0, stack.y + stack.height, 26, top - stack.y - stack.height);
+    let code = Pane::new(27, 0, 40, top);
+    let video = Pane::new(68, 0, width.saturating_sub(68), top);
+    let memory = Pane::new(0, top, 74, 10);
+    let trace = Pane::new(75, top, width.saturating_sub(75), 10);
+
+    left.draw(stdout, "Registers", &register_lines(&app.debugger))?;
+    stack.draw(
+        stdout,
+        "Stack",
+        &stack_lines(&app.debugger.cpu, stack.rows()),
+    )?;
+    let bps: Vec<String> = app
+        .debugger
+        .breakpoints()
+        .map(|addr| {
+            let breakpoint = app.debugger.breakpoint(*addr);
+            let kind = match breakpoint.map(|b| (&b.condition, &b.log)) {
+                Some((_, Some(_))) => "log",
+                Some((Some(_), None)) => "if",
+                _ => "",
+            };
+            format!("* {:04X} {}", addr, kind)
+        })
+        .chain(app.debugger.conditions().map(|b| {
+            format!(
+                "? {}",
+                b.condition
+                    .as_ref()
+                    .map(|c| c.to_string())
+                    .unwrap_or_default()
+            )
+        }))
+        .collect();
+    breakpoints.draw(stdout, "Breakpoints", &bps)?;
+    code.draw(
+        stdout,
+        "Disassembly",
+        &code_lines(&app.debugger, code.rows()),
+    )?;
+    video.draw(
+        stdout,
+        "Video",
+        &video_lines(app.debugger.cpu.get_video_memory()),
+    )?;
+    let title = format!("Memory {:04X}", app.memory_addr);
+    memory.draw(
+        stdout,
+        &title,
+        &memory_lines(&app.debugger.cpu, app.memory_addr),
+    )?;
+    trace.draw(stdout, "Trace", &trace_lines(&app.debugger, trace.rows()))?;
+
+    let status = match &app.command {
+        Some(command) => format!(":{}", command),
+        None => format!("{} | {}", app.status, HELP),
+    };
+    queue!(
+        stdout,
+        MoveTo(0, height.saturating_sub(2)),
+        Print(fit(&status, width))
+    )?;
+    stdout.flush()
+}
+
+impl Pane {
+    fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Pane {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn rows(&self) -> usize {
+        self.height.saturating_sub(1) as usize
+    }
+
+    // Every row is padded out to the pane width so nothing stale is left behind.
+    fn draw(&self, stdout: &mut Stdout, title: &str, lines: &[String]) -> io::Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Ok(());
+        }
+        let header = format!("-- {} {}", title, "-".repeat(self.width as usize));
+        queue!(
+            stdout,
+            MoveTo(self.x, self.y),
+            Print(fit(&header, self.width))
+        )?;
+        for row in 0..self.rows() {
+            let line = lines.get(row).map(|l| l.as_str()).unwrap_or("");
+            queue!(
+                stdout,
+                MoveTo(self.x, self.y + 1 + row as u16),
+                Print(fit(line, self.width))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn fit(line: &str, width: u16) -> String {
+    let width = width as usize;
+    let line: String = line.chars().take(width).collect();
+    format!("{:<width$}", line, width = width)
+}
+
+fn register_lines(debugger: &Debugger) -> Vec<String> {
+    let cpu = &debugger.cpu;
+    let reg = |name| cpu.read_register(name);
+    vec![
+        format!("A  {:02X}    F  {}", reg(RegisterName::A), cpu.get_flags()),
+        format!(
+            "BC {:04X}  DE {:04X}",
+            reg(RegisterName::BC),
+            reg(RegisterName::DE)
+        ),
+        format!(
+            "HL {:04X}  SP {:04X}",
+            reg(RegisterName::HL),
+            reg(RegisterName::SP)
+        ),
+        format!("PC {:04X}", reg(RegisterName::PC)),
+        format!("cycles {}", debugger.cycles()),
+    ]
+}
+
+fn stack_lines(cpu: &Cpu, rows: usize) -> Vec<String> {
+    let sp = cpu.read_register(RegisterName::SP);
+    (0..rows as u16)
+        .map(|i| {
+            let addr = sp.wrapping_add(i * 2);
+            let word = u16::from(cpu.read_memory(addr))
+                | (u16::from(cpu.read_memory(addr.wrapping_add(1))) << 8);
+            format!("{:04X}  {:04X}", addr, word)
+        })
+        .collect()
+}
+
+fn code_lines(debugger: &Debugger, rows: usize) -> Vec<String> {
+    let memory = debugger.cpu.get_memory();
+    let pc = debugger.cpu.get_pc();
+    let breakpoints: Vec<u16> = debugger.breakpoints().cloned().collect();
+    let start = disasm::find_previous(memory, pc, rows / 3);
+    let mut lines: Vec<String> = disasm::disassemble_range(memory, start, rows)
+        .iter()
+        .flat_map(|instruction| {
+            let marker = if instruction.address == pc { '>' } else { ' ' };
+            let bp = if breakpoints.contains(&instruction.address) {
+                '*'
+            } else {
+                ' '
+            };
+            let line = disasm::format_symbolic_listing(instruction, &debugger.symbols);
+            let label = debugger.symbols.name(instruction.address);
+            label
+                .map(|name| format!("{}:", name))
+                .into_iter()
+                .chain(Some(format!("{}{}{}", bp, marker, line)))
+        })
+        .collect();
+    lines.truncate(rows);
+    lines
+}
+
+fn memory_lines(cpu: &Cpu, addr: u16) -> Vec<String> {
+    (0..8u16)
+        .map(|row| {
+            let start = addr.wrapping_add(row * 16);
+            let bytes: Vec<u8> = (0..16u16)
+                .map(|i| cpu.read_memory(start.wrapping_add(i)))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| {
+                    if (0x20..0x7F).contains(&b) {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:04X}  {}  {}", start, hex.join(" "), ascii)
+        })
+        .collect()
+}
+
+fn trace_lines(debugger: &Debugger, rows: usize) -> Vec<String> {
+    let memory = debugger.cpu.get_memory();
+    let history: Vec<u16> = debugger.history().cloned().collect();
+    history[history.len().saturating_sub(rows)..]
+        .iter()
+        .map(|&addr| {
+            let instruction = disasm::disassemble(memory, addr);
+            disasm::format_symbolic_listing(&instruction, &debugger.symbols)
+        })
+        .collect()
+}
+
+// The 256x224 bitmap squeezed into 64x28 characters, one per 4x8 block of pixels. Pixels are
+// stored least significant bit first.
+fn video_lines(video: &[u8]) -> Vec<String> {
+    (0..28)
+        .map(|row| {
+            (0..64)
+                .map(|col| {
+                    let lit: u32 = (0..8)
+                        .map(|y| {
+                            let byte = video[(row * 8 + y) * 32 + col / 2];
+                            let nibble = if col % 2 == 0 { byte & 0x0F } else { byte >> 4 };
+                            nibble.count_ones()
+                        })
+                        .sum();
+                    match lit {
+                        0 => ' ',
+                        1..=4 => '.',
+                        5..=12 => ':',
+                        _ => '#',
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
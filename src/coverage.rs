@@ -0,0 +1,332 @@
+use crate::disasm::{self, format_byte};
+use crate::symbols::SymbolTable;
+use crate::{opcode_length, Access, Cpu};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::ops::RangeInclusive;
+
+// What has happened to a byte, as bits so one address can be several at once.
+pub const EXECUTED: u8 = 1;
+pub const OPERAND: u8 = 2;
+pub const READ: u8 = 4;
+pub const WRITTEN: u8 = 8;
+
+// Binary maps start with this, then hold two addresses a byte, the even one in the low nibble.
+const MAP_MAGIC: &[u8; 4] = b"COV1";
+const MAP_LENGTH: usize = MAP_MAGIC.len() + 0x8000;
+
+// How many bytes in a range have been each kind of used. A byte can count under several.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub executed: usize,
+    pub operand: usize,
+    pub read: usize,
+    pub written: usize,
+    pub untouched: usize,
+}
+
+// Marks every byte of memory with how it has been used: run as an opcode, fetched as an
+// operand, read as data or written. Call record() ahead of each instruction.
+#[derive(Clone)]
+pub struct Coverage {
+    marks: Vec<u8>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {
+            marks: vec![0; 0x10000],
+        }
+    }
+
+    pub fn record(&mut self, cpu: &Cpu) {
+        let pc = cpu.get_pc();
+        self.mark(pc, EXECUTED);
+        let length = opcode_length(cpu.read_memory(pc));
+        for offset in 1..length {
+            self.mark(pc.wrapping_add(offset), OPERAND);
+        }
+        for access in cpu.memory_accesses() {
+            let kind = match access.kind {
+                Access::Read => READ,
+                Access::Write => WRITTEN,
+            };
+            self.mark(access.addr, kind);
+        }
+    }
+
+    pub fn mark(&mut self, addr: u16, kind: u8) {
+        self.marks[usize::from(addr)] |= kind;
+    }
+
+    pub fn get(&self, addr: u16) -> u8 {
+        self.marks[usize::from(addr)]
+    }
+
+    // Adds in what another session covered.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (mark, other) in self.marks.iter_mut().zip(&other.marks) {
+            *mark |= other;
+        }
+    }
+
+    pub fn summary(&self, range: RangeInclusive<u16>) -> Summary {
+        let mut summary = Summary::default();
+        for addr in range {
+            let mark = self.get(addr);
+            let count = |kind: u8| usize::from(mark & kind != 0);
+            summary.executed += count(EXECUTED);
+            summary.operand += count(OPERAND);
+            summary.read += count(READ);
+            summary.written += count(WRITTEN);
+            summary.untouched += usize::from(mark == 0);
+        }
+        summary
+    }
+
+    // From the lowest to the highest byte anything touched.
+    pub fn extent(&self) -> Option<RangeInclusive<u16>> {
+        let start = self.marks.iter().position(|mark| *mark != 0)?;
+        let end = self.marks.iter().rposition(|mark| *mark != 0)?;
+        Some(start as u16..=end as u16)
+    }
+
+    // Labels in range that never ran, which with a good symbol file are the routines nothing
+    // has reached yet.
+    pub fn unreached<'a>(
+        &self,
+        range: RangeInclusive<u16>,
+        symbols: &'a SymbolTable,
+    ) -> Vec<(u16, &'a str)> {
+        symbols
+            .labels()
+            .filter(|(addr, _)| range.contains(addr) && self.get(*addr) & EXECUTED == 0)
+            .collect()
+    }
+
+    // A listing of range with a column saying how each line's bytes were used:
+    //   xo..  0A30  3E 20     MVI A,20H
+    //   ..r.  1B00  1F 24     DB 1FH,24H
+    //         1C00-1C3F       not touched (64 bytes)
+    // Bytes that ran as opcodes are disassembled. Everything else is shown as data, eight
+    // bytes to a line, and stretches nothing touched are folded into one line.
+    pub fn write_listing<W: Write + ?Sized>(
+        &self,
+        out: &mut W,
+        memory: &[u8],
+        symbols: &SymbolTable,
+        range: RangeInclusive<u16>,
+    ) -> io::Result<()> {
+        let (start, end) = (u32::from(*range.start()), u32::from(*range.end()));
+        let summary = self.summary(range.clone());
+        writeln!(out, "; Coverage of {:04X}-{:04X}", start, end)?;
+        writeln!(
+            out,
+            "; {} bytes run as opcodes, {} as operands, {} read as data, {} written, {} never touched",
+            summary.executed, summary.operand, summary.read, summary.written, summary.untouched
+        )?;
+        writeln!(out, "; x opcode run, o operand fetched, r read, w written")?;
+        let unreached = self.unreached(range, symbols);
+        if !unreached.is_empty() {
+            writeln!(out, "; Labels never reached:")?;
+            for (addr, name) in unreached {
+                writeln!(out, ";   {:04X}  {}", addr, name)?;
+            }
+        }
+        writeln!(out)?;
+
+        let mut addr = start;
+        while addr <= end {
+            let here = addr as u16;
+            if let Some(name) = symbols.name(here) {
+                writeln!(out, "{}:", name)?;
+            }
+            let mark = self.get(here);
+            if mark & EXECUTED != 0 {
+                let instruction = disasm::disassemble(memory, here);
+                let mut marks = 0;
+                for offset in 0..instruction.length {
+                    marks |= self.get(here.wrapping_add(offset));
+                }
+                writeln!(
+                    out,
+                    "{}  {}",
+                    flags(marks),
+                    disasm::format_symbolic_listing(&instruction, symbols)
+                )?;
+                addr += u32::from(instruction.length);
+                continue;
+            }
+            // A run of bytes used the same way, stopping at the next label.
+            let mut next = addr + 1;
+            while next <= end
+                && self.get(next as u16) == mark
+                && symbols.name(next as u16).is_none()
+                && (mark == 0 || next - addr < 8)
+            {
+                next += 1;
+            }
+            if mark == 0 {
+                writeln!(
+                    out,
+                    "      {:04X}-{:04X}       not touched ({} bytes)",
+                    addr,
+                    next - 1,
+                    next - addr
+                )?;
+            } else {
+                let bytes: Vec<String> = (addr..next)
+                    .map(|a| format_byte(memory[a as usize]))
+                    .collect();
+                writeln!(
+                    out,
+                    "{}  {:04X}  {:<9} DB {}",
+                    flags(mark),
+                    addr,
+                    "",
+                    bytes.join(",")
+                )?;
+            }
+            addr = next;
+        }
+        Ok(())
+    }
+
+    pub fn to_map(&self) -> Vec<u8> {
+        let mut map = MAP_MAGIC.to_vec();
+        map.extend(self.marks.chunks(2).map(|pair| pair[0] | (pair[1] << 4)));
+        map
+    }
+
+    pub fn from_map(map: &[u8]) -> Option<Coverage> {
+        if map.len() != MAP_LENGTH || !map.starts_with(MAP_MAGIC) {
+            return None;
+        }
+        let mut coverage = Coverage::new();
+        for (i, byte) in map[MAP_MAGIC.len()..].iter().enumerate() {
+            coverage.marks[2 * i] = byte & 0xF;
+            coverage.marks[2 * i + 1] = byte >> 4;
+        }
+        Some(coverage)
+    }
+
+    // A map saved earlier, or empty coverage if there isn't one yet.
+    pub fn load(path: &str) -> io::Result<Coverage> {
+        match fs::read(path) {
+            Ok(map) => Coverage::from_map(&map).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} isn't a coverage map", path),
+                )
+            }),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Coverage::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Writes prefix.lst with the listing of range and prefix.cov with the map of all memory.
+    pub fn save(
+        &self,
+        prefix: &str,
+        memory: &[u8],
+        symbols: &SymbolTable,
+        range: RangeInclusive<u16>,
+    ) -> io::Result<()> {
+        let mut listing = BufWriter::new(File::create(format!("{}.lst", prefix))?);
+        self.write_listing(&mut listing, memory, symbols, range)?;
+        listing.flush()?;
+        fs::write(format!("{}.cov", prefix), self.to_map())
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+fn flags(mark: u8) -> String {
+    [(EXECUTED, 'x'), (OPERAND, 'o'), (READ, 'r'), (WRITTEN, 'w')]
+        .iter()
+        .map(|(kind, c)| if mark & kind != 0 { *c } else { '.' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::Debugger;
+    use crate::machine::Bare;
+    use crate::RegisterName;
+
+    // LDA 0010 / STA 0011 / JMP 000A / NOP / HLT, then data at 0010.
+    fn covered() -> Debugger {
+        let program = [
+            0x3A, 0x10, 0x00, 0x32, 0x11, 0x00, 0xC3, 0x0A, 0x00, 0x00, 0x76,
+        ];
+        let mut cpu = Cpu::new();
+        for (i, byte) in program.iter().enumerate() {
+            cpu.write_memory(i as u16, *byte);
+        }
+        cpu.write_memory(0x0010, 0x42);
+        cpu.write_register(RegisterName::SP, 0x2400);
+        let mut debugger = Debugger::new(cpu, Box::new(Bare));
+        debugger.set_coverage(Some(Coverage::new()));
+        debugger.cont();
+        debugger
+    }
+
+    #[test]
+    fn test_marks() {
+        let debugger = covered();
+        let coverage = debugger.coverage().unwrap();
+
+        assert_eq!(coverage.get(0x0000), EXECUTED);
+        assert_eq!(coverage.get(0x0001), OPERAND);
+        assert_eq!(coverage.get(0x0009), 0);
+        assert_eq!(coverage.get(0x0010), READ);
+        assert_eq!(coverage.get(0x0011), WRITTEN);
+        assert_eq!(
+            coverage.summary(0x0000..=0x0011),
+            Summary {
+                executed: 3,
+                operand: 6,
+                read: 1,
+                written: 1,
+                untouched: 7,
+            }
+        );
+
+        let map = coverage.to_map();
+        assert_eq!(map.len(), MAP_LENGTH);
+        let reloaded = Coverage::from_map(&map).unwrap();
+        assert!(reloaded.marks == coverage.marks);
+    }
+
+    #[test]
+    fn test_listing() {
+        let debugger = covered();
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x0009, "dead");
+        let mut out = Vec::new();
+        debugger
+            .coverage()
+            .unwrap()
+            .write_listing(
+                &mut out,
+                debugger.cpu.get_memory(),
+                &symbols,
+                0x0000..=0x0011,
+            )
+            .unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert!(lines.contains(&";   0009  dead"));
+        assert!(lines.contains(&"xo..  0000  3A 10 00  LDA 0010H"));
+        assert!(lines.contains(&"dead:"));
+        assert!(lines.contains(&"      0009-000F       not touched (7 bytes)"));
+        assert!(lines.contains(&"..r.  0010            DB 42H"));
+        assert!(lines.contains(&"...w  0011            DB 42H"));
+    }
+}
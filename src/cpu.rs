@@ -1,17 +1,22 @@
+mod access;
+mod call_stack;
 mod flags;
 mod memory;
 mod opcode;
 mod pointers;
 mod registers;
 
-use flags::Flags;
+pub use access::{Access, MemoryAccess};
+pub use call_stack::{CallStack, Frame, FrameKind, Mismatch, MismatchKind};
+pub use flags::Flags;
 use memory::Memory;
-use opcode::Opcode;
+pub use opcode::{opcode_length, Opcode};
 use pointers::Pointer;
 use registers::Register;
+pub use registers::RegisterName;
 use std::fmt;
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Default)]
 pub struct Cpu {
     a: Register,
     b: Register,
@@ -25,6 +30,7 @@ pub struct Cpu {
     memory: Memory,
     flags: Flags,
     interrupts_enabled: bool,
+    call_stack: Option<CallStack>,
 }
 
 impl Cpu {
@@ -32,18 +38,15 @@ impl Cpu {
         Self::default()
     }
 
-    pub fn load_rom_into_memory(&mut self, start_addr: usize, rom: &[u8; 0x7FF]) {
-        for b in 0..rom.len() {
-            self.memory.ram[start_addr + b] = rom[b];
-        }
+    // Panics if the ROM doesn't fit below 10000H. crate::image checks that first.
+    pub fn load_rom_into_memory(&mut self, start_addr: usize, rom: &[u8]) {
+        self.memory.ram[start_addr..start_addr + rom.len()].copy_from_slice(rom);
     }
 
-    pub fn execute_opcode(&mut self, count: &u128) -> u8 {
+    pub fn execute_opcode(&mut self) -> u8 {
         let mut op = self.get_current_opcode();
         let mem_ref = self.get_memory_reference();
         op.next_bytes = mem_ref;
-        debug!("{:?}", self);
-        debug!("{:?}      | {}\n", op, count);
         let mut changed_pc = false;
         match op.code {
             0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xCB | 0xD9 | 0xDD | 0xED
@@ -98,7 +101,9 @@ impl Cpu {
             }
             0xC5 | 0xD5 | 0xE5 | 0xF5 => self.push_operation(op.code),
             0xC6 => self.adi(),
-            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => self.rst_operation(op.code),
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                changed_pc = self.rst_operation(op.code)
+            }
             0xCE => self.aci(),
             0xD3 => {
                 // handled elsewhere
@@ -293,6 +298,7 @@ impl Cpu {
             }
             0x31 => {
                 self.sp = self.get_memory_reference().into();
+                self.track_stack_moved();
             }
             _ => panic!("Bug in opcode routing."),
         }
@@ -329,6 +335,7 @@ impl Cpu {
                 let val: u16 = self.sp.into();
                 let result = val.wrapping_add(1);
                 self.sp = result.into();
+                self.track_stack_moved();
             }
             _ => panic!("Bug in opcode routing"),
         }
@@ -397,6 +404,7 @@ impl Cpu {
 
     fn pop_operation(&mut self, code: u8) {
         let (msb, lsb) = self.pop_off_stack();
+        self.track_stack_moved();
         match code {
             0xC1 => {
                 self.b = msb.into();
@@ -587,6 +595,7 @@ impl Cpu {
         if true_condition {
             let mem_ref = self.get_memory_reference();
             self.push_to_stack((self.pc + 3).into());
+            self.track_call(FrameKind::Call, mem_ref, (self.pc + 3).into());
             self.pc = mem_ref.into();
             true
         } else {
@@ -612,8 +621,13 @@ impl Cpu {
 
     fn return_from_subroutine(&mut self, true_condition: bool) -> bool {
         if true_condition {
+            let sp = self.sp.into();
             let (msb, lsb) = self.pop_off_stack();
-            self.pc = self.get_pair_value(msb, lsb).into();
+            let to = self.get_pair_value(msb, lsb);
+            if let Some(stack) = self.call_stack.as_mut() {
+                stack.pop(self.pc.into(), sp, to);
+            }
+            self.pc = to.into();
             return true;
         }
         false
@@ -634,9 +648,11 @@ impl Cpu {
         })
     }
 
-    fn rst_operation(&mut self, code: u8) {
-        self.push_to_stack(self.pc.into());
+    fn rst_operation(&mut self, code: u8) -> bool {
+        self.push_to_stack((self.pc + 1).into());
+        self.track_call(FrameKind::Rst, u16::from(code & 0x38), (self.pc + 1).into());
         self.pc = (code & 0x38).into();
+        true
     }
 
     fn mov_b_operation(&mut self, code: u8) {
@@ -713,6 +729,9 @@ impl Cpu {
     }
 
     fn xthl(&mut self) {
+        if let Some(stack) = self.call_stack.as_mut() {
+            stack.exchanged(self.pc.into(), self.sp.into());
+        }
         let l_val: u8 = self.l.into();
         let h_val: u8 = self.h.into();
         let sp_1: u8 = self.memory.ram[usize::from(self.sp)];
@@ -735,7 +754,8 @@ impl Cpu {
     }
 
     fn sphl(&mut self) {
-        self.sp = self.get_reg_pair_value(self.h, self.l).into()
+        self.sp = self.get_reg_pair_value(self.h, self.l).into();
+        self.track_stack_moved();
     }
 
     fn ldax_operation(&mut self, code: u8) {
@@ -806,11 +826,11 @@ impl Cpu {
         Cpu::return_split_registers(result)
     }
 
-    fn get_reg_value(self, code: u8) -> u8 {
+    fn get_reg_value(&self, code: u8) -> u8 {
         self.get_register(code).into()
     }
 
-    fn get_register(self, code: u8) -> Register {
+    fn get_register(&self, code: u8) -> Register {
         match code % 8 {
             0 => self.b,
             1 => self.c,
@@ -827,21 +847,21 @@ impl Cpu {
         }
     }
 
-    fn get_reg_pair_value(self, msb: Register, lsb: Register) -> u16 {
+    fn get_reg_pair_value(&self, msb: Register, lsb: Register) -> u16 {
         self.get_pair_value(msb.into(), lsb.into())
     }
 
-    fn get_pair_value(self, msb: u8, lsb: u8) -> u16 {
+    fn get_pair_value(&self, msb: u8, lsb: u8) -> u16 {
         ((msb as u16) << 8) | lsb as u16
     }
 
-    fn get_memory_reference(self) -> u16 {
+    fn get_memory_reference(&self) -> u16 {
         let low_adr: u16 = self.memory.ram[usize::from(self.pc + 1)].into();
         let high_adr: u16 = self.memory.ram[usize::from(self.pc + 2)].into();
         (high_adr << 8) | low_adr
     }
 
-    pub fn get_next_byte(self) -> u8 {
+    pub fn get_next_byte(&self) -> u8 {
         self.memory.ram[usize::from(self.pc + 1)]
     }
 
@@ -884,6 +904,129 @@ impl Cpu {
     pub fn increment_pc(&mut self, val: u8) {
         self.pc += val.into();
     }
+
+    pub fn get_pc(&self) -> u16 {
+        self.pc.into()
+    }
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.pc = addr.into();
+    }
+
+    pub fn get_flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn read_register(&self, name: RegisterName) -> u16 {
+        let pair = |msb: Register, lsb: Register| (u16::from(msb) << 8) | u16::from(lsb);
+        match name {
+            RegisterName::A => self.a.into(),
+            RegisterName::B => self.b.into(),
+            RegisterName::C => self.c.into(),
+            RegisterName::D => self.d.into(),
+            RegisterName::E => self.e.into(),
+            RegisterName::H => self.h.into(),
+            RegisterName::L => self.l.into(),
+            RegisterName::F => u8::from(self.flags).into(),
+            RegisterName::BC => pair(self.b, self.c),
+            RegisterName::DE => pair(self.d, self.e),
+            RegisterName::HL => pair(self.h, self.l),
+            RegisterName::PSW => (u16::from(self.a) << 8) | u16::from(u8::from(self.flags)),
+            RegisterName::SP => self.sp.into(),
+            RegisterName::PC => self.pc.into(),
+        }
+    }
+
+    pub fn write_register(&mut self, name: RegisterName, val: u16) {
+        let (msb, lsb) = Cpu::return_split_registers(val);
+        match name {
+            RegisterName::A => self.a = val.into(),
+            RegisterName::B => self.b = val.into(),
+            RegisterName::C => self.c = val.into(),
+            RegisterName::D => self.d = val.into(),
+            RegisterName::E => self.e = val.into(),
+            RegisterName::H => self.h = val.into(),
+            RegisterName::L => self.l = val.into(),
+            RegisterName::F => self.flags = (val as u8).into(),
+            RegisterName::BC => {
+                self.b = msb;
+                self.c = lsb;
+            }
+            RegisterName::DE => {
+                self.d = msb;
+                self.e = lsb;
+            }
+            RegisterName::HL => {
+                self.h = msb;
+                self.l = lsb;
+            }
+            RegisterName::PSW => {
+                self.a = msb;
+                self.flags = u8::from(lsb).into();
+            }
+            RegisterName::SP => self.sp = val.into(),
+            RegisterName::PC => self.pc = val.into(),
+        }
+    }
+
+    pub fn read_memory(&self, addr: u16) -> u8 {
+        self.memory.ram[addr as usize]
+    }
+
+    pub fn write_memory(&mut self, addr: u16, val: u8) {
+        self.memory.ram[addr as usize] = val;
+    }
+
+    pub fn get_memory(&self) -> &[u8] {
+        &self.memory.ram[..]
+    }
+
+    // Acknowledges an interrupt the way the Invaders hardware does, by jamming RST n onto
+    // the bus. Ignored while interrupts are disabled.
+    pub fn generate_interrupt(&mut self, vector: u8) -> bool {
+        if !self.interrupts_enabled {
+            return false;
+        }
+        self.interrupts_enabled = false;
+        self.push_to_stack(self.pc.into());
+        let target = u16::from(vector & 0x7) * 8;
+        self.track_call(FrameKind::Interrupt, target, self.pc.into());
+        self.pc = target.into();
+        true
+    }
+
+    // The shadow call stack is off by default, since it costs a little on every call and
+    // return.
+    pub fn set_call_tracking(&mut self, on: bool) {
+        self.call_stack = if on { Some(CallStack::default()) } else { None };
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    pub fn call_stack_mut(&mut self) -> Option<&mut CallStack> {
+        self.call_stack.as_mut()
+    }
+
+    // Called once the return address has been pushed, before pc moves.
+    fn track_call(&mut self, kind: FrameKind, target: u16, return_addr: u16) {
+        if let Some(stack) = self.call_stack.as_mut() {
+            stack.push(Frame {
+                kind,
+                site: self.pc.into(),
+                target,
+                return_addr,
+                sp: self.sp.into(),
+            });
+        }
+    }
+
+    fn track_stack_moved(&mut self) {
+        if let Some(stack) = self.call_stack.as_mut() {
+            stack.stack_moved(self.pc.into(), self.sp.into());
+        }
+    }
 }
 
 fn wrapping_add_u16(val: u16, operand: u16) -> u16 {
@@ -959,6 +1102,16 @@ mod tests {
         assert_eq!(cpu.memory.ram[addr2], val2);
     }
 
+    #[test]
+    fn test_every_opcode_decodes() {
+        for code in 0..=0xFFu8 {
+            let op = Opcode::new(code);
+
+            assert!(!op.operation_name.is_empty());
+            assert!(op.length() >= 1 && op.length() <= 3);
+        }
+    }
+
     // opcode tests
 
     #[test]
@@ -1546,6 +1699,50 @@ mod tests {
         assert_eq!(cpu.pc, 0x18);
     }
 
+    #[test]
+    fn test_rst_pushes_next_instruction() {
+        // RST 3 at 0100 should come back to 0101, and run the vector from its first byte.
+        let mut cpu = Cpu::new();
+        cpu.sp = 0x2400u16.into();
+        cpu.pc = 0x0100u16.into();
+        cpu.memory.ram[0x0100] = 0xDF;
+        cpu.execute_opcode();
+
+        assert_eq!(cpu.pc, 0x18);
+        assert_eq!(cpu.memory.ram[0x23FE], 0x01);
+        assert_eq!(cpu.memory.ram[0x23FF], 0x01);
+    }
+
+    #[test]
+    fn test_call_tracking() {
+        // CALL 0008 / CALL 000A / HLT / HLT / RET / POP H / RET
+        let program = [
+            0xCD, 0x08, 0x00, 0xCD, 0x0A, 0x00, 0x76, 0x76, 0xC9, 0x00, 0xE1, 0xC9,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.memory.ram[..program.len()].copy_from_slice(&program);
+        cpu.sp = 0x2400u16.into();
+        cpu.set_call_tracking(true);
+        cpu.execute_opcode();
+
+        assert_eq!(cpu.call_stack().unwrap().frames()[0].return_addr, 0x0003);
+        cpu.execute_opcode();
+        assert!(cpu.call_stack().unwrap().frames().is_empty());
+        cpu.execute_opcode();
+        cpu.execute_opcode();
+        cpu.execute_opcode();
+
+        let mismatches = cpu.call_stack_mut().unwrap().take_mismatches();
+        let kinds: Vec<MismatchKind> = mismatches.iter().map(|m| m.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                MismatchKind::Discarded,
+                MismatchKind::UnmatchedReturn { to: 0x0000 }
+            ]
+        );
+    }
+
     #[test]
     fn test_xthl() {
         let mut cpu = Cpu::new();
@@ -0,0 +1,102 @@
+use super::Cpu;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub kind: Access,
+}
+
+impl MemoryAccess {
+    fn read(addr: u16) -> Self {
+        MemoryAccess {
+            addr,
+            kind: Access::Read,
+        }
+    }
+
+    fn write(addr: u16) -> Self {
+        MemoryAccess {
+            addr,
+            kind: Access::Write,
+        }
+    }
+}
+
+impl Cpu {
+    // The data reads and writes the instruction under pc is about to make, worked out from the
+    // current state. Fetching the instruction's own bytes isn't included.
+    pub fn memory_accesses(&self) -> Vec<MemoryAccess> {
+        let code = self.memory.ram[usize::from(self.pc)];
+        let hl = self.get_reg_pair_value(self.h, self.l);
+        let sp: u16 = self.sp.into();
+        // Read with wrapping, so looking ahead of a pc at FFFE or FFFF doesn't overflow.
+        let pc: u16 = self.pc.into();
+        let operand =
+            |offset: u16| u16::from(self.memory.ram[usize::from(pc.wrapping_add(offset))]);
+        let addr = (operand(2) << 8) | operand(1);
+        let push = vec![
+            MemoryAccess::write(sp.wrapping_sub(1)),
+            MemoryAccess::write(sp.wrapping_sub(2)),
+        ];
+        let pop = vec![
+            MemoryAccess::read(sp),
+            MemoryAccess::read(sp.wrapping_add(1)),
+        ];
+        match code {
+            0x46 | 0x4E | 0x56 | 0x5E | 0x66 | 0x6E | 0x7E => vec![MemoryAccess::read(hl)],
+            0x86 | 0x8E | 0x96 | 0x9E | 0xA6 | 0xAE | 0xB6 | 0xBE => vec![MemoryAccess::read(hl)],
+            0x70..=0x75 | 0x77 | 0x36 => vec![MemoryAccess::write(hl)],
+            0x34 | 0x35 => vec![MemoryAccess::read(hl), MemoryAccess::write(hl)],
+            0x02 => vec![MemoryAccess::write(self.get_reg_pair_value(self.b, self.c))],
+            0x12 => vec![MemoryAccess::write(self.get_reg_pair_value(self.d, self.e))],
+            0x0A => vec![MemoryAccess::read(self.get_reg_pair_value(self.b, self.c))],
+            0x1A => vec![MemoryAccess::read(self.get_reg_pair_value(self.d, self.e))],
+            0x32 => vec![MemoryAccess::write(addr)],
+            0x3A => vec![MemoryAccess::read(addr)],
+            0x22 => vec![
+                MemoryAccess::write(addr),
+                MemoryAccess::write(addr.wrapping_add(1)),
+            ],
+            0x2A => vec![
+                MemoryAccess::read(addr),
+                MemoryAccess::read(addr.wrapping_add(1)),
+            ],
+            0xC5 | 0xD5 | 0xE5 | 0xF5 | 0xCD => push,
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => push,
+            0xC1 | 0xD1 | 0xE1 | 0xF1 | 0xC9 => pop,
+            0xC4 | 0xCC | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC if self.condition_met(code) => {
+                push
+            }
+            0xC0 | 0xC8 | 0xD0 | 0xD8 | 0xE0 | 0xE8 | 0xF0 | 0xF8 if self.condition_met(code) => {
+                pop
+            }
+            0xE3 => {
+                let mut accesses = pop;
+                accesses.push(MemoryAccess::write(sp));
+                accesses.push(MemoryAccess::write(sp.wrapping_add(1)));
+                accesses
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Conditional jumps, calls and returns keep their condition in bits 3-5.
+    pub fn condition_met(&self, code: u8) -> bool {
+        match (code >> 3) & 0x7 {
+            0 => !self.flags.z,
+            1 => self.flags.z,
+            2 => !self.flags.cy,
+            3 => self.flags.cy,
+            4 => !self.flags.p,
+            5 => self.flags.p,
+            6 => !self.flags.s,
+            _ => self.flags.s,
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt;
+
+// Kept short, since code that gets the stack wrong tends to do it over and over.
+const MAX_MISMATCHES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // The CALL or RST instruction, or the instruction an interrupt came in front of.
+    pub site: u16,
+    pub target: u16,
+    pub return_addr: u16,
+    // Where the return address was pushed.
+    pub sp: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MismatchKind {
+    // A RET that didn't match any call. PUSH then RET as a computed jump does this.
+    UnmatchedReturn { to: u16 },
+    // The return address was changed before the RET used it.
+    WrongReturn { to: u16 },
+    // The return address was taken off the stack without a RET, by POP, SPHL or similar.
+    Discarded,
+    // XTHL swapped the return address into HL.
+    Exchanged,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mismatch {
+    pub pc: u16,
+    pub kind: MismatchKind,
+    pub frame: Option<Frame>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let caller = |f: &mut fmt::Formatter| match self.frame {
+            Some(frame) => write!(f, " (called from {:04X})", frame.site),
+            None => Ok(()),
+        };
+        match self.kind {
+            MismatchKind::UnmatchedReturn { to } => {
+                write!(f, "{:04X}: return to {:04X} without a call", self.pc, to)
+            }
+            MismatchKind::WrongReturn { to } => {
+                let expected = self.frame.map_or(0, |frame| frame.return_addr);
+                write!(
+                    f,
+                    "{:04X}: returned to {:04X} instead of {:04X}",
+                    self.pc, to, expected
+                )?;
+                caller(f)
+            }
+            MismatchKind::Discarded => {
+                write!(f, "{:04X}: return address dropped from the stack", self.pc)?;
+                caller(f)
+            }
+            MismatchKind::Exchanged => {
+                write!(f, "{:04X}: return address exchanged into HL", self.pc)?;
+                caller(f)
+            }
+        }
+    }
+}
+
+// A shadow of the calls the program has made, kept alongside the real stack.
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatches: Vec<Mismatch>,
+}
+
+impl CallStack {
+    // Outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    pub fn take_mismatches(&mut self) -> Vec<Mismatch> {
+        std::mem::take(&mut self.mismatches)
+    }
+
+    pub(super) fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    // sp is where the address being returned to was popped from.
+    pub(super) fn pop(&mut self, pc: u16, sp: u16, to: u16) {
+        self.discard_below(pc, sp);
+        match self.frames.last() {
+            Some(frame) if frame.sp == sp => {
+                let frame = *frame;
+                self.frames.pop();
+                if frame.return_addr != to {
+                    self.flag(pc, MismatchKind::WrongReturn { to }, Some(frame));
+                }
+            }
+            _ => self.flag(pc, MismatchKind::UnmatchedReturn { to }, None),
+        }
+    }
+
+    // Called whenever sp moves up without a RET. Frames whose return address is now above
+    // the stack were never returned from.
+    pub(super) fn stack_moved(&mut self, pc: u16, sp: u16) {
+        self.discard_below(pc, sp);
+    }
+
+    pub(super) fn exchanged(&mut self, pc: u16, sp: u16) {
+        if let Some(frame) = self.frames.last().copied() {
+            if frame.sp == sp {
+                self.flag(pc, MismatchKind::Exchanged, Some(frame));
+            }
+        }
+    }
+
+    fn discard_below(&mut self, pc: u16, sp: u16) {
+        while let Some(frame) = self.frames.last().copied() {
+            if frame.sp >= sp {
+                break;
+            }
+            self.frames.pop();
+            self.flag(pc, MismatchKind::Discarded, Some(frame));
+        }
+    }
+
+    fn flag(&mut self, pc: u16, kind: MismatchKind, frame: Option<Frame>) {
+        if self.mismatches.len() == MAX_MISMATCHES {
+            self.mismatches.remove(0);
+        }
+        self.mismatches.push(Mismatch { pc, kind, frame });
+    }
+
+    // One line per frame, innermost first. name turns an address into a symbol, if it knows
+    // one.
+    pub fn backtrace<F: Fn(u16) -> Option<String>>(&self, pc: u16, name: F) -> Vec<String> {
+        let location = |addr: u16, routine: Option<u16>| match routine {
+            Some(start) => {
+                let routine = name(start).unwrap_or_else(|| format!("{:04X}", start));
+                if addr == start {
+                    format!("{:04X}  {}", addr, routine)
+                } else {
+                    format!("{:04X}  {}+{:X}", addr, routine, addr.wrapping_sub(start))
+                }
+            }
+            None => format!("{:04X}", addr),
+        };
+        let mut lines = Vec::new();
+        let mut addr = pc;
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Call => "",
+                FrameKind::Rst => "  (rst)",
+                FrameKind::Interrupt => "  (interrupt)",
+            };
+            lines.push(format!(
+                "#{:<2} {}{}",
+                depth,
+                location(addr, Some(frame.target)),
+                kind
+            ));
+            addr = frame.site;
+        }
+        let depth = self.frames.len();
+        lines.push(format!("#{:<2} {}", depth, location(addr, None)));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(site: u16, target: u16, sp: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            site,
+            target,
+            return_addr: site + 3,
+            sp,
+        }
+    }
+
+    #[test]
+    fn test_matched_return() {
+        let mut stack = CallStack::default();
+        stack.push(call(0x0100, 0x0200, 0x23FE));
+        stack.pop(0x0205, 0x23FE, 0x0103);
+
+        assert!(stack.frames().is_empty());
+        assert!(stack.mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_popped_return_address() {
+        let mut stack = CallStack::default();
+        stack.push(call(0x0100, 0x0200, 0x23FE));
+        stack.stack_moved(0x0200, 0x2400);
+
+        assert!(stack.frames().is_empty());
+        assert_eq!(stack.mismatches()[0].kind, MismatchKind::Discarded);
+    }
+
+    #[test]
+    fn test_wrong_and_unmatched_returns() {
+        let mut stack = CallStack::default();
+        stack.push(call(0x0100, 0x0200, 0x23FE));
+        stack.pop(0x0205, 0x23FE, 0x0300);
+        stack.pop(0x0305, 0x2400, 0x0400);
+
+        let kinds: Vec<MismatchKind> = stack.mismatches().iter().map(|m| m.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                MismatchKind::WrongReturn { to: 0x0300 },
+                MismatchKind::UnmatchedReturn { to: 0x0400 }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut stack = CallStack::default();
+        stack.push(call(0x0100, 0x0200, 0x23FE));
+        stack.push(call(0x0210, 0x0300, 0x23FC));
+        let name = |addr| {
+            if addr == 0x0200 {
+                Some(String::from("draw"))
+            } else {
+                None
+            }
+        };
+
+        assert_eq!(
+            stack.backtrace(0x0304, name),
+            vec!["#0  0304  0300+4", "#1  0210  draw+10", "#2  0100"]
+        );
+    }
+}
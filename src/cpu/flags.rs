@@ -1,4 +1,6 @@
-#[derive(Clone, Copy, Default)]
+use std::fmt;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct Flags {
     pub z: bool,
     pub s: bool,
@@ -6,3 +8,68 @@ pub struct Flags {
     pub cy: bool,
     pub ac: bool,
 }
+
+impl Flags {
+    pub fn get_by_name(&self, name: &str) -> Option<bool> {
+        match name.to_ascii_lowercase().as_str() {
+            "z" => Some(self.z),
+            "s" => Some(self.s),
+            "p" => Some(self.p),
+            "cy" | "c" => Some(self.cy),
+            "ac" => Some(self.ac),
+            _ => None,
+        }
+    }
+
+    pub fn set_by_name(&mut self, name: &str, value: bool) -> bool {
+        match name.to_ascii_lowercase().as_str() {
+            "z" => self.z = value,
+            "s" => self.s = value,
+            "p" => self.p = value,
+            "cy" | "c" => self.cy = value,
+            "ac" => self.ac = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+// Layout of the flag byte pushed by PUSH PSW: S Z 0 AC 0 P 1 CY
+impl From<Flags> for u8 {
+    fn from(t: Flags) -> u8 {
+        let mut byte: u8 = 0x2;
+        byte |= (t.s as u8) << 7;
+        byte |= (t.z as u8) << 6;
+        byte |= (t.ac as u8) << 4;
+        byte |= (t.p as u8) << 2;
+        byte |= t.cy as u8;
+        byte
+    }
+}
+
+impl From<u8> for Flags {
+    fn from(t: u8) -> Self {
+        Flags {
+            s: (t & 0x80) >> 7 == 1,
+            z: (t & 0x40) >> 6 == 1,
+            ac: (t & 0x10) >> 4 == 1,
+            p: (t & 0x4) >> 2 == 1,
+            cy: (t & 0x1) == 1,
+        }
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |set: bool, name: &'static str| if set { name } else { "-" };
+        write!(
+            f,
+            "{}{}{}{}{}",
+            show(self.s, "S"),
+            show(self.z, "Z"),
+            show(self.ac, "A"),
+            show(self.p, "P"),
+            show(self.cy, "C")
+        )
+    }
+}
@@ -26,6 +26,21 @@ impl Opcode {
             cycles: opcode_name(val).1,
         }
     }
+
+    pub fn length(&self) -> u16 {
+        opcode_length(self.code)
+    }
+}
+
+pub fn opcode_length(val: u8) -> u16 {
+    match val {
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2A | 0x32 | 0x3A => 3,
+        0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA | 0xE2 | 0xEA | 0xF2 | 0xFA => 3,
+        0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC => 3,
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => 2,
+        0xC6 | 0xCE | 0xD3 | 0xD6 | 0xDB | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => 2,
+        _ => 1,
+    }
 }
 
 fn opcode_name(val: u8) -> (String, u8) {
@@ -53,6 +68,7 @@ fn opcode_name(val: u8) -> (String, u8) {
         0x37 => ("STC", 4),
         0x3A => ("LDA", 13),
         0x3F => ("CMC", 4),
+        0x76 => ("HLT", 7),
         0x40...0x7F => ("MOV", 6),
         0x80...0x87 => ("ADD", 4),
         0x88...0x8F => ("ADC", 4),
@@ -62,6 +78,7 @@ fn opcode_name(val: u8) -> (String, u8) {
         0xA8...0xAF => ("XRA", 4),
         0xB0...0xB7 => ("ORA", 4),
         0xB8...0xBF => ("CMP", 4),
+        0xC0 => ("RNZ", 11),
         0xC1 | 0xD1 | 0xE1 | 0xF1 => ("POP", 10),
         0xC2 => ("JNZ", 10),
         0xC3 => ("JMP", 10),
@@ -81,6 +98,7 @@ fn opcode_name(val: u8) -> (String, u8) {
         0xD4 => ("CNC", 17),
         0xD6 => ("SUI", 7),
         0xD8 => ("RC", 11),
+        0xDE => ("SBI", 7),
         0xDA => ("JC", 10),
         0xDB => ("IN", 10),
         0xDC => ("CC", 17),
@@ -106,7 +124,6 @@ fn opcode_name(val: u8) -> (String, u8) {
         0xFB => ("EI", 4),
         0xFC => ("CM", 17),
         0xFE => ("CPI", 7),
-        _ => panic!("How is babby formed {:x}", val),
     };
     (String::from(s.0), s.1)
 }
@@ -36,17 +36,22 @@ impl From<Pointer> for u8 {
     }
 }
 
+// Addresses wrap around at the ends of memory, as on the real chip. Strict mode reports it.
 impl Add<u16> for Pointer {
     type Output = Pointer;
 
     fn add(self, other: u16) -> Pointer {
-        Pointer { x: self.x + other }
+        Pointer {
+            x: self.x.wrapping_add(other),
+        }
     }
 }
 
 impl AddAssign<u16> for Pointer {
     fn add_assign(&mut self, other: u16) {
-        *self = Pointer { x: self.x + other }
+        *self = Pointer {
+            x: self.x.wrapping_add(other),
+        }
     }
 }
 
@@ -54,13 +59,17 @@ impl Sub<u16> for Pointer {
     type Output = Pointer;
 
     fn sub(self, other: u16) -> Pointer {
-        Pointer { x: self.x - other }
+        Pointer {
+            x: self.x.wrapping_sub(other),
+        }
     }
 }
 
 impl SubAssign<u16> for Pointer {
     fn sub_assign(&mut self, other: u16) {
-        *self = Pointer { x: self.x - other }
+        *self = Pointer {
+            x: self.x.wrapping_sub(other),
+        }
     }
 }
 
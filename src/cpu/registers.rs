@@ -52,3 +52,56 @@ impl fmt::Display for Register {
         write!(f, "({:x})", self.0)
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterName {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    F,
+    BC,
+    DE,
+    HL,
+    PSW,
+    SP,
+    PC,
+}
+
+impl RegisterName {
+    pub fn from_name(name: &str) -> Option<RegisterName> {
+        let reg = match name.to_ascii_lowercase().as_str() {
+            "a" => RegisterName::A,
+            "b" => RegisterName::B,
+            "c" => RegisterName::C,
+            "d" => RegisterName::D,
+            "e" => RegisterName::E,
+            "h" => RegisterName::H,
+            "l" => RegisterName::L,
+            "f" => RegisterName::F,
+            "bc" => RegisterName::BC,
+            "de" => RegisterName::DE,
+            "hl" => RegisterName::HL,
+            "psw" | "af" => RegisterName::PSW,
+            "sp" => RegisterName::SP,
+            "pc" => RegisterName::PC,
+            _ => return None,
+        };
+        Some(reg)
+    }
+
+    pub fn is_pair(self) -> bool {
+        matches!(
+            self,
+            RegisterName::BC
+                | RegisterName::DE
+                | RegisterName::HL
+                | RegisterName::PSW
+                | RegisterName::SP
+                | RegisterName::PC
+        )
+    }
+}
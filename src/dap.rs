@@ -0,0 +1,960 @@
+use crate::debugger::{load_file, parse_number, Breakpoint, Debugger, StopReason};
+use crate::disasm;
+use crate::expression::LogMessage;
+use crate::hash::crc32;
+use crate::listing::Listing;
+use crate::machine::cpm::{Cpm, TPA};
+use crate::machine::invaders::Invaders;
+use crate::machine::Bare;
+use crate::strict::Strict;
+use crate::symbols::{user_file, SymbolTable};
+use crate::{Cpu, RegisterName};
+use base64::Engine;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+// How long a continue runs before checking for a pause.
+const CYCLES_PER_SLICE: u64 = 100_000;
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+const REGISTERS: [RegisterName; 12] = [
+    RegisterName::A,
+    RegisterName::B,
+    RegisterName::C,
+    RegisterName::D,
+    RegisterName::E,
+    RegisterName::H,
+    RegisterName::L,
+    RegisterName::BC,
+    RegisterName::DE,
+    RegisterName::HL,
+    RegisterName::SP,
+    RegisterName::PC,
+];
+const FLAGS: [&str; 5] = ["s", "z", "ac", "p", "cy"];
+
+// Messages are JSON with an HTTP style Content-Length header. Returns None at end of input.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| invalid_data("Message without a Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid_data(&e.to_string()))
+}
+
+pub fn write_message<W: Write>(out: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Handles requests until the client disconnects. Requests come in on a channel so a running
+// program can be paused.
+pub fn serve<W: Write>(requests: Receiver<Value>, out: W) -> io::Result<()> {
+    let mut server = Server::new(out);
+    while !server.quit {
+        let request = if server.running {
+            match requests.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => {
+                    server.run_slice()?;
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match requests.recv() {
+                Ok(request) => request,
+                Err(_) => break,
+            }
+        };
+        server.handle(&request)?;
+    }
+    Ok(())
+}
+
+pub struct Server<W: Write> {
+    out: W,
+    seq: u64,
+    debugger: Option<Debugger>,
+    console: Option<Rc<RefCell<Cpm>>>,
+    listings: HashMap<String, Listing>,
+    // The listing stack frames point into, from the launch arguments.
+    main_listing: Option<String>,
+    source_breakpoints: HashMap<String, BTreeMap<u16, Breakpoint>>,
+    instruction_breakpoints: BTreeMap<u16, Breakpoint>,
+    events: Vec<Value>,
+    configured: bool,
+    stop_on_entry: bool,
+    running: bool,
+    quit: bool,
+}
+
+impl<W: Write> Server<W> {
+    pub fn new(out: W) -> Self {
+        Server {
+            out,
+            seq: 0,
+            debugger: None,
+            console: None,
+            listings: HashMap::new(),
+            main_listing: None,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: BTreeMap::new(),
+            events: Vec::new(),
+            configured: false,
+            stop_on_entry: false,
+            running: false,
+            quit: false,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // Answers a request, then sends any events it raised.
+    pub fn handle(&mut self, request: &Value) -> io::Result<()> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        debug!("dap <- {}", request);
+        let result = self.dispatch(command, args);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)?;
+        for event in std::mem::take(&mut self.events) {
+            self.send(event)?;
+        }
+        Ok(())
+    }
+
+    // Runs the program for a while, reporting if it stops.
+    pub fn run_slice(&mut self) -> io::Result<()> {
+        let reason = match self.debugger.as_mut() {
+            Some(debugger) => debugger.run_for(CYCLES_PER_SLICE),
+            None => return Ok(()),
+        };
+        if reason != StopReason::Step {
+            self.running = false;
+            self.stopped(reason);
+        }
+        self.flush_console();
+        for event in std::mem::take(&mut self.events) {
+            self.send(event)?;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                self.event("initialized", json!({}));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsHitConditionalBreakpoints": true,
+                    "supportsLogPoints": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsSetVariable": true,
+                    "supportsStepBack": true,
+                    "supportsTerminateRequest": true,
+                }))
+            }
+            "launch" => self.launch(args),
+            "configurationDone" => {
+                self.configured = true;
+                self.start();
+                Ok(json!({}))
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "8080" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({
+                "scopes": [{
+                    "name": "Registers",
+                    "presentationHint": "registers",
+                    "variablesReference": REGISTERS_REFERENCE,
+                    "expensive": false,
+                }]
+            })),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "evaluate" => self.evaluate(args),
+            "continue" => {
+                self.debugger()?;
+                self.running = true;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                let debugger = self.debugger_mut()?;
+                let reason = match command {
+                    "next" => debugger.step_over(),
+                    "stepIn" => debugger.step(),
+                    "stepBack" => debugger.reverse_next(),
+                    "reverseContinue" => debugger.reverse_continue(),
+                    _ => debugger.step_out(),
+                };
+                self.stopped(reason);
+                self.flush_console();
+                Ok(json!({}))
+            }
+            "pause" => {
+                if self.running {
+                    self.running = false;
+                    self.stopped(StopReason::Interrupted);
+                }
+                Ok(json!({}))
+            }
+            "readMemory" => self.read_memory(args),
+            "disassemble" => self.disassemble(args),
+            "terminate" => {
+                self.running = false;
+                self.event("terminated", json!({}));
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.quit = true;
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    // Arguments: program, and optionally machine ("cpm", "bare" or "invaders"), loadAddress,
+    // listing and stopOnEntry. .COM files default to cpm.
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("launch needs a program")?;
+        let is_com = program.to_lowercase().ends_with(".com");
+        let machine = args["machine"]
+            .as_str()
+            .unwrap_or(if is_com { "cpm" } else { "bare" });
+        let load_address = match &args["loadAddress"] {
+            Value::Null if machine == "cpm" => TPA,
+            Value::Null => 0,
+            Value::Number(number) => number
+                .as_u64()
+                .and_then(|number| u16::try_from(number).ok())
+                .ok_or("Bad loadAddress")?,
+            value => parse_number(value.as_str().unwrap_or_default()).ok_or("Bad loadAddress")?,
+        };
+
+        let mut cpu = Cpu::new();
+        let (loaded, length) = load_file(&mut cpu, program, load_address)
+            .map_err(|e| format!("Couldn't load {}: {}", program, e))?;
+        cpu.set_pc(load_address);
+        let mut debugger = match machine {
+            "cpm" => {
+                Cpm::install(&mut cpu);
+                let console = Rc::new(RefCell::new(Cpm::new()));
+                self.console = Some(console.clone());
+                Debugger::new(cpu, Box::new(console))
+            }
+            "invaders" => Debugger::new(cpu, Box::new(Invaders::new())),
+            "bare" => Debugger::new(cpu, Box::new(Bare)),
+            _ => return Err(format!("Unknown machine {}", machine)),
+        };
+        // strict stops on stack and execution faults, as an exception.
+        if args["strict"].as_bool().unwrap_or(false) {
+            let map = debugger.machine.memory_map();
+            debugger.set_strict(Some(Strict::new(map)));
+        }
+        self.debugger = Some(debugger);
+
+        if let Some(path) = args["listing"].as_str() {
+            let labels = self.listing(path)?.labels().to_vec();
+            let symbols = &mut self.debugger_mut()?.symbols;
+            for (addr, name) in labels {
+                symbols.add(addr, &name);
+            }
+            self.main_listing = Some(path.to_string());
+        }
+        // symbols is a symbol file, or a list of them.
+        let symbol_files = match &args["symbols"] {
+            Value::String(path) => vec![path.as_str()],
+            value => value
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|path| path.as_str())
+                .collect(),
+        };
+        let memory = self.debugger()?.cpu.get_memory();
+        let end = (usize::from(loaded) + length).min(memory.len());
+        let hash = crc32(&memory[usize::from(loaded)..end]);
+        let symbols = &mut self.debugger_mut()?.symbols;
+        for path in symbol_files {
+            symbols
+                .load(path)
+                .map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+        }
+        if let Some(path) = user_file(hash) {
+            symbols
+                .attach(path)
+                .map_err(|e| format!("Couldn't read user labels: {}", e))?;
+        }
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.sync_breakpoints();
+        self.start();
+        Ok(json!({}))
+    }
+
+    // Launch and configurationDone can come in either order. The program starts after both.
+    fn start(&mut self) {
+        if !self.configured || self.debugger.is_none() || self.running {
+            return;
+        }
+        if self.stop_on_entry {
+            self.stop_on_entry = false;
+            self.event(
+                "stopped",
+                json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }),
+            );
+        } else {
+            self.running = true;
+        }
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or("Breakpoints need a source path")?
+            .to_string();
+        let requested: Vec<(u64, Result<Breakpoint, String>)> = args["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|b| Some((b["line"].as_u64()?, self.breakpoint(b))))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let listing = self.listing(&path)?;
+        let mut addresses = BTreeMap::new();
+        let breakpoints: Vec<Value> = requested
+            .into_iter()
+            .map(
+                |(line, breakpoint)| match (listing.address_of_line(line as usize), breakpoint) {
+                    (Some((line, addr)), Ok(breakpoint)) => {
+                        addresses.insert(addr, breakpoint);
+                        json!({
+                            "verified": true,
+                            "line": line,
+                            "instructionReference": memory_reference(addr),
+                        })
+                    }
+                    (_, Err(message)) => json!({ "verified": false, "message": message }),
+                    (None, _) => {
+                        json!({ "verified": false, "message": "No code at or after this line" })
+                    }
+                },
+            )
+            .collect();
+        self.source_breakpoints.insert(path, addresses);
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"]
+                .as_str()
+                .unwrap_or_default();
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let symbols = self.debugger.as_ref().map(|d| &d.symbols);
+            let addr = match symbols {
+                Some(symbols) => symbols.resolve(reference),
+                None => parse_number(reference),
+            };
+            match (addr, self.breakpoint(breakpoint)) {
+                (Some(addr), Ok(condition)) => match offset_address(addr, offset) {
+                    Some(addr) => {
+                        self.instruction_breakpoints.insert(addr, condition);
+                        breakpoints.push(json!({
+                            "verified": true,
+                            "instructionReference": memory_reference(addr),
+                        }));
+                    }
+                    None => breakpoints.push(json!({
+                        "verified": false,
+                        "message": format!("{} offset {} is outside memory", reference, offset),
+                    })),
+                },
+                (_, Err(message)) => {
+                    breakpoints.push(json!({ "verified": false, "message": message }))
+                }
+                (None, _) => breakpoints.push(json!({ "verified": false })),
+            }
+        }
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // Source and instruction breakpoints are set separately by the client but share the
+    // debugger's single set. Instruction breakpoints win where both are at one address.
+    // Setting breakpoints starts their hit counts again.
+    fn sync_breakpoints(&mut self) {
+        let breakpoints: BTreeMap<u16, Breakpoint> = self
+            .source_breakpoints
+            .values()
+            .flatten()
+            .chain(self.instruction_breakpoints.iter())
+            .map(|(addr, breakpoint)| (*addr, breakpoint.clone()))
+            .collect();
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.clear_breakpoints();
+            for (addr, breakpoint) in breakpoints {
+                debugger.set_breakpoint(addr, breakpoint);
+            }
+        }
+    }
+
+    // condition and logMessage use the debugger's expression language. hitCondition is a
+    // decimal count, stopping from that hit on, or a comparison with one, like "== 5" or
+    // "% 3" for every third hit.
+    fn breakpoint(&self, args: &Value) -> Result<Breakpoint, String> {
+        let empty = SymbolTable::new();
+        let symbols = self.debugger.as_ref().map_or(&empty, |d| &d.symbols);
+        let mut conditions = Vec::new();
+        if let Some(condition) = args["condition"].as_str().filter(|c| !c.trim().is_empty()) {
+            conditions.push(format!("({})", condition));
+        }
+        if let Some(hits) = args["hitCondition"].as_str().map(str::trim) {
+            let split = hits
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(hits.len());
+            let (op, count) = (hits[..split].trim(), &hits[split..]);
+            let count: u64 = count
+                .parse()
+                .map_err(|_| format!("Bad hit condition {}", hits))?;
+            conditions.push(match op {
+                "" => format!("hits >= #{}", count),
+                "%" => format!("hits % #{} == 0", count),
+                "=" => format!("hits == #{}", count),
+                "==" | "!=" | "<" | "<=" | ">" | ">=" => format!("hits {} #{}", op, count),
+                _ => return Err(format!("Bad hit condition {}", hits)),
+            });
+        }
+        let mut text = String::new();
+        if !conditions.is_empty() {
+            text = format!("if {}", conditions.join(" && "));
+        }
+        let mut breakpoint = Breakpoint::parse(&text, symbols)?;
+        if let Some(message) = args["logMessage"].as_str() {
+            breakpoint.log = Some(LogMessage::parse(message, symbols)?);
+        }
+        Ok(breakpoint)
+    }
+
+    // One frame per call on the shadow call stack, innermost first.
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let debugger = self.debugger()?;
+        let cpu = &debugger.cpu;
+        let mut locations = Vec::new();
+        let mut addr = cpu.get_pc();
+        if let Some(stack) = cpu.call_stack() {
+            for frame in stack.frames().iter().rev() {
+                locations.push((addr, Some(frame.target)));
+                addr = frame.site;
+            }
+        }
+        locations.push((addr, None));
+        let frames: Vec<Value> = locations
+            .iter()
+            .enumerate()
+            .map(|(id, (addr, routine))| {
+                let at = routine.unwrap_or(*addr);
+                let name = match debugger.symbols.name(at) {
+                    Some(name) => name.to_string(),
+                    None => format!("{:04X}", at),
+                };
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": memory_reference(*addr),
+                });
+                if let Some(path) = &self.main_listing {
+                    if let Some(line) = self.listings[path].line_of_address(*addr) {
+                        frame["line"] = json!(line);
+                        frame["column"] = json!(1);
+                        frame["source"] = json!({ "path": path });
+                    }
+                }
+                frame
+            })
+            .collect();
+        Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let cpu = &self.debugger()?.cpu;
+        let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => {
+                let mut variables: Vec<Value> = REGISTERS
+                    .iter()
+                    .map(|reg| {
+                        let value = cpu.read_register(*reg);
+                        let mut variable = json!({
+                            "name": format!("{:?}", reg),
+                            "value": format_register(*reg, value),
+                            "variablesReference": 0,
+                        });
+                        if reg.is_pair() {
+                            variable["memoryReference"] = json!(memory_reference(value));
+                        }
+                        variable
+                    })
+                    .collect();
+                variables.push(json!({
+                    "name": "Flags",
+                    "value": cpu.get_flags().to_string(),
+                    "variablesReference": FLAGS_REFERENCE,
+                }));
+                variables
+            }
+            Some(FLAGS_REFERENCE) => {
+                let flags = cpu.get_flags();
+                FLAGS
+                    .iter()
+                    .map(|name| {
+                        let value = flags.get_by_name(name).unwrap_or(false);
+                        json!({
+                            "name": name.to_uppercase(),
+                            "value": if value { "1" } else { "0" },
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or_default().to_lowercase();
+        let text = args["value"].as_str().unwrap_or_default();
+        let value = parse_number(text).ok_or_else(|| format!("{} isn't a hex number", text))?;
+        let cpu = &mut self.debugger_mut()?.cpu;
+        let shown = match RegisterName::from_name(&name) {
+            Some(reg) => {
+                if !reg.is_pair() && value > 0xFF {
+                    return Err(format!("{:X} doesn't fit in {}", value, name));
+                }
+                cpu.write_register(reg, value);
+                format_register(reg, value)
+            }
+            None => {
+                let mut flags = cpu.get_flags();
+                if !flags.set_by_name(&name, value != 0) {
+                    return Err(format!("Unknown register {}", name));
+                }
+                cpu.write_register(RegisterName::F, u16::from(u8::from(flags)));
+                String::from(if value != 0 { "1" } else { "0" })
+            }
+        };
+        self.debugger_mut()?.state_changed();
+        Ok(json!({ "value": shown }))
+    }
+
+    // Hovering or typing a register or flag name shows its value, and a label shows its
+    // address.
+    fn evaluate(&mut self, args: &Value) -> Result<Value, String> {
+        let expression = args["expression"].as_str().unwrap_or_default().trim();
+        let name = expression.to_lowercase();
+        let debugger = self.debugger()?;
+        let cpu = &debugger.cpu;
+        let result = match RegisterName::from_name(&name) {
+            Some(reg) => format_register(reg, cpu.read_register(reg)),
+            None => match cpu.get_flags().get_by_name(&name) {
+                Some(value) => String::from(if value { "1" } else { "0" }),
+                None => match debugger.symbols.resolve(expression) {
+                    Some(addr) => {
+                        return Ok(json!({
+                            "result": format!("{:04X}", addr),
+                            "memoryReference": memory_reference(addr),
+                            "variablesReference": 0,
+                        }))
+                    }
+                    None => return Err(format!("Can't evaluate {}", expression)),
+                },
+            },
+        };
+        Ok(json!({ "result": result, "variablesReference": 0 }))
+    }
+
+    fn read_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let start = self.address_argument(args)?;
+        let count = args["count"].as_u64().unwrap_or(0) as usize;
+        let memory = self.debugger()?.cpu.get_memory();
+        let end = usize::from(start).saturating_add(count).min(memory.len());
+        let data =
+            base64::engine::general_purpose::STANDARD.encode(&memory[usize::from(start)..end]);
+        Ok(json!({
+            "address": memory_reference(start),
+            "data": data,
+            "unreadableBytes": count - (end - usize::from(start)),
+        }))
+    }
+
+    fn disassemble(&mut self, args: &Value) -> Result<Value, String> {
+        let start = self.address_argument(args)?;
+        let skip = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64().unwrap_or(0) as usize;
+        let listing = self.main_listing.as_ref().map(|path| &self.listings[path]);
+        let debugger = self.debugger()?;
+        let memory = debugger.cpu.get_memory();
+        let start = if skip < 0 {
+            disasm::find_previous(memory, start, skip.unsigned_abs() as usize)
+        } else {
+            start
+        };
+        let instructions: Vec<Value> =
+            disasm::disassemble_range(memory, start, count.saturating_add(skip.max(0) as usize))
+                .into_iter()
+                .skip(skip.max(0) as usize)
+                .map(|instruction| {
+                    let bytes: Vec<String> = instruction
+                        .bytes()
+                        .iter()
+                        .map(|b| format!("{:02X}", b))
+                        .collect();
+                    let mut value = json!({
+                        "address": memory_reference(instruction.address),
+                        "instructionBytes": bytes.join(" "),
+                        "instruction": disasm::symbolic_text(&instruction, &debugger.symbols),
+                    });
+                    if let Some(name) = debugger.symbols.name(instruction.address) {
+                        value["symbol"] = json!(name);
+                    }
+                    let line = listing.and_then(|l| l.line_of_address(instruction.address));
+                    if let (Some(line), Some(path)) = (line, &self.main_listing) {
+                        value["line"] = json!(line);
+                        value["location"] = json!({ "path": path });
+                    }
+                    value
+                })
+                .collect();
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn address_argument(&self, args: &Value) -> Result<u16, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let addr = parse_number(reference).ok_or_else(|| format!("Bad address {}", reference))?;
+        let offset = args["offset"].as_i64().unwrap_or(0);
+        offset_address(addr, offset)
+            .ok_or_else(|| format!("{} offset {} is outside memory", reference, offset))
+    }
+
+    fn listing(&mut self, path: &str) -> Result<&Listing, String> {
+        if !self.listings.contains_key(path) {
+            let listing =
+                Listing::load(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+            self.listings.insert(path.to_string(), listing);
+        }
+        Ok(&self.listings[path])
+    }
+
+    fn stopped(&mut self, reason: StopReason) {
+        let reason = match reason {
+            StopReason::Step => "step",
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::Watchpoint(_, _) => "data breakpoint",
+            StopReason::Interrupted => "pause",
+            StopReason::Fault(_) => {
+                self.report_faults();
+                "exception"
+            }
+            StopReason::StartOfHistory => {
+                self.output("console", "No earlier history\n");
+                "step"
+            }
+            StopReason::Halted(addr) => {
+                self.flush_console();
+                self.output("console", &format!("Halted at {:04X}\n", addr));
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+                return;
+            }
+        };
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        );
+    }
+
+    fn report_faults(&mut self) {
+        let lines: Vec<String> = match self.debugger.as_mut() {
+            Some(debugger) => debugger
+                .take_faults()
+                .iter()
+                .flat_map(|fault| fault.report(debugger.cpu.get_memory(), &debugger.symbols))
+                .collect(),
+            None => return,
+        };
+        for line in lines {
+            self.output("console", &format!("{}\n", line));
+        }
+    }
+
+    // Log-only breakpoint messages, then what the program printed.
+    fn flush_console(&mut self) {
+        let messages = self
+            .debugger
+            .as_mut()
+            .map(|debugger| debugger.take_messages())
+            .unwrap_or_default();
+        for message in messages {
+            self.output("console", &format!("{}\n", message));
+        }
+        let output = match &self.console {
+            Some(console) => console.borrow_mut().take_output(),
+            None => return,
+        };
+        if !output.is_empty() {
+            self.output("stdout", &String::from_utf8_lossy(&output));
+        }
+    }
+
+    fn output(&mut self, category: &str, text: &str) {
+        self.event("output", json!({ "category": category, "output": text }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.events
+            .push(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        debug!("dap -> {}", message);
+        write_message(&mut self.out, &message)
+    }
+
+    fn debugger(&self) -> Result<&Debugger, String> {
+        self.debugger
+            .as_ref()
+            .ok_or_else(|| String::from("Nothing has been launched"))
+    }
+
+    fn debugger_mut(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger
+            .as_mut()
+            .ok_or_else(|| String::from("Nothing has been launched"))
+    }
+}
+
+fn memory_reference(addr: u16) -> String {
+    format!("0x{:04X}", addr)
+}
+
+fn format_register(reg: RegisterName, value: u16) -> String {
+    if reg.is_pair() {
+        format!("0x{:04X}", value)
+    } else {
+        format!("0x{:02X}", value)
+    }
+}
+
+// addr moved by a client's offset, if that stays inside memory.
+fn offset_address(addr: u16, offset: i64) -> Option<u16> {
+    i64::from(addr)
+        .checked_add(offset)
+        .and_then(|addr| u16::try_from(addr).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn request(seq: u64, command: &str, arguments: Value) -> Value {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    fn messages(out: &[u8]) -> Vec<Value> {
+        let mut input = out;
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut input).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let mut out = Vec::new();
+        write_message(&mut out, &json!({ "seq": 1 })).unwrap();
+
+        assert_eq!(out, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+        assert_eq!(messages(&out), vec![json!({ "seq": 1 })]);
+    }
+
+    #[test]
+    fn test_breakpoint_conditions() {
+        let server = Server::new(Vec::new());
+        let breakpoint = server
+            .breakpoint(&json!({ "condition": "a > 10", "hitCondition": "% 3" }))
+            .unwrap();
+        assert_eq!(
+            breakpoint.condition.unwrap().to_string(),
+            "(a > 10) && hits % #3 == 0"
+        );
+        let logpoint = server
+            .breakpoint(&json!({ "hitCondition": "5", "logMessage": "a={a}" }))
+            .unwrap();
+        assert_eq!(logpoint.condition.unwrap().to_string(), "hits >= #5");
+        assert_eq!(logpoint.log.unwrap().to_string(), "a={a}");
+        assert!(server
+            .breakpoint(&json!({ "hitCondition": "~ 2" }))
+            .is_err());
+        assert!(server.breakpoint(&json!({ "condition": "a >" })).is_err());
+    }
+
+    #[test]
+    fn test_bad_load_address() {
+        let mut server = Server::new(Vec::new());
+        let launch = json!({ "program": "hello.com", "loadAddress": 0x10000 });
+        assert_eq!(server.launch(&launch).unwrap_err(), "Bad loadAddress");
+    }
+
+    #[test]
+    fn test_offsets_outside_memory() {
+        let dir = std::env::temp_dir().join(format!("emu8080-dap-offsets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("ret.com");
+        fs::write(&program, [0xC9]).unwrap();
+        let mut server = Server::new(Vec::new());
+        server.handle(&request(1, "initialize", json!({}))).unwrap();
+        let launch = json!({ "program": program.to_str().unwrap() });
+        server.handle(&request(2, "launch", launch)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let breakpoints = json!({ "breakpoints": [
+            { "instructionReference": "0x0100", "offset": 2 },
+            { "instructionReference": "0xFFFF", "offset": 1 },
+            { "instructionReference": "0x0100", "offset": i64::MAX },
+        ] });
+        server
+            .handle(&request(3, "setInstructionBreakpoints", breakpoints))
+            .unwrap();
+        let read = json!({ "memoryReference": "0x0100", "offset": i64::MAX, "count": 4 });
+        server.handle(&request(4, "readMemory", read)).unwrap();
+        let disassemble =
+            json!({ "memoryReference": "0x0100", "offset": -0x101, "instructionCount": 1 });
+        server
+            .handle(&request(5, "disassemble", disassemble))
+            .unwrap();
+
+        let messages = messages(&server.out);
+        let response = |seq: u64| {
+            messages
+                .iter()
+                .find(|m| m["type"] == "response" && m["request_seq"] == seq)
+                .unwrap()
+        };
+        let breakpoints = &response(3)["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["instructionReference"], "0x0102");
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert_eq!(breakpoints[2]["verified"], false);
+        assert!(breakpoints[2]["message"].is_string());
+        assert_eq!(response(4)["success"], false);
+        assert_eq!(response(5)["success"], false);
+    }
+
+    #[test]
+    fn test_launch_and_break_in_listing() {
+        let dir = std::env::temp_dir().join(format!("emu8080-dap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("hello.com");
+        let listing = dir.join("hello.lst");
+        // MVI C,2 / MVI E,'!' / CALL 0005 / RET
+        fs::write(&program, [0x0E, 0x02, 0x1E, 0x21, 0xCD, 0x05, 0x00, 0xC9]).unwrap();
+        fs::write(
+            &listing,
+            "0100 0E02      MVI C,2\n\
+             0102 1E21      MVI E,'!'\n\
+             0104 CD0500    CALL 5\n\
+             0107 C9        RET\n",
+        )
+        .unwrap();
+        let listing = listing.to_str().unwrap();
+
+        let mut server = Server::new(Vec::new());
+        server.handle(&request(1, "initialize", json!({}))).unwrap();
+        let launch = json!({ "program": program.to_str().unwrap(), "listing": listing });
+        server.handle(&request(2, "launch", launch)).unwrap();
+        let breakpoints = json!({ "source": { "path": listing }, "breakpoints": [{ "line": 3 }] });
+        server
+            .handle(&request(3, "setBreakpoints", breakpoints))
+            .unwrap();
+        server
+            .handle(&request(4, "configurationDone", json!({})))
+            .unwrap();
+        while server.is_running() {
+            server.run_slice().unwrap();
+        }
+        server.handle(&request(5, "stackTrace", json!({}))).unwrap();
+        server.handle(&request(6, "continue", json!({}))).unwrap();
+        while server.is_running() {
+            server.run_slice().unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let messages = messages(&server.out);
+        let find = |kind: &str, name: &str| {
+            messages
+                .iter()
+                .find(|m| m["type"] == kind && (m["command"] == name || m["event"] == name))
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(find("event", "stopped")["body"]["reason"], "breakpoint");
+        let frame = &find("response", "stackTrace")["body"]["stackFrames"][0];
+        assert_eq!(frame["line"], 3);
+        assert_eq!(frame["instructionPointerReference"], "0x0104");
+        assert_eq!(find("event", "output")["body"]["output"], "!");
+        find("event", "terminated");
+    }
+}
@@ -0,0 +1,689 @@
+mod reverse;
+
+use crate::coverage::Coverage;
+use crate::expression::{Expression, LogMessage, State};
+use crate::heatmap::Heatmap;
+use crate::image::{Image, ImageError};
+use crate::machine::{self, Machine};
+use crate::profile::Profiler;
+use crate::strict::{Fault, Strict};
+use crate::symbols::SymbolTable;
+use crate::trace::Tracer;
+use crate::{Access, Cpu, Mismatch, RegisterName};
+use reverse::{Event, Recorder, Replay, Snapshot, SNAPSHOT_INTERVAL};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const HISTORY_LENGTH: usize = 64;
+// Log messages waiting for a frontend to print them. The oldest go first.
+const MAX_MESSAGES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    Watchpoint(u16, Access),
+    Halted(u16),
+    Interrupted,
+    // Strict mode caught the instruction at this address doing something it shouldn't. The
+    // instruction hasn't run; stepping or continuing runs it.
+    Fault(u16),
+    // Reverse execution ran out of snapshots to go back to.
+    StartOfHistory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub len: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn hit_by(&self, addr: u16, access: Access) -> bool {
+        let in_range = addr.wrapping_sub(self.addr) < self.len.max(1);
+        let kind_matches = matches!(
+            (self.kind, access),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, Access::Read)
+                | (WatchKind::Write, Access::Write)
+        );
+        in_range && kind_matches
+    }
+}
+
+// Stops when execution reaches it and the condition, if any, holds. Breakpoints with a log
+// message print it instead and carry on. hits is how many times execution has reached the
+// breakpoint's address, this time included. Breakpoints with no address are checked before
+// every instruction, and count the times they've fired.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Breakpoint {
+    pub condition: Option<Expression>,
+    pub log: Option<LogMessage>,
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    // The way frontends take them: [if expr] [log text], e.g.
+    //   if a > 10 && flags.cy log score {w[20F8]}
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<Breakpoint, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let (condition, log) = match words.iter().position(|word| *word == "log") {
+            Some(i) => (&words[..i], Some(words[i + 1..].join(" "))),
+            None => (&words[..], None),
+        };
+        let condition = match condition.split_first() {
+            Some((&"if", expr)) => Some(Expression::parse(&expr.join(" "), symbols)?),
+            Some((word, _)) => return Err(format!("Expected if or log, not {}", word)),
+            None => None,
+        };
+        let log = log
+            .map(|text| LogMessage::parse(&text, symbols))
+            .transpose()?;
+        Ok(Breakpoint {
+            condition,
+            log,
+            hits: 0,
+        })
+    }
+
+    fn holds(&self, state: &State) -> bool {
+        self.condition
+            .as_ref()
+            .is_none_or(|condition| condition.is_true(state))
+    }
+}
+
+// Wraps a cpu and the machine it's plugged into, and runs them under control of a frontend.
+pub struct Debugger {
+    pub cpu: Cpu,
+    pub machine: Box<dyn Machine>,
+    pub symbols: SymbolTable,
+    breakpoints: BTreeMap<u16, Breakpoint>,
+    conditions: Vec<Breakpoint>,
+    messages: VecDeque<String>,
+    watchpoints: Vec<Watchpoint>,
+    cycles: u64,
+    steps: u128,
+    history: VecDeque<u16>,
+    interrupt: Arc<AtomicBool>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    heatmap: Option<Heatmap>,
+    strict: Option<Strict>,
+    faults: Vec<Fault>,
+    // The step a fault stopped in front of, so the next step goes through.
+    fault_step: Option<u128>,
+    snapshots: VecDeque<Snapshot>,
+    events: VecDeque<(u128, Event)>,
+    // The furthest step run live. Steps before it are replayed.
+    present: u128,
+}
+
+impl Debugger {
+    pub fn new(mut cpu: Cpu, machine: Box<dyn Machine>) -> Self {
+        if cpu.call_stack().is_none() {
+            cpu.set_call_tracking(true);
+        }
+        Debugger {
+            cpu,
+            machine,
+            symbols: SymbolTable::new(),
+            breakpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+            messages: VecDeque::new(),
+            watchpoints: Vec::new(),
+            cycles: 0,
+            steps: 0,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            heatmap: None,
+            strict: None,
+            faults: Vec::new(),
+            fault_step: None,
+            snapshots: VecDeque::new(),
+            events: VecDeque::new(),
+            present: 0,
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn steps(&self) -> u128 {
+        self.steps
+    }
+
+    // Addresses of the most recently executed instructions, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &u16> {
+        self.history.iter()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &u16> {
+        self.breakpoints.keys()
+    }
+
+    pub fn breakpoint(&self, addr: u16) -> Option<&Breakpoint> {
+        self.breakpoints.get(&addr)
+    }
+
+    // An unconditional breakpoint. One already at addr is left as it is.
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        if self.breakpoints.contains_key(&addr) {
+            return false;
+        }
+        self.breakpoints.insert(addr, Breakpoint::default());
+        true
+    }
+
+    // Replaces any breakpoint already at addr.
+    pub fn set_breakpoint(&mut self, addr: u16, breakpoint: Breakpoint) {
+        self.breakpoints.insert(addr, breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    // Breakpoints without an address, in the order they were added.
+    pub fn conditions(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.conditions.iter()
+    }
+
+    pub fn add_condition(&mut self, breakpoint: Breakpoint) {
+        self.conditions.push(breakpoint);
+    }
+
+    pub fn remove_condition(&mut self, index: usize) -> bool {
+        if index >= self.conditions.len() {
+            return false;
+        }
+        self.conditions.remove(index);
+        true
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.conditions.clear();
+    }
+
+    // What log-only breakpoints have printed since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        self.messages.drain(..).collect()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.watchpoints.iter()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    // Innermost frame first, starting from pc.
+    pub fn backtrace(&self) -> Vec<String> {
+        match self.cpu.call_stack() {
+            Some(stack) => stack.backtrace(self.cpu.get_pc(), |addr| {
+                self.symbols.name(addr).map(String::from)
+            }),
+            None => Vec::new(),
+        }
+    }
+
+    // Stack misuse the shadow call stack has noticed since the last call.
+    pub fn take_mismatches(&mut self) -> Vec<Mismatch> {
+        self.cpu
+            .call_stack_mut()
+            .map(|stack| stack.take_mismatches())
+            .unwrap_or_default()
+    }
+
+    // Every instruction run from now on is written to the tracer. None turns tracing off.
+    // Traces use the names known when tracing starts.
+    pub fn set_tracer(&mut self, mut tracer: Option<Tracer>) {
+        if let Some(tracer) = tracer.as_mut() {
+            tracer.set_symbols(self.symbols.clone());
+        }
+        self.tracer = tracer;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    // Every instruction run from now on is counted by the profiler. None turns it off.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Every instruction run from now on marks the bytes it uses. None turns it off.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // Every instruction run from now on is counted in the heatmap. None turns it off.
+    pub fn set_heatmap(&mut self, heatmap: Option<Heatmap>) {
+        self.heatmap = heatmap;
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_ref()
+    }
+
+    // Every instruction run from now on is checked first, and execution stops in front of
+    // any that fault. None turns it off.
+    pub fn set_strict(&mut self, strict: Option<Strict>) {
+        self.strict = strict;
+    }
+
+    pub fn strict_mut(&mut self) -> Option<&mut Strict> {
+        self.strict.as_mut()
+    }
+
+    // Faults found since the last call.
+    pub fn take_faults(&mut self) -> Vec<Fault> {
+        std::mem::take(&mut self.faults)
+    }
+
+    // Setting the returned flag from another thread (a Ctrl-C handler, say) stops a running
+    // continue at the next instruction boundary.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    pub fn fire_interrupt(&mut self, vector: u8) -> bool {
+        let taken = self.cpu.generate_interrupt(vector);
+        self.state_changed();
+        taken
+    }
+
+    // HLT isn't executed. The cpu would panic, and a debugger is more use stopped in front of it.
+    // A watchpoint stops after the instruction that touched it has run.
+    pub fn step(&mut self) -> StopReason {
+        let pc = self.cpu.get_pc();
+        if self.cpu.read_memory(pc) == 0x76 {
+            return StopReason::Halted(pc);
+        }
+        let replaying = self.is_replaying();
+        if let Some(strict) = self.strict.as_mut().filter(|_| !replaying) {
+            if self.fault_step != Some(self.steps) {
+                let faults = strict.check(&self.cpu);
+                if !faults.is_empty() {
+                    self.fault_step = Some(self.steps);
+                    self.faults.extend(faults);
+                    return StopReason::Fault(pc);
+                }
+            }
+        }
+        let watched = self.watched_access();
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(pc);
+        // Replayed instructions were traced, profiled and covered the first time round.
+        if let Some(tracer) = self.tracer.as_mut().filter(|_| !replaying) {
+            if let Err(e) = tracer.record(self.cycles, &self.cpu) {
+                warn!("Tracing stopped: {}", e);
+                self.tracer = None;
+            }
+        }
+        let cycles = if replaying {
+            let mut replay = Replay {
+                events: &self.events,
+                step: self.steps,
+            };
+            let cycles = execute(&mut self.cpu, &mut replay, self.cycles);
+            // These were reported the first time round too.
+            self.take_mismatches();
+            cycles
+        } else {
+            if self.steps.is_multiple_of(SNAPSHOT_INTERVAL) || self.snapshots.is_empty() {
+                self.take_snapshot();
+            }
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.before(&self.cpu);
+            }
+            if let Some(coverage) = self.coverage.as_mut() {
+                coverage.record(&self.cpu);
+            }
+            if let Some(heatmap) = self.heatmap.as_mut() {
+                heatmap.record(&self.cpu);
+            }
+            let mut recorder = Recorder {
+                machine: &mut *self.machine,
+                events: &mut self.events,
+                step: self.steps,
+            };
+            let cycles = execute(&mut self.cpu, &mut recorder, self.cycles);
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.after(cycles);
+            }
+            cycles
+        };
+        self.cycles += cycles;
+        self.steps += 1;
+        self.present = self.present.max(self.steps);
+        match watched {
+            Some((addr, access)) => StopReason::Watchpoint(addr, access),
+            None => StopReason::Step,
+        }
+    }
+
+    fn watched_access(&self) -> Option<(u16, Access)> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        self.cpu.memory_accesses().into_iter().find_map(|access| {
+            self.watchpoints
+                .iter()
+                .find(|w| w.hit_by(access.addr, access.kind))
+                .map(|_| (access.addr, access.kind))
+        })
+    }
+
+    // Steps over subroutine calls. Any breakpoint hit inside the call still stops.
+    pub fn step_over(&mut self) -> StopReason {
+        let op = self.cpu.get_current_opcode();
+        match op.code {
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC | 0xC7 | 0xCF | 0xD7
+            | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                let return_addr = self.cpu.get_pc().wrapping_add(op.length());
+                let sp = self.cpu.read_register(RegisterName::SP);
+                self.run_until(|debugger| {
+                    let cpu = &debugger.cpu;
+                    cpu.get_pc() == return_addr && cpu.read_register(RegisterName::SP) >= sp
+                })
+            }
+            _ => self.step(),
+        }
+    }
+
+    // Runs until the current subroutine returns.
+    pub fn step_out(&mut self) -> StopReason {
+        let sp = self.cpu.read_register(RegisterName::SP);
+        self.run_until(|debugger| {
+            let returned = debugger.history.back().is_some_and(|pc| {
+                let code = debugger.cpu.read_memory(*pc);
+                code == 0xC9 || code & 0xC7 == 0xC0
+            });
+            returned && debugger.cpu.read_register(RegisterName::SP) > sp
+        })
+    }
+
+    pub fn cont(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    // Runs for roughly the given number of cycles, for frontends that redraw between slices.
+    pub fn run_for(&mut self, cycles: u64) -> StopReason {
+        let end = self.cycles + cycles;
+        self.run_until(|debugger| debugger.cycles >= end)
+    }
+
+    // The instruction under pc always runs, so continuing from a breakpoint doesn't stop at
+    // the same place again.
+    fn run_until<F: Fn(&Debugger) -> bool>(&mut self, done: F) -> StopReason {
+        self.interrupt.store(false, Ordering::SeqCst);
+        loop {
+            let reason = self.step();
+            if reason != StopReason::Step {
+                return reason;
+            }
+            if self.hit_breakpoint() {
+                return StopReason::Breakpoint(self.cpu.get_pc());
+            }
+            if done(self) {
+                return StopReason::Step;
+            }
+            if self.interrupt.load(Ordering::SeqCst) {
+                return StopReason::Interrupted;
+            }
+        }
+    }
+
+    // Counts the hit, logs for log-only breakpoints, and says whether to stop.
+    fn hit_breakpoint(&mut self) -> bool {
+        let pc = self.cpu.get_pc();
+        let (cpu, cycles, steps) = (&self.cpu, self.cycles, self.steps);
+        let messages = &mut self.messages;
+        let mut stop = false;
+        let mut fire = |breakpoint: &mut Breakpoint| {
+            let state = State {
+                cpu,
+                cycles,
+                steps,
+                hits: breakpoint.hits,
+            };
+            if !breakpoint.holds(&state) {
+                return false;
+            }
+            match &breakpoint.log {
+                Some(log) => {
+                    let message = log.format(&state);
+                    info!("{}", message);
+                    if messages.len() == MAX_MESSAGES {
+                        messages.pop_front();
+                    }
+                    messages.push_back(message);
+                }
+                None => stop = true,
+            }
+            true
+        };
+        if let Some(breakpoint) = self.breakpoints.get_mut(&pc) {
+            breakpoint.hits += 1;
+            fire(breakpoint);
+        }
+        for breakpoint in self.conditions.iter_mut() {
+            if fire(breakpoint) {
+                breakpoint.hits += 1;
+            }
+        }
+        stop
+    }
+
+    // Whether a breakpoint would stop here, without counting a hit. Going backwards uses this,
+    // so conditions see the hit counts as they are now.
+    fn stops_here(&self) -> bool {
+        let pc = self.cpu.get_pc();
+        let stops = |breakpoint: &Breakpoint| {
+            breakpoint.log.is_none()
+                && breakpoint.holds(&State {
+                    cpu: &self.cpu,
+                    cycles: self.cycles,
+                    steps: self.steps,
+                    hits: breakpoint.hits,
+                })
+        };
+        self.breakpoints.get(&pc).is_some_and(stops) || self.conditions.iter().any(stops)
+    }
+}
+
+// One instruction, then any interrupt the machine raises after it.
+fn execute(cpu: &mut Cpu, machine: &mut dyn Machine, cycles: u64) -> u64 {
+    let taken = u64::from(machine::step(cpu, machine));
+    if let Some(vector) = machine.pending_interrupt(cycles + taken) {
+        cpu.generate_interrupt(vector);
+    }
+    taken
+}
+
+// A raw binary goes at addr. Intel HEX and S-record files, known by their extensions, go where
+// they say. Returns the lowest address loaded and the length from there to the highest.
+pub fn load_file(cpu: &mut Cpu, path: &str, addr: u16) -> Result<(u16, usize), ImageError> {
+    let image = Image::load(path, addr)?;
+    image.load_into(cpu);
+    Ok(match image.extent() {
+        Some(extent) => (*extent.start(), extent.len()),
+        None => (addr, 0),
+    })
+}
+
+// Loads a command line argument of the form path[@addr]. Returns the address and length.
+pub fn load_file_arg(cpu: &mut Cpu, arg: &str) -> Result<(u16, usize), String> {
+    let (path, addr) = match arg.find('@') {
+        Some(i) => (&arg[..i], parse_number(&arg[i + 1..])),
+        None => (arg, Some(0)),
+    };
+    let addr = addr.ok_or_else(|| format!("Bad load address in {}", arg))?;
+    load_file(cpu, path, addr).map_err(|e| format!("Couldn't load {}: {}", path, e))
+}
+
+// Numbers typed into a debugger are hex. 0x, $ and a trailing H are all accepted.
+pub fn parse_number(arg: &str) -> Option<u16> {
+    let digits = arg
+        .trim_start_matches("0x")
+        .trim_start_matches('$')
+        .trim_end_matches(&['h', 'H'][..]);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Bare;
+    use crate::strict::FaultKind;
+
+    fn debugger_with(program: &[u8]) -> Debugger {
+        let mut cpu = Cpu::new();
+        for (i, byte) in program.iter().enumerate() {
+            cpu.write_memory(i as u16, *byte);
+        }
+        cpu.write_register(RegisterName::SP, 0x2400);
+        Debugger::new(cpu, Box::new(Bare))
+    }
+
+    #[test]
+    fn test_cont_stops_at_breakpoint() {
+        // MVI A,1 / INR A / INR A / HLT
+        let mut debugger = debugger_with(&[0x3E, 0x01, 0x3C, 0x3C, 0x76]);
+        debugger.add_breakpoint(0x3);
+
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(0x3));
+        assert_eq!(debugger.cpu.read_register(RegisterName::A), 2);
+        assert_eq!(debugger.cont(), StopReason::Halted(0x4));
+        assert_eq!(debugger.cpu.read_register(RegisterName::A), 3);
+    }
+
+    #[test]
+    fn test_conditional_and_log_breakpoints() {
+        // MVI A,0 / INR A / JMP 0002
+        let mut debugger = debugger_with(&[0x3E, 0x00, 0x3C, 0xC3, 0x02, 0x00]);
+        let symbols = SymbolTable::new();
+        debugger.set_breakpoint(
+            0x3,
+            Breakpoint::parse("if a == 5 && hits == #5", &symbols).unwrap(),
+        );
+        debugger.set_breakpoint(
+            0x2,
+            Breakpoint::parse("if a >= 2 log a={a} hit {hits:d}", &symbols).unwrap(),
+        );
+
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(0x3));
+        assert_eq!(debugger.cpu.read_register(RegisterName::A), 5);
+        assert_eq!(
+            debugger.take_messages(),
+            vec!["a=02 hit 3", "a=03 hit 4", "a=04 hit 5"]
+        );
+
+        debugger.clear_breakpoints();
+        debugger.add_condition(Breakpoint::parse("if a == 0A", &symbols).unwrap());
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(0x3));
+        assert_eq!(debugger.cpu.read_register(RegisterName::A), 0x0A);
+        assert!(Breakpoint::parse("when a == 1", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_strict_stops_in_front_of_fault() {
+        // LXI SP,0001 / PUSH B / HLT
+        let mut debugger = debugger_with(&[0x31, 0x01, 0x00, 0xC5, 0x76]);
+        debugger.set_strict(Some(Strict::new(debugger.machine.memory_map())));
+
+        assert_eq!(debugger.cont(), StopReason::Fault(0x3));
+        assert_eq!(debugger.cpu.read_register(RegisterName::SP), 0x0001);
+        let faults = debugger.take_faults();
+        assert_eq!(faults.len(), 1);
+        assert_eq!(faults[0].kind, FaultKind::SpWrap);
+        assert_eq!(faults[0].recent, vec![0x0, 0x3]);
+        // Continuing runs the faulting instruction, which wraps round.
+        assert_eq!(debugger.cont(), StopReason::Halted(0x4));
+        assert_eq!(debugger.cpu.read_register(RegisterName::SP), 0xFFFF);
+    }
+
+    #[test]
+    fn test_watchpoint_stops_after_write() {
+        // LXI H,2000 / MVI M,5 / MOV A,M / HLT
+        let mut debugger = debugger_with(&[0x21, 0x00, 0x20, 0x36, 0x05, 0x7E, 0x76]);
+        debugger.add_watchpoint(Watchpoint {
+            addr: 0x2000,
+            len: 1,
+            kind: WatchKind::Write,
+        });
+
+        assert_eq!(
+            debugger.cont(),
+            StopReason::Watchpoint(0x2000, Access::Write)
+        );
+        assert_eq!(debugger.cpu.get_pc(), 0x5);
+        assert_eq!(debugger.cont(), StopReason::Halted(0x6));
+    }
+
+    #[test]
+    fn test_accesses_at_top_of_memory() {
+        // A NOP in the last bytes of memory has no operand to read past FFFF.
+        let mut cpu = Cpu::new();
+        for pc in [0xFFFE, 0xFFFF].iter() {
+            cpu.write_register(RegisterName::PC, *pc);
+            assert!(cpu.memory_accesses().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_step_out() {
+        // CALL 0004 / HLT / PUSH B / POP B / RET
+        let mut debugger = debugger_with(&[0xCD, 0x04, 0x00, 0x76, 0xC5, 0xC1, 0xC9]);
+        debugger.step();
+
+        assert_eq!(debugger.step_out(), StopReason::Step);
+        assert_eq!(debugger.cpu.get_pc(), 0x3);
+    }
+
+    #[test]
+    fn test_step_over_call() {
+        // CALL 0006 / HLT / NOP / NOP / INR B / RET
+        let mut debugger = debugger_with(&[0xCD, 0x06, 0x00, 0x76, 0x00, 0x00, 0x04, 0xC9]);
+
+        assert_eq!(debugger.step_over(), StopReason::Step);
+        assert_eq!(debugger.cpu.get_pc(), 0x3);
+        assert_eq!(debugger.cpu.read_register(RegisterName::B), 1);
+    }
+}
@@ -0,0 +1,356 @@
+use super::{Debugger, StopReason};
+use crate::machine::Machine;
+use crate::{Cpu, RegisterName};
+use std::collections::VecDeque;
+
+// A snapshot every this many instructions. Going back one instruction replays at most this
+// many from the snapshot before it.
+pub const SNAPSHOT_INTERVAL: u128 = 20_000;
+// About 6.5MB of snapshots, or several seconds of Invaders.
+pub const MAX_SNAPSHOTS: usize = 100;
+
+pub(super) struct Snapshot {
+    pub step: u128,
+    pub cycles: u64,
+    pub cpu: Cpu,
+}
+
+// What the machine did during one instruction, so re-executing it doesn't need the machine.
+pub(super) enum Event {
+    Input(u8),
+    Interrupt(u8),
+    Trap(Box<Cpu>),
+}
+
+// Passes everything through to the live machine and logs what came back.
+pub(super) struct Recorder<'a> {
+    pub machine: &'a mut dyn Machine,
+    pub events: &'a mut VecDeque<(u128, Event)>,
+    pub step: u128,
+}
+
+impl<'a> Machine for Recorder<'a> {
+    fn input(&mut self, port: u8) -> u8 {
+        let value = self.machine.input(port);
+        self.events.push_back((self.step, Event::Input(value)));
+        value
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        self.machine.output(port, value)
+    }
+
+    fn pending_interrupt(&mut self, cycles: u64) -> Option<u8> {
+        let vector = self.machine.pending_interrupt(cycles);
+        if let Some(vector) = vector {
+            self.events.push_back((self.step, Event::Interrupt(vector)));
+        }
+        vector
+    }
+
+    fn trap(&mut self, cpu: &mut Cpu) -> bool {
+        let trapped = self.machine.trap(cpu);
+        if trapped {
+            self.events
+                .push_back((self.step, Event::Trap(Box::new(cpu.clone()))));
+        }
+        trapped
+    }
+}
+
+// Stands in for the machine while going over instructions that have already run. The live
+// machine has seen them once already, so outputs go nowhere.
+pub(super) struct Replay<'a> {
+    pub events: &'a VecDeque<(u128, Event)>,
+    pub step: u128,
+}
+
+impl<'a> Replay<'a> {
+    fn events(&self) -> impl Iterator<Item = &Event> {
+        let step = self.step;
+        let start = self.events.partition_point(|(s, _)| *s < step);
+        self.events
+            .range(start..)
+            .take_while(move |(s, _)| *s == step)
+            .map(|(_, event)| event)
+    }
+}
+
+impl<'a> Machine for Replay<'a> {
+    fn input(&mut self, _port: u8) -> u8 {
+        self.events()
+            .find_map(|event| match event {
+                Event::Input(value) => Some(*value),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    fn pending_interrupt(&mut self, _cycles: u64) -> Option<u8> {
+        self.events().find_map(|event| match event {
+            Event::Interrupt(vector) => Some(*vector),
+            _ => None,
+        })
+    }
+
+    fn trap(&mut self, cpu: &mut Cpu) -> bool {
+        let state = self.events().find_map(|event| match event {
+            Event::Trap(state) => Some(state),
+            _ => None,
+        });
+        match state {
+            Some(state) => {
+                *cpu = (**state).clone();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Going backwards restores the last snapshot before the target and re-executes from there,
+// feeding the cpu the inputs it saw the first time. The machine itself stays in the present, so
+// running forward again replays until it catches up and then carries on live.
+impl Debugger {
+    pub fn reverse_step(&mut self) -> StopReason {
+        if self.steps <= self.oldest_step() {
+            return StopReason::StartOfHistory;
+        }
+        self.travel_to(self.steps - 1);
+        StopReason::Step
+    }
+
+    // Steps back over calls, landing on the CALL rather than the RET. Breakpoints inside the
+    // call still stop it.
+    pub fn reverse_next(&mut self) -> StopReason {
+        let depth = self.call_depth();
+        self.scan_back(|debugger| {
+            if debugger.stops_here() {
+                Some((
+                    debugger.steps,
+                    StopReason::Breakpoint(debugger.cpu.get_pc()),
+                ))
+            } else if debugger.call_depth() <= depth {
+                Some((debugger.steps, StopReason::Step))
+            } else {
+                None
+            }
+        })
+    }
+
+    // Back to the last breakpoint or watchpoint hit before now.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        self.scan_back(|debugger| {
+            let pc = debugger.cpu.get_pc();
+            if let Some((addr, access)) = debugger.watched_access() {
+                Some((debugger.steps + 1, StopReason::Watchpoint(addr, access)))
+            } else if debugger.stops_here() {
+                Some((debugger.steps, StopReason::Breakpoint(pc)))
+            } else {
+                None
+            }
+        })
+    }
+
+    // The debugger can't see changes made straight to cpu, so frontends call this after
+    // editing registers or memory. History after this point is thrown away.
+    pub fn state_changed(&mut self) {
+        let step = self.steps;
+        while self.snapshots.back().is_some_and(|s| s.step >= step) {
+            self.snapshots.pop_back();
+        }
+        while self.events.back().is_some_and(|(s, _)| *s >= step) {
+            self.events.pop_back();
+        }
+        self.present = step;
+        self.take_snapshot();
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.steps < self.present
+    }
+
+    pub(super) fn take_snapshot(&mut self) {
+        if self.snapshots.len() == MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+            let oldest = self.oldest_step();
+            while self.events.front().is_some_and(|(s, _)| *s < oldest) {
+                self.events.pop_front();
+            }
+        }
+        self.snapshots.push_back(Snapshot {
+            step: self.steps,
+            cycles: self.cycles,
+            cpu: self.cpu.clone(),
+        });
+    }
+
+    fn oldest_step(&self) -> u128 {
+        self.snapshots.front().map_or(self.steps, |s| s.step)
+    }
+
+    fn call_depth(&self) -> i64 {
+        match self.cpu.call_stack() {
+            Some(stack) => stack.frames().len() as i64,
+            None => -i64::from(self.cpu.read_register(RegisterName::SP)),
+        }
+    }
+
+    fn restore(&mut self, index: usize) {
+        let snapshot = &self.snapshots[index];
+        self.cpu = snapshot.cpu.clone();
+        self.cycles = snapshot.cycles;
+        self.steps = snapshot.step;
+        self.history.clear();
+    }
+
+    fn travel_to(&mut self, target: u128) {
+        let index = match self.snapshots.iter().rposition(|s| s.step <= target) {
+            Some(index) => index,
+            None => return,
+        };
+        self.restore(index);
+        while self.steps < target {
+            if self.step() == StopReason::Halted(self.cpu.get_pc()) {
+                break;
+            }
+        }
+    }
+
+    // Replays each stretch between snapshots, newest first, and goes to the latest moment
+    // found says to stop at.
+    fn scan_back<F: Fn(&Debugger) -> Option<(u128, StopReason)>>(
+        &mut self,
+        found: F,
+    ) -> StopReason {
+        let now = self.steps;
+        let mut end = now;
+        for index in (0..self.snapshots.len()).rev() {
+            if self.snapshots[index].step >= end {
+                continue;
+            }
+            self.restore(index);
+            let mut latest = None;
+            while self.steps < end {
+                if let Some((step, reason)) = found(self) {
+                    if step < now {
+                        latest = Some((step, reason));
+                    }
+                }
+                if let StopReason::Halted(_) = self.step() {
+                    break;
+                }
+            }
+            if let Some((step, reason)) = latest {
+                self.travel_to(step);
+                return reason;
+            }
+            end = self.snapshots[index].step;
+        }
+        self.travel_to(self.oldest_step());
+        StopReason::StartOfHistory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debugger::{Debugger, StopReason, WatchKind, Watchpoint};
+    use crate::machine::Machine;
+    use crate::{Access, Cpu, RegisterName};
+
+    // Counts up on every IN, so replays that asked the machine again would read new values.
+    struct Counter(u8);
+
+    impl Machine for Counter {
+        fn input(&mut self, _port: u8) -> u8 {
+            self.0 += 1;
+            self.0
+        }
+
+        fn output(&mut self, _port: u8, _value: u8) {}
+    }
+
+    // IN 0 / STA 2000 / CALL 000A / JMP 0000 / INR B / RET
+    fn debugger() -> Debugger {
+        let program = [
+            0xDB, 0x00, 0x32, 0x00, 0x20, 0xCD, 0x0B, 0x00, 0xC3, 0x00, 0x00, 0x04, 0xC9,
+        ];
+        let mut cpu = Cpu::new();
+        for (i, byte) in program.iter().enumerate() {
+            cpu.write_memory(i as u16, *byte);
+        }
+        cpu.write_register(RegisterName::SP, 0x2400);
+        Debugger::new(cpu, Box::new(Counter(0)))
+    }
+
+    #[test]
+    fn test_reverse_step_replays_inputs() {
+        let mut debugger = debugger();
+        for _ in 0..14 {
+            debugger.step();
+        }
+        assert_eq!(debugger.cpu.read_memory(0x2000), 3);
+
+        debugger.reverse_step();
+        debugger.reverse_step();
+        assert_eq!(debugger.steps(), 12);
+        assert_eq!(debugger.cpu.get_pc(), 0x0000);
+        assert_eq!(debugger.cpu.read_register(RegisterName::A), 2);
+        assert_eq!(debugger.cpu.read_memory(0x2000), 2);
+
+        // Forward again replays the 3, then picks up live inputs.
+        debugger.step();
+        assert_eq!(debugger.cpu.read_register(RegisterName::A), 3);
+        for _ in 0..7 {
+            debugger.step();
+        }
+        assert_eq!(debugger.cpu.read_register(RegisterName::A), 4);
+        assert_eq!(debugger.cpu.read_memory(0x2000), 4);
+    }
+
+    #[test]
+    fn test_reverse_continue_finds_last_write() {
+        let mut debugger = debugger();
+        for _ in 0..20 {
+            debugger.step();
+        }
+        debugger.add_watchpoint(Watchpoint {
+            addr: 0x2000,
+            len: 1,
+            kind: WatchKind::Write,
+        });
+
+        assert_eq!(
+            debugger.reverse_continue(),
+            StopReason::Watchpoint(0x2000, Access::Write)
+        );
+        assert_eq!(debugger.cpu.get_pc(), 0x0005);
+        assert_eq!(debugger.steps(), 14);
+        assert_eq!(debugger.cpu.read_memory(0x2000), 3);
+        assert_eq!(
+            debugger.reverse_continue(),
+            StopReason::Watchpoint(0x2000, Access::Write)
+        );
+        assert_eq!(debugger.cpu.read_memory(0x2000), 2);
+    }
+
+    #[test]
+    fn test_reverse_next_skips_call() {
+        let mut debugger = debugger();
+        for _ in 0..5 {
+            debugger.step();
+        }
+        // Just back from the call, at the JMP.
+        assert_eq!(debugger.cpu.get_pc(), 0x0008);
+
+        assert_eq!(debugger.reverse_next(), StopReason::Step);
+        assert_eq!(debugger.cpu.get_pc(), 0x0005);
+        for _ in 0..2 {
+            debugger.reverse_step();
+        }
+        assert_eq!(debugger.steps(), 0);
+        assert_eq!(debugger.reverse_step(), StopReason::StartOfHistory);
+    }
+}
@@ -0,0 +1,432 @@
+pub mod analysis;
+pub mod graph;
+pub mod xref;
+
+use crate::opcode_length;
+use crate::symbols::SymbolTable;
+use std::fmt;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+const STACK_PAIRS: [&str; 4] = ["B", "D", "H", "PSW"];
+const Z80_REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const Z80_PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const Z80_STACK_PAIRS: [&str; 4] = ["BC", "DE", "HL", "AF"];
+
+// Intel's 8080 mnemonics, or the Z80 ones Zilog gave the same instructions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Syntax {
+    Intel,
+    Zilog,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub code: u8,
+    pub operand: u16,
+    pub length: u16,
+}
+
+impl Instruction {
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.code];
+        if self.length > 1 {
+            bytes.push((self.operand & 0xFF) as u8);
+        }
+        if self.length > 2 {
+            bytes.push((self.operand >> 8) as u8);
+        }
+        bytes
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.length)
+    }
+
+    pub fn text(&self, syntax: Syntax) -> String {
+        match syntax {
+            Syntax::Intel => intel_text(self.code, self.operand),
+            Syntax::Zilog => zilog_text(self.code, self.operand),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", intel_text(self.code, self.operand))
+    }
+}
+
+// Reads the instruction at addr, wrapping around the top of the address space for operands.
+pub fn disassemble(memory: &[u8], addr: u16) -> Instruction {
+    let byte = |offset: u16| u16::from(memory[addr.wrapping_add(offset) as usize]);
+    let code = memory[addr as usize];
+    let length = opcode_length(code);
+    let operand = match length {
+        2 => byte(1),
+        3 => (byte(2) << 8) | byte(1),
+        _ => 0,
+    };
+    Instruction {
+        address: addr,
+        code,
+        operand,
+        length,
+    }
+}
+
+// Disassembles count instructions starting at addr.
+pub fn disassemble_range(memory: &[u8], addr: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut addr = addr;
+    for _ in 0..count {
+        let instruction = disassemble(memory, addr);
+        addr = instruction.next_address();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// Instructions are variable length, so there's no way to walk backwards exactly. This picks the
+// furthest start address (up to count instructions back) whose linear sweep lands on addr.
+pub fn find_previous(memory: &[u8], addr: u16, count: usize) -> u16 {
+    let mut best = addr;
+    let mut best_count = 0;
+    for back in 1..=(count as u16 * 3) {
+        let start = addr.wrapping_sub(back);
+        let mut cursor = start;
+        let mut steps = 0;
+        while cursor != addr && steps <= count {
+            cursor = disassemble(memory, cursor).next_address();
+            steps += 1;
+            if addr.wrapping_sub(cursor) > back {
+                break;
+            }
+        }
+        if cursor == addr && steps <= count && steps >= best_count {
+            best = start;
+            best_count = steps;
+        }
+    }
+    best
+}
+
+// Address, raw bytes and text in fixed columns, the way a monitor prints them.
+pub fn format_listing(instruction: &Instruction) -> String {
+    columns(instruction, &instruction.to_string())
+}
+
+fn columns(instruction: &Instruction, text: &str) -> String {
+    let bytes: Vec<String> = instruction
+        .bytes()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    format!(
+        "{:04X}  {:<9} {}",
+        instruction.address,
+        bytes.join(" "),
+        text
+    )
+}
+
+// The instruction with its address operand replaced by a name, if it has one.
+pub fn symbolic_text(instruction: &Instruction, symbols: &SymbolTable) -> String {
+    symbolic_text_in(instruction, symbols, Syntax::Intel)
+}
+
+pub fn symbolic_text_in(
+    instruction: &Instruction,
+    symbols: &SymbolTable,
+    syntax: Syntax,
+) -> String {
+    let text = instruction.text(syntax);
+    if instruction.length != 3 {
+        return text;
+    }
+    match symbols.name(instruction.operand) {
+        Some(name) => text.replace(&format_word(instruction.operand), name),
+        None => text,
+    }
+}
+
+// format_listing with names for operands and any comment at the end.
+pub fn format_symbolic_listing(instruction: &Instruction, symbols: &SymbolTable) -> String {
+    format_symbolic_listing_in(instruction, symbols, Syntax::Intel)
+}
+
+pub fn format_symbolic_listing_in(
+    instruction: &Instruction,
+    symbols: &SymbolTable,
+    syntax: Syntax,
+) -> String {
+    let mut line = columns(instruction, &symbolic_text_in(instruction, symbols, syntax));
+    if let Some(comment) = symbols.comment(instruction.address) {
+        line = format!("{:<34}; {}", line, comment);
+    }
+    line
+}
+
+pub fn format_byte(val: u8) -> String {
+    format_hex(format!("{:02X}", val))
+}
+
+pub fn format_word(val: u16) -> String {
+    format_hex(format!("{:04X}", val))
+}
+
+// Intel syntax needs a leading zero when a hex number would otherwise start with a letter.
+fn format_hex(digits: String) -> String {
+    if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}H", digits)
+    } else {
+        format!("{}H", digits)
+    }
+}
+
+fn intel_text(code: u8, operand: u16) -> String {
+    let dst = REGISTERS[((code >> 3) & 0x7) as usize];
+    let src = REGISTERS[(code & 0x7) as usize];
+    let pair = PAIRS[((code >> 4) & 0x3) as usize];
+    let byte = format_byte(operand as u8);
+    let word = format_word(operand);
+    match code {
+        0x00 => String::from("NOP"),
+        0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xCB | 0xD9 | 0xDD | 0xED | 0xFD => {
+            format!("DB {}", format_byte(code))
+        }
+        0x01 | 0x11 | 0x21 | 0x31 => format!("LXI {},{}", pair, word),
+        0x02 | 0x12 => format!("STAX {}", pair),
+        0x03 | 0x13 | 0x23 | 0x33 => format!("INX {}", pair),
+        0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => format!("INR {}", dst),
+        0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => format!("DCR {}", dst),
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+            format!("MVI {},{}", dst, byte)
+        }
+        0x07 => String::from("RLC"),
+        0x09 | 0x19 | 0x29 | 0x39 => format!("DAD {}", pair),
+        0x0A | 0x1A => format!("LDAX {}", pair),
+        0x0B | 0x1B | 0x2B | 0x3B => format!("DCX {}", pair),
+        0x0F => String::from("RRC"),
+        0x17 => String::from("RAL"),
+        0x1F => String::from("RAR"),
+        0x22 => format!("SHLD {}", word),
+        0x27 => String::from("DAA"),
+        0x2A => format!("LHLD {}", word),
+        0x2F => String::from("CMA"),
+        0x32 => format!("STA {}", word),
+        0x37 => String::from("STC"),
+        0x3A => format!("LDA {}", word),
+        0x3F => String::from("CMC"),
+        0x76 => String::from("HLT"),
+        0x40..=0x7F => format!("MOV {},{}", dst, src),
+        0x80..=0xBF => {
+            let names = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+            format!("{} {}", names[((code >> 3) & 0x7) as usize], src)
+        }
+        0xC0 | 0xC8 | 0xD0 | 0xD8 | 0xE0 | 0xE8 | 0xF0 | 0xF8 => {
+            format!("R{}", condition(code))
+        }
+        0xC2 | 0xCA | 0xD2 | 0xDA | 0xE2 | 0xEA | 0xF2 | 0xFA => {
+            format!("J{} {}", condition(code), word)
+        }
+        0xC4 | 0xCC | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC => {
+            format!("C{} {}", condition(code), word)
+        }
+        0xC1 | 0xD1 | 0xE1 | 0xF1 => {
+            format!("POP {}", STACK_PAIRS[((code >> 4) & 0x3) as usize])
+        }
+        0xC5 | 0xD5 | 0xE5 | 0xF5 => {
+            format!("PUSH {}", STACK_PAIRS[((code >> 4) & 0x3) as usize])
+        }
+        0xC3 => format!("JMP {}", word),
+        0xC6 => format!("ADI {}", byte),
+        0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+            format!("RST {}", (code >> 3) & 0x7)
+        }
+        0xC9 => String::from("RET"),
+        0xCD => format!("CALL {}", word),
+        0xCE => format!("ACI {}", byte),
+        0xD3 => format!("OUT {}", byte),
+        0xD6 => format!("SUI {}", byte),
+        0xDB => format!("IN {}", byte),
+        0xDE => format!("SBI {}", byte),
+        0xE3 => String::from("XTHL"),
+        0xE6 => format!("ANI {}", byte),
+        0xE9 => String::from("PCHL"),
+        0xEB => String::from("XCHG"),
+        0xEE => format!("XRI {}", byte),
+        0xF3 => String::from("DI"),
+        0xF6 => format!("ORI {}", byte),
+        0xF9 => String::from("SPHL"),
+        0xFB => String::from("EI"),
+        0xFE => format!("CPI {}", byte),
+    }
+}
+
+// The same instructions as intel_text, spelled the way Z80 assemblers want them.
+fn zilog_text(code: u8, operand: u16) -> String {
+    let dst = Z80_REGISTERS[((code >> 3) & 0x7) as usize];
+    let src = Z80_REGISTERS[(code & 0x7) as usize];
+    let pair = Z80_PAIRS[((code >> 4) & 0x3) as usize];
+    let byte = format_byte(operand as u8);
+    let word = format_word(operand);
+    match code {
+        0x00 => String::from("NOP"),
+        0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xCB | 0xD9 | 0xDD | 0xED | 0xFD => {
+            format!("DB {}", format_byte(code))
+        }
+        0x01 | 0x11 | 0x21 | 0x31 => format!("LD {},{}", pair, word),
+        0x02 | 0x12 => format!("LD ({}),A", pair),
+        0x03 | 0x13 | 0x23 | 0x33 => format!("INC {}", pair),
+        0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => format!("INC {}", dst),
+        0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => format!("DEC {}", dst),
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+            format!("LD {},{}", dst, byte)
+        }
+        0x07 => String::from("RLCA"),
+        0x09 | 0x19 | 0x29 | 0x39 => format!("ADD HL,{}", pair),
+        0x0A | 0x1A => format!("LD A,({})", pair),
+        0x0B | 0x1B | 0x2B | 0x3B => format!("DEC {}", pair),
+        0x0F => String::from("RRCA"),
+        0x17 => String::from("RLA"),
+        0x1F => String::from("RRA"),
+        0x22 => format!("LD ({}),HL", word),
+        0x27 => String::from("DAA"),
+        0x2A => format!("LD HL,({})", word),
+        0x2F => String::from("CPL"),
+        0x32 => format!("LD ({}),A", word),
+        0x37 => String::from("SCF"),
+        0x3A => format!("LD A,({})", word),
+        0x3F => String::from("CCF"),
+        0x76 => String::from("HALT"),
+        0x40..=0x7F => format!("LD {},{}", dst, src),
+        0x80..=0xBF => {
+            let names = [
+                "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+            ];
+            format!("{}{}", names[((code >> 3) & 0x7) as usize], src)
+        }
+        0xC0 | 0xC8 | 0xD0 | 0xD8 | 0xE0 | 0xE8 | 0xF0 | 0xF8 => {
+            format!("RET {}", condition(code))
+        }
+        0xC2 | 0xCA | 0xD2 | 0xDA | 0xE2 | 0xEA | 0xF2 | 0xFA => {
+            format!("JP {},{}", condition(code), word)
+        }
+        0xC4 | 0xCC | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC => {
+            format!("CALL {},{}", condition(code), word)
+        }
+        0xC1 | 0xD1 | 0xE1 | 0xF1 => {
+            format!("POP {}", Z80_STACK_PAIRS[((code >> 4) & 0x3) as usize])
+        }
+        0xC5 | 0xD5 | 0xE5 | 0xF5 => {
+            format!("PUSH {}", Z80_STACK_PAIRS[((code >> 4) & 0x3) as usize])
+        }
+        0xC3 => format!("JP {}", word),
+        0xC6 => format!("ADD A,{}", byte),
+        0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+            format!("RST {}", format_byte(code & 0x38))
+        }
+        0xC9 => String::from("RET"),
+        0xCD => format!("CALL {}", word),
+        0xCE => format!("ADC A,{}", byte),
+        0xD3 => format!("OUT ({}),A", byte),
+        0xD6 => format!("SUB {}", byte),
+        0xDB => format!("IN A,({})", byte),
+        0xDE => format!("SBC A,{}", byte),
+        0xE3 => String::from("EX (SP),HL"),
+        0xE6 => format!("AND {}", byte),
+        0xE9 => String::from("JP (HL)"),
+        0xEB => String::from("EX DE,HL"),
+        0xEE => format!("XOR {}", byte),
+        0xF3 => String::from("DI"),
+        0xF6 => format!("OR {}", byte),
+        0xF9 => String::from("LD SP,HL"),
+        0xFB => String::from("EI"),
+        0xFE => format!("CP {}", byte),
+    }
+}
+
+fn condition(code: u8) -> &'static str {
+    ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"][((code >> 3) & 0x7) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_immediates() {
+        let mut memory = [0u8; 0x10000];
+        memory[0..7].copy_from_slice(&[0x3E, 0x20, 0x21, 0x00, 0x24, 0xC2, 0x3C]);
+        memory[7] = 0x0A;
+        let lines: Vec<String> = disassemble_range(&memory, 0, 3)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+
+        assert_eq!(lines, vec!["MVI A,20H", "LXI H,2400H", "JNZ 0A3CH"]);
+    }
+
+    #[test]
+    fn test_disassemble_registers() {
+        let mut memory = [0u8; 0x10000];
+        memory[0..4].copy_from_slice(&[0x7E, 0xB8, 0xF5, 0xDF]);
+        let lines: Vec<String> = disassemble_range(&memory, 0, 4)
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+
+        assert_eq!(lines, vec!["MOV A,M", "CMP B", "PUSH PSW", "RST 3"]);
+    }
+
+    #[test]
+    fn test_zilog_syntax() {
+        let mut memory = [0u8; 0x10000];
+        memory[0..11].copy_from_slice(&[
+            0x3E, 0x20, 0x21, 0x00, 0x24, 0xC2, 0x3C, 0x0A, 0x7E, 0xDF, 0x1A,
+        ]);
+        let lines: Vec<String> = disassemble_range(&memory, 0, 6)
+            .iter()
+            .map(|i| i.text(Syntax::Zilog))
+            .collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                "LD A,20H",
+                "LD HL,2400H",
+                "JP NZ,0A3CH",
+                "LD A,(HL)",
+                "RST 18H",
+                "LD A,(DE)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbolic_text() {
+        let mut memory = [0u8; 0x10000];
+        memory[0..6].copy_from_slice(&[0x21, 0x00, 0x24, 0xC3, 0x03, 0x00]);
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x2400, "VRAM");
+        symbols.add(0x0003, "spin");
+        let instructions = disassemble_range(&memory, 0, 2);
+
+        assert_eq!(symbolic_text(&instructions[0], &symbols), "LXI H,VRAM");
+        assert_eq!(
+            format_symbolic_listing(&instructions[1], &symbols),
+            "0003  C3 03 00  JMP spin"
+        );
+    }
+
+    #[test]
+    fn test_find_previous() {
+        let mut memory = [0u8; 0x10000];
+        memory[0x100..0x107].copy_from_slice(&[0x21, 0x00, 0x24, 0x3E, 0x01, 0x77, 0xC9]);
+
+        assert_eq!(find_previous(&memory, 0x106, 3), 0x100);
+        assert_eq!(find_previous(&memory, 0x106, 1), 0x105);
+    }
+}
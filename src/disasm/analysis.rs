@@ -0,0 +1,432 @@
+use super::{disassemble, format_byte, format_word, Instruction, Syntax};
+use crate::symbols::SymbolTable;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+// Longest run of bytes put on one DB line.
+const DB_WIDTH: usize = 8;
+// Cross-references listed on a label's line before the rest are summed up.
+const MAX_XREFS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mark {
+    Unknown,
+    Code,
+    Operand,
+    // Marked as data up front, so never traced into.
+    Data,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RefKind {
+    Jump,
+    // A conditional jump.
+    Branch,
+    Call,
+    Rst,
+    // An address operand that isn't a jump or call: LXI, LDA, STA, LHLD or SHLD.
+    Data,
+}
+
+impl RefKind {
+    fn letter(&self) -> char {
+        match self {
+            RefKind::Jump => 'j',
+            RefKind::Branch => 'b',
+            RefKind::Call => 'c',
+            RefKind::Rst => 'r',
+            RefKind::Data => 'd',
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reference {
+    pub from: u16,
+    pub kind: RefKind,
+}
+
+// A tracing disassembler. Starting from the entry points, it follows jumps, calls and branches
+// through range, so only bytes that can be reached are taken as code. Everything else is data.
+// The source it writes assembles back to the same bytes.
+pub struct Analysis {
+    memory: Vec<u8>,
+    range: RangeInclusive<u16>,
+    marks: Vec<Mark>,
+    entries: Vec<u16>,
+    references: BTreeMap<u16, Vec<Reference>>,
+    syntax: Syntax,
+}
+
+impl Analysis {
+    pub fn new(memory: &[u8], range: RangeInclusive<u16>) -> Self {
+        let mut copy = vec![0; 0x10000];
+        copy[..memory.len().min(0x10000)].copy_from_slice(&memory[..memory.len().min(0x10000)]);
+        Analysis {
+            memory: copy,
+            range,
+            marks: vec![Mark::Unknown; 0x10000],
+            entries: Vec::new(),
+            references: BTreeMap::new(),
+            syntax: Syntax::Intel,
+        }
+    }
+
+    // The mnemonics text and write_source use.
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+    }
+
+    pub fn add_entry(&mut self, addr: u16) {
+        self.entries.push(addr);
+    }
+
+    // RST n jumps to n * 8, and interrupts come in the same way.
+    pub fn add_rst_vectors(&mut self) {
+        for vector in 0..8 {
+            if self.range.contains(&(vector * 8)) {
+                self.entries.push(vector * 8);
+            }
+        }
+    }
+
+    // Keeps the tracer out of a table or text it would otherwise run into.
+    pub fn add_data(&mut self, range: RangeInclusive<u16>) {
+        for addr in range {
+            self.marks[usize::from(addr)] = Mark::Data;
+        }
+    }
+
+    pub fn run(&mut self) {
+        let mut work = self.entries.clone();
+        work.reverse();
+        while let Some(start) = work.pop() {
+            let mut addr = start;
+            while let Some(instruction) = self.decode(addr) {
+                for offset in 0..instruction.length {
+                    let mark = if offset == 0 {
+                        Mark::Code
+                    } else {
+                        Mark::Operand
+                    };
+                    self.marks[usize::from(addr + offset)] = mark;
+                }
+                let (target, falls_through) = flow(&instruction);
+                if let Some((target, kind)) = target {
+                    self.refer(target, addr, kind);
+                    if kind != RefKind::Data {
+                        work.push(target);
+                    }
+                }
+                let next = u32::from(addr) + u32::from(instruction.length);
+                if !falls_through || next > u32::from(*self.range.end()) {
+                    break;
+                }
+                addr = instruction.next_address();
+            }
+        }
+    }
+
+    // The instruction at addr, if it can be code: it's all inside the range, hasn't been
+    // claimed already, and isn't one of the unused opcodes.
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        let instruction = disassemble(&self.memory, addr);
+        let end = u32::from(addr) + u32::from(instruction.length) - 1;
+        let fits = end <= u32::from(*self.range.end())
+            && self.range.contains(&addr)
+            && (addr..=end as u16).all(|addr| self.marks[usize::from(addr)] == Mark::Unknown);
+        let unused = instruction.to_string().starts_with("DB ");
+        Some(instruction).filter(|_| fits && !unused)
+    }
+
+    fn refer(&mut self, target: u16, from: u16, kind: RefKind) {
+        let references = self.references.entry(target).or_default();
+        if !references.contains(&Reference { from, kind }) {
+            references.push(Reference { from, kind });
+        }
+    }
+
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.range.clone()
+    }
+
+    pub fn entries(&self) -> &[u16] {
+        &self.entries
+    }
+
+    // The instruction starting at addr, if the trace found one there.
+    pub fn instruction(&self, addr: u16) -> Option<Instruction> {
+        Some(disassemble(&self.memory, addr))
+            .filter(|_| self.marks[usize::from(addr)] == Mark::Code)
+    }
+
+    pub fn is_code(&self, addr: u16) -> bool {
+        matches!(self.marks[usize::from(addr)], Mark::Code | Mark::Operand)
+    }
+
+    // Where addr is referred to from, in the order they were found.
+    pub fn references(&self, addr: u16) -> &[Reference] {
+        self.references.get(&addr).map_or(&[], |refs| &refs[..])
+    }
+
+    // Names for every referenced address in range, plus the entry points. Names from symbols
+    // win. An address inside an instruction gets the instruction's label plus an offset.
+    pub fn labels(&self, symbols: &SymbolTable) -> BTreeMap<u16, String> {
+        let mut targets: Vec<u16> = self.references.keys().copied().collect();
+        // A vector can land inside an instruction that runs through it, and then it isn't one.
+        targets.extend(
+            self.entries
+                .iter()
+                .filter(|addr| self.marks[usize::from(**addr)] == Mark::Code),
+        );
+        let mut labels = BTreeMap::new();
+        for target in targets.into_iter().filter(|addr| self.range.contains(addr)) {
+            let start = self.instruction_start(target);
+            let kinds: Vec<RefKind> = self.references(start).iter().map(|r| r.kind).collect();
+            let prefix = if !self.is_code(start) {
+                'D'
+            } else if kinds.contains(&RefKind::Call) || kinds.contains(&RefKind::Rst) {
+                'S'
+            } else {
+                'L'
+            };
+            labels
+                .entry(start)
+                .or_insert_with(|| match symbols.name(start) {
+                    Some(name) => name.to_string(),
+                    None => format!("{}{:04X}", prefix, start),
+                });
+        }
+        labels
+    }
+
+    // The instruction with its address operand named from labels, or from symbols outside the
+    // range.
+    pub fn text(
+        &self,
+        instruction: &Instruction,
+        labels: &BTreeMap<u16, String>,
+        symbols: &SymbolTable,
+    ) -> String {
+        let text = instruction.text(self.syntax);
+        if instruction.length != 3 {
+            return text;
+        }
+        let addr = instruction.operand;
+        let name = if self.range.contains(&addr) {
+            let start = self.instruction_start(addr);
+            labels.get(&start).map(|name| match addr - start {
+                0 => name.clone(),
+                offset => format!("{}+{}", name, offset),
+            })
+        } else {
+            symbols.name(addr).map(String::from)
+        };
+        match name {
+            Some(name) => text.replace(&format_word(addr), &name),
+            None => text,
+        }
+    }
+
+    fn instruction_start(&self, addr: u16) -> u16 {
+        let mut start = addr;
+        while self.marks[usize::from(start)] == Mark::Operand && start != *self.range.start() {
+            start -= 1;
+        }
+        start
+    }
+
+    // Source for the range. Addresses outside it that symbols has names for are given EQUs.
+    pub fn write_source<W: Write>(&self, out: &mut W, symbols: &SymbolTable) -> io::Result<()> {
+        let labels = self.labels(symbols);
+        let (start, end) = (*self.range.start(), *self.range.end());
+        let code = (start..=end).filter(|addr| self.is_code(*addr)).count();
+        writeln!(
+            out,
+            "; {:04X}-{:04X}: {} bytes of code, {} of data",
+            start,
+            end,
+            code,
+            usize::from(end - start) + 1 - code
+        )?;
+        writeln!(out)?;
+        let external: BTreeMap<u16, String> = self
+            .references
+            .keys()
+            .filter(|addr| !self.range.contains(addr))
+            .filter_map(|addr| Some((*addr, symbols.name(*addr)?.to_string())))
+            .collect();
+        for (addr, name) in &external {
+            writeln!(out, "{:<8}EQU {}", name, format_word(*addr))?;
+        }
+        if !external.is_empty() {
+            writeln!(out)?;
+        }
+        writeln!(out, "        ORG {}", format_word(start))?;
+
+        let mut addr = u32::from(start);
+        while addr <= u32::from(end) {
+            let here = addr as u16;
+            if let Some(label) = labels.get(&here) {
+                self.write_label(out, label, here)?;
+            }
+            if self.is_code(here) {
+                let instruction = disassemble(&self.memory, here);
+                let text = self.text(&instruction, &labels, symbols);
+                writeln!(out, "        {:<24}; {:04X}", text, here)?;
+                addr += u32::from(instruction.length);
+            } else {
+                // Up to the next label or instruction.
+                let run = (addr..=u32::from(end))
+                    .take(DB_WIDTH)
+                    .take_while(|a| {
+                        *a == addr
+                            || (!labels.contains_key(&(*a as u16)) && !self.is_code(*a as u16))
+                    })
+                    .map(|a| format_byte(self.memory[a as usize]))
+                    .collect::<Vec<String>>();
+                writeln!(
+                    out,
+                    "        {:<24}; {:04X}",
+                    format!("DB {}", run.join(",")),
+                    here
+                )?;
+                addr += run.len() as u32;
+            }
+        }
+        writeln!(out, "        END")
+    }
+
+    fn write_label<W: Write>(&self, out: &mut W, label: &str, addr: u16) -> io::Result<()> {
+        let references = self.references(addr);
+        if references.is_empty() {
+            return writeln!(out, "{}:", label);
+        }
+        let mut xrefs: Vec<String> = references
+            .iter()
+            .take(MAX_XREFS)
+            .map(|r| format!("{:04X}{}", r.from, r.kind.letter()))
+            .collect();
+        if references.len() > MAX_XREFS {
+            xrefs.push(format!("+{} more", references.len() - MAX_XREFS));
+        }
+        writeln!(
+            out,
+            "{:<32}; from {}",
+            format!("{}:", label),
+            xrefs.join(" ")
+        )
+    }
+}
+
+// Where an instruction can send execution, or the address it names, and whether execution
+// can carry on to the next instruction.
+pub fn flow(instruction: &Instruction) -> (Option<(u16, RefKind)>, bool) {
+    let code = instruction.code;
+    let target = instruction.operand;
+    match code {
+        0xC3 => (Some((target, RefKind::Jump)), false),
+        0xC9 | 0xE9 => (None, false),
+        0xCD => (Some((target, RefKind::Call)), true),
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2A | 0x32 | 0x3A => {
+            (Some((target, RefKind::Data)), true)
+        }
+        _ => match code & 0xC7 {
+            0xC2 => (Some((target, RefKind::Branch)), true),
+            0xC4 => (Some((target, RefKind::Call)), true),
+            0xC7 => (Some((u16::from(code & 0x38), RefKind::Rst)), true),
+            _ => (None, true),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program() -> Vec<u8> {
+        let mut memory = vec![0; 0x10000];
+        let code = [
+            0x21, 0x0F, 0x00, // 0000 LXI H,000F
+            0xCD, 0x0B, 0x00, // 0003 CALL 000B
+            0xC2, 0x00, 0x00, // 0006 JNZ 0000
+            0xC9, 0x00, // 0009 RET, then a stray byte
+            0x7E, 0xC9, // 000B MOV A,M / RET
+            0xC3, 0x00, // 000D what looks like a JMP, never reached
+            0x41, 0x42, // 000F the table
+        ];
+        memory[..code.len()].copy_from_slice(&code);
+        memory
+    }
+
+    fn analyse(memory: &[u8]) -> Analysis {
+        let mut analysis = Analysis::new(memory, 0x0000..=0x0010);
+        analysis.add_entry(0);
+        analysis.run();
+        analysis
+    }
+
+    #[test]
+    fn test_code_and_data() {
+        let analysis = analyse(&program());
+
+        assert!(analysis.is_code(0x0000));
+        assert!(analysis.is_code(0x0002));
+        assert!(analysis.is_code(0x000C));
+        assert!(!analysis.is_code(0x000A));
+        assert!(!analysis.is_code(0x000D));
+        assert_eq!(
+            analysis.references(0x000B),
+            &[Reference {
+                from: 0x0003,
+                kind: RefKind::Call
+            }]
+        );
+        let labels = analysis.labels(&SymbolTable::new());
+        assert_eq!(labels[&0x0000], "L0000");
+        assert_eq!(labels[&0x000B], "S000B");
+        assert_eq!(labels[&0x000F], "D000F");
+        assert_eq!(labels.len(), 3);
+    }
+
+    #[test]
+    fn test_source() {
+        let analysis = analyse(&program());
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x000B, "fetch");
+        let mut out = Vec::new();
+        analysis.write_source(&mut out, &symbols).unwrap();
+        let source = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = source.lines().map(str::trim_end).collect();
+
+        assert_eq!(lines[0], "; 0000-0010: 12 bytes of code, 5 of data");
+        assert!(lines.contains(&"L0000:                          ; from 0006b"));
+        assert!(lines.contains(&"        LXI H,D000F             ; 0000"));
+        assert!(lines.contains(&"        CALL fetch              ; 0003"));
+        assert!(lines.contains(&"        DB 00H                  ; 000A"));
+        assert!(lines.contains(&"fetch:                          ; from 0003c"));
+        assert!(lines.contains(&"        DB 0C3H,00H             ; 000D"));
+        assert!(lines.contains(&"D000F:                          ; from 0000d"));
+        assert!(lines.contains(&"        DB 41H,42H              ; 000F"));
+        assert_eq!(lines.last(), Some(&"        END"));
+    }
+
+    #[test]
+    fn test_zilog_source() {
+        let mut analysis = analyse(&program());
+        analysis.set_syntax(Syntax::Zilog);
+        let mut out = Vec::new();
+        analysis
+            .write_source(&mut out, &SymbolTable::new())
+            .unwrap();
+        let source = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = source.lines().map(str::trim_end).collect();
+
+        assert!(lines.contains(&"        LD HL,D000F             ; 0000"));
+        assert!(lines.contains(&"        CALL S000B              ; 0003"));
+        assert!(lines.contains(&"        JP NZ,L0000             ; 0006"));
+        assert!(lines.contains(&"        LD A,(HL)               ; 000B"));
+        assert!(lines.contains(&"        DB 0C3H,00H             ; 000D"));
+    }
+}
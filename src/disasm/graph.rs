@@ -0,0 +1,420 @@
+use super::analysis::{flow, Analysis, RefKind};
+use crate::symbols::SymbolTable;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+// How control gets from one block to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    // Straight on into a block that something else jumps to.
+    Fallthrough,
+    Jump,
+    // Whether a conditional jump or return went its way or not.
+    Taken,
+    NotTaken,
+}
+
+// Straight-line code: only the first instruction is jumped to, and only the last one jumps.
+// Calls don't end a block, as they come back.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    // Each instruction's address and text, with operands named as in the disassembly.
+    pub lines: Vec<(u16, String)>,
+    pub edges: Vec<(u16, EdgeKind)>,
+    // Where calls and RSTs in the block go.
+    pub calls: Vec<(u16, RefKind)>,
+}
+
+// The blocks of a traced disassembly, grouped into routines. A routine starts at an entry
+// point or something called, and has every block it reaches without calling. A jump to
+// another routine's start is a tail call, and stops there.
+pub struct ControlFlow {
+    blocks: BTreeMap<u16, Block>,
+    routines: BTreeMap<u16, Vec<u16>>,
+    entries: Vec<u16>,
+    names: BTreeMap<u16, String>,
+}
+
+impl ControlFlow {
+    pub fn new(analysis: &Analysis, symbols: &SymbolTable) -> Self {
+        let labels = analysis.labels(symbols);
+        let is_start = |addr: &u16| analysis.instruction(*addr).is_some();
+        let entries: Vec<u16> = analysis
+            .entries()
+            .iter()
+            .copied()
+            .filter(is_start)
+            .collect();
+        let mut heads: BTreeSet<u16> = entries.iter().copied().collect();
+        let mut leaders = heads.clone();
+        for addr in analysis.range() {
+            let instruction = match analysis.instruction(addr) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            match flow(&instruction).0 {
+                Some((target, RefKind::Jump)) | Some((target, RefKind::Branch)) => {
+                    leaders.insert(target);
+                }
+                Some((target, RefKind::Call)) | Some((target, RefKind::Rst)) => {
+                    leaders.insert(target);
+                    heads.insert(target);
+                }
+                _ => {}
+            }
+            if ends_block(instruction.code) {
+                leaders.insert(instruction.next_address());
+            }
+        }
+        leaders.retain(is_start);
+        heads.retain(is_start);
+
+        let mut blocks = BTreeMap::new();
+        for start in &leaders {
+            let mut block = Block {
+                start: *start,
+                lines: Vec::new(),
+                edges: Vec::new(),
+                calls: Vec::new(),
+            };
+            let mut addr = *start;
+            while let Some(instruction) = analysis.instruction(addr) {
+                block
+                    .lines
+                    .push((addr, analysis.text(&instruction, &labels, symbols)));
+                let (target, falls_through) = flow(&instruction);
+                match target {
+                    Some((target, RefKind::Jump)) => block.edges.push((target, EdgeKind::Jump)),
+                    Some((target, RefKind::Branch)) => block.edges.push((target, EdgeKind::Taken)),
+                    Some((target, kind @ RefKind::Call)) | Some((target, kind @ RefKind::Rst)) => {
+                        block.calls.push((target, kind))
+                    }
+                    _ => {}
+                }
+                let next = instruction.next_address();
+                let goes_on = falls_through && next > addr && is_start(&next);
+                if goes_on && !ends_block(instruction.code) && !leaders.contains(&next) {
+                    addr = next;
+                    continue;
+                }
+                if goes_on {
+                    let kind = if ends_block(instruction.code) {
+                        EdgeKind::NotTaken
+                    } else {
+                        EdgeKind::Fallthrough
+                    };
+                    block.edges.push((next, kind));
+                }
+                break;
+            }
+            blocks.insert(*start, block);
+        }
+
+        let mut routines = BTreeMap::new();
+        for head in &heads {
+            let mut reached = BTreeSet::new();
+            let mut work = vec![*head];
+            while let Some(start) = work.pop() {
+                if !reached.insert(start) {
+                    continue;
+                }
+                for (target, _) in &blocks[&start].edges {
+                    if blocks.contains_key(target) && !heads.contains(target) {
+                        work.push(*target);
+                    }
+                }
+            }
+            routines.insert(*head, reached.into_iter().collect());
+        }
+
+        // Names for everything a graph can mention that has one. The rest go by address.
+        let mut names = BTreeMap::new();
+        let targets = blocks.values().flat_map(|block: &Block| {
+            let edges = block.edges.iter().map(|(target, _)| *target);
+            let calls = block.calls.iter().map(|(target, _)| *target);
+            edges.chain(calls).chain(Some(block.start))
+        });
+        for addr in targets {
+            let name = labels
+                .get(&addr)
+                .cloned()
+                .or_else(|| symbols.name(addr).map(String::from));
+            if let Some(name) = name {
+                names.insert(addr, name);
+            }
+        }
+        ControlFlow {
+            blocks,
+            routines,
+            entries,
+            names,
+        }
+    }
+
+    pub fn block(&self, addr: u16) -> Option<&Block> {
+        self.blocks.get(&addr)
+    }
+
+    // Where each routine starts.
+    pub fn routines(&self) -> Vec<u16> {
+        self.routines.keys().copied().collect()
+    }
+
+    // The routine's blocks by address, starting at head.
+    pub fn routine(&self, head: u16) -> Option<&[u16]> {
+        self.routines.get(&head).map(|blocks| &blocks[..])
+    }
+
+    pub fn name(&self, addr: u16) -> String {
+        self.names
+            .get(&addr)
+            .cloned()
+            .unwrap_or_else(|| format!("{:04X}", addr))
+    }
+
+    // A routine by the name graphs give it, generated labels included.
+    pub fn resolve(&self, name: &str) -> Option<u16> {
+        self.routines
+            .keys()
+            .copied()
+            .find(|head| self.name(*head).eq_ignore_ascii_case(name))
+    }
+
+    // What a routine calls, and the other routines it jumps into, each once.
+    pub fn callees(&self, head: u16) -> BTreeSet<(u16, RefKind)> {
+        let mut callees = BTreeSet::new();
+        for start in self.routine(head).unwrap_or(&[]) {
+            let block = &self.blocks[start];
+            callees.extend(block.calls.iter().copied());
+            for (target, _) in &block.edges {
+                if !self.routines[&head].contains(target) {
+                    callees.insert((*target, RefKind::Jump));
+                }
+            }
+        }
+        callees
+    }
+
+    // A digraph with a cluster of blocks for each routine in heads. Taken branches are green,
+    // branches not taken red and jumps blue. Calls are dashed lines to the routine called,
+    // which is drawn as an ellipse like anything else outside the routine.
+    pub fn write_dot<W: Write>(&self, out: &mut W, heads: &[u16]) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"Courier\"];")?;
+        let mut outside = BTreeSet::new();
+        let mut edges = Vec::new();
+        for head in heads {
+            let blocks = match self.routine(*head) {
+                Some(blocks) => blocks,
+                None => continue,
+            };
+            let node = |addr: u16| {
+                if blocks.contains(&addr) {
+                    format!("{:04X}:{:04X}", head, addr)
+                } else {
+                    self.name(addr)
+                }
+            };
+            for start in blocks {
+                let block = &self.blocks[start];
+                for (target, kind) in &block.edges {
+                    let style = match kind {
+                        EdgeKind::Fallthrough => "",
+                        EdgeKind::Jump => " [color=blue]",
+                        EdgeKind::Taken => " [color=darkgreen]",
+                        EdgeKind::NotTaken => " [color=red]",
+                    };
+                    edges.push(format!(
+                        "{} -> {}{}",
+                        quote(&node(*start)),
+                        quote(&node(*target)),
+                        style
+                    ));
+                    if !blocks.contains(target) {
+                        outside.insert(*target);
+                    }
+                }
+                for (target, kind) in &block.calls {
+                    let style = match kind {
+                        RefKind::Rst => " [style=dashed, label=\"RST\"]",
+                        _ => " [style=dashed]",
+                    };
+                    edges.push(format!(
+                        "{} -> {}{}",
+                        quote(&node(*start)),
+                        quote(&self.name(*target)),
+                        style
+                    ));
+                    outside.insert(*target);
+                }
+            }
+        }
+        // Declared before the clusters, or the first cluster to mention one would claim it.
+        for addr in &outside {
+            writeln!(out, "    {} [shape=ellipse];", quote(&self.name(*addr)))?;
+        }
+        for head in heads {
+            let blocks = match self.routine(*head) {
+                Some(blocks) => blocks,
+                None => continue,
+            };
+            writeln!(
+                out,
+                "    subgraph {} {{",
+                quote(&format!("cluster_{:04X}", head))
+            )?;
+            writeln!(out, "        label={};", quote(&self.name(*head)))?;
+            for start in blocks {
+                let block = &self.blocks[start];
+                let mut label = String::new();
+                if let Some(name) = self.names.get(start) {
+                    label.push_str(&format!("{}:\\l", escape(name)));
+                }
+                for (addr, text) in &block.lines {
+                    label.push_str(&format!("{:04X}  {}\\l", addr, escape(text)));
+                }
+                writeln!(
+                    out,
+                    "        {} [label=\"{}\"];",
+                    quote(&format!("{:04X}:{:04X}", head, start)),
+                    label
+                )?;
+            }
+            writeln!(out, "    }}")?;
+        }
+        for edge in edges {
+            writeln!(out, "    {};", edge)?;
+        }
+        writeln!(out, "}}")
+    }
+
+    // One node per routine and an edge for each call. RSTs are dashed and tail calls dotted.
+    // Entry points are drawn bold.
+    pub fn write_call_graph<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph calls {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"Courier\"];")?;
+        for head in self.routines.keys() {
+            let style = if self.entries.contains(head) {
+                " [style=bold]"
+            } else {
+                ""
+            };
+            writeln!(out, "    {}{};", quote(&self.name(*head)), style)?;
+        }
+        for head in self.routines.keys() {
+            for (target, kind) in self.callees(*head) {
+                let style = match kind {
+                    RefKind::Rst => " [style=dashed]",
+                    RefKind::Jump => " [style=dotted]",
+                    _ => "",
+                };
+                writeln!(
+                    out,
+                    "    {} -> {}{};",
+                    quote(&self.name(*head)),
+                    quote(&self.name(target)),
+                    style
+                )?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+// Jumps and returns end a block, whether they're conditional or not.
+fn ends_block(code: u8) -> bool {
+    matches!(code, 0xC3 | 0xC9 | 0xE9) || matches!(code & 0xC7, 0xC2 | 0xC0)
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", escape(text))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control_flow() -> ControlFlow {
+        let mut memory = vec![0; 0x10000];
+        let code = [
+            0x06, 0x03, // 0000 MVI B,3
+            0xCD, 0x0C, 0x00, // 0002 CALL 000C
+            0x05, // 0005 DCR B
+            0xC2, 0x02, 0x00, // 0006 JNZ 0002
+            0xC3, 0x00, 0x00, // 0009 JMP 0000
+            0xAF, // 000C XRA A
+            0xC8, // 000D RZ
+            0xC7, // 000E RST 0
+            0xC9, // 000F RET
+        ];
+        memory[..code.len()].copy_from_slice(&code);
+        let mut analysis = Analysis::new(&memory, 0x0000..=0x000F);
+        analysis.add_entry(0);
+        analysis.run();
+        ControlFlow::new(&analysis, &SymbolTable::new())
+    }
+
+    #[test]
+    fn test_blocks() {
+        let flow = control_flow();
+        assert_eq!(flow.routines(), vec![0x0000, 0x000C]);
+        assert_eq!(flow.routine(0x0000), Some(&[0x0000, 0x0002, 0x0009][..]));
+        assert_eq!(flow.routine(0x000C), Some(&[0x000C, 0x000E][..]));
+
+        let loop_block = flow.block(0x0002).unwrap();
+        assert_eq!(loop_block.lines.len(), 3);
+        assert_eq!(loop_block.lines[0], (0x0002, "CALL S000C".to_string()));
+        assert_eq!(loop_block.calls, vec![(0x000C, RefKind::Call)]);
+        assert_eq!(
+            loop_block.edges,
+            vec![(0x0002, EdgeKind::Taken), (0x0009, EdgeKind::NotTaken)]
+        );
+        assert_eq!(
+            flow.block(0x0000).unwrap().edges,
+            vec![(0x0002, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(
+            flow.block(0x0009).unwrap().edges,
+            vec![(0x0000, EdgeKind::Jump)]
+        );
+        assert_eq!(
+            flow.block(0x000C).unwrap().edges,
+            vec![(0x000E, EdgeKind::NotTaken)]
+        );
+        assert_eq!(
+            flow.block(0x000E).unwrap().calls,
+            vec![(0x0000, RefKind::Rst)]
+        );
+        assert_eq!(flow.resolve("s000c"), Some(0x000C));
+    }
+
+    #[test]
+    fn test_dot() {
+        let flow = control_flow();
+        let mut out = Vec::new();
+        flow.write_dot(&mut out, &[0x0000]).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        assert!(dot.contains("    \"S000C\" [shape=ellipse];\n    subgraph \"cluster_0000\" {"));
+        assert!(dot.contains(
+            "        \"0000:0002\" [label=\"L0002:\\l0002  CALL S000C\\l0005  DCR B\\l\
+             0006  JNZ L0002\\l\"];"
+        ));
+        assert!(dot.contains("    \"0000:0002\" -> \"0000:0002\" [color=darkgreen];"));
+        assert!(dot.contains("    \"0000:0002\" -> \"0000:0009\" [color=red];"));
+        assert!(dot.contains("    \"0000:0002\" -> \"S000C\" [style=dashed];"));
+        assert!(dot.ends_with("}\n"));
+
+        let mut out = Vec::new();
+        flow.write_call_graph(&mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = dot.lines().collect();
+        assert!(lines.contains(&"    \"S0000\" [style=bold];"));
+        assert!(lines.contains(&"    \"S0000\" -> \"S000C\";"));
+        assert!(lines.contains(&"    \"S000C\" -> \"S0000\" [style=dashed];"));
+    }
+}
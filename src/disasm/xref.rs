@@ -0,0 +1,425 @@
+use super::analysis::{flow, Analysis, RefKind};
+use super::graph::ControlFlow;
+use crate::machine::{MemoryMap, Region};
+use crate::symbols::SymbolTable;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+// Where a report line wraps its list of sites.
+const REPORT_WIDTH: usize = 100;
+const SITE_INDENT: usize = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Jump,
+    // A conditional jump.
+    Branch,
+    Call,
+    Rst,
+    // LDA and LHLD, or STA and SHLD.
+    Read,
+    Write,
+    // An address loaded with LXI, to be used later.
+    Address,
+    In,
+    Out,
+}
+
+impl Access {
+    fn word(&self) -> &'static str {
+        match self {
+            Access::Jump => "jump",
+            Access::Branch => "branch",
+            Access::Call => "call",
+            Access::Rst => "rst",
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Address => "address",
+            Access::In => "in",
+            Access::Out => "out",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Site {
+    pub from: u16,
+    pub access: Access,
+}
+
+// Every address and port the traced code names, and where from. Only direct addressing can be
+// seen this way: a byte read through HL or a table of addresses doesn't show up.
+pub struct CrossReference {
+    range: RangeInclusive<u16>,
+    targets: BTreeMap<u16, Vec<Site>>,
+    ports: BTreeMap<u8, Vec<Site>>,
+    // The routines each instruction is in, usually one.
+    routines: BTreeMap<u16, Vec<String>>,
+    // Where instructions start, in order.
+    code: Vec<u16>,
+    labels: BTreeMap<u16, String>,
+    symbols: SymbolTable,
+    map: MemoryMap,
+    port_names: BTreeMap<(u8, Access), String>,
+}
+
+impl CrossReference {
+    pub fn new(analysis: &Analysis, symbols: &SymbolTable) -> Self {
+        let mut targets: BTreeMap<u16, Vec<Site>> = BTreeMap::new();
+        let mut ports: BTreeMap<u8, Vec<Site>> = BTreeMap::new();
+        let mut code = Vec::new();
+        for addr in analysis.range() {
+            let instruction = match analysis.instruction(addr) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            code.push(addr);
+            let (target, _) = flow(&instruction);
+            let access = match (instruction.code, target) {
+                (0xDB, _) => Some((instruction.operand, Access::In)),
+                (0xD3, _) => Some((instruction.operand, Access::Out)),
+                (0x3A, _) | (0x2A, _) => Some((instruction.operand, Access::Read)),
+                (0x32, _) | (0x22, _) => Some((instruction.operand, Access::Write)),
+                (_, Some((target, kind))) => Some((
+                    target,
+                    match kind {
+                        RefKind::Jump => Access::Jump,
+                        RefKind::Branch => Access::Branch,
+                        RefKind::Call => Access::Call,
+                        RefKind::Rst => Access::Rst,
+                        RefKind::Data => Access::Address,
+                    },
+                )),
+                _ => None,
+            };
+            let site = |access| Site { from: addr, access };
+            match access {
+                Some((port, access @ Access::In)) | Some((port, access @ Access::Out)) => {
+                    ports.entry(port as u8).or_default().push(site(access))
+                }
+                Some((target, access)) => targets.entry(target).or_default().push(site(access)),
+                None => {}
+            }
+        }
+
+        let flow = ControlFlow::new(analysis, symbols);
+        let mut routines: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+        for head in flow.routines() {
+            for start in flow.routine(head).unwrap_or(&[]) {
+                let lines = flow.block(*start).map_or(&[][..], |block| &block.lines[..]);
+                for (addr, _) in lines {
+                    routines.entry(*addr).or_default().push(flow.name(head));
+                }
+            }
+        }
+        CrossReference {
+            range: analysis.range(),
+            targets,
+            ports,
+            routines,
+            code,
+            labels: analysis.labels(symbols),
+            symbols: symbols.clone(),
+            map: MemoryMap::new(),
+            port_names: BTreeMap::new(),
+        }
+    }
+
+    // Says what's RAM, ROM and so on outside the image. Without one it's all RAM.
+    pub fn set_memory_map(&mut self, map: MemoryMap) {
+        self.map = map;
+    }
+
+    // What a port is for, when read from with In or written to with Out.
+    pub fn name_port(&mut self, port: u8, access: Access, name: &str) {
+        self.port_names.insert((port, access), name.to_string());
+    }
+
+    // Where addr is named from, in address order.
+    pub fn references(&self, addr: u16) -> &[Site] {
+        self.targets.get(&addr).map_or(&[], |sites| &sites[..])
+    }
+
+    pub fn port(&self, port: u8) -> &[Site] {
+        self.ports.get(&port).map_or(&[], |sites| &sites[..])
+    }
+
+    // The routines the instruction at addr is part of.
+    pub fn routines(&self, addr: u16) -> &[String] {
+        self.routines.get(&addr).map_or(&[], |names| &names[..])
+    }
+
+    // Code targets first, then data in the image, then everything outside it by region with
+    // the bytes of a variable together under its name. Each port comes last with the routines
+    // that use it.
+    pub fn write_report<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (start, end) = (*self.range.start(), *self.range.end());
+        writeln!(
+            out,
+            "; Cross-reference of {:04X}-{:04X}: {} instructions, {} addresses and {} ports named",
+            start,
+            end,
+            self.code.len(),
+            self.targets.len(),
+            self.ports.len()
+        )?;
+        writeln!(
+            out,
+            "; Only traced code is included. Code reached only through RAM or a table of addresses"
+        )?;
+        writeln!(out, "; is left out unless it's given as an entry point.")?;
+
+        let inside = |addr: &&u16| self.range.contains(addr);
+        let code: Vec<u16> = self
+            .targets
+            .keys()
+            .filter(inside)
+            .copied()
+            .filter(|addr| self.code.binary_search(addr).is_ok())
+            .collect();
+        let data: Vec<u16> = self
+            .targets
+            .keys()
+            .filter(inside)
+            .copied()
+            .filter(|addr| self.code.binary_search(addr).is_err())
+            .collect();
+        self.write_section(out, "Code", &code)?;
+        self.write_section(out, "Data in the image", &data)?;
+
+        let mut outside: BTreeMap<Region, Vec<u16>> = BTreeMap::new();
+        for addr in self
+            .targets
+            .keys()
+            .filter(|addr| !self.range.contains(addr))
+        {
+            outside
+                .entry(self.map.region(*addr))
+                .or_default()
+                .push(*addr);
+        }
+        for (region, addrs) in &outside {
+            let title = match region {
+                Region::Ram => "RAM",
+                Region::Video => "Video RAM",
+                Region::Rom => "ROM outside the image",
+                Region::Unmapped => "Unmapped",
+            };
+            self.write_section(out, title, addrs)?;
+        }
+
+        if self.ports.is_empty() {
+            return Ok(());
+        }
+        writeln!(out)?;
+        writeln!(out, "; I/O ports")?;
+        for (port, sites) in &self.ports {
+            for access in [Access::In, Access::Out] {
+                let sites: Vec<&Site> = sites.iter().filter(|s| s.access == access).collect();
+                if sites.is_empty() {
+                    continue;
+                }
+                let name = self.port_names.get(&(*port, access));
+                writeln!(out)?;
+                let heading = format!(
+                    "port {:02X}  {:<4}{}",
+                    port,
+                    access.word(),
+                    name.map_or("", String::as_str)
+                );
+                writeln!(out, "{}", heading.trim_end())?;
+                // By routine, as which code drives a port matters more than where.
+                let mut by_routine: BTreeMap<String, Vec<u16>> = BTreeMap::new();
+                for site in sites {
+                    let routines = self.routines(site.from);
+                    let routine = if routines.is_empty() {
+                        "?".to_string()
+                    } else {
+                        routines.join("/")
+                    };
+                    by_routine.entry(routine).or_default().push(site.from);
+                }
+                for (routine, froms) in by_routine {
+                    let froms: Vec<String> = froms.iter().map(|a| format!("{:04X}", a)).collect();
+                    write_wrapped(out, &format!("        {:<8} ", routine), &froms)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_section<W: Write>(&self, out: &mut W, title: &str, addrs: &[u16]) -> io::Result<()> {
+        if addrs.is_empty() {
+            return Ok(());
+        }
+        writeln!(out)?;
+        writeln!(out, "; {}", title)?;
+        let mut group = None;
+        for addr in addrs {
+            // A name and how far into it addr is. Outside the image, unnamed bytes belong to
+            // the variable before them in the same region.
+            let (base, name) = match self.labels.get(addr) {
+                Some(label) => (*addr, label.clone()),
+                None => match self.symbols.nearest(*addr) {
+                    Some((base, name))
+                        if !self.range.contains(&base)
+                            && self.map.region(base) == self.map.region(*addr) =>
+                    {
+                        (base, name.to_string())
+                    }
+                    _ => (*addr, String::new()),
+                },
+            };
+            if group != Some(base) {
+                writeln!(out)?;
+                writeln!(out, "{}", format!("{:04X}  {}", base, name).trim_end())?;
+                group = Some(base);
+            }
+            let offset = match addr - base {
+                0 => String::new(),
+                offset => format!("+{}", offset),
+            };
+            let sites = self.references(*addr);
+            for access in ACCESSES.iter() {
+                let sites: Vec<String> = sites
+                    .iter()
+                    .filter(|site| site.access == *access)
+                    .map(|site| self.site(site.from))
+                    .collect();
+                if !sites.is_empty() {
+                    write_wrapped(out, &format!("  {:<6}{:<9}", offset, access.word()), &sites)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn site(&self, addr: u16) -> String {
+        match self.routines(addr) {
+            [] => format!("{:04X}", addr),
+            routines => format!("{:04X} {}", addr, routines.join("/")),
+        }
+    }
+}
+
+const ACCESSES: [Access; 7] = [
+    Access::Call,
+    Access::Rst,
+    Access::Jump,
+    Access::Branch,
+    Access::Read,
+    Access::Write,
+    Access::Address,
+];
+
+// The lead and then the items, as many a line as fit.
+fn write_wrapped<W: Write>(out: &mut W, lead: &str, items: &[String]) -> io::Result<()> {
+    let mut line = lead.to_string();
+    for (i, item) in items.iter().enumerate() {
+        let separator = if i + 1 < items.len() { "," } else { "" };
+        if line.len() > SITE_INDENT && line.len() + item.len() + 2 > REPORT_WIDTH {
+            writeln!(out, "{}", line.trim_end())?;
+            line = " ".repeat(SITE_INDENT);
+        }
+        line.push_str(item);
+        line.push_str(separator);
+        line.push(' ');
+    }
+    writeln!(out, "{}", line.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cross_reference() -> CrossReference {
+        let mut memory = vec![0; 0x10000];
+        let code = [
+            0xCD, 0x08, 0x00, // 0000 CALL 0008
+            0x32, 0x01, 0x20, // 0003 STA 2001
+            0x76, // 0006 HLT
+            0x00, // 0007 NOP
+            0x3A, 0x00, 0x20, // 0008 LDA 2000
+            0xD3, 0x04, // 000B OUT 4
+            0xDB, 0x03, // 000D IN 3
+            0x2A, 0x00, 0x20, // 000F LHLD 2000
+            0x21, 0x16, 0x00, // 0012 LXI H,0016
+            0xC9, // 0015 RET
+            0x55, // 0016 a table
+        ];
+        memory[..code.len()].copy_from_slice(&code);
+        let mut analysis = Analysis::new(&memory, 0x0000..=0x0016);
+        analysis.add_entry(0);
+        analysis.run();
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x2000, "score");
+        let mut xref = CrossReference::new(&analysis, &symbols);
+        xref.name_port(4, Access::Out, "shift data");
+        xref
+    }
+
+    #[test]
+    fn test_references() {
+        let xref = cross_reference();
+        assert_eq!(
+            xref.references(0x2000),
+            &[
+                Site {
+                    from: 0x0008,
+                    access: Access::Read
+                },
+                Site {
+                    from: 0x000F,
+                    access: Access::Read
+                }
+            ]
+        );
+        assert_eq!(xref.references(0x2001)[0].access, Access::Write);
+        assert_eq!(xref.references(0x0016)[0].access, Access::Address);
+        assert_eq!(xref.port(3)[0].access, Access::In);
+        assert_eq!(xref.routines(0x000B), &["S0008".to_string()]);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut out = Vec::new();
+        cross_reference().write_report(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "; Cross-reference of 0000-0016: 10 instructions, 4 addresses and 2 ports named"
+        );
+        let expected = [
+            "; Code",
+            "",
+            "0008  S0008",
+            "        call     0000 L0000",
+            "",
+            "; Data in the image",
+            "",
+            "0016  D0016",
+            "        address  0012 S0008",
+            "",
+            "; RAM",
+            "",
+            "2000  score",
+            "        read     0008 S0008, 000F S0008",
+            "  +1    write    0003 L0000",
+            "",
+            "; I/O ports",
+            "",
+            "port 03  in",
+            "        S0008    000D",
+            "",
+            "port 04  out shift data",
+            "        S0008    000B",
+        ];
+        assert_eq!(
+            lines[1],
+            "; Only traced code is included. Code reached only through RAM or a table of addresses"
+        );
+        assert_eq!(&lines[4..], &expected[..]);
+    }
+}
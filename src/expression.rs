@@ -0,0 +1,499 @@
+use crate::symbols::SymbolTable;
+use crate::{Cpu, RegisterName};
+use std::fmt;
+
+// Everything an expression can look at.
+pub struct State<'a> {
+    pub cpu: &'a Cpu,
+    pub cycles: u64,
+    pub steps: u128,
+    pub hits: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Variable {
+    Register(RegisterName),
+    Flag(&'static str),
+    // The byte at hl, as in MOV A,M.
+    M,
+    Cycles,
+    Steps,
+    Hits,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Number(i64),
+    Variable(Variable),
+    Byte(Box<Node>),
+    Word(Box<Node>),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+// Binary operators, loosest binding first.
+const LEVELS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+// Longer operators come before their prefixes so << isn't read as <.
+const SYMBOLS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[", "]",
+];
+
+// A condition over the cpu, like
+//   pc == 0x0A30 && a > 10 && [0x20F8] != 0 && flags.cy
+// Names are registers (a b c d e h l f), pairs (bc de hl psw sp pc), flags (flags.s flags.z
+// flags.ac flags.p flags.cy), m for the byte at hl, cycles, steps, hits, or labels, which stand
+// for their address. [addr] reads a byte and w[addr] a little-endian word. Operators and their
+// precedence are C's. Numbers are hex, as everywhere else in the debugger, unless they start
+// with # for decimal. Anything non-zero is true, and dividing by zero gives zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression {
+    node: Node,
+    text: String,
+}
+
+impl Expression {
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<Expression, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            symbols,
+        };
+        let node = parser.binary(0)?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {} in {}", describe(token), text));
+        }
+        Ok(Expression {
+            node,
+            text: text.trim().to_string(),
+        })
+    }
+
+    pub fn evaluate(&self, state: &State) -> i64 {
+        evaluate(&self.node, state)
+    }
+
+    pub fn is_true(&self, state: &State) -> bool {
+        self.evaluate(state) != 0
+    }
+
+    // How many hex digits the value naturally has: 2 for bytes, 4 for words.
+    fn digits(&self) -> Option<usize> {
+        match &self.node {
+            Node::Byte(_) | Node::Variable(Variable::M) => Some(2),
+            Node::Word(_) => Some(4),
+            Node::Variable(Variable::Register(name)) if name.is_pair() => Some(4),
+            Node::Variable(Variable::Register(RegisterName::SP))
+            | Node::Variable(Variable::Register(RegisterName::PC)) => Some(4),
+            Node::Variable(Variable::Register(_)) => Some(2),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Hex(Expression),
+    Decimal(Expression),
+}
+
+// Text with expressions in braces, printed by log-only breakpoints:
+//   score {w[0x20F8]} lives {[0x21FF]:d} at {pc}
+// Values are hex, padded to the width of a byte or word where that's obvious. :d prints
+// decimal. {{ and }} are literal braces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogMessage {
+    parts: Vec<Part>,
+    text: String,
+}
+
+impl LogMessage {
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<LogMessage, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inner.push(c),
+                            None => return Err(format!("Missing }} in {}", text)),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Text(literal.split_off(0)));
+                    }
+                    parts.push(match inner.strip_suffix(":d") {
+                        Some(inner) => Part::Decimal(Expression::parse(inner, symbols)?),
+                        None => Part::Hex(Expression::parse(&inner, symbols)?),
+                    });
+                }
+                '}' => return Err(format!("Unmatched }} in {}", text)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        Ok(LogMessage {
+            parts,
+            text: text.to_string(),
+        })
+    }
+
+    pub fn format(&self, state: &State) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Decimal(expression) => out.push_str(&expression.evaluate(state).to_string()),
+                Part::Hex(expression) => {
+                    let value = expression.evaluate(state);
+                    let digits = expression.digits().unwrap_or(0);
+                    out.push_str(&format!("{:01$X}", value, digits));
+                }
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for LogMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(s)) if *s == symbol => Ok(()),
+            Some(token) => Err(format!("Expected {} but found {}", symbol, describe(token))),
+            None => Err(format!("Expected {} at the end", symbol)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Symbol(op)) = self.peek() {
+            if !LEVELS[level].contains(op) {
+                break;
+            }
+            self.next();
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some(Token::Symbol(op)) if ["!", "~", "-"].contains(op) => {
+                self.next();
+                Ok(Node::Unary(op, Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Node::Number(*n)),
+            Some(Token::Symbol("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Symbol("[")) => {
+                let node = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Node::Byte(Box::new(node)))
+            }
+            Some(Token::Name(name))
+                if name.eq_ignore_ascii_case("w") && self.peek() == Some(&Token::Symbol("[")) =>
+            {
+                self.next();
+                let node = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Node::Word(Box::new(node)))
+            }
+            Some(Token::Name(name)) => self.name(name),
+            Some(token) => Err(format!("Unexpected {}", describe(token))),
+            None => Err(String::from("Expression ends too soon")),
+        }
+    }
+
+    fn name(&self, name: &str) -> Result<Node, String> {
+        let lower = name.to_ascii_lowercase();
+        let variable =
+            match lower.as_str() {
+                "m" => Some(Variable::M),
+                "cycles" => Some(Variable::Cycles),
+                "steps" => Some(Variable::Steps),
+                "hits" => Some(Variable::Hits),
+                _ => match lower.strip_prefix("flags.") {
+                    Some(flag) => Some(Variable::Flag(flag_name(flag).ok_or_else(|| {
+                        format!("{} isn't a flag. Try s, z, ac, p or cy.", flag)
+                    })?)),
+                    None => RegisterName::from_name(&lower).map(Variable::Register),
+                },
+            };
+        match variable {
+            Some(variable) => Ok(Node::Variable(variable)),
+            None => self
+                .symbols
+                .address(name)
+                .map(|addr| Node::Number(i64::from(addr)))
+                .ok_or_else(|| format!("{} isn't a register, flag or label", name)),
+        }
+    }
+}
+
+fn flag_name(name: &str) -> Option<&'static str> {
+    match name {
+        "s" => Some("s"),
+        "z" => Some("z"),
+        "ac" => Some("ac"),
+        "p" => Some("p"),
+        "cy" | "c" => Some("cy"),
+        _ => None,
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(n) => format!("{:X}", n),
+        Token::Name(name) => name.clone(),
+        Token::Symbol(symbol) => symbol.to_string(),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let word_end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "_.?@$#".contains(c)))
+            .unwrap_or(rest.len());
+        if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if word_end == 0 {
+            let c = rest.chars().next().unwrap_or_default();
+            return Err(format!("Unexpected {} in {}", c, text));
+        } else {
+            let word = &rest[..word_end];
+            if word.starts_with(|c: char| c.is_ascii_digit() || c == '$' || c == '#') {
+                let n = number(word).ok_or_else(|| format!("{} isn't a number", word))?;
+                tokens.push(Token::Number(n));
+            } else {
+                tokens.push(Token::Name(word.to_string()));
+            }
+            rest = &rest[word_end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// Hex like parse_number, with 0x, $ or a trailing H, or decimal after a #.
+fn number(word: &str) -> Option<i64> {
+    if let Some(decimal) = word.strip_prefix('#') {
+        return decimal.parse().ok();
+    }
+    let digits = word
+        .trim_start_matches("0x")
+        .trim_start_matches('$')
+        .trim_end_matches(&['h', 'H'][..]);
+    i64::from_str_radix(digits, 16).ok()
+}
+
+fn evaluate(node: &Node, state: &State) -> i64 {
+    let cpu = state.cpu;
+    match node {
+        Node::Number(n) => *n,
+        Node::Variable(variable) => match variable {
+            Variable::Register(name) => i64::from(cpu.read_register(*name)),
+            Variable::Flag(name) => i64::from(cpu.get_flags().get_by_name(name) == Some(true)),
+            Variable::M => i64::from(cpu.read_memory(cpu.read_register(RegisterName::HL))),
+            Variable::Cycles => state.cycles as i64,
+            Variable::Steps => state.steps as i64,
+            Variable::Hits => state.hits as i64,
+        },
+        Node::Byte(addr) => i64::from(cpu.read_memory(evaluate(addr, state) as u16)),
+        Node::Word(addr) => {
+            let addr = evaluate(addr, state) as u16;
+            let low = i64::from(cpu.read_memory(addr));
+            let high = i64::from(cpu.read_memory(addr.wrapping_add(1)));
+            (high << 8) | low
+        }
+        Node::Unary(op, operand) => {
+            let value = evaluate(operand, state);
+            match *op {
+                "!" => i64::from(value == 0),
+                "~" => !value,
+                _ => value.wrapping_neg(),
+            }
+        }
+        Node::Binary("&&", left, right) => {
+            i64::from(evaluate(left, state) != 0 && evaluate(right, state) != 0)
+        }
+        Node::Binary("||", left, right) => {
+            i64::from(evaluate(left, state) != 0 || evaluate(right, state) != 0)
+        }
+        Node::Binary(op, left, right) => {
+            let (a, b) = (evaluate(left, state), evaluate(right, state));
+            match *op {
+                "|" => a | b,
+                "^" => a ^ b,
+                "&" => a & b,
+                "==" => i64::from(a == b),
+                "!=" => i64::from(a != b),
+                "<" => i64::from(a < b),
+                "<=" => i64::from(a <= b),
+                ">" => i64::from(a > b),
+                ">=" => i64::from(a >= b),
+                "<<" => a.wrapping_shl(b as u32),
+                ">>" => a.wrapping_shr(b as u32),
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "*" => a.wrapping_mul(b),
+                "/" if b == 0 => 0,
+                "/" => a.wrapping_div(b),
+                "%" if b == 0 => 0,
+                _ => a.wrapping_rem(b),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.write_register(RegisterName::PC, 0x0A30);
+        cpu.write_register(RegisterName::A, 0x11);
+        cpu.write_register(RegisterName::HL, 0x20F8);
+        cpu.write_memory(0x20F8, 0x34);
+        cpu.write_memory(0x20F9, 0x12);
+        let mut flags = cpu.get_flags();
+        flags.cy = true;
+        cpu.write_register(RegisterName::F, u16::from(u8::from(flags)));
+        cpu
+    }
+
+    fn evaluate(text: &str) -> i64 {
+        let cpu = cpu();
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x20F8, "score");
+        let state = State {
+            cpu: &cpu,
+            cycles: 1000,
+            steps: 10,
+            hits: 3,
+        };
+        Expression::parse(text, &symbols).unwrap().evaluate(&state)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(
+            evaluate("pc == 0x0A30 && a > 0x10 && [0x20F8] != 0 && flags.cy"),
+            1
+        );
+        assert_eq!(evaluate("a > 20 || flags.z"), 0);
+        assert_eq!(evaluate("w[score]"), 0x1234);
+        assert_eq!(evaluate("m == 34 && hl == score"), 1);
+        assert_eq!(evaluate("1 + 2 * 3 == 7"), 1);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9);
+        assert_eq!(evaluate("cycles == #1000 && hits >= #3"), 1);
+        assert_eq!(evaluate("!flags.cy | ~0 & 0FFH"), 0xFF);
+        assert_eq!(evaluate("-1 < 0 && 5 / 0 == 0"), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let symbols = SymbolTable::new();
+        assert!(Expression::parse("pc ==", &symbols).is_err());
+        assert!(Expression::parse("(a", &symbols).is_err());
+        assert!(Expression::parse("nowhere > 1", &symbols).is_err());
+        assert!(Expression::parse("flags.q", &symbols).is_err());
+        assert!(Expression::parse("a b", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_log_message() {
+        let cpu = cpu();
+        let state = State {
+            cpu: &cpu,
+            cycles: 0,
+            steps: 0,
+            hits: 12,
+        };
+        let message = LogMessage::parse(
+            "{{score}} {w[hl]} a={a} hit {hits:d} {a + 1}",
+            &SymbolTable::new(),
+        )
+        .unwrap();
+        assert_eq!(message.format(&state), "{score} 1234 a=11 hit 12 12");
+        assert!(LogMessage::parse("{a", &SymbolTable::new()).is_err());
+    }
+}
@@ -0,0 +1,538 @@
+use crate::debugger::{Debugger, StopReason, WatchKind, Watchpoint};
+use crate::RegisterName;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+// gdb has no 8080 target. Its z80 one is a superset, so registers go out in that layout
+// (af bc de hl sp pc ix iy af' bc' de' hl' ir) with the z80-only ones reading as zero.
+// Start gdb with `set architecture z80`.
+const REGISTERS: [RegisterName; 6] = [
+    RegisterName::PSW,
+    RegisterName::BC,
+    RegisterName::DE,
+    RegisterName::HL,
+    RegisterName::SP,
+    RegisterName::PC,
+];
+const REGISTER_COUNT: usize = 13;
+
+// How long a continue runs between checks for a Ctrl-C from gdb.
+const CYCLES_PER_POLL: u64 = 20_000;
+
+// Speaks the remote serial protocol to a single gdb connection.
+pub struct GdbStub<'a> {
+    debugger: &'a mut Debugger,
+    stream: TcpStream,
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(debugger: &'a mut Debugger, stream: TcpStream) -> Self {
+        GdbStub { debugger, stream }
+    }
+
+    // Serves packets until gdb detaches, kills the target or goes away.
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(Packet::Command(packet)) => packet,
+                Some(Packet::Interrupt) => {
+                    self.send("S02")?;
+                    continue;
+                }
+                None => return Ok(()),
+            };
+            debug!("gdb <- {}", packet);
+            match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => return self.send("OK"),
+                Some(b'c') => {
+                    self.resume_at(&packet[1..]);
+                    let reason = self.run()?;
+                    self.send(&self.stop_reply(reason))?;
+                }
+                Some(b's') => {
+                    self.resume_at(&packet[1..]);
+                    let reason = self.debugger.step();
+                    self.send(&self.stop_reply(reason))?;
+                }
+                // Reverse step and reverse continue.
+                Some(b'b') if packet == "bs" || packet == "bc" => {
+                    let reason = if packet == "bs" {
+                        self.debugger.reverse_step()
+                    } else {
+                        self.debugger.reverse_continue()
+                    };
+                    self.send(&self.stop_reply(reason))?;
+                }
+                _ => {
+                    let reply = self.handle(&packet);
+                    self.send(&reply)?;
+                }
+            }
+        }
+    }
+
+    // Everything that answers straight away. An empty reply tells gdb a packet isn't supported.
+    fn handle(&mut self, packet: &str) -> String {
+        if !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (command, args) = packet.split_at(1);
+        let reply = match command {
+            "?" => Some(String::from("S05")),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" | "z" => self.change_breakpoint(command == "Z", args),
+            "H" | "T" => Some(String::from("OK")),
+            "q" => query(args),
+            _ => Some(String::new()),
+        };
+        reply.unwrap_or_else(|| String::from("E01"))
+    }
+
+    fn resume_at(&mut self, addr: &str) {
+        if let Some(addr) = parse_hex(addr) {
+            self.debugger.cpu.set_pc(addr);
+            self.debugger.state_changed();
+        }
+    }
+
+    fn run(&mut self) -> io::Result<StopReason> {
+        self.stream.set_nonblocking(true)?;
+        let reason = loop {
+            let reason = self.debugger.run_for(CYCLES_PER_POLL);
+            if reason != StopReason::Step {
+                break reason;
+            }
+            if self.interrupt_requested()? {
+                break StopReason::Interrupted;
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        Ok(reason)
+    }
+
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(0) => Err(io::Error::new(ErrorKind::UnexpectedEof, "gdb went away")),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Watchpoint(addr, access) => {
+                let kind = self
+                    .debugger
+                    .watchpoints()
+                    .find(|w| w.hit_by(addr, access))
+                    .map_or(WatchKind::Write, |w| w.kind);
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{}:{:04x};", name, addr)
+            }
+            StopReason::Interrupted => String::from("S02"),
+            StopReason::StartOfHistory => String::from("T05replaylog:begin;"),
+            _ => String::from("S05"),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTER_COUNT)
+            .map(|i| encode_word(self.register_value(i)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        for (i, reg) in REGISTERS.iter().enumerate() {
+            let value = decode_word(args.get(i * 4..i * 4 + 4)?)?;
+            self.debugger.cpu.write_register(*reg, value);
+        }
+        self.debugger.state_changed();
+        Some(String::from("OK"))
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = usize::from(parse_hex(args)?);
+        if index >= REGISTER_COUNT {
+            return None;
+        }
+        Some(encode_word(self.register_value(index)))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (index, value) = split_once(args, '=')?;
+        let index = usize::from(parse_hex(index)?);
+        let value = decode_word(value)?;
+        if let Some(reg) = REGISTERS.get(index) {
+            self.debugger.cpu.write_register(*reg, value);
+        }
+        self.debugger.state_changed();
+        Some(String::from("OK"))
+    }
+
+    fn register_value(&self, index: usize) -> u16 {
+        REGISTERS
+            .get(index)
+            .map_or(0, |reg| self.debugger.cpu.read_register(*reg))
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = split_once(args, ',')?;
+        let addr = parse_hex(addr)?;
+        let len = parse_hex(len)?;
+        Some(
+            (0..len)
+                .map(|i| {
+                    format!(
+                        "{:02x}",
+                        self.debugger.cpu.read_memory(addr.wrapping_add(i))
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = split_once(args, ':')?;
+        let (addr, len) = split_once(range, ',')?;
+        let addr = parse_hex(addr)?;
+        let bytes = decode_bytes(data)?;
+        if bytes.len() != usize::from(parse_hex(len)?) {
+            return None;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.debugger
+                .cpu
+                .write_memory(addr.wrapping_add(i as u16), *byte);
+        }
+        self.debugger.state_changed();
+        Some(String::from("OK"))
+    }
+
+    // Z/z type,addr,kind. For watchpoints kind is the length being watched.
+    fn change_breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+        let len = fields.next().and_then(parse_hex).unwrap_or(1);
+        let watch = |kind| Watchpoint { addr, len, kind };
+        match (kind, insert) {
+            ("0", true) | ("1", true) => {
+                self.debugger.add_breakpoint(addr);
+            }
+            ("0", false) | ("1", false) => {
+                self.debugger.remove_breakpoint(addr);
+            }
+            ("2", true) => self.debugger.add_watchpoint(watch(WatchKind::Write)),
+            ("3", true) => self.debugger.add_watchpoint(watch(WatchKind::Read)),
+            ("4", true) => self.debugger.add_watchpoint(watch(WatchKind::Access)),
+            ("2", false) => {
+                self.debugger.remove_watchpoint(watch(WatchKind::Write));
+            }
+            ("3", false) => {
+                self.debugger.remove_watchpoint(watch(WatchKind::Read));
+            }
+            ("4", false) => {
+                self.debugger.remove_watchpoint(watch(WatchKind::Access));
+            }
+            _ => return Some(String::new()),
+        }
+        Some(String::from("OK"))
+    }
+
+    // Returns None when the connection closes.
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let byte = match self.read_byte()? {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            match byte {
+                0x03 => return Ok(Some(Packet::Interrupt)),
+                b'$' => {}
+                _ => continue,
+            }
+            let mut body = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => body.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut sum = [0; 2];
+            self.stream.read_exact(&mut sum)?;
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected == Some(checksum(&body)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(Packet::Command(unescape(&body))));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // gdb acks every packet, but over TCP a lost one isn't worth waiting for.
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        debug!("gdb -> {}", reply);
+        self.stream.write_all(frame(reply).as_bytes())
+    }
+}
+
+fn query(args: &str) -> Option<String> {
+    let reply = if args.starts_with("Supported") {
+        "PacketSize=1000;ReverseStep+;ReverseContinue+"
+    } else if args == "Attached" {
+        "1"
+    } else if args == "C" {
+        "QC1"
+    } else if args == "fThreadInfo" {
+        "m1"
+    } else if args == "sThreadInfo" {
+        "l"
+    } else {
+        ""
+    };
+    Some(String::from(reply))
+}
+
+pub fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+pub fn frame(body: &str) -> String {
+    format!("${}#{:02x}", body, checksum(body.as_bytes()))
+}
+
+// '}' escapes the byte after it, xored with 0x20.
+fn unescape(body: &[u8]) -> String {
+    let mut bytes = Vec::with_capacity(body.len());
+    let mut escaped = false;
+    for byte in body {
+        if escaped {
+            bytes.push(byte ^ 0x20);
+            escaped = false;
+        } else if *byte == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(*byte);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn split_once(text: &str, separator: char) -> Option<(&str, &str)> {
+    let i = text.find(separator)?;
+    Some((&text[..i], &text[i + 1..]))
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// Registers go over the wire in target byte order.
+fn encode_word(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xFF, value >> 8)
+}
+
+fn decode_word(text: &str) -> Option<u16> {
+    let bytes = decode_bytes(text)?;
+    match bytes.as_slice() {
+        [low, high] => Some(u16::from(*low) | u16::from(*high) << 8),
+        _ => None,
+    }
+}
+
+fn decode_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() & 1 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Bare;
+    use crate::Cpu;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // LXI SP,0100 / MVI A,42 / STA 0080 / LDA 0081 / HLT
+    const PROGRAM: [u8; 12] = [
+        0x31, 0x00, 0x01, 0x3E, 0x42, 0x32, 0x80, 0x00, 0x3A, 0x81, 0x00, 0x76,
+    ];
+
+    fn debugger() -> Debugger {
+        let mut cpu = Cpu::new();
+        for (i, byte) in PROGRAM.iter().enumerate() {
+            cpu.write_memory(i as u16, *byte);
+        }
+        Debugger::new(cpu, Box::new(Bare))
+    }
+
+    // Plays gdb from another thread, sending each packet once the last one's been answered,
+    // and returns the stub's replies.
+    fn session(debugger: &mut Debugger, packets: &[&str]) -> Vec<String> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let packets: Vec<String> = packets.iter().map(|packet| packet.to_string()).collect();
+        let gdb = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut replies = Vec::new();
+            for packet in packets {
+                stream.write_all(frame(&packet).as_bytes()).unwrap();
+                replies.push(read_reply(&mut stream));
+            }
+            stream.write_all(frame("k").as_bytes()).unwrap();
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(debugger, stream).serve().unwrap();
+        gdb.join().unwrap()
+    }
+
+    // Skips the ack and takes the body of the packet after it.
+    fn read_reply(stream: &mut TcpStream) -> String {
+        let mut bytes = Read::bytes(&mut *stream).map(Result::unwrap);
+        bytes.find(|byte| *byte == b'$');
+        let body: Vec<u8> = bytes.by_ref().take_while(|byte| *byte != b'#').collect();
+        bytes.nth(1);
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame(""), "$#00");
+    }
+
+    #[test]
+    fn test_words_are_little_endian() {
+        assert_eq!(encode_word(0x1234), "3412");
+        assert_eq!(decode_word("3412"), Some(0x1234));
+        assert_eq!(decode_word("34"), None);
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(b"M0,1:}\x03"), "M0,1:#");
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut debugger = debugger();
+        // af comes low byte first, so flags then A. The 8080 keeps bit 1 of the flags set.
+        let replies = session(
+            &mut debugger,
+            &[
+                "Gc54234127856bc9a00240300",
+                "g",
+                "P0=d742",
+                "p0",
+                "p5",
+                "p6",
+                "pd",
+            ],
+        );
+        // af bc de hl sp pc, then the z80's ix iy af' bc' de' hl' ir reading as zero.
+        let registers = format!("c74234127856bc9a00240300{}", "0000".repeat(7));
+        assert_eq!(
+            replies,
+            [
+                "OK",
+                registers.as_str(),
+                "OK",
+                "d742",
+                "0300",
+                "0000",
+                "E01",
+            ]
+        );
+        assert_eq!(debugger.cpu.read_register(RegisterName::BC), 0x1234);
+        assert_eq!(debugger.cpu.read_register(RegisterName::SP), 0x2400);
+        assert_eq!(debugger.cpu.get_pc(), 0x0003);
+        let flags = debugger.cpu.get_flags();
+        assert!(flags.s && flags.z && flags.ac && flags.p && flags.cy);
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut debugger = debugger();
+        let replies = session(
+            &mut debugger,
+            &["M80,2:abcd", "m80,2", "m0,3", "M80,2:ab", "m80"],
+        );
+        assert_eq!(replies, ["OK", "abcd", "310001", "E01", "E01"]);
+        assert_eq!(debugger.cpu.read_memory(0x81), 0xCD);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+        let replies = session(
+            &mut debugger,
+            &["?", "Z0,8,1", "c", "p5", "s", "p5", "z0,8,1", "c0"],
+        );
+        assert_eq!(
+            replies,
+            ["S05", "OK", "S05", "0800", "S05", "0b00", "OK", "S05"]
+        );
+        assert_eq!(debugger.cpu.read_memory(0x80), 0x42);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        let replies = session(
+            &mut debugger,
+            &[
+                "Z2,80,1", "c", "z2,80,1", "Z3,81,1", "c0", "z3,81,1", "Z4,80,2", "c0", "z4,80,2",
+                "c0",
+            ],
+        );
+        assert_eq!(
+            replies,
+            [
+                "OK",
+                "T05watch:0080;",
+                "OK",
+                "OK",
+                "T05rwatch:0081;",
+                "OK",
+                "OK",
+                "T05awatch:0080;",
+                "OK",
+                "S05",
+            ]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// CRC-32 as zip, PNG and MAME use it: reflected, polynomial EDB88320, inverted at both ends.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+// For hashing a ROM set that arrives in pieces.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { value: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.value ^= u32::from(*byte);
+            for _ in 0..8 {
+                let mask = (self.value & 1).wrapping_neg();
+                self.value = (self.value >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.finish()
+}
+
+// SHA-1 as FIPS 180 gives it, which MAME lists beside each ROM's CRC.
+#[derive(Clone, Debug)]
+pub struct Sha1 {
+    state: [u32; 5],
+    // Bytes waiting for a full 64-byte block.
+    block: Vec<u8>,
+    length: u64,
+}
+
+impl Sha1 {
+    pub fn new() -> Self {
+        Sha1 {
+            state: [
+                0x6745_2301,
+                0xEFCD_AB89,
+                0x98BA_DCFE,
+                0x1032_5476,
+                0xC3D2_E1F0,
+            ],
+            block: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.length += data.len() as u64;
+        for byte in data {
+            self.block.push(*byte);
+            if self.block.len() == 64 {
+                self.compress();
+            }
+        }
+    }
+
+    pub fn finish(&self) -> [u8; 20] {
+        let mut sha1 = self.clone();
+        let bits = sha1.length * 8;
+        sha1.block.push(0x80);
+        if sha1.block.len() > 56 {
+            sha1.block.resize(64, 0);
+            sha1.compress();
+        }
+        sha1.block.resize(56, 0);
+        sha1.block.extend(&bits.to_be_bytes());
+        sha1.compress();
+        let mut digest = [0; 20];
+        for (i, word) in sha1.state.iter().enumerate() {
+            digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 80];
+        for (i, word) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+        self.block.clear();
+    }
+}
+
+impl Default for Sha1 {
+    fn default() -> Self {
+        Sha1::new()
+    }
+}
+
+// Lower case, as MAME writes them.
+pub fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut crc = Crc32::new();
+        crc.update(b"12345");
+        crc.update(b"6789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            hex_digest(&sha1(b"")),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            hex_digest(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        let mut sha1 = Sha1::new();
+        for _ in 0..1000 {
+            sha1.update(b"a");
+        }
+        // A byte at a time, across several blocks.
+        assert_eq!(
+            hex_digest(&sha1.finish()),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use crate::hash::crc32;
+use crate::symbols::SymbolTable;
+use crate::{opcode_length, Access, Cpu};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FindingKind {
+    // A write to a byte that has already run as part of an instruction.
+    SelfModifying,
+    // A write into a range marked as ROM.
+    RomWrite,
+}
+
+// A store that deserves a look. The same store hitting the same address again only adds to
+// count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub pc: u16,
+    pub addr: u16,
+    pub count: u64,
+}
+
+// Counts reads, writes and executions of every address, and watches writes for code being
+// changed under itself. Call record() ahead of each instruction.
+pub struct Heatmap {
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    executes: Vec<u64>,
+    rom: Vec<RangeInclusive<u16>>,
+    findings: BTreeMap<(FindingKind, u16, u16), u64>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap {
+            reads: vec![0; 0x10000],
+            writes: vec![0; 0x10000],
+            executes: vec![0; 0x10000],
+            rom: Vec::new(),
+            findings: BTreeMap::new(),
+        }
+    }
+
+    // Writes into range are reported. On Invaders that's machine::invaders::ROM_AREA.
+    pub fn add_rom(&mut self, range: RangeInclusive<u16>) {
+        self.rom.push(range);
+    }
+
+    pub fn record(&mut self, cpu: &Cpu) {
+        let pc = cpu.get_pc();
+        for offset in 0..opcode_length(cpu.read_memory(pc)) {
+            self.executes[usize::from(pc.wrapping_add(offset))] += 1;
+        }
+        for access in cpu.memory_accesses() {
+            let addr = usize::from(access.addr);
+            match access.kind {
+                Access::Read => self.reads[addr] += 1,
+                Access::Write => {
+                    self.writes[addr] += 1;
+                    if self.executes[addr] > 0 {
+                        self.report(FindingKind::SelfModifying, pc, access.addr);
+                    }
+                    if self.rom.iter().any(|range| range.contains(&access.addr)) {
+                        self.report(FindingKind::RomWrite, pc, access.addr);
+                    }
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, kind: FindingKind, pc: u16, addr: u16) {
+        let count = self.findings.entry((kind, pc, addr)).or_insert(0);
+        if *count == 0 {
+            match kind {
+                FindingKind::SelfModifying => {
+                    warn!("{:04X} wrote to {:04X}, which has run as code", pc, addr)
+                }
+                FindingKind::RomWrite => warn!("{:04X} wrote to {:04X} in ROM", pc, addr),
+            }
+        }
+        *count += 1;
+    }
+
+    pub fn reads(&self, addr: u16) -> u64 {
+        self.reads[usize::from(addr)]
+    }
+
+    pub fn writes(&self, addr: u16) -> u64 {
+        self.writes[usize::from(addr)]
+    }
+
+    pub fn executes(&self, addr: u16) -> u64 {
+        self.executes[usize::from(addr)]
+    }
+
+    // By kind, then by the address of the store.
+    pub fn findings(&self) -> Vec<Finding> {
+        self.findings
+            .iter()
+            .map(|((kind, pc, addr), count)| Finding {
+                kind: *kind,
+                pc: *pc,
+                addr: *addr,
+                count: *count,
+            })
+            .collect()
+    }
+
+    // One pixel per address, 256 to a row, so each row is a page and 0000 is top left.
+    // Writes show in red, reads in green and executed code in blue, each brighter the more
+    // often it happened on a log scale, so a handful of hits still shows up.
+    pub fn pixels(&self) -> Vec<u8> {
+        let scale = |counts: &[u64]| {
+            let max = (*counts.iter().max().unwrap_or(&0) as f64).ln_1p();
+            move |count: u64| {
+                if count == 0 {
+                    0
+                } else {
+                    // Anything touched at all gets at least a dim pixel.
+                    (48.0 + 207.0 * (count as f64).ln_1p() / max.max(f64::MIN_POSITIVE)) as u8
+                }
+            }
+        };
+        let (red, green, blue) = (
+            scale(&self.writes),
+            scale(&self.reads),
+            scale(&self.executes),
+        );
+        let mut pixels = Vec::with_capacity(3 * 0x10000);
+        for addr in 0..0x10000 {
+            pixels.push(red(self.writes[addr]));
+            pixels.push(green(self.reads[addr]));
+            pixels.push(blue(self.executes[addr]));
+        }
+        pixels
+    }
+
+    pub fn write_png<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        write_png(out, 256, 256, &self.pixels())
+    }
+
+    // Every finding, then the most written addresses.
+    pub fn write_report<W: Write + ?Sized>(
+        &self,
+        out: &mut W,
+        symbols: &SymbolTable,
+        limit: usize,
+    ) -> io::Result<()> {
+        let name = |addr: u16| match symbols.nearest(addr) {
+            Some((start, name)) if start == addr => format!("{:04X} {}", addr, name),
+            Some((start, name)) => format!("{:04X} {}+{:X}", addr, name, addr - start),
+            None => format!("{:04X}", addr),
+        };
+        let findings = self.findings();
+        writeln!(out, "{} suspicious writes", findings.len())?;
+        for finding in &findings {
+            let kind = match finding.kind {
+                FindingKind::SelfModifying => "code changed",
+                FindingKind::RomWrite => "ROM written",
+            };
+            writeln!(
+                out,
+                "  {:<13} by {:<24} at {:<24} {} times",
+                kind,
+                name(finding.pc),
+                name(finding.addr),
+                finding.count
+            )?;
+        }
+
+        writeln!(out, "\nMost written:")?;
+        writeln!(out, "        writes         reads  address")?;
+        let mut addresses: Vec<u16> = (0..=0xFFFFu16).filter(|a| self.writes(*a) > 0).collect();
+        addresses.sort_by(|a, b| self.writes(*b).cmp(&self.writes(*a)).then(a.cmp(b)));
+        for addr in addresses.into_iter().take(limit) {
+            writeln!(
+                out,
+                "{:>14} {:>13}  {}",
+                self.writes(addr),
+                self.reads(addr),
+                name(addr)
+            )?;
+        }
+        Ok(())
+    }
+
+    // Writes prefix.png with the heatmap and prefix.txt with the report.
+    pub fn save(&self, prefix: &str, symbols: &SymbolTable) -> io::Result<()> {
+        let mut image = Vec::new();
+        self.write_png(&mut image)?;
+        fs::write(format!("{}.png", prefix), image)?;
+        let mut report = BufWriter::new(File::create(format!("{}.txt", prefix))?);
+        self.write_report(&mut report, symbols, 50)?;
+        report.flush()
+    }
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Heatmap::new()
+    }
+}
+
+// An 8-bit RGB PNG. The image data goes in stored (uncompressed) deflate blocks, which every
+// reader accepts and which don't need a compressor.
+fn write_png<W: Write + ?Sized>(
+    out: &mut W,
+    width: u32,
+    height: u32,
+    rgb: &[u8],
+) -> io::Result<()> {
+    let chunk = |out: &mut W, kind: &[u8], data: &[u8]| -> io::Result<()> {
+        out.write_all(&(data.len() as u32).to_be_bytes())?;
+        let mut checked = kind.to_vec();
+        checked.extend_from_slice(data);
+        out.write_all(&checked)?;
+        out.write_all(&crc32(&checked).to_be_bytes())
+    };
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, RGB, deflate, no filtering, not interlaced.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Each row starts with its filter type, 0 for none.
+    let row = 3 * width as usize;
+    let mut raw = Vec::with_capacity((row + 1) * height as usize);
+    for line in rgb.chunks(row) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xFFFF).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push(u8::from(i == blocks.len() - 1));
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    chunk(out, b"IHDR", &header)?;
+    chunk(out, b"IDAT", &zlib)?;
+    chunk(out, b"IEND", &[])
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + u32::from(*byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::Debugger;
+    use crate::machine::Bare;
+    use crate::RegisterName;
+
+    // MVI A,3C / STA 0001 / STA 1000 / JMP 0000, with 0000-0FFF as ROM. The store to 0001
+    // changes the MVI's operand, in ROM. The one to 1000 is fine.
+    fn mapped() -> Debugger {
+        let program = [
+            0x3E, 0x3C, 0x32, 0x01, 0x00, 0x32, 0x00, 0x10, 0xC3, 0x00, 0x00,
+        ];
+        let mut cpu = Cpu::new();
+        for (i, byte) in program.iter().enumerate() {
+            cpu.write_memory(i as u16, *byte);
+        }
+        cpu.write_register(RegisterName::SP, 0x2400);
+        let mut heatmap = Heatmap::new();
+        heatmap.add_rom(0x0000..=0x0FFF);
+        let mut debugger = Debugger::new(cpu, Box::new(Bare));
+        debugger.set_heatmap(Some(heatmap));
+        for _ in 0..8 {
+            debugger.step();
+        }
+        debugger
+    }
+
+    #[test]
+    fn test_findings() {
+        let debugger = mapped();
+        let heatmap = debugger.heatmap().unwrap();
+
+        assert_eq!(heatmap.writes(0x0001), 2);
+        assert_eq!(heatmap.executes(0x0000), 2);
+        assert_eq!(
+            heatmap.findings(),
+            vec![
+                Finding {
+                    kind: FindingKind::SelfModifying,
+                    pc: 0x0002,
+                    addr: 0x0001,
+                    count: 2,
+                },
+                Finding {
+                    kind: FindingKind::RomWrite,
+                    pc: 0x0002,
+                    addr: 0x0001,
+                    count: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_png() {
+        let debugger = mapped();
+        let heatmap = debugger.heatmap().unwrap();
+        let pixels = heatmap.pixels();
+        assert_eq!(pixels.len(), 3 * 256 * 256);
+        // 0001 is written and executed, and never read.
+        assert_eq!(pixels[3], 255);
+        assert_eq!(pixels[4], 0);
+        assert_eq!(pixels[5], 255);
+
+        let mut png = Vec::new();
+        heatmap.write_png(&mut png).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert!(png
+            .ends_with(&[0x00, 0x00, 0x00, 0x00, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
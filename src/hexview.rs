@@ -0,0 +1,501 @@
+use crate::symbols::SymbolTable;
+use crate::Cpu;
+
+pub mod font;
+
+pub const COLUMNS: usize = 16;
+// How many frames a changed byte stays highlighted, fading as it goes.
+pub const FADE_FRAMES: u8 = 30;
+
+const MEMORY_SIZE: u32 = 0x10000;
+
+// What the frontend passes on from the keyboard. Char is typed text, which fills in the goto
+// prompt or edits the byte under the cursor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Tab,
+    Goto,
+    Enter,
+    Escape,
+    Backspace,
+    Char(char),
+}
+
+// Which side of the row typing goes to: hex digits, or characters stored as ASCII.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pane {
+    Hex,
+    Ascii,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Normal,
+    Address,
+    // Unprintable bytes in the ASCII column.
+    Dim,
+    // Frames left before the highlight fades, out of FADE_FRAMES.
+    Changed(u8),
+    Cursor,
+    // The cursor's byte in the pane that isn't taking input.
+    Mirror,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub style: Style,
+}
+
+// A scrolling hex and ASCII view of memory, with a cursor for editing it in place. Call
+// frame() with the memory each time the view is drawn, so bytes that changed since the last
+// one are highlighted.
+pub struct HexView {
+    rows: usize,
+    top: u16,
+    cursor: u16,
+    pane: Pane,
+    // Set once the high nibble of the cursor's byte has been typed.
+    low_nibble: bool,
+    goto: Option<String>,
+    message: Option<String>,
+    previous: Vec<u8>,
+    ages: Vec<u8>,
+}
+
+impl HexView {
+    pub fn new(rows: usize) -> Self {
+        HexView {
+            rows: rows.clamp(1, MEMORY_SIZE as usize / COLUMNS),
+            top: 0,
+            cursor: 0,
+            pane: Pane::Hex,
+            low_nibble: false,
+            goto: None,
+            message: None,
+            previous: Vec::new(),
+            ages: vec![0; MEMORY_SIZE as usize],
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn top(&self) -> u16 {
+        self.top
+    }
+
+    pub fn cursor(&self) -> u16 {
+        self.cursor
+    }
+
+    pub fn pane(&self) -> Pane {
+        self.pane
+    }
+
+    // The address typed so far, while the goto prompt is open.
+    pub fn goto_text(&self) -> Option<&str> {
+        self.goto.as_deref()
+    }
+
+    // Frames left on addr's highlight, or 0 if it hasn't changed lately.
+    pub fn age(&self, addr: u16) -> u8 {
+        self.ages[usize::from(addr)]
+    }
+
+    // Moves the cursor to addr, scrolling it to the top row if it's out of view.
+    pub fn jump(&mut self, addr: u16) {
+        self.cursor = addr;
+        self.low_nibble = false;
+        if !self.visible(addr) {
+            self.top = row_start(addr);
+            self.top = self.clamp_top(u32::from(self.top));
+        }
+    }
+
+    // Scrolls by lines rows, negative being up, taking the cursor along if it falls out of view.
+    pub fn scroll(&mut self, lines: i32) {
+        let top = i64::from(self.top) + i64::from(lines) * COLUMNS as i64;
+        self.top = self.clamp_top(top.max(0) as u32);
+        if !self.visible(self.cursor) {
+            let column = self.cursor % COLUMNS as u16;
+            let row = if self.cursor < self.top {
+                self.top
+            } else {
+                self.last_row()
+            };
+            self.cursor = row + column;
+            self.low_nibble = false;
+        }
+    }
+
+    // Compares memory with the last frame's, restarting the highlight on every byte that
+    // changed and fading the rest.
+    pub fn frame(&mut self, memory: &[u8]) {
+        if self.previous.len() == memory.len() {
+            for ((age, old), new) in self.ages.iter_mut().zip(&self.previous).zip(memory) {
+                *age = if old != new {
+                    FADE_FRAMES
+                } else {
+                    age.saturating_sub(1)
+                };
+            }
+            self.previous.copy_from_slice(memory);
+        } else {
+            self.previous = memory.to_vec();
+        }
+    }
+
+    // Handles a key. Edits are written straight into the cpu's memory, so they take effect
+    // while the program runs.
+    pub fn key(&mut self, key: Key, cpu: &mut Cpu, symbols: &SymbolTable) {
+        if self.goto.is_some() {
+            self.goto_key(key, symbols);
+            return;
+        }
+        self.message = None;
+        let row = COLUMNS as i32;
+        let page = self.rows as i32 * row;
+        match key {
+            Key::Up => self.move_cursor(-row),
+            Key::Down => self.move_cursor(row),
+            Key::Left | Key::Backspace => self.move_cursor(-1),
+            Key::Right => self.move_cursor(1),
+            Key::PageUp => self.page(-page),
+            Key::PageDown => self.page(page),
+            Key::Home => self.jump(row_start(self.cursor)),
+            Key::End => self.jump(row_start(self.cursor) + (COLUMNS as u16 - 1)),
+            Key::Tab => {
+                self.pane = match self.pane {
+                    Pane::Hex => Pane::Ascii,
+                    Pane::Ascii => Pane::Hex,
+                };
+                self.low_nibble = false;
+            }
+            Key::Goto => self.goto = Some(String::new()),
+            Key::Char(c) => self.type_char(c, cpu),
+            Key::Enter | Key::Escape => {}
+        }
+    }
+
+    fn goto_key(&mut self, key: Key, symbols: &SymbolTable) {
+        let text = self.goto.as_mut().unwrap();
+        match key {
+            Key::Char(c) if !c.is_control() => text.push(c),
+            Key::Backspace => {
+                text.pop();
+            }
+            Key::Escape => self.goto = None,
+            Key::Enter => {
+                let text = self.goto.take().unwrap();
+                match symbols.resolve(text.trim()) {
+                    Some(addr) => self.jump(addr),
+                    None => self.message = Some(format!("Unknown address: {}", text.trim())),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn type_char(&mut self, c: char, cpu: &mut Cpu) {
+        match self.pane {
+            Pane::Hex => {
+                let digit = match c.to_digit(16) {
+                    Some(digit) => digit as u8,
+                    None => return,
+                };
+                let byte = cpu.read_memory(self.cursor);
+                if self.low_nibble {
+                    cpu.write_memory(self.cursor, (byte & 0xF0) | digit);
+                    self.move_cursor(1);
+                } else {
+                    cpu.write_memory(self.cursor, (digit << 4) | (byte & 0x0F));
+                    self.low_nibble = true;
+                }
+            }
+            Pane::Ascii => {
+                if (' '..='~').contains(&c) {
+                    cpu.write_memory(self.cursor, c as u8);
+                    self.move_cursor(1);
+                }
+            }
+        }
+    }
+
+    // Moves the view and the cursor together.
+    fn page(&mut self, by: i32) {
+        let top = (i64::from(self.top) + i64::from(by)).max(0);
+        self.top = self.clamp_top(top as u32);
+        self.move_cursor(by);
+    }
+
+    // Stops at either end of memory rather than wrapping round.
+    fn move_cursor(&mut self, by: i32) {
+        let addr = (i64::from(self.cursor) + i64::from(by)).clamp(0, i64::from(u16::MAX));
+        self.cursor = addr as u16;
+        self.low_nibble = false;
+        if self.cursor < self.top {
+            self.top = row_start(self.cursor);
+        } else if self.cursor > self.last_row() + (COLUMNS as u16 - 1) {
+            let bottom = u32::from(row_start(self.cursor)) + COLUMNS as u32;
+            self.top = self.clamp_top(bottom - (self.rows * COLUMNS) as u32);
+        }
+    }
+
+    fn visible(&self, addr: u16) -> bool {
+        addr >= self.top && addr <= self.last_row() + (COLUMNS as u16 - 1)
+    }
+
+    fn last_row(&self) -> u16 {
+        self.top + ((self.rows - 1) * COLUMNS) as u16
+    }
+
+    fn clamp_top(&self, top: u32) -> u16 {
+        top.min(MEMORY_SIZE - (self.rows * COLUMNS) as u32) as u16
+    }
+
+    // The visible rows, laid out as "2000  00 01 .. 07  08 .. 0F  ................".
+    pub fn lines(&self, memory: &[u8]) -> Vec<Vec<Cell>> {
+        (0..self.rows)
+            .map(|row| self.line(memory, self.top + (row * COLUMNS) as u16))
+            .collect()
+    }
+
+    fn line(&self, memory: &[u8], start: u16) -> Vec<Cell> {
+        let mut cells = Vec::new();
+        let mut push = |text: &str, style: Style| {
+            cells.extend(text.chars().map(|ch| Cell { ch, style }));
+        };
+        push(&format!("{:04X}  ", start), Style::Address);
+        for column in 0..COLUMNS as u16 {
+            let addr = start + column;
+            let hex = format!("{:02X}", memory[usize::from(addr)]);
+            let style = self.style(addr, Pane::Hex);
+            if addr == self.cursor && self.pane == Pane::Hex && self.low_nibble {
+                push(&hex[..1], Style::Normal);
+                push(&hex[1..], Style::Cursor);
+            } else {
+                push(&hex, style);
+            }
+            push(if column == 7 { "  " } else { " " }, Style::Normal);
+        }
+        push(" ", Style::Normal);
+        for column in 0..COLUMNS as u16 {
+            let addr = start + column;
+            let byte = memory[usize::from(addr)];
+            match (self.style(addr, Pane::Ascii), byte) {
+                (Style::Normal, 0x20..=0x7E) => push(&(byte as char).to_string(), Style::Normal),
+                (Style::Normal, _) => push(".", Style::Dim),
+                (style, 0x20..=0x7E) => push(&(byte as char).to_string(), style),
+                (style, _) => push(".", style),
+            }
+        }
+        cells
+    }
+
+    fn style(&self, addr: u16, pane: Pane) -> Style {
+        if addr == self.cursor {
+            if pane == self.pane {
+                Style::Cursor
+            } else {
+                Style::Mirror
+            }
+        } else if self.age(addr) > 0 {
+            Style::Changed(self.age(addr))
+        } else {
+            Style::Normal
+        }
+    }
+
+    // The line under the rows: the goto prompt while it's open, then any complaint about the
+    // address typed, otherwise where the cursor is and what's there.
+    pub fn status(&self, memory: &[u8], symbols: &SymbolTable) -> String {
+        if let Some(text) = &self.goto {
+            return format!("Go to: {}_", text);
+        }
+        if let Some(message) = &self.message {
+            return message.clone();
+        }
+        let addr = self.cursor;
+        let name = match symbols.nearest(addr) {
+            Some((start, name)) if start == addr => format!(" {}", name),
+            Some((start, name)) => format!(" {}+{:X}", name, addr - start),
+            None => String::new(),
+        };
+        let pane = match self.pane {
+            Pane::Hex => "hex",
+            Pane::Ascii => "ascii",
+        };
+        format!(
+            "{:04X}{} = {:02X}  editing {}  Tab switches  Ctrl+G goes to",
+            addr,
+            name,
+            memory[usize::from(addr)],
+            pane
+        )
+    }
+}
+
+fn row_start(addr: u16) -> u16 {
+    addr - addr % COLUMNS as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(cells: &[Cell]) -> String {
+        cells.iter().map(|cell| cell.ch).collect()
+    }
+
+    fn type_text(view: &mut HexView, cpu: &mut Cpu, symbols: &SymbolTable, text: &str) {
+        for c in text.chars() {
+            view.key(Key::Char(c), cpu, symbols);
+        }
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut cpu = Cpu::new();
+        for (i, byte) in b"Hi\x00\xFF".iter().enumerate() {
+            cpu.write_memory(0x2000 + i as u16, *byte);
+        }
+        let mut view = HexView::new(4);
+        view.jump(0x2000);
+        let lines = view.lines(cpu.get_memory());
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            text(&lines[0]),
+            "2000  48 69 00 FF 00 00 00 00  00 00 00 00 00 00 00 00  Hi.............."
+        );
+        assert_eq!(text(&lines[3])[..4], *"2030");
+        // The cursor's byte, in both panes.
+        assert_eq!(lines[0][6].style, Style::Cursor);
+        assert_eq!(lines[0][56].style, Style::Mirror);
+        assert_eq!(lines[0][58].style, Style::Dim);
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut cpu = Cpu::new();
+        let symbols = SymbolTable::new();
+        let mut view = HexView::new(4);
+        view.key(Key::Up, &mut cpu, &symbols);
+        view.key(Key::Left, &mut cpu, &symbols);
+        assert_eq!((view.cursor(), view.top()), (0x0000, 0x0000));
+
+        for _ in 0..4 {
+            view.key(Key::Down, &mut cpu, &symbols);
+        }
+        assert_eq!((view.cursor(), view.top()), (0x0040, 0x0010));
+        view.key(Key::End, &mut cpu, &symbols);
+        assert_eq!(view.cursor(), 0x004F);
+        view.key(Key::PageDown, &mut cpu, &symbols);
+        assert_eq!((view.cursor(), view.top()), (0x008F, 0x0050));
+
+        view.jump(0xFFF0);
+        assert_eq!((view.cursor(), view.top()), (0xFFF0, 0xFFC0));
+        view.key(Key::Right, &mut cpu, &symbols);
+        view.scroll(10);
+        assert_eq!((view.cursor(), view.top()), (0xFFF1, 0xFFC0));
+        // Scrolling the cursor out of view takes it along.
+        view.scroll(-8);
+        assert_eq!((view.cursor(), view.top()), (0xFF71, 0xFF40));
+    }
+
+    #[test]
+    fn test_editing() {
+        let mut cpu = Cpu::new();
+        let symbols = SymbolTable::new();
+        let mut view = HexView::new(4);
+        view.jump(0x2000);
+        type_text(&mut view, &mut cpu, &symbols, "a");
+        assert_eq!(cpu.read_memory(0x2000), 0xA0);
+        assert_eq!(view.cursor(), 0x2000);
+        type_text(&mut view, &mut cpu, &symbols, "5xC3");
+        assert_eq!(cpu.read_memory(0x2000), 0xA5);
+        assert_eq!(cpu.read_memory(0x2001), 0xC3);
+        assert_eq!(view.cursor(), 0x2002);
+
+        view.key(Key::Tab, &mut cpu, &symbols);
+        assert_eq!(view.pane(), Pane::Ascii);
+        type_text(&mut view, &mut cpu, &symbols, "OK");
+        assert_eq!(&cpu.get_memory()[0x2002..0x2004], b"OK");
+        view.key(Key::Backspace, &mut cpu, &symbols);
+        assert_eq!(view.cursor(), 0x2003);
+    }
+
+    #[test]
+    fn test_goto() {
+        let mut cpu = Cpu::new();
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x20C0, "shotSync");
+        let mut view = HexView::new(4);
+        view.key(Key::Goto, &mut cpu, &symbols);
+        type_text(&mut view, &mut cpu, &symbols, "shotsync+4");
+        assert_eq!(view.goto_text(), Some("shotsync+4"));
+        view.key(Key::Enter, &mut cpu, &symbols);
+        assert_eq!((view.cursor(), view.top()), (0x20C4, 0x20C0));
+        // Typing at the prompt doesn't edit memory.
+        assert_eq!(cpu.read_memory(0x20C4), 0);
+        assert_eq!(
+            view.status(cpu.get_memory(), &symbols)[..18],
+            *"20C4 shotSync+4 = "
+        );
+
+        view.key(Key::Goto, &mut cpu, &symbols);
+        type_text(&mut view, &mut cpu, &symbols, "nowhere");
+        view.key(Key::Enter, &mut cpu, &symbols);
+        assert_eq!(view.cursor(), 0x20C4);
+        assert_eq!(
+            view.status(cpu.get_memory(), &symbols),
+            "Unknown address: nowhere"
+        );
+
+        view.key(Key::Goto, &mut cpu, &symbols);
+        type_text(&mut view, &mut cpu, &symbols, "2400");
+        view.key(Key::Escape, &mut cpu, &symbols);
+        assert_eq!(view.goto_text(), None);
+        assert_eq!(view.cursor(), 0x20C4);
+    }
+
+    #[test]
+    fn test_changes_fade() {
+        let mut memory = vec![0; 0x10000];
+        let mut view = HexView::new(4);
+        view.frame(&memory);
+        memory[0x0005] = 1;
+        view.frame(&memory);
+        assert_eq!(view.age(0x0005), FADE_FRAMES);
+        assert_eq!(view.age(0x0006), 0);
+        assert_eq!(
+            view.lines(&memory)[0][21].style,
+            Style::Changed(FADE_FRAMES)
+        );
+        view.frame(&memory);
+        assert_eq!(view.age(0x0005), FADE_FRAMES - 1);
+        for _ in 0..FADE_FRAMES {
+            view.frame(&memory);
+        }
+        assert_eq!(view.age(0x0005), 0);
+    }
+
+    #[test]
+    fn test_font() {
+        // 0 is a box with a hole down the middle.
+        assert!(font::lit('0', 0, 0) && font::lit('0', 2, 4));
+        assert!(!font::lit('0', 1, 2));
+        assert!(!font::lit(' ', 1, 1));
+        assert_eq!(font::lit('\u{7}', 0, 0), font::lit('?', 0, 0));
+        assert!(!font::lit('0', 3, 0));
+    }
+}
@@ -0,0 +1,113 @@
+// A 3x5 pixel font for printable ASCII, so the memory window doesn't need SDL_ttf or a font
+// file. Each glyph is five rows of three bits, top row first, with the leftmost pixel in the
+// row's high bit.
+
+pub const WIDTH: u32 = 3;
+pub const HEIGHT: u32 = 5;
+
+const GLYPHS: [u16; 95] = [
+    0b000_000_000_000_000, // space
+    0b010_010_010_000_010, // !
+    0b101_101_000_000_000, // "
+    0b101_111_101_111_101, // #
+    0b011_110_010_011_110, // $
+    0b100_001_010_100_001, // %
+    0b010_101_010_101_011, // &
+    0b010_010_000_000_000, // '
+    0b001_010_010_010_001, // (
+    0b100_010_010_010_100, // )
+    0b000_101_010_101_000, // *
+    0b000_010_111_010_000, // +
+    0b000_000_000_010_100, // ,
+    0b000_000_111_000_000, // -
+    0b000_000_000_000_010, // .
+    0b001_001_010_100_100, // /
+    0b111_101_101_101_111, // 0
+    0b010_110_010_010_111, // 1
+    0b110_001_010_100_111, // 2
+    0b110_001_010_001_110, // 3
+    0b101_101_111_001_001, // 4
+    0b111_100_110_001_110, // 5
+    0b011_100_111_101_111, // 6
+    0b111_001_010_010_010, // 7
+    0b111_101_111_101_111, // 8
+    0b111_101_111_001_110, // 9
+    0b000_010_000_010_000, // :
+    0b000_010_000_010_100, // ;
+    0b001_010_100_010_001, // <
+    0b000_111_000_111_000, // =
+    0b100_010_001_010_100, // >
+    0b110_001_010_000_010, // ?
+    0b111_101_111_100_011, // @
+    0b010_101_111_101_101, // A
+    0b110_101_110_101_110, // B
+    0b011_100_100_100_011, // C
+    0b110_101_101_101_110, // D
+    0b111_100_110_100_111, // E
+    0b111_100_110_100_100, // F
+    0b011_100_101_101_011, // G
+    0b101_101_111_101_101, // H
+    0b111_010_010_010_111, // I
+    0b001_001_001_101_010, // J
+    0b101_101_110_101_101, // K
+    0b100_100_100_100_111, // L
+    0b101_111_111_101_101, // M
+    0b110_101_101_101_101, // N
+    0b010_101_101_101_010, // O
+    0b110_101_110_100_100, // P
+    0b010_101_101_110_011, // Q
+    0b110_101_110_101_101, // R
+    0b011_100_010_001_110, // S
+    0b111_010_010_010_010, // T
+    0b101_101_101_101_011, // U
+    0b101_101_101_010_010, // V
+    0b101_101_111_111_101, // W
+    0b101_101_010_101_101, // X
+    0b101_101_010_010_010, // Y
+    0b111_001_010_100_111, // Z
+    0b110_100_100_100_110, // [
+    0b100_100_010_001_001, // \
+    0b011_001_001_001_011, // ]
+    0b010_101_000_000_000, // ^
+    0b000_000_000_000_111, // _
+    0b100_010_000_000_000, // `
+    0b000_011_101_101_011, // a
+    0b100_110_101_101_110, // b
+    0b000_011_100_100_011, // c
+    0b001_011_101_101_011, // d
+    0b000_010_111_100_011, // e
+    0b001_010_111_010_010, // f
+    0b000_011_101_011_110, // g
+    0b100_110_101_101_101, // h
+    0b010_000_010_010_010, // i
+    0b001_000_001_101_010, // j
+    0b100_101_110_110_101, // k
+    0b110_010_010_010_111, // l
+    0b000_111_111_101_101, // m
+    0b000_110_101_101_101, // n
+    0b000_010_101_101_010, // o
+    0b000_110_101_110_100, // p
+    0b000_011_101_011_001, // q
+    0b000_011_100_100_100, // r
+    0b000_011_010_001_110, // s
+    0b010_111_010_010_001, // t
+    0b000_101_101_101_011, // u
+    0b000_101_101_010_010, // v
+    0b000_101_101_111_111, // w
+    0b000_101_010_010_101, // x
+    0b000_101_011_001_110, // y
+    0b000_111_010_100_111, // z
+    0b011_010_110_010_011, // {
+    0b010_010_010_010_010, // |
+    0b110_010_011_010_110, // }
+    0b000_011_110_000_000, // ~
+];
+
+// Whether pixel (x, y) of c's glyph is lit. Anything outside printable ASCII draws as '?'.
+pub fn lit(c: char, x: u32, y: u32) -> bool {
+    let glyph = match c {
+        ' '..='~' => GLYPHS[c as usize - 0x20],
+        _ => GLYPHS[usize::from(b'?' - 0x20)],
+    };
+    x < WIDTH && y < HEIGHT && (glyph >> ((HEIGHT - 1 - y) * WIDTH + (WIDTH - 1 - x))) & 1 != 0
+}
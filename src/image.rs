@@ -0,0 +1,508 @@
+use crate::Cpu;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+// Data bytes in each record written.
+const RECORD_BYTES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Binary,
+    IntelHex,
+    SRecord,
+}
+
+impl Format {
+    // By extension. Anything not recognised is taken for a raw binary.
+    pub fn from_path(path: &str) -> Format {
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "hex" | "ihx" | "ihex" => Format::IntelHex,
+            "s19" | "s28" | "s37" | "srec" | "mot" => Format::SRecord,
+            _ => Format::Binary,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    // A line that isn't a record. Lines count from 1.
+    Record {
+        line: usize,
+        message: String,
+    },
+    Checksum {
+        line: usize,
+        expected: u8,
+        found: u8,
+    },
+    // Something tried to load the same address twice.
+    Overlap(u16),
+    // Data that would go past FFFF, at its first address beyond it.
+    OutOfRange(u32),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "{}", e),
+            ImageError::Record { line, message } => write!(f, "line {}: {}", line, message),
+            ImageError::Checksum {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {}: the checksum is {:02X} but should be {:02X}",
+                line, found, expected
+            ),
+            ImageError::Overlap(addr) => write!(f, "{:04X} is loaded more than once", addr),
+            ImageError::OutOfRange(addr) => write!(f, "data at {:X} is past FFFF", addr),
+        }
+    }
+}
+
+impl error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+
+// The bytes a program puts in memory, and where it starts if the file says.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Image {
+    bytes: BTreeMap<u16, u8>,
+    start: Option<u16>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_binary(addr: u16, data: &[u8]) -> Result<Image, ImageError> {
+        let mut image = Image::new();
+        image.add(u32::from(addr), data)?;
+        Ok(image)
+    }
+
+    pub fn from_memory(memory: &[u8], range: RangeInclusive<u16>) -> Image {
+        let bytes = range
+            .filter_map(|addr| Some((addr, *memory.get(usize::from(addr))?)))
+            .collect();
+        Image { bytes, start: None }
+    }
+
+    // Raw binaries go at addr. Intel HEX and S-record files say where they go themselves.
+    pub fn load(path: &str, addr: u16) -> Result<Image, ImageError> {
+        match Format::from_path(path) {
+            Format::Binary => Image::from_binary(addr, &fs::read(path)?),
+            Format::IntelHex => Image::parse_hex(&fs::read_to_string(path)?),
+            Format::SRecord => Image::parse_srecords(&fs::read_to_string(path)?),
+        }
+    }
+
+    // Everything is checked before anything is added, so a failed add changes nothing.
+    pub fn add(&mut self, addr: u32, data: &[u8]) -> Result<(), ImageError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        // Worked out in u64, since an extended address near 4G plus the length can pass u32.
+        let end = u64::from(addr) + data.len() as u64;
+        if end > 0x10000 {
+            return Err(ImageError::OutOfRange(addr.max(0x10000)));
+        }
+        if let Some((addr, _)) = self.bytes.range(addr as u16..).next() {
+            if u64::from(*addr) < end {
+                return Err(ImageError::Overlap(*addr));
+            }
+        }
+        for (i, byte) in data.iter().enumerate() {
+            self.bytes.insert((addr + i as u32) as u16, *byte);
+        }
+        Ok(())
+    }
+
+    // Another image's bytes, which mustn't overlap these. Its start wins if it has one.
+    pub fn merge(&mut self, other: &Image) -> Result<(), ImageError> {
+        if let Some(addr) = other
+            .bytes
+            .keys()
+            .find(|addr| self.bytes.contains_key(addr))
+        {
+            return Err(ImageError::Overlap(*addr));
+        }
+        self.bytes.extend(&other.bytes);
+        self.start = other.start.or(self.start);
+        Ok(())
+    }
+
+    pub fn start(&self) -> Option<u16> {
+        self.start
+    }
+
+    pub fn set_start(&mut self, start: Option<u16>) {
+        self.start = start;
+    }
+
+    pub fn bytes(&self) -> &BTreeMap<u16, u8> {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn extent(&self) -> Option<RangeInclusive<u16>> {
+        let first = *self.bytes.keys().next()?;
+        let last = *self.bytes.keys().next_back()?;
+        Some(first..=last)
+    }
+
+    // Runs of consecutive addresses.
+    pub fn segments(&self) -> Vec<(u16, Vec<u8>)> {
+        let mut segments: Vec<(u16, Vec<u8>)> = Vec::new();
+        for (addr, byte) in &self.bytes {
+            match segments.last_mut() {
+                Some((start, data))
+                    if u32::from(*start) + data.len() as u32 == u32::from(*addr) =>
+                {
+                    data.push(*byte)
+                }
+                _ => segments.push((*addr, vec![*byte])),
+            }
+        }
+        segments
+    }
+
+    // The bytes from the lowest address to the highest, with zeros in any gaps.
+    pub fn binary(&self) -> Vec<u8> {
+        match self.extent() {
+            Some(extent) => extent
+                .map(|addr| self.bytes.get(&addr).copied().unwrap_or(0))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn load_into(&self, cpu: &mut Cpu) {
+        for (addr, byte) in &self.bytes {
+            cpu.write_memory(*addr, *byte);
+        }
+    }
+
+    // Record types 00 to 05. A start address can come in a 03 or 05 record, or, as 8080
+    // assemblers wrote it, in the end of file record.
+    pub fn parse_hex(text: &str) -> Result<Image, ImageError> {
+        let mut image = Image::new();
+        let mut base = 0u32;
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = |message: &str| ImageError::Record {
+                line: line_number,
+                message: message.to_string(),
+            };
+            let hex = line
+                .strip_prefix(':')
+                .ok_or_else(|| record("Intel HEX records start with :"))?;
+            let bytes = decode(hex).ok_or_else(|| record("bad hex digits"))?;
+            if bytes.len() < 5 || bytes.len() != usize::from(bytes[0]) + 5 {
+                return Err(record("the record's length doesn't match its byte count"));
+            }
+            let (body, found) = bytes.split_at(bytes.len() - 1);
+            let expected = body
+                .iter()
+                .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+                .wrapping_neg();
+            if expected != found[0] {
+                return Err(ImageError::Checksum {
+                    line: line_number,
+                    expected,
+                    found: found[0],
+                });
+            }
+            let addr = u16::from_be_bytes([body[1], body[2]]);
+            let data = &body[4..];
+            let value = data
+                .iter()
+                .fold(0u32, |value, byte| value << 8 | u32::from(*byte));
+            match (body[3], data.len()) {
+                (0x00, _) => image.add(base + u32::from(addr), data)?,
+                (0x01, _) => {
+                    if addr != 0 {
+                        image.start = Some(addr);
+                    }
+                    break;
+                }
+                (0x02, 2) => base = value << 4,
+                (0x03, 4) => image.start = Some(start((value >> 16 << 4) + (value & 0xFFFF))?),
+                (0x04, 2) => base = value << 16,
+                (0x05, 4) => image.start = Some(start(value)?),
+                (0x02..=0x05, _) => return Err(record("wrong length for the record type")),
+                (kind, _) => return Err(record(&format!("unknown record type {:02X}", kind))),
+            }
+        }
+        Ok(image)
+    }
+
+    // S0 headers are skipped. S5 and S6 counts are checked against the data records read.
+    pub fn parse_srecords(text: &str) -> Result<Image, ImageError> {
+        let mut image = Image::new();
+        let mut records = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = |message: &str| ImageError::Record {
+                line: line_number,
+                message: message.to_string(),
+            };
+            let mut rest = line
+                .strip_prefix(['S', 's'])
+                .ok_or_else(|| record("S-records start with S"))?
+                .chars();
+            let kind = rest
+                .next()
+                .ok_or_else(|| record("S-records start with S"))?;
+            let bytes = decode(rest.as_str()).ok_or_else(|| record("bad hex digits"))?;
+            if bytes.len() < 2 || bytes.len() != usize::from(bytes[0]) + 1 {
+                return Err(record("the record's length doesn't match its byte count"));
+            }
+            let (body, found) = bytes.split_at(bytes.len() - 1);
+            let expected = !body.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if expected != found[0] {
+                return Err(ImageError::Checksum {
+                    line: line_number,
+                    expected,
+                    found: found[0],
+                });
+            }
+            let address_length = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(record(&format!("unknown record type S{}", kind))),
+            };
+            if body.len() < 1 + address_length {
+                return Err(record("the record is too short for its address"));
+            }
+            let addr = body[1..=address_length]
+                .iter()
+                .fold(0u32, |value, byte| value << 8 | u32::from(*byte));
+            let data = &body[1 + address_length..];
+            match kind {
+                '1' | '2' | '3' => {
+                    image.add(addr, data)?;
+                    records += 1;
+                }
+                '5' | '6' if addr != records => {
+                    return Err(record(&format!(
+                        "the file says it has {} data records, but there are {}",
+                        addr, records
+                    )))
+                }
+                '7' | '8' | '9' => {
+                    image.start = Some(start(addr)?);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        Ok(image)
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W, format: Format) -> io::Result<()> {
+        match format {
+            Format::Binary => out.write_all(&self.binary()),
+            Format::IntelHex => self.write_hex(out),
+            Format::SRecord => self.write_srecords(out),
+        }
+    }
+
+    // In the format the extension asks for.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        self.write(&mut out, Format::from_path(path))?;
+        out.flush()
+    }
+
+    // Only 16-bit addresses are needed, so there are only data records, and the start address
+    // goes in the end of file record where 8080 loaders look for it.
+    pub fn write_hex<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (addr, data) in self.records() {
+            write_hex_record(out, addr, 0x00, &data)?;
+        }
+        write_hex_record(out, self.start.unwrap_or(0), 0x01, &[])
+    }
+
+    pub fn write_srecords<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_srecord(out, '0', 0, &[])?;
+        let records = self.records();
+        for (addr, data) in &records {
+            write_srecord(out, '1', *addr, data)?;
+        }
+        write_srecord(out, '5', records.len() as u16, &[])?;
+        write_srecord(out, '9', self.start.unwrap_or(0), &[])
+    }
+
+    fn records(&self) -> Vec<(u16, Vec<u8>)> {
+        let mut records = Vec::new();
+        for (addr, data) in self.segments() {
+            for (i, chunk) in data.chunks(RECORD_BYTES).enumerate() {
+                records.push((addr + (i * RECORD_BYTES) as u16, chunk.to_vec()));
+            }
+        }
+        records
+    }
+}
+
+fn start(addr: u32) -> Result<u16, ImageError> {
+    u16::try_from(addr).map_err(|_| ImageError::OutOfRange(addr))
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn write_hex_record<W: Write>(out: &mut W, addr: u16, kind: u8, data: &[u8]) -> io::Result<()> {
+    let mut record = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(sum.wrapping_neg());
+    writeln!(out, ":{}", encode(&record))
+}
+
+fn write_srecord<W: Write>(out: &mut W, kind: char, addr: u16, data: &[u8]) -> io::Result<()> {
+    let mut record = vec![data.len() as u8 + 3, (addr >> 8) as u8, addr as u8];
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(!sum);
+    writeln!(out, "S{}{}", kind, encode(&record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intel_hex() {
+        let image = Image::parse_hex(
+            ":040100003100242185\n\
+             :020000020100FB\n\
+             :02000000C9C96C\n\
+             :00010001FE\n",
+        )
+        .unwrap();
+        assert_eq!(
+            image.segments(),
+            vec![
+                (0x0100, vec![0x31, 0x00, 0x24, 0x21]),
+                (0x1000, vec![0xC9, 0xC9])
+            ]
+        );
+        let image = Image::parse_hex(":040100003100242185\n:00010001FE\n").unwrap();
+        assert_eq!(image.start(), Some(0x0100));
+
+        let mut hex = Vec::new();
+        image.write_hex(&mut hex).unwrap();
+        assert_eq!(
+            String::from_utf8(hex).unwrap(),
+            ":040100003100242185\n:00010001FE\n"
+        );
+    }
+
+    #[test]
+    fn test_hex_errors() {
+        let error = Image::parse_hex(":040100003100242186\n").unwrap_err();
+        assert!(matches!(
+            error,
+            ImageError::Checksum {
+                line: 1,
+                expected: 0x85,
+                found: 0x86
+            }
+        ));
+        let error = Image::parse_hex("\n:04010000310024\n").unwrap_err();
+        assert!(matches!(error, ImageError::Record { line: 2, .. }));
+        let error = Image::parse_hex(":040100003100242185\n:02010200AABB96\n").unwrap_err();
+        assert!(matches!(error, ImageError::Overlap(0x0102)));
+        let error = Image::parse_hex(":02FFFF00AABB9B\n").unwrap_err();
+        assert!(matches!(error, ImageError::OutOfRange(0x10000)));
+        let error = Image::parse_hex(":02000004FFFFFC\n:02FFFF00AABB9B\n").unwrap_err();
+        assert!(matches!(error, ImageError::OutOfRange(0xFFFFFFFF)));
+    }
+
+    #[test]
+    fn test_srecords() {
+        let mut image = Image::from_binary(0x1000, &[1, 2, 3]).unwrap();
+        image.add(0x2000, &[4]).unwrap();
+        image.set_start(Some(0x1000));
+        let mut text = Vec::new();
+        image.write_srecords(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(
+            text,
+            "S0030000FC\nS1061000010203E3\nS104200004D7\nS5030002FA\nS9031000EC\n"
+        );
+        assert_eq!(Image::parse_srecords(&text).unwrap(), image);
+
+        let error = Image::parse_srecords("S104200004D7\nS5030002FA\n").unwrap_err();
+        assert!(matches!(error, ImageError::Record { line: 2, .. }));
+        let error = Image::parse_srecords("S20510000001E9\n").unwrap_err();
+        assert!(matches!(error, ImageError::OutOfRange(0x100000)));
+        let error = Image::parse_srecords("S307FFFFFFFFAABB97\n").unwrap_err();
+        assert!(matches!(error, ImageError::OutOfRange(0xFFFFFFFF)));
+        for line in ["S\u{e9}00\n", "S1\u{e9}\n", "S\n"].iter() {
+            let error = Image::parse_srecords(line).unwrap_err();
+            assert!(matches!(error, ImageError::Record { line: 1, .. }));
+        }
+    }
+
+    #[test]
+    fn test_binary() {
+        assert!(matches!(
+            Image::from_binary(0xFFFE, &[1, 2, 3]),
+            Err(ImageError::OutOfRange(0x10000))
+        ));
+        let mut image = Image::from_binary(0xFFFE, &[1, 2]).unwrap();
+        image.add(0x0000, &[3]).unwrap();
+        assert_eq!(image.binary().len(), 0x10000);
+        assert_eq!(image.segments().len(), 2);
+        assert!(matches!(
+            image.merge(&Image::from_binary(0xFFFF, &[9]).unwrap()),
+            Err(ImageError::Overlap(0xFFFF))
+        ));
+    }
+}
@@ -1,8 +1,28 @@
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+pub mod asm;
+pub mod coverage;
 mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
 mod event_signal;
+pub mod expression;
+pub mod gdb;
+pub mod hash;
+pub mod heatmap;
+pub mod hexview;
+pub mod image;
+pub mod listing;
+pub mod machine;
+pub mod patch;
+pub mod profile;
+pub mod romset;
+pub mod search;
+pub mod strict;
+pub mod symbols;
+pub mod trace;
 
 pub use cpu::*;
 pub use event_signal::*;
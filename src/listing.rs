@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+
+// An assembler listing, mapping source lines to the addresses of the code they produced. Lines
+// that made code start with the address and bytes, optionally after a line number:
+//   0100 3E07      MVI A,7
+//     12  0100  3E 07   MVI A,7
+pub struct Listing {
+    addresses: Vec<Option<u16>>,
+    labels: Vec<(u16, String)>,
+}
+
+impl Listing {
+    pub fn parse(text: &str) -> Self {
+        let addresses: Vec<Option<u16>> = text.lines().map(line_address).collect();
+        // A label on a line of its own belongs to the next line with code.
+        let mut labels = Vec::new();
+        let mut pending = Vec::new();
+        for (line, addr) in text.lines().zip(&addresses) {
+            pending.extend(line_label(line));
+            if let Some(addr) = addr {
+                labels.extend(pending.drain(..).map(|label| (*addr, label)));
+            }
+        }
+        Listing { addresses, labels }
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Listing::parse(&fs::read_to_string(path)?))
+    }
+
+    // Lines count from 1. A line without code maps to the next one that has some, so a
+    // breakpoint on a label or comment lands on the instruction after it.
+    pub fn address_of_line(&self, line: usize) -> Option<(usize, u16)> {
+        let start = line.checked_sub(1)?;
+        self.addresses
+            .iter()
+            .enumerate()
+            .skip(start)
+            .find_map(|(i, addr)| addr.map(|addr| (i + 1, addr)))
+    }
+
+    // Labels defined with a colon, in the order they appear.
+    pub fn labels(&self) -> &[(u16, String)] {
+        &self.labels
+    }
+
+    pub fn line_of_address(&self, addr: u16) -> Option<usize> {
+        self.addresses
+            .iter()
+            .position(|a| *a == Some(addr))
+            .map(|i| i + 1)
+    }
+}
+
+fn line_address(line: &str) -> Option<u16> {
+    let tokens: Vec<&str> = line.split_whitespace().take(3).collect();
+    let numbered = tokens.len() == 3
+        && tokens[0]
+            .trim_end_matches(&['+', ':'][..])
+            .chars()
+            .all(|c| c.is_ascii_digit())
+        && is_address(tokens[1])
+        && is_bytes(tokens[2]);
+    if numbered {
+        return u16::from_str_radix(tokens[1].trim_end_matches(':'), 16).ok();
+    }
+    if tokens.len() >= 2 && is_address(tokens[0]) && is_bytes(tokens[1]) {
+        return u16::from_str_radix(tokens[0].trim_end_matches(':'), 16).ok();
+    }
+    None
+}
+
+// The source text starts after the line number, address and bytes.
+fn line_label(line: &str) -> Option<String> {
+    let mut tokens = line.split_whitespace().peekable();
+    if tokens.peek()?.chars().all(|c| c.is_ascii_digit()) {
+        tokens.next();
+    }
+    if is_address(tokens.peek()?) {
+        tokens.next();
+    }
+    while is_bytes(tokens.peek()?) {
+        tokens.next();
+    }
+    let label = tokens.next()?.strip_suffix(':')?;
+    let identifier = label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if identifier {
+        Some(label.to_string())
+    } else {
+        None
+    }
+}
+
+fn is_address(token: &str) -> bool {
+    let token = token.trim_end_matches(':');
+    token.len() == 4 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_bytes(token: &str) -> bool {
+    token.len() & 1 == 0
+        && (2..=8).contains(&token.len())
+        && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addresses() {
+        let listing = Listing::parse(
+            "; hello\n\
+             0100 3E07      MVI A,7\n\
+             start:\n\
+             0102: C3 00 01 JMP 0100H\n\
+             \x20 12  0105  76  HLT\n",
+        );
+
+        assert_eq!(listing.address_of_line(1), Some((2, 0x0100)));
+        assert_eq!(listing.address_of_line(3), Some((4, 0x0102)));
+        assert_eq!(listing.address_of_line(5), Some((5, 0x0105)));
+        assert_eq!(listing.address_of_line(6), None);
+        assert_eq!(listing.line_of_address(0x0102), Some(4));
+        assert_eq!(listing.labels(), &[(0x0102, String::from("start"))]);
+    }
+}
@@ -0,0 +1,124 @@
+pub mod cpm;
+pub mod invaders;
+
+use crate::Cpu;
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+// The hardware wrapped around the cpu. IN and OUT are routed here instead of being executed by
+// the cpu itself.
+pub trait Machine {
+    fn input(&mut self, port: u8) -> u8;
+    fn output(&mut self, port: u8, value: u8);
+
+    // Hardware that raises interrupts returns the RST number once cycles passes the point
+    // where it fires.
+    fn pending_interrupt(&mut self, _cycles: u64) -> Option<u8> {
+        None
+    }
+
+    // Called before each instruction so firmware can be emulated in Rust. Returning true means
+    // the machine has dealt with the instruction itself and the cpu shouldn't run it.
+    fn trap(&mut self, _cpu: &mut Cpu) -> bool {
+        false
+    }
+
+    // What's where in the address space, for strict mode's checks.
+    fn memory_map(&self) -> MemoryMap {
+        MemoryMap::new()
+    }
+}
+
+// Lets a frontend keep its own handle on the machine, to press buttons say, while a debugger
+// drives it.
+impl<M: Machine> Machine for Rc<RefCell<M>> {
+    fn input(&mut self, port: u8) -> u8 {
+        self.borrow_mut().input(port)
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        self.borrow_mut().output(port, value)
+    }
+
+    fn pending_interrupt(&mut self, cycles: u64) -> Option<u8> {
+        self.borrow_mut().pending_interrupt(cycles)
+    }
+
+    fn trap(&mut self, cpu: &mut Cpu) -> bool {
+        self.borrow_mut().trap(cpu)
+    }
+
+    fn memory_map(&self) -> MemoryMap {
+        self.borrow().memory_map()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Region {
+    Rom,
+    Ram,
+    Video,
+    // Nothing answers here, or it mirrors somewhere else and a program shouldn't rely on it.
+    Unmapped,
+}
+
+// Regions of the address space. Anything not given a region is RAM.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    regions: Vec<(RangeInclusive<u16>, Region)>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap::default()
+    }
+
+    // Later regions win where they overlap earlier ones.
+    pub fn add(&mut self, range: RangeInclusive<u16>, region: Region) {
+        self.regions.push((range, region));
+    }
+
+    pub fn region(&self, addr: u16) -> Region {
+        self.regions
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&addr))
+            .map_or(Region::Ram, |(_, region)| *region)
+    }
+}
+
+// Nothing attached to the ports. Reads float to zero and writes go nowhere.
+#[derive(Clone, Default)]
+pub struct Bare;
+
+impl Machine for Bare {
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+}
+
+// Runs a single instruction, handing any port access to the machine. Returns the cycles taken.
+pub fn step(cpu: &mut Cpu, machine: &mut dyn Machine) -> u8 {
+    if machine.trap(cpu) {
+        return 0;
+    }
+    let op = cpu.get_current_opcode();
+    match op.code {
+        0xD3 => {
+            let (port, register) = cpu.output();
+            machine.output(port, register.into());
+            cpu.increment_pc(2);
+            op.cycles
+        }
+        0xDB => {
+            let input = machine.input(cpu.get_next_byte());
+            cpu.input(input);
+            cpu.increment_pc(2);
+            op.cycles
+        }
+        _ => cpu.execute_opcode(),
+    }
+}
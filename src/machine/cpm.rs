@@ -0,0 +1,117 @@
+use super::Machine;
+use crate::{Cpu, RegisterName};
+
+// Just enough of CP/M to run .COM files: programs load at 0100, call the BDOS through 0005 and
+// exit by jumping to 0000, where a HLT stops the debugger.
+pub const TPA: u16 = 0x0100;
+pub const BDOS: u16 = 0xFE00;
+
+#[derive(Clone, Default)]
+pub struct Cpm {
+    output: Vec<u8>,
+}
+
+impl Cpm {
+    pub fn new() -> Self {
+        Cpm::default()
+    }
+
+    // Sets up page zero and the stack around a program already loaded at TPA.
+    pub fn install(cpu: &mut Cpu) {
+        cpu.write_memory(0x0000, 0x76);
+        // JMP BDOS. Programs read the top of memory from 0006.
+        cpu.write_memory(0x0005, 0xC3);
+        cpu.write_memory(0x0006, BDOS as u8);
+        cpu.write_memory(0x0007, (BDOS >> 8) as u8);
+        // RET, which runs once the trap has done the work.
+        cpu.write_memory(BDOS, 0xC9);
+        // A RET from the program goes to the warm boot at 0000.
+        cpu.write_memory(BDOS - 2, 0x00);
+        cpu.write_memory(BDOS - 1, 0x00);
+        cpu.write_register(RegisterName::SP, BDOS - 2);
+        cpu.set_pc(TPA);
+    }
+
+    // Console output written since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Machine for Cpm {
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    fn trap(&mut self, cpu: &mut Cpu) -> bool {
+        if cpu.get_pc() != BDOS {
+            return false;
+        }
+        match cpu.read_register(RegisterName::C) {
+            0 => {
+                cpu.set_pc(0x0000);
+                return true;
+            }
+            2 => self.output.push(cpu.read_register(RegisterName::E) as u8),
+            9 => {
+                // At most a lap of memory, in case the string has no terminator.
+                let start = cpu.read_register(RegisterName::DE);
+                let mut terminated = false;
+                for offset in 0..=0xFFFF {
+                    let byte = cpu.read_memory(start.wrapping_add(offset));
+                    if byte == b'$' {
+                        terminated = true;
+                        break;
+                    }
+                    self.output.push(byte);
+                }
+                if !terminated {
+                    warn!("BDOS print string at {:04X} has no '$'", start);
+                }
+            }
+            function => warn!("Unsupported BDOS function {}", function),
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine;
+
+    #[test]
+    fn test_print_string() {
+        // LXI D,0109 / MVI C,9 / CALL 0005 / RET / "Hi$"
+        let program = [
+            0x11, 0x09, 0x01, 0x0E, 0x09, 0xCD, 0x05, 0x00, 0xC9, b'H', b'i', b'$',
+        ];
+        let mut cpu = Cpu::new();
+        for (i, byte) in program.iter().enumerate() {
+            cpu.write_memory(TPA + i as u16, *byte);
+        }
+        Cpm::install(&mut cpu);
+        let mut cpm = Cpm::new();
+        while cpu.read_memory(cpu.get_pc()) != 0x76 {
+            machine::step(&mut cpu, &mut cpm);
+        }
+
+        assert_eq!(cpu.get_pc(), 0x0000);
+        assert_eq!(cpm.take_output(), b"Hi");
+    }
+
+    #[test]
+    fn test_print_string_without_terminator() {
+        // Memory starts out zeroed, so there's no '$' anywhere.
+        let mut cpu = Cpu::new();
+        cpu.write_register(RegisterName::C, 9);
+        cpu.write_register(RegisterName::DE, TPA);
+        cpu.set_pc(BDOS);
+        let mut cpm = Cpm::new();
+
+        assert!(!cpm.trap(&mut cpu));
+        assert_eq!(cpm.take_output().len(), 0x10000);
+    }
+}
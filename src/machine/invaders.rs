@@ -0,0 +1,261 @@
+use super::{Machine, MemoryMap, Region};
+use crate::romset::{parse_manifest, RomSet};
+use std::ops::RangeInclusive;
+
+// The CPU runs at 2MHz and the screen refreshes at 60Hz. The hardware interrupts twice a
+// frame: RST 1 when the beam reaches the middle of the screen and RST 2 at vblank.
+const CYCLES_PER_HALF_FRAME: u64 = 2_000_000 / 60 / 2;
+
+// The four ROM halves as MAME knows them, and where they load. See romset::parse_manifest.
+pub const MANIFEST: &str = "\
+[invaders]
+invaders.h 800 0000 734f5ad8 ff6200af4c9110d8181249cbcef1a8a40fa40b7f
+invaders.g 800 0800 6bfaca4a 16f48649b531bdef8c2d1446c429b5f414524350
+invaders.f 800 1000 0ccead96 537aef03468f63c5b9e11dd61e253f7ae17d9743
+invaders.e 800 1800 14e538b0 1d6ca0c99f9df71e2990b610deb9d7da0125e2d8
+";
+
+pub fn rom_set() -> RomSet {
+    parse_manifest(MANIFEST)
+        .expect("The Invaders manifest is broken.")
+        .remove(0)
+}
+
+// What the ports are wired to. Reads and writes of the same port go to different things.
+pub const INPUT_PORTS: [(u8, &str); 4] = [
+    (0, "inputs, unused by the game"),
+    (1, "coin, start buttons and player 1"),
+    (2, "player 2 and DIP switches"),
+    (3, "shift register result"),
+];
+pub const OUTPUT_PORTS: [(u8, &str); 5] = [
+    (2, "shift amount"),
+    (3, "sounds"),
+    (4, "shift data"),
+    (5, "more sounds"),
+    (6, "watchdog"),
+];
+
+// Code the ROM only reaches through addresses in RAM, so tracing from the reset and interrupt
+// vectors misses it. The game loop at 024B runs each object in the table copied to 2010 from
+// 1B10: the player, the player's shot and the three alien shots. It calls them with PCHL, with
+// 026F pushed as where to come back to.
+pub const OBJECT_HANDLERS: [u16; 6] = [0x026F, 0x028E, 0x03BB, 0x0476, 0x04B6, 0x0682];
+
+// Nothing should write here. The hardware ignores it, but this emulator doesn't protect it.
+pub const ROM_AREA: RangeInclusive<u16> = 0x0000..=0x1FFF;
+// Work RAM, where the game keeps its variables. Video RAM follows it at 2400.
+pub const RAM: RangeInclusive<u16> = 0x2000..=0x23FF;
+pub const VIDEO_RAM: RangeInclusive<u16> = 0x2400..=0x3FFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Coin,
+    P1Start,
+    P2Start,
+    P1Left,
+    P1Right,
+    P1Fire,
+    P2Left,
+    P2Right,
+    P2Fire,
+}
+
+#[derive(Clone)]
+pub struct Invaders {
+    p0: u8,
+    p1: u8,
+    p2: u8,
+    shift: u16,
+    offset: u8,
+    next_interrupt: u64,
+    next_vector: u8,
+}
+
+impl Invaders {
+    pub fn new() -> Self {
+        Invaders {
+            p0: 0,
+            p1: 1,
+            p2: 0,
+            shift: 0,
+            offset: 0,
+            next_interrupt: CYCLES_PER_HALF_FRAME,
+            next_vector: 1,
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        let (port, mask) = self.button_bit(button);
+        *port |= mask;
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let (port, mask) = self.button_bit(button);
+        *port &= !mask;
+    }
+
+    fn button_bit(&mut self, button: Button) -> (&mut u8, u8) {
+        match button {
+            Button::Coin => (&mut self.p1, 0x01),
+            Button::P2Start => (&mut self.p1, 0x02),
+            Button::P1Start => (&mut self.p1, 0x04),
+            Button::P1Fire => (&mut self.p1, 0x10),
+            Button::P1Left => (&mut self.p1, 0x20),
+            Button::P1Right => (&mut self.p1, 0x40),
+            Button::P2Fire => (&mut self.p2, 0x10),
+            Button::P2Left => (&mut self.p2, 0x20),
+            Button::P2Right => (&mut self.p2, 0x40),
+        }
+    }
+}
+
+impl Default for Invaders {
+    fn default() -> Self {
+        Invaders::new()
+    }
+}
+
+impl Machine for Invaders {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            0 => self.p0,
+            1 => self.p1,
+            2 => self.p2,
+            3 => ((self.shift >> (8 - self.offset)) & 0xFF) as u8,
+            _ => {
+                warn!("IN from port {}, which the cabinet doesn't have", port);
+                0
+            }
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            2 => self.offset = value & 0x7,
+            3 => {
+                // sound stuff
+            }
+            4 => self.shift = (self.shift >> 8) | ((value as u16) << 8),
+            _ => {
+                // do nothing
+            }
+        }
+    }
+
+    fn pending_interrupt(&mut self, cycles: u64) -> Option<u8> {
+        if cycles < self.next_interrupt {
+            return None;
+        }
+        let vector = self.next_vector;
+        self.next_interrupt += CYCLES_PER_HALF_FRAME;
+        self.next_vector = if vector == 1 { 2 } else { 1 };
+        Some(vector)
+    }
+
+    fn memory_map(&self) -> MemoryMap {
+        let mut map = MemoryMap::new();
+        map.add(0x0000..=0xFFFF, Region::Unmapped);
+        map.add(ROM_AREA, Region::Rom);
+        map.add(RAM, Region::Ram);
+        map.add(VIDEO_RAM, Region::Video);
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::analysis::Analysis;
+
+    #[test]
+    fn test_shift_register() {
+        let mut cabinet = Invaders::new();
+        cabinet.output(4, 0xAB);
+        cabinet.output(4, 0xCD);
+        cabinet.output(2, 4);
+
+        assert_eq!(cabinet.input(3), 0xDA);
+    }
+
+    #[test]
+    fn test_unknown_port() {
+        let mut cabinet = Invaders::new();
+        assert_eq!(cabinet.input(7), 0);
+    }
+
+    #[test]
+    fn test_rom_set() {
+        let set = rom_set();
+        let loads: Vec<(&str, u16)> = set.roms.iter().map(|rom| (&*rom.name, rom.addr)).collect();
+        assert_eq!(
+            loads,
+            [
+                ("invaders.h", 0x0000),
+                ("invaders.g", 0x0800),
+                ("invaders.f", 0x1000),
+                ("invaders.e", 0x1800),
+            ]
+        );
+        let source = crate::romset::Source::open("src/roms", &set.name).unwrap();
+        assert!(set
+            .verify(&source)
+            .unwrap()
+            .iter()
+            .all(|status| *status == crate::romset::Status::Good));
+    }
+
+    #[test]
+    fn test_object_handlers() {
+        let set = rom_set();
+        let source = crate::romset::Source::open("src/roms", &set.name).unwrap();
+        let memory = set.load(&source).unwrap().binary();
+        // Each object's handler address sits 3 bytes into its 16-byte slot.
+        for (i, handler) in OBJECT_HANDLERS[1..].iter().enumerate() {
+            let slot = 0x1B10 + 16 * i;
+            assert_eq!(
+                u16::from_le_bytes([memory[slot + 3], memory[slot + 4]]),
+                *handler
+            );
+        }
+        assert_eq!(memory[0x1B10 + 16 * 5], 0xFF);
+
+        let mut analysis = Analysis::new(&memory, ROM_AREA);
+        analysis.add_entry(0x0000);
+        analysis.add_rst_vectors();
+        analysis.run();
+        assert!(!analysis.is_code(0x1400));
+        for handler in OBJECT_HANDLERS.iter() {
+            analysis.add_entry(*handler);
+        }
+        analysis.run();
+        // The sprite drawing the handlers jump to, which drives the shift register.
+        assert!(analysis.is_code(0x1400) && analysis.is_code(0x1408));
+    }
+
+    #[test]
+    fn test_interrupts_alternate() {
+        let mut cabinet = Invaders::new();
+
+        assert_eq!(cabinet.pending_interrupt(100), None);
+        assert_eq!(cabinet.pending_interrupt(CYCLES_PER_HALF_FRAME), Some(1));
+        assert_eq!(cabinet.pending_interrupt(CYCLES_PER_HALF_FRAME + 1), None);
+        assert_eq!(
+            cabinet.pending_interrupt(CYCLES_PER_HALF_FRAME * 2),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_buttons() {
+        let mut cabinet = Invaders::new();
+        cabinet.press(Button::P1Fire);
+        cabinet.press(Button::P2Left);
+
+        assert_eq!(cabinet.input(1), 0x11);
+        assert_eq!(cabinet.input(2), 0x20);
+        cabinet.release(Button::P1Fire);
+
+        assert_eq!(cabinet.input(1), 0x01);
+    }
+}
@@ -0,0 +1,468 @@
+use crate::hash::crc32;
+use std::error;
+use std::fmt;
+use std::io;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_END: &[u8] = b"EOF";
+// An IPS record can't start here, as its offset would read as the end marker.
+const IPS_END_OFFSET: usize = 0x45_4F46;
+const IPS_MAX_OFFSET: usize = 0xFF_FFFF;
+const IPS_MAX_RECORD: usize = 0xFFFF;
+// Unchanged bytes cheaper to repeat than to start a new record for: an offset and a size.
+const IPS_RECORD_COST: usize = 5;
+const BPS_MAGIC: &[u8] = b"BPS1";
+// Source, target and patch CRC-32s.
+const BPS_FOOTER: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Ips,
+    Bps,
+}
+
+impl Format {
+    // By extension, as Image does it. Anything that isn't .bps is taken for IPS.
+    pub fn from_path(path: &str) -> Format {
+        if path.to_lowercase().ends_with(".bps") {
+            Format::Bps
+        } else {
+            Format::Ips
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(io::Error),
+    // Not a patch, or a damaged one.
+    Invalid(String),
+    // BPS says what it applies to and what it makes. These are what was found instead.
+    SourceSize { expected: usize, found: usize },
+    SourceChecksum { expected: u32, found: u32 },
+    TargetChecksum { expected: u32, found: u32 },
+    PatchChecksum { expected: u32, found: u32 },
+    // The images are too big for IPS, which only has 24-bit offsets.
+    TooLarge(usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Io(e) => write!(f, "{}", e),
+            PatchError::Invalid(message) => write!(f, "{}", message),
+            PatchError::SourceSize { expected, found } => write!(
+                f,
+                "the patch is for a {} byte file, not {} bytes",
+                expected, found
+            ),
+            PatchError::SourceChecksum { expected, found } => write!(
+                f,
+                "the patch is for a file with crc32 {:08x}, not {:08x}",
+                expected, found
+            ),
+            PatchError::TargetChecksum { expected, found } => write!(
+                f,
+                "the patched file's crc32 is {:08x} but should be {:08x}",
+                found, expected
+            ),
+            PatchError::PatchChecksum { expected, found } => write!(
+                f,
+                "the patch's crc32 is {:08x} but should be {:08x}, so it's damaged",
+                found, expected
+            ),
+            PatchError::TooLarge(size) => write!(f, "{} bytes is too large for IPS", size),
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+impl From<io::Error> for PatchError {
+    fn from(e: io::Error) -> Self {
+        PatchError::Io(e)
+    }
+}
+
+fn invalid<T>(message: &str) -> Result<T, PatchError> {
+    Err(PatchError::Invalid(message.to_string()))
+}
+
+// The patched copy of source, whichever kind of patch it is.
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(BPS_MAGIC) {
+        apply_bps(patch, source)
+    } else if patch.starts_with(IPS_MAGIC) {
+        apply_ips(patch, source)
+    } else {
+        invalid("it isn't an IPS or BPS patch")
+    }
+}
+
+pub fn create(format: Format, source: &[u8], target: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match format {
+        Format::Ips => create_ips(source, target),
+        Format::Bps => Ok(create_bps(source, target)),
+    }
+}
+
+// IPS: records of an offset and the bytes to put there, or a byte to repeat. The file grows
+// to fit, and the common extension of a size after the end marker shrinks it.
+pub fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return invalid("it isn't an IPS patch");
+    }
+    let mut target = source.to_vec();
+    let mut at = IPS_MAGIC.len();
+    let truncated = || PatchError::Invalid("the patch is truncated".to_string());
+    let field = |at: usize, length: usize| -> Result<usize, PatchError> {
+        let bytes = patch.get(at..at + length).ok_or_else(truncated)?;
+        Ok(big_endian(bytes))
+    };
+    loop {
+        if patch.get(at..at + 3) == Some(IPS_END) {
+            at += 3;
+            break;
+        }
+        let offset = field(at, 3)?;
+        let (data, length) = match field(at + 3, 2)? {
+            0 => {
+                let count = field(at + 5, 2)?;
+                let byte = *patch.get(at + 7).ok_or_else(truncated)?;
+                at += 8;
+                (vec![byte; count], count)
+            }
+            length => {
+                let data = patch.get(at + 5..at + 5 + length).ok_or_else(truncated)?;
+                at += 5 + length;
+                (data.to_vec(), length)
+            }
+        };
+        if target.len() < offset + length {
+            target.resize(offset + length, 0);
+        }
+        target[offset..offset + length].copy_from_slice(&data);
+    }
+    match patch.len() - at {
+        0 => {}
+        3 => target.truncate(big_endian(&patch[at..])),
+        _ => return invalid("there's something after the end of the patch"),
+    }
+    Ok(target)
+}
+
+fn big_endian(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |value, byte| (value << 8) | usize::from(*byte))
+}
+
+pub fn create_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let size = source.len().max(target.len());
+    if size > IPS_MAX_OFFSET {
+        return Err(PatchError::TooLarge(size));
+    }
+    let differs = |i: usize| source.get(i) != Some(&target[i]);
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut i = 0;
+    while i < target.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        // Back one byte rather than start where the end marker would be read.
+        let start = if i == IPS_END_OFFSET { i - 1 } else { i };
+        let mut end = i + 1;
+        // Runs on through short gaps of unchanged bytes, as they cost less than a new record.
+        while end < target.len() && end - start < IPS_MAX_RECORD {
+            if differs(end) {
+                end += 1;
+                continue;
+            }
+            let gap = (end..target.len().min(end + IPS_RECORD_COST + 1))
+                .take_while(|j| !differs(*j))
+                .count();
+            if gap > IPS_RECORD_COST || end + gap >= target.len() {
+                break;
+            }
+            end = (end + gap).min(start + IPS_MAX_RECORD);
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        i = end;
+    }
+    patch.extend_from_slice(IPS_END);
+    if target.len() < source.len() {
+        patch.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+// BPS: the sizes of source and target, then actions that build the target from runs of
+// either, or of the patch itself. CRC-32s of all three come last.
+pub fn apply_bps(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(BPS_MAGIC) || patch.len() < BPS_MAGIC.len() + BPS_FOOTER {
+        return invalid("it isn't a BPS patch");
+    }
+    let footer = patch.len() - BPS_FOOTER;
+    let checksum =
+        |at: usize| u32::from_le_bytes([patch[at], patch[at + 1], patch[at + 2], patch[at + 3]]);
+    let expected = checksum(footer + 8);
+    let found = crc32(&patch[..footer + 8]);
+    if found != expected {
+        return Err(PatchError::PatchChecksum { expected, found });
+    }
+    let expected = checksum(footer);
+    let found = crc32(source);
+    let mut reader = Reader {
+        data: &patch[..footer],
+        at: BPS_MAGIC.len(),
+    };
+    let source_size = reader.number()?;
+    if source_size != source.len() {
+        return Err(PatchError::SourceSize {
+            expected: source_size,
+            found: source.len(),
+        });
+    }
+    if found != expected {
+        return Err(PatchError::SourceChecksum { expected, found });
+    }
+    let target_size = reader.number()?;
+    let metadata = reader.number()?;
+    reader.take(metadata)?;
+
+    let mut target = Vec::new();
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while reader.at < reader.data.len() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if target.len() + length > target_size {
+            return invalid("the patch writes past the end of the target");
+        }
+        match action & 3 {
+            0 => {
+                let at = target.len();
+                let data = source
+                    .get(at..at + length)
+                    .ok_or_else(|| PatchError::Invalid("a read is past the source".into()))?;
+                target.extend_from_slice(data);
+            }
+            1 => target.extend_from_slice(reader.take(length)?),
+            2 => {
+                source_offset = reader.offset(source_offset)?;
+                let data = source
+                    .get(source_offset..source_offset + length)
+                    .ok_or_else(|| PatchError::Invalid("a copy is past the source".into()))?;
+                target.extend_from_slice(data);
+                source_offset += length;
+            }
+            _ => {
+                target_offset = reader.offset(target_offset)?;
+                if target_offset >= target.len() {
+                    return invalid("a copy is past what's been written");
+                }
+                // Byte by byte, as a copy can run into what it writes.
+                for _ in 0..length {
+                    target.push(target[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return invalid("the patch stops short of the end of the target");
+    }
+    let expected = checksum(footer + 4);
+    let found = crc32(&target);
+    if found != expected {
+        return Err(PatchError::TargetChecksum { expected, found });
+    }
+    Ok(target)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], PatchError> {
+        let data = self
+            .data
+            .get(self.at..self.at + length)
+            .ok_or_else(|| PatchError::Invalid("the patch is truncated".to_string()))?;
+        self.at += length;
+        Ok(data)
+    }
+
+    // Seven bits a byte, the top bit marking the last. Each byte but the last also adds one
+    // to what follows, so there's only one way to write a number.
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.take(1)?[0];
+            value = shift
+                .checked_mul(usize::from(byte & 0x7F))
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or_else(|| PatchError::Invalid("a number is too large".to_string()))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_shl(7)
+                .filter(|shift| *shift != 0)
+                .ok_or_else(|| PatchError::Invalid("a number is too large".to_string()))?;
+            value += shift;
+        }
+    }
+
+    // An offset given relative to the last, with its sign in the bottom bit.
+    fn offset(&mut self, from: usize) -> Result<usize, PatchError> {
+        let delta = self.number()?;
+        let offset = if delta & 1 == 0 {
+            from.checked_add(delta >> 1)
+        } else {
+            from.checked_sub(delta >> 1)
+        };
+        offset.ok_or_else(|| PatchError::Invalid("an offset is out of range".to_string()))
+    }
+}
+
+fn write_number(patch: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let bits = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            patch.push(0x80 | bits);
+            return;
+        }
+        patch.push(bits);
+        value -= 1;
+    }
+}
+
+// Runs the source has in the same place are read from it, runs of one byte are copied from
+// the target as it's written, and everything else is in the patch.
+pub fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    write_number(&mut patch, source.len());
+    write_number(&mut patch, target.len());
+    write_number(&mut patch, 0);
+    let same = |i: usize| source.get(i) == Some(&target[i]);
+    let mut target_offset = 0;
+    let mut literal: Option<usize> = None;
+    let mut i = 0;
+    while i <= target.len() {
+        let run = if i == target.len() {
+            0
+        } else if same(i) {
+            (i..target.len()).take_while(|j| same(*j)).count()
+        } else {
+            (i..target.len())
+                .take_while(|j| target[*j] == target[i])
+                .count()
+        };
+        // Short runs aren't worth an action of their own.
+        if i < target.len() && run < 4 {
+            literal.get_or_insert(i);
+            i += 1;
+            continue;
+        }
+        if let Some(start) = literal.take() {
+            write_number(&mut patch, ((i - start - 1) << 2) | 1);
+            patch.extend_from_slice(&target[start..i]);
+        }
+        if i == target.len() {
+            break;
+        }
+        if same(i) {
+            write_number(&mut patch, (run - 1) << 2);
+        } else {
+            // One byte as it is, then the rest copied from it.
+            write_number(&mut patch, 1);
+            patch.push(target[i]);
+            let delta = i as isize - target_offset as isize;
+            let encoded = (delta.unsigned_abs() << 1) | usize::from(delta < 0);
+            write_number(&mut patch, ((run - 2) << 2) | 3);
+            write_number(&mut patch, encoded);
+            target_offset = i + run - 1;
+        }
+        i += run;
+    }
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let checksum = crc32(&patch);
+    patch.extend_from_slice(&checksum.to_le_bytes());
+    patch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn images() -> (Vec<u8>, Vec<u8>) {
+        let source: Vec<u8> = (0..=255).cycle().take(0x800).collect();
+        let mut target = source.clone();
+        target[0x10] = 0xC9;
+        target[0x14..0x18].copy_from_slice(&[0; 4]);
+        target[0x400..0x480]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xAA);
+        target.extend_from_slice(b"extra");
+        (source, target)
+    }
+
+    #[test]
+    fn test_ips() {
+        // A record, then 3 FFs run-length encoded, then the size to cut to.
+        let patch = b"PATCH\x00\x00\x01\x00\x02AB\x00\x00\x04\x00\x00\x00\x03\xFFEOF\x00\x00\x06";
+        assert_eq!(
+            apply(patch, &[0; 8]).unwrap(),
+            [0, b'A', b'B', 0, 0xFF, 0xFF]
+        );
+        assert!(apply_ips(b"PATCH\x00\x00\x01\x00\x02A", &[0; 8]).is_err());
+
+        let (source, target) = images();
+        let patch = create_ips(&source, &target).unwrap();
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+        // The bytes at 11-13 are repeated rather than starting another record.
+        assert_eq!(&patch[5..10], &[0x00, 0x00, 0x10, 0x00, 0x08]);
+        let patch = create_ips(&target, &source).unwrap();
+        assert_eq!(apply(&patch, &target).unwrap(), source);
+    }
+
+    #[test]
+    fn test_bps() {
+        let (source, target) = images();
+        let patch = create_bps(&source, &target);
+        assert!(patch.len() < 100);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+        assert_eq!(
+            apply(&create_bps(&target, &source), &target).unwrap(),
+            source
+        );
+        assert_eq!(apply(&create_bps(&[], &target), &[]).unwrap(), target);
+
+        let mut wrong = source.clone();
+        wrong[0] ^= 1;
+        assert!(matches!(
+            apply(&patch, &wrong),
+            Err(PatchError::SourceChecksum { .. })
+        ));
+        assert!(matches!(
+            apply(&patch, &source[1..]),
+            Err(PatchError::SourceSize { .. })
+        ));
+        let mut damaged = patch.clone();
+        damaged[8] ^= 1;
+        assert!(matches!(
+            apply(&damaged, &source),
+            Err(PatchError::PatchChecksum { .. })
+        ));
+        assert!(apply(b"not a patch", &source).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use crate::disasm;
+use crate::symbols::SymbolTable;
+use crate::{Cpu, FrameKind};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// The calls active while an instruction ran, outermost first, by kind and target.
+type Stack = Vec<(FrameKind, u16)>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cost {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Cost {
+    fn add(&mut self, other: Cost) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+// A routine is the target of a call, RST or interrupt. None is code run outside any of them.
+// Self cost is what ran in the routine itself, total cost includes everything it called.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Routine {
+    pub frame: Option<(FrameKind, u16)>,
+    pub self_cost: Cost,
+    pub total_cost: Cost,
+}
+
+// Counts hits and cycles for every address, and for every call stack they were run under.
+// Call before() ahead of each instruction and after() with the cycles it took.
+pub struct Profiler {
+    hits: Vec<u64>,
+    cycles: Vec<u64>,
+    stacks: HashMap<Stack, Cost>,
+    current: Stack,
+    pc: u16,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            hits: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            stacks: HashMap::new(),
+            current: Vec::new(),
+            pc: 0,
+        }
+    }
+
+    pub fn before(&mut self, cpu: &Cpu) {
+        self.pc = cpu.get_pc();
+        let frames = cpu.call_stack().map_or(&[][..], |stack| stack.frames());
+        let unchanged = frames.len() == self.current.len()
+            && frames
+                .iter()
+                .zip(&self.current)
+                .all(|(frame, (kind, target))| frame.kind == *kind && frame.target == *target);
+        if !unchanged {
+            self.current = frames.iter().map(|f| (f.kind, f.target)).collect();
+        }
+    }
+
+    pub fn after(&mut self, cycles: u64) {
+        let addr = usize::from(self.pc);
+        self.hits[addr] += 1;
+        self.cycles[addr] += cycles;
+        let cost = Cost {
+            instructions: 1,
+            cycles,
+        };
+        match self.stacks.get_mut(&self.current) {
+            Some(total) => total.add(cost),
+            None => {
+                self.stacks.insert(self.current.clone(), cost);
+            }
+        }
+    }
+
+    pub fn hits(&self, addr: u16) -> u64 {
+        self.hits[usize::from(addr)]
+    }
+
+    pub fn cycles(&self, addr: u16) -> u64 {
+        self.cycles[usize::from(addr)]
+    }
+
+    pub fn total(&self) -> Cost {
+        let mut total = Cost::default();
+        for cost in self.stacks.values() {
+            total.add(*cost);
+        }
+        total
+    }
+
+    // Most self cycles first.
+    pub fn routines(&self) -> Vec<Routine> {
+        let mut routines: HashMap<Option<(FrameKind, u16)>, Routine> = HashMap::new();
+        for (stack, cost) in &self.stacks {
+            let innermost = stack.last().copied();
+            routine_entry(&mut routines, innermost).self_cost.add(*cost);
+            // Recursion shouldn't count a routine's time twice.
+            let mut seen: Vec<Option<(FrameKind, u16)>> = vec![None];
+            seen.extend(stack.iter().map(|frame| Some(*frame)));
+            seen.sort();
+            seen.dedup();
+            for frame in seen {
+                routine_entry(&mut routines, frame).total_cost.add(*cost);
+            }
+        }
+        let mut routines: Vec<Routine> = routines.into_values().collect();
+        routines.sort_by(|a, b| {
+            b.self_cost
+                .cycles
+                .cmp(&a.self_cost.cycles)
+                .then(a.frame.cmp(&b.frame))
+        });
+        routines
+    }
+
+    // Cost of each labelled stretch of code, by the nearest label at or before each address.
+    // Code before the first label is counted under None. Most cycles first.
+    pub fn labels(&self, symbols: &SymbolTable) -> Vec<(Option<u16>, Cost)> {
+        let mut labels: HashMap<Option<u16>, Cost> = HashMap::new();
+        for addr in 0..=0xFFFFu16 {
+            let hits = self.hits(addr);
+            if hits == 0 {
+                continue;
+            }
+            let label = symbols.nearest(addr).map(|(start, _)| start);
+            labels.entry(label).or_default().add(Cost {
+                instructions: hits,
+                cycles: self.cycles(addr),
+            });
+        }
+        let mut labels: Vec<(Option<u16>, Cost)> = labels.into_iter().collect();
+        labels.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        labels
+    }
+
+    // Routines, labels if there are any, then the limit hottest instructions.
+    pub fn write_report<W: Write + ?Sized>(
+        &self,
+        out: &mut W,
+        memory: &[u8],
+        symbols: &SymbolTable,
+        limit: usize,
+    ) -> io::Result<()> {
+        let total = self.total();
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total.cycles.max(1) as f64;
+        writeln!(
+            out,
+            "{} cycles in {} instructions",
+            total.cycles, total.instructions
+        )?;
+
+        writeln!(out, "\nRoutines by self cycles:")?;
+        writeln!(
+            out,
+            "  self%   self cycles  total%  total cycles  instructions  routine"
+        )?;
+        for routine in self.routines().iter().take(limit) {
+            writeln!(
+                out,
+                "{:>6.2}% {:>13} {:>6.2}% {:>13} {:>13}  {}",
+                percent(routine.self_cost.cycles),
+                routine.self_cost.cycles,
+                percent(routine.total_cost.cycles),
+                routine.total_cost.cycles,
+                routine.self_cost.instructions,
+                routine_name(routine.frame, symbols)
+            )?;
+        }
+
+        if !symbols.is_empty() {
+            writeln!(out, "\nLabels by cycles:")?;
+            writeln!(out, "      %        cycles  instructions  label")?;
+            for (label, cost) in self.labels(symbols).iter().take(limit) {
+                let name = label
+                    .and_then(|addr| symbols.name(addr))
+                    .unwrap_or("(unlabelled)");
+                writeln!(
+                    out,
+                    "{:>6.2}% {:>13} {:>13}  {}",
+                    percent(cost.cycles),
+                    cost.cycles,
+                    cost.instructions,
+                    name
+                )?;
+            }
+        }
+
+        writeln!(out, "\nHottest instructions:")?;
+        writeln!(out, "      %        cycles          hits  instruction")?;
+        let mut addresses: Vec<u16> = (0..=0xFFFFu16).filter(|a| self.hits(*a) > 0).collect();
+        addresses.sort_by(|a, b| self.cycles(*b).cmp(&self.cycles(*a)).then(a.cmp(b)));
+        for addr in addresses.into_iter().take(limit) {
+            let instruction = disasm::disassemble(memory, addr);
+            writeln!(
+                out,
+                "{:>6.2}% {:>13} {:>13}  {}",
+                percent(self.cycles(addr)),
+                self.cycles(addr),
+                self.hits(addr),
+                disasm::format_symbolic_listing(&instruction, symbols)
+            )?;
+        }
+        Ok(())
+    }
+
+    // One line per call stack, the way flamegraph.pl and inferno take them:
+    //   top;game_loop;draw_sprite 12345
+    // Counts are cycles.
+    pub fn write_collapsed<W: Write + ?Sized>(
+        &self,
+        out: &mut W,
+        symbols: &SymbolTable,
+    ) -> io::Result<()> {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .filter(|(_, cost)| cost.cycles > 0)
+            .map(|(stack, cost)| {
+                let mut names = vec![String::from("top")];
+                names.extend(
+                    stack
+                        .iter()
+                        .map(|frame| routine_name(Some(*frame), symbols)),
+                );
+                format!("{} {}", names.join(";"), cost.cycles)
+            })
+            .collect();
+        lines.sort();
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    // Writes prefix.txt with the report and prefix.folded with the collapsed stacks.
+    pub fn save(&self, prefix: &str, memory: &[u8], symbols: &SymbolTable) -> io::Result<()> {
+        let mut report = BufWriter::new(File::create(format!("{}.txt", prefix))?);
+        self.write_report(&mut report, memory, symbols, 50)?;
+        report.flush()?;
+        let mut collapsed = BufWriter::new(File::create(format!("{}.folded", prefix))?);
+        self.write_collapsed(&mut collapsed, symbols)?;
+        collapsed.flush()
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+fn routine_entry(
+    routines: &mut HashMap<Option<(FrameKind, u16)>, Routine>,
+    frame: Option<(FrameKind, u16)>,
+) -> &mut Routine {
+    routines.entry(frame).or_insert(Routine {
+        frame,
+        self_cost: Cost::default(),
+        total_cost: Cost::default(),
+    })
+}
+
+// Flamegraph tools split on ; so names must not contain one.
+fn routine_name(frame: Option<(FrameKind, u16)>, symbols: &SymbolTable) -> String {
+    let (kind, target) = match frame {
+        Some(frame) => frame,
+        None => return String::from("top"),
+    };
+    let name = match symbols.name(target) {
+        Some(name) => name.replace(';', "_"),
+        None => format!("{:04X}", target),
+    };
+    match kind {
+        FrameKind::Interrupt => format!("{} (interrupt)", name),
+        FrameKind::Call | FrameKind::Rst => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::Debugger;
+    use crate::machine::Bare;
+    use crate::RegisterName;
+
+    // CALL 0007 / CALL 0007 / HLT / INR B / RET
+    fn profiled() -> Debugger {
+        let program = [0xCD, 0x07, 0x00, 0xCD, 0x07, 0x00, 0x76, 0x04, 0xC9];
+        let mut cpu = Cpu::new();
+        for (i, byte) in program.iter().enumerate() {
+            cpu.write_memory(i as u16, *byte);
+        }
+        cpu.write_register(RegisterName::SP, 0x2400);
+        let mut debugger = Debugger::new(cpu, Box::new(Bare));
+        debugger.set_profiler(Some(Profiler::new()));
+        debugger.cont();
+        debugger
+    }
+
+    #[test]
+    fn test_routines() {
+        let debugger = profiled();
+        let profiler = debugger.profiler().unwrap();
+
+        assert_eq!(profiler.hits(0x0007), 2);
+        assert_eq!(profiler.cycles(0x0007), 10);
+        let routines = profiler.routines();
+        let top = routines.iter().find(|r| r.frame.is_none()).unwrap();
+        let sub = routines
+            .iter()
+            .find(|r| r.frame == Some((FrameKind::Call, 0x0007)))
+            .unwrap();
+        assert_eq!(top.self_cost.cycles, 34);
+        assert_eq!(top.total_cost.cycles, 34 + 30);
+        assert_eq!(sub.self_cost.instructions, 4);
+        assert_eq!(sub.self_cost.cycles, 30);
+    }
+
+    #[test]
+    fn test_collapsed_stacks() {
+        let debugger = profiled();
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x0007, "bump");
+        let mut out = Vec::new();
+        debugger
+            .profiler()
+            .unwrap()
+            .write_collapsed(&mut out, &symbols)
+            .unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "top 34\ntop;bump 30\n");
+    }
+}
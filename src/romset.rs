@@ -0,0 +1,469 @@
+use crate::hash::{crc32, hex_digest, sha1};
+use crate::image::{Image, ImageError};
+use crate::patch::{self, PatchError};
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+mod inflate;
+pub mod zip;
+
+use self::zip::Zip;
+
+// One dump in a set, with the hashes a good one has. Not every manifest gives SHA-1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomFile {
+    pub name: String,
+    pub size: usize,
+    pub addr: u16,
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+}
+
+// The files a machine needs, named as MAME names the set: invaders.zip, or a directory
+// called invaders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomSet {
+    pub name: String,
+    pub roms: Vec<RomFile>,
+    pub patches: Vec<RomPatch>,
+}
+
+// An IPS or BPS patch applied to one of a set's files as it loads, so a fixed or translated
+// game can be run from the original dumps.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomPatch {
+    pub rom: String,
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    Zip {
+        path: String,
+        message: String,
+    },
+    // Lines count from 1.
+    Manifest {
+        line: usize,
+        message: String,
+    },
+    Missing(String),
+    WrongSize {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    Image(ImageError),
+    Patch {
+        path: String,
+        error: PatchError,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::Zip { path, message } => write!(f, "{}: {}", path, message),
+            RomError::Manifest { line, message } => write!(f, "line {}: {}", line, message),
+            RomError::Missing(name) => write!(f, "{} is missing", name),
+            RomError::WrongSize {
+                name,
+                expected,
+                found,
+            } => write!(f, "{} is {} bytes but should be {}", name, found, expected),
+            RomError::Image(e) => write!(f, "{}", e),
+            RomError::Patch { path, error } => write!(f, "{}: {}", path, error),
+        }
+    }
+}
+
+impl error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+impl From<ImageError> for RomError {
+    fn from(e: ImageError) -> Self {
+        RomError::Image(e)
+    }
+}
+
+// What verifying found for one file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Good,
+    Missing,
+    // Shorter or longer than it should be, by its actual size.
+    Truncated(usize),
+    Oversized(usize),
+    // The right size with the wrong contents, and what it hashes to instead.
+    BadChecksum { crc32: u32, sha1: [u8; 20] },
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Good => write!(f, "good"),
+            Status::Missing => write!(f, "missing"),
+            Status::Truncated(size) => write!(f, "truncated to {} bytes", size),
+            Status::Oversized(size) => write!(f, "too long at {} bytes", size),
+            Status::BadChecksum { crc32, sha1 } => {
+                write!(f, "bad dump: crc32 {:08x} sha1 {}", crc32, hex_digest(sha1))
+            }
+        }
+    }
+}
+
+// A manifest has a [name] line for each set, then a line for each file in it:
+//
+//   name  size  addr  crc32  [sha1]
+//
+// with the size and load address in hex, as MAME's ROM_LOAD gives them. A line of
+//
+//   patch  name  file
+//
+// applies an IPS or BPS patch to a file listed before it. # starts a comment.
+pub fn parse_manifest(text: &str) -> Result<Vec<RomSet>, RomError> {
+    let mut sets: Vec<RomSet> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: &str| RomError::Manifest {
+            line: i + 1,
+            message: message.to_string(),
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|n| n.strip_suffix(']')) {
+            sets.push(RomSet {
+                name: name.trim().to_string(),
+                roms: Vec::new(),
+                patches: Vec::new(),
+            });
+            continue;
+        }
+        let set = sets
+            .last_mut()
+            .ok_or_else(|| error("a file comes before any [set]"))?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields[0] == "patch" {
+            if fields.len() != 3 {
+                return Err(error("expected patch, the file to patch and the patch"));
+            }
+            if !set.roms.iter().any(|rom| rom.name == fields[1]) {
+                return Err(error("the file to patch isn't in the set"));
+            }
+            set.patches.push(RomPatch {
+                rom: fields[1].to_string(),
+                path: fields[2].to_string(),
+            });
+            continue;
+        }
+        if fields.len() < 4 || fields.len() > 5 {
+            return Err(error("expected name, size, address, crc32 and maybe sha1"));
+        }
+        let size = usize::from_str_radix(fields[1], 16).map_err(|_| error("bad size"))?;
+        let addr = u16::from_str_radix(fields[2], 16).map_err(|_| error("bad address"))?;
+        if usize::from(addr)
+            .checked_add(size)
+            .is_none_or(|end| end > 0x10000)
+        {
+            return Err(error("the file goes past FFFF"));
+        }
+        let crc32 = u32::from_str_radix(fields[3], 16).map_err(|_| error("bad crc32"))?;
+        let sha1 = match fields.get(4) {
+            Some(digest) => Some(parse_sha1(digest).ok_or_else(|| error("bad sha1"))?),
+            None => None,
+        };
+        set.roms.push(RomFile {
+            name: fields[0].to_string(),
+            size,
+            addr,
+            crc32,
+            sha1,
+        });
+    }
+    Ok(sets)
+}
+
+// Patches are found relative to the manifest.
+pub fn load_manifest(path: &str) -> Result<Vec<RomSet>, RomError> {
+    let mut sets = parse_manifest(&fs::read_to_string(path)?)?;
+    let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    for patch in sets.iter_mut().flat_map(|set| set.patches.iter_mut()) {
+        patch.path = directory.join(&patch.path).to_string_lossy().into_owned();
+    }
+    Ok(sets)
+}
+
+fn parse_sha1(text: &str) -> Option<[u8; 20]> {
+    if text.len() != 40 {
+        return None;
+    }
+    let mut digest = [0; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+// Where a set's files are read from.
+pub enum Source {
+    Directory(PathBuf),
+    Zip(Zip),
+}
+
+impl Source {
+    // As MAME looks for them: a zip named directly, or in path, set.zip and then a set
+    // directory. Failing those, the files are taken to be in path itself.
+    pub fn open(path: &str, set: &str) -> Result<Source, RomError> {
+        let path = Path::new(path);
+        let is_zip = |path: &Path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+        };
+        if path.is_file() && is_zip(path) {
+            return Source::open_zip(path);
+        }
+        let zip = path.join(format!("{}.zip", set));
+        if zip.is_file() {
+            return Source::open_zip(&zip);
+        }
+        let directory = path.join(set);
+        if directory.is_dir() {
+            return Ok(Source::Directory(directory));
+        }
+        if path.is_dir() {
+            return Ok(Source::Directory(path.to_path_buf()));
+        }
+        Err(RomError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} isn't a directory or zip file", path.display()),
+        )))
+    }
+
+    fn open_zip(path: &Path) -> Result<Source, RomError> {
+        let data = fs::read(path)?;
+        Zip::parse(data)
+            .map(Source::Zip)
+            .map_err(|message| RomError::Zip {
+                path: path.display().to_string(),
+                message,
+            })
+    }
+
+    // The file's contents, or None if it isn't there. Names are matched ignoring case, and in a
+    // zip a file with the right CRC will do if none has the right name.
+    pub fn read(&self, rom: &RomFile) -> Result<Option<Vec<u8>>, RomError> {
+        match self {
+            Source::Directory(directory) => {
+                let exact = directory.join(&rom.name);
+                if exact.is_file() {
+                    return Ok(Some(fs::read(exact)?));
+                }
+                for entry in fs::read_dir(directory)? {
+                    let entry = entry?;
+                    if entry
+                        .file_name()
+                        .to_string_lossy()
+                        .eq_ignore_ascii_case(&rom.name)
+                        && entry.path().is_file()
+                    {
+                        return Ok(Some(fs::read(entry.path())?));
+                    }
+                }
+                Ok(None)
+            }
+            Source::Zip(zip) => match zip.find(&rom.name).or_else(|| zip.find_crc(rom.crc32)) {
+                Some(entry) => zip.read(entry).map(Some).map_err(|message| RomError::Zip {
+                    path: rom.name.clone(),
+                    message,
+                }),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl RomFile {
+    pub fn check(&self, data: Option<&[u8]>) -> Status {
+        let data = match data {
+            Some(data) => data,
+            None => return Status::Missing,
+        };
+        if data.len() < self.size {
+            return Status::Truncated(data.len());
+        }
+        if data.len() > self.size {
+            return Status::Oversized(data.len());
+        }
+        let crc = crc32(data);
+        let digest = sha1(data);
+        if crc != self.crc32 || self.sha1.is_some_and(|sha1| sha1 != digest) {
+            return Status::BadChecksum {
+                crc32: crc,
+                sha1: digest,
+            };
+        }
+        Status::Good
+    }
+}
+
+impl RomSet {
+    pub fn verify(&self, source: &Source) -> Result<Vec<Status>, RomError> {
+        self.roms
+            .iter()
+            .map(|rom| Ok(rom.check(source.read(rom)?.as_deref())))
+            .collect()
+    }
+
+    // Every file must be there at the right size, but bad dumps load anyway: a modified ROM
+    // is often the point. verify says which they are. Patches go on after the size is checked,
+    // and can make a file longer or shorter.
+    pub fn load(&self, source: &Source) -> Result<Image, RomError> {
+        let mut image = Image::new();
+        for rom in &self.roms {
+            let mut data = source
+                .read(rom)?
+                .ok_or_else(|| RomError::Missing(rom.name.clone()))?;
+            if data.len() != rom.size {
+                return Err(RomError::WrongSize {
+                    name: rom.name.clone(),
+                    expected: rom.size,
+                    found: data.len(),
+                });
+            }
+            for patch in self.patches.iter().filter(|patch| patch.rom == rom.name) {
+                let error = |error| RomError::Patch {
+                    path: patch.path.clone(),
+                    error,
+                };
+                let bytes = fs::read(&patch.path).map_err(|e| error(PatchError::Io(e)))?;
+                data = patch::apply(&bytes, &data).map_err(error)?;
+            }
+            image.add(u32::from(rom.addr), &data)?;
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "\
+# Two files
+[test]
+rom.h 4  0000 8bb98613
+rom.g 40 0800 90152601  # deflated in the zip
+";
+
+    #[test]
+    fn test_manifest() {
+        let sets = parse_manifest(MANIFEST).unwrap();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].name, "test");
+        assert_eq!(
+            sets[0].roms[1],
+            RomFile {
+                name: "rom.g".to_string(),
+                size: 0x40,
+                addr: 0x800,
+                crc32: 0x9015_2601,
+                sha1: None,
+            }
+        );
+        let sha1 = "ff6200af4c9110d8181249cbcef1a8a40fa40b7f";
+        let sets = parse_manifest(&format!("[a]\nx 800 0 734f5ad8 {}", sha1)).unwrap();
+        assert_eq!(
+            sets[0].roms[0].sha1.map(|s| hex_digest(&s)),
+            Some(sha1.into())
+        );
+
+        assert!(matches!(
+            parse_manifest("x 1 0 0"),
+            Err(RomError::Manifest { line: 1, .. })
+        ));
+        assert!(matches!(
+            parse_manifest("[a]\n\nx 1 FFFF 0 12"),
+            Err(RomError::Manifest { line: 3, .. })
+        ));
+        assert!(parse_manifest("[a]\nx 2 FFFF 0").is_err());
+        assert!(matches!(
+            parse_manifest("[a]\nx FFFFFFFFFFFFFFFF 1 0"),
+            Err(RomError::Manifest { line: 2, .. })
+        ));
+
+        let sets = parse_manifest("[a]\nx 1 0 0\npatch x fix.ips").unwrap();
+        assert_eq!(
+            sets[0].patches,
+            vec![RomPatch {
+                rom: "x".to_string(),
+                path: "fix.ips".to_string()
+            }]
+        );
+        assert!(parse_manifest("[a]\npatch x fix.ips\nx 1 0 0").is_err());
+    }
+
+    #[test]
+    fn test_verify_and_load() {
+        let set = parse_manifest(MANIFEST).unwrap().remove(0);
+        let dir = std::env::temp_dir().join(format!("emu8080-romset-{}", std::process::id()));
+        fs::create_dir_all(dir.join("test")).unwrap();
+        fs::write(dir.join("test.zip"), &zip::tests::ZIP[..]).unwrap();
+        let path = dir.to_str().unwrap();
+
+        // The zip wins over the directory.
+        let source = Source::open(path, "test").unwrap();
+        assert_eq!(set.verify(&source).unwrap(), vec![Status::Good; 2]);
+        let image = set.load(&source).unwrap();
+        assert_eq!(image.len(), 0x44);
+        assert_eq!(image.binary()[..4], [0, 1, 2, 3]);
+
+        fs::remove_file(dir.join("test.zip")).unwrap();
+        fs::write(dir.join("test").join("ROM.H"), [0, 1, 2]).unwrap();
+        let source = Source::open(path, "test").unwrap();
+        assert_eq!(
+            set.verify(&source).unwrap(),
+            vec![Status::Truncated(3), Status::Missing]
+        );
+        assert!(matches!(set.load(&source), Err(RomError::WrongSize { .. })));
+
+        fs::write(dir.join("test").join("ROM.H"), [0, 1, 2, 4]).unwrap();
+        fs::write(dir.join("test").join("rom.g"), [0xAA; 0x40]).unwrap();
+        let source = Source::open(path, "test").unwrap();
+        let statuses = set.verify(&source).unwrap();
+        assert!(matches!(statuses[0], Status::BadChecksum { .. }));
+        assert_eq!(statuses[1], Status::Good);
+        assert!(set.load(&source).is_ok());
+
+        // Back to a good dump with a patch, then a patch for another dump.
+        let mut set = set;
+        let ips = dir.join("fix.ips");
+        fs::write(&ips, b"PATCH\x00\x00\x03\x00\x01\x03EOF").unwrap();
+        set.patches.push(RomPatch {
+            rom: "rom.h".to_string(),
+            path: ips.to_string_lossy().into_owned(),
+        });
+        let image = set.load(&source).unwrap();
+        assert_eq!(image.binary()[..4], [0, 1, 2, 3]);
+        fs::write(&ips, patch::create_bps(&[0, 1, 2, 3], &[9; 4])).unwrap();
+        assert!(matches!(
+            set.load(&source),
+            Err(RomError::Patch {
+                error: PatchError::SourceChecksum { .. },
+                ..
+            })
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+// DEFLATE (RFC 1951) decompression, after zlib's puff. It's slow, but ROMs are small.
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// The order a dynamic block gives the lengths of its code length code in.
+const ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut bits = Bits { data, position: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, &mut out)?,
+            1 => {
+                let (lengths, distances) = fixed()?;
+                codes(&mut bits, &mut out, &lengths, &distances)?
+            }
+            2 => {
+                let (lengths, distances) = dynamic(&mut bits)?;
+                codes(&mut bits, &mut out, &lengths, &distances)?
+            }
+            _ => return Err("a block has an invalid type".to_string()),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+// Bits come least significant first.
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, count: u32) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .data
+                .get(self.position / 8)
+                .ok_or("the compressed data ends early")?;
+            value |= u32::from(byte >> (self.position % 8) & 1) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+// A canonical Huffman code: how many codes there are of each length, and the symbols in code
+// order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[usize::from(*length)] += 1;
+        }
+        let mut left = 1i32;
+        for count in &counts[1..] {
+            left = left * 2 - i32::from(*count);
+            if left < 0 {
+                return Err("a Huffman code is over-subscribed".to_string());
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                let offset = &mut offsets[usize::from(*length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = i32::from(*count);
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("a code isn't in its Huffman table".to_string())
+    }
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>) -> Result<(), String> {
+    bits.align();
+    let start = bits.position / 8;
+    let header = bits
+        .data
+        .get(start..start + 4)
+        .ok_or("the compressed data ends early")?;
+    let length = usize::from(u16::from_le_bytes([header[0], header[1]]));
+    if header[0] != !header[2] || header[1] != !header[3] {
+        return Err("a stored block's length doesn't match its complement".to_string());
+    }
+    let data = bits
+        .data
+        .get(start + 4..start + 4 + length)
+        .ok_or("the compressed data ends early")?;
+    out.extend_from_slice(data);
+    bits.position += (4 + length) * 8;
+    Ok(())
+}
+
+fn fixed() -> Result<(Huffman, Huffman), String> {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].iter_mut().for_each(|length| *length = 9);
+    lengths[256..280].iter_mut().for_each(|length| *length = 7);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic(bits: &mut Bits) -> Result<(Huffman, Huffman), String> {
+    let literals = bits.take(5)? as usize + 257;
+    let distances = bits.take(5)? as usize + 1;
+    let code_lengths = bits.take(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err("a dynamic block has too many codes".to_string());
+    }
+    let mut lengths = [0u8; 19];
+    for i in &ORDER[..code_lengths] {
+        lengths[*i] = bits.take(3)? as u8;
+    }
+    let code = Huffman::new(&lengths)?;
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (length, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or("a length repeats before any is given")?;
+                (previous, 3 + bits.take(2)?)
+            }
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?),
+        };
+        if lengths.len() + repeat as usize > literals + distances {
+            return Err("a dynamic block has too many lengths".to_string());
+        }
+        lengths.extend((0..repeat).map(|_| length));
+    }
+    if lengths[256] == 0 {
+        return Err("a dynamic block has no end code".to_string());
+    }
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+fn codes(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    lengths: &Huffman,
+    distances: &Huffman,
+) -> Result<(), String> {
+    loop {
+        let symbol = usize::from(lengths.decode(bits)?);
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err("a length code is out of range".to_string());
+        }
+        let length = usize::from(LENGTH_BASE[symbol]) + bits.take(LENGTH_EXTRA[symbol])? as usize;
+        let symbol = usize::from(distances.decode(bits)?);
+        if symbol >= DISTANCE_BASE.len() {
+            return Err("a distance code is out of range".to_string());
+        }
+        let distance =
+            usize::from(DISTANCE_BASE[symbol]) + bits.take(DISTANCE_EXTRA[symbol])? as usize;
+        if distance > out.len() {
+            return Err("a distance goes back before the start".to_string());
+        }
+        for _ in 0..length {
+            out.push(out[out.len() - distance]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inflate() {
+        let stored = [0x01, 0x04, 0x00, 0xFB, 0xFF, 0x38, 0x30, 0x38, 0x30];
+        assert_eq!(inflate(&stored).unwrap(), b"8080");
+
+        let fixed = [
+            0xF3, 0x0D, 0xF3, 0x54, 0x70, 0xD4, 0x31, 0x54, 0xF0, 0x45, 0xA5, 0x01,
+        ];
+        assert_eq!(inflate(&fixed).unwrap(), b"MVI A,1 MVI A,1 MVI A,1");
+
+        let dynamic = [
+            0x1D, 0xCC, 0x3B, 0x0A, 0x84, 0x40, 0x10, 0x00, 0xD1, 0xAB, 0xD4, 0x01, 0x5A, 0xD1,
+            0x44, 0x86, 0x35, 0xF2, 0x07, 0x06, 0x06, 0xC2, 0xEE, 0x05, 0x46, 0x6C, 0x74, 0x12,
+            0x91, 0x69, 0xF5, 0xFC, 0xBA, 0x86, 0xF5, 0x82, 0xFA, 0xAD, 0x8A, 0xCB, 0x5C, 0xC6,
+            0xEA, 0x0D, 0xD3, 0x4B, 0x37, 0x5C, 0x32, 0x85, 0x83, 0xA8, 0x4B, 0xB0, 0x43, 0xA3,
+            0x7D, 0xA8, 0x84, 0x5A, 0x68, 0x84, 0x56, 0xE8, 0x84, 0x1E, 0xBF, 0xCD, 0x0C, 0x29,
+            0xF5, 0x9F, 0xBA, 0xB7, 0xFA, 0x81, 0xDD, 0x87, 0xC8, 0xB9, 0x97, 0x7C, 0xC7, 0x97,
+            0xC6, 0x06, 0x1F, 0x95, 0xBC, 0xE0, 0xD9, 0x59, 0x7A, 0x03,
+        ];
+        assert_eq!(
+            inflate(&dynamic).unwrap(),
+            &b"The 8080 has seven 8-bit registers: A, B, C, D, E, H and L. BC, DE and HL pair up; \
+               SP and PC are 16 bits."[..]
+        );
+
+        assert!(inflate(&fixed[..6]).is_err());
+        assert!(inflate(&[0x07]).is_err());
+    }
+
+    #[test]
+    fn test_overlapping_copy() {
+        // A literal A, then 19 bytes copied from 1 back, each one written just before it's read.
+        let run = [0x73, 0x74, 0xC4, 0x04, 0x00];
+        assert_eq!(inflate(&run).unwrap(), [b'A'; 20]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        // A dynamic block giving four code length codes, all 1 bit long.
+        assert_eq!(
+            inflate(&[0x05, 0x00, 0x92, 0x04]),
+            Err("a Huffman code is over-subscribed".to_string())
+        );
+        // A fixed block copying from 2 back after a single literal.
+        assert_eq!(
+            inflate(&[0x73, 0x04, 0x42, 0x00]),
+            Err("a distance goes back before the start".to_string())
+        );
+        // A stored block 4 bytes long, with 2 of them there.
+        assert_eq!(
+            inflate(&[0x01, 0x04, 0x00, 0xFB, 0xFF, 0x38, 0x30]),
+            Err("the compressed data ends early".to_string())
+        );
+        assert_eq!(
+            inflate(&[0x01, 0x04, 0x00, 0xFB, 0xFE, 0x38, 0x30, 0x38, 0x30]),
+            Err("a stored block's length doesn't match its complement".to_string())
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use super::inflate::inflate;
+use crate::hash::crc32;
+
+const END_SIGNATURE: u32 = 0x0605_4B50;
+const ENTRY_SIGNATURE: u32 = 0x0201_4B50;
+const LOCAL_SIGNATURE: u32 = 0x0403_4B50;
+// The end record is 22 bytes and its comment at most FFFF.
+const END_LENGTH: usize = 22;
+
+// A file in the archive, as the central directory lists it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub size: usize,
+    pub crc32: u32,
+    method: u16,
+    flags: u16,
+    compressed_size: usize,
+    offset: usize,
+}
+
+// A zip archive read whole into memory. Only what MAME's ROM sets use is supported: stored and
+// deflated files, no encryption and no zip64.
+pub struct Zip {
+    data: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+impl Zip {
+    pub fn parse(data: Vec<u8>) -> Result<Zip, String> {
+        let end = (0..=data.len().saturating_sub(END_LENGTH))
+            .rev()
+            .take(0x10000)
+            .find(|i| read_u32(&data, *i) == Some(END_SIGNATURE))
+            .ok_or("it isn't a zip file")?;
+        let count = read_u16(&data, end + 10).ok_or("it's truncated")?;
+        let mut offset = read_u32(&data, end + 16).ok_or("it's truncated")? as usize;
+        if count == 0xFFFF || offset == 0xFFFF_FFFF {
+            return Err("zip64 archives aren't supported".to_string());
+        }
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            if read_u32(&data, offset) != Some(ENTRY_SIGNATURE) {
+                return Err("the central directory is damaged".to_string());
+            }
+            let field = |at: usize| read_u32(&data, offset + at).ok_or("it's truncated");
+            let short = |at: usize| read_u16(&data, offset + at).ok_or("it's truncated");
+            let name_length = usize::from(short(28)?);
+            let name = data
+                .get(offset + 46..offset + 46 + name_length)
+                .ok_or("it's truncated")?;
+            entries.push(Entry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                size: field(24)? as usize,
+                crc32: field(16)?,
+                method: short(10)?,
+                flags: short(8)?,
+                compressed_size: field(20)? as usize,
+                offset: field(42)? as usize,
+            });
+            offset += 46 + name_length + usize::from(short(30)?) + usize::from(short(32)?);
+        }
+        Ok(Zip { data, entries })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    // By name, ignoring case and any directory, as MAME does.
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| {
+            let base = entry.name.rsplit('/').next().unwrap_or(&entry.name);
+            base.eq_ignore_ascii_case(name)
+        })
+    }
+
+    // Renamed dumps are still found by their contents.
+    pub fn find_crc(&self, crc32: u32) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.crc32 == crc32)
+    }
+
+    pub fn read(&self, entry: &Entry) -> Result<Vec<u8>, String> {
+        if entry.flags & 1 != 0 {
+            return Err(format!("{} is encrypted", entry.name));
+        }
+        if read_u32(&self.data, entry.offset) != Some(LOCAL_SIGNATURE) {
+            return Err(format!("{}'s header is damaged", entry.name));
+        }
+        let skip = |at: usize| read_u16(&self.data, entry.offset + at).map(usize::from);
+        let start = match (skip(26), skip(28)) {
+            (Some(name), Some(extra)) => entry.offset + 30 + name + extra,
+            _ => return Err(format!("{} is truncated", entry.name)),
+        };
+        let compressed = self
+            .data
+            .get(start..start + entry.compressed_size)
+            .ok_or_else(|| format!("{} is truncated", entry.name))?;
+        let data = match entry.method {
+            0 => compressed.to_vec(),
+            8 => inflate(compressed).map_err(|e| format!("{}: {}", entry.name, e))?,
+            method => {
+                return Err(format!(
+                    "{} uses compression method {}, which isn't supported",
+                    entry.name, method
+                ))
+            }
+        };
+        if data.len() != entry.size || crc32(&data) != entry.crc32 {
+            return Err(format!(
+                "{} doesn't match its CRC in the archive",
+                entry.name
+            ));
+        }
+        Ok(data)
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*data.get(at)?, *data.get(at + 1)?]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from(read_u16(data, at)?) | (u32::from(read_u16(data, at + 2)?) << 16))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // ROM.H stored with 00 01 02 03, and rom.g deflated with 64 AAs.
+    pub const ZIP: [u8; 204] = [
+        0x50, 0x4B, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x00, 0x13,
+        0x86, 0xB9, 0x8B, 0x04, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+        0x52, 0x4F, 0x4D, 0x2E, 0x48, 0x00, 0x01, 0x02, 0x03, 0x50, 0x4B, 0x03, 0x04, 0x14, 0x00,
+        0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x21, 0x00, 0x01, 0x26, 0x15, 0x90, 0x06, 0x00, 0x00,
+        0x00, 0x40, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x72, 0x6F, 0x6D, 0x2E, 0x67, 0x5B,
+        0xB5, 0x8A, 0x32, 0x00, 0x00, 0x50, 0x4B, 0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x21, 0x00, 0x13, 0x86, 0xB9, 0x8B, 0x04, 0x00, 0x00, 0x00, 0x04,
+        0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x52, 0x4F, 0x4D, 0x2E, 0x48, 0x50, 0x4B, 0x01, 0x02,
+        0x14, 0x03, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x21, 0x00, 0x01, 0x26, 0x15,
+        0x90, 0x06, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x01, 0x27, 0x00, 0x00, 0x00, 0x72, 0x6F, 0x6D,
+        0x2E, 0x67, 0x50, 0x4B, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02, 0x00, 0x66,
+        0x00, 0x00, 0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_zip() {
+        let zip = Zip::parse(ZIP.to_vec()).unwrap();
+        assert_eq!(zip.entries().len(), 2);
+        let h = zip.find("rom.h").unwrap();
+        assert_eq!(zip.read(h).unwrap(), vec![0, 1, 2, 3]);
+        let g = zip.find_crc(crc32(&[0xAA; 64])).unwrap();
+        assert_eq!(g.name, "rom.g");
+        assert_eq!(zip.read(g).unwrap(), vec![0xAA; 64]);
+        assert!(zip.find("rom.f").is_none());
+
+        // A flipped byte in the stored data.
+        let mut damaged = ZIP.to_vec();
+        damaged[36] ^= 1;
+        let zip = Zip::parse(damaged).unwrap();
+        assert!(zip.read(zip.find("ROM.H").unwrap()).is_err());
+        assert!(Zip::parse(b"not a zip".to_vec()).is_err());
+    }
+}
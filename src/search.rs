@@ -0,0 +1,140 @@
+use std::ops::RangeInclusive;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Equal(u8),
+    Increased,
+    Decreased,
+    Changed,
+    Unchanged,
+    // A decimal number stored two digits a byte, as DAA leaves it, in either byte order. The
+    // candidate is the number's first byte.
+    Bcd(u32),
+}
+
+// Narrows down where a program keeps a value by watching how RAM changes. Start it, play until
+// the value changes in a known way, filter, and repeat. Each filter compares memory with what
+// it was at the last filter (or the start) and keeps the addresses that fit.
+#[derive(Clone, Debug)]
+pub struct RamSearch {
+    range: RangeInclusive<u16>,
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    pub fn new(memory: &[u8], range: RangeInclusive<u16>) -> Self {
+        let candidates = range.clone().collect();
+        RamSearch {
+            range,
+            snapshot: memory.to_vec(),
+            candidates,
+        }
+    }
+
+    pub fn range(&self) -> RangeInclusive<u16> {
+        self.range.clone()
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    // What addr held when the last snapshot was taken.
+    pub fn previous(&self, addr: u16) -> u8 {
+        self.snapshot[usize::from(addr)]
+    }
+
+    // Keeps the candidates that pass and snapshots memory for next time. Returns how many are
+    // left.
+    pub fn filter(&mut self, memory: &[u8], filter: Filter) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|addr| {
+            let (old, new) = (snapshot[usize::from(*addr)], memory[usize::from(*addr)]);
+            match filter {
+                Filter::Equal(value) => new == value,
+                Filter::Increased => new > old,
+                Filter::Decreased => new < old,
+                Filter::Changed => new != old,
+                Filter::Unchanged => new == old,
+                Filter::Bcd(value) => holds_bcd(memory, *addr, value),
+            }
+        });
+        self.snapshot = memory.to_vec();
+        self.candidates.len()
+    }
+
+    // Starts again with every address in range a candidate.
+    pub fn reset(&mut self, memory: &[u8]) {
+        *self = RamSearch::new(memory, self.range.clone());
+    }
+}
+
+fn holds_bcd(memory: &[u8], addr: u16, value: u32) -> bool {
+    let mut bytes = Vec::new();
+    let mut rest = value;
+    loop {
+        let low = rest % 10;
+        let high = (rest / 10) % 10;
+        bytes.push((high << 4 | low) as u8);
+        rest /= 100;
+        if rest == 0 {
+            break;
+        }
+    }
+    let at = |offset: usize| memory[usize::from(addr.wrapping_add(offset as u16))];
+    let little = bytes.iter().enumerate().all(|(i, byte)| at(i) == *byte);
+    let big = bytes
+        .iter()
+        .rev()
+        .enumerate()
+        .all(|(i, byte)| at(i) == *byte);
+    little || big
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_narrowing() {
+        let mut memory = vec![0u8; 0x10000];
+        memory[0x2010] = 3;
+        memory[0x2020] = 7;
+        let mut search = RamSearch::new(&memory, 0x2000..=0x20FF);
+        assert_eq!(search.candidates().len(), 0x100);
+
+        memory[0x2010] = 2;
+        memory[0x2020] = 8;
+        assert_eq!(search.filter(&memory, Filter::Changed), 2);
+        assert_eq!(search.previous(0x2010), 2);
+
+        memory[0x2010] = 1;
+        assert_eq!(search.filter(&memory, Filter::Decreased), 1);
+        assert_eq!(search.candidates(), &[0x2010]);
+        assert_eq!(search.filter(&memory, Filter::Equal(1)), 1);
+        assert_eq!(search.filter(&memory, Filter::Unchanged), 1);
+
+        search.reset(&memory);
+        memory[0x2020] = 9;
+        assert_eq!(search.filter(&memory, Filter::Increased), 1);
+        assert_eq!(search.candidates(), &[0x2020]);
+    }
+
+    #[test]
+    fn test_bcd() {
+        let mut memory = vec![0u8; 0x10000];
+        // 1230 little-endian at 20F8, as Invaders keeps the score, and 450 big-endian at 2100.
+        memory[0x20F8] = 0x30;
+        memory[0x20F9] = 0x12;
+        memory[0x2100] = 0x04;
+        memory[0x2101] = 0x50;
+        let mut search = RamSearch::new(&memory, 0x2000..=0x23FF);
+        assert_eq!(search.filter(&memory, Filter::Bcd(1230)), 1);
+        assert_eq!(search.candidates(), &[0x20F8]);
+
+        search.reset(&memory);
+        assert_eq!(search.filter(&memory, Filter::Bcd(450)), 1);
+        assert_eq!(search.candidates(), &[0x2100]);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::disasm::{disassemble, format_symbolic_listing};
+use crate::machine::{MemoryMap, Region};
+use crate::symbols::SymbolTable;
+use crate::{opcode_length, Cpu, Frame, RegisterName};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+
+// Instructions kept to show what led up to a fault.
+const RECENT_LENGTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FaultKind {
+    // A push, pop, call or return touching the stack where it's in ROM.
+    StackInRom,
+    StackInVideo,
+    ExecuteVideo,
+    ExecuteUnmapped,
+    // The instruction's bytes run past FFFF, so pc comes round to 0000.
+    PcWrap,
+    // A push below 0000 or a pop above FFFF.
+    SpWrap,
+}
+
+impl FaultKind {
+    pub const ALL: [FaultKind; 6] = [
+        FaultKind::StackInRom,
+        FaultKind::StackInVideo,
+        FaultKind::ExecuteVideo,
+        FaultKind::ExecuteUnmapped,
+        FaultKind::PcWrap,
+        FaultKind::SpWrap,
+    ];
+
+    // Short names for frontends to turn checks on and off with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FaultKind::StackInRom => "stack-rom",
+            FaultKind::StackInVideo => "stack-video",
+            FaultKind::ExecuteVideo => "exec-video",
+            FaultKind::ExecuteUnmapped => "exec-unmapped",
+            FaultKind::PcWrap => "pc-wrap",
+            FaultKind::SpWrap => "sp-wrap",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FaultKind> {
+        FaultKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+// Something the program did that's almost certainly a bug, caught before the instruction ran.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub pc: u16,
+    pub sp: u16,
+    // The address that broke the rule: the stack byte touched, or pc for execution faults.
+    pub addr: u16,
+    // Addresses of the instructions run up to and including this one, oldest first.
+    pub recent: Vec<u16>,
+    // The shadow call stack, outermost first, if the cpu is tracking calls.
+    pub frames: Vec<Frame>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FaultKind::StackInRom => write!(
+                f,
+                "{:04X}: stack access at {:04X} is in ROM (SP={:04X})",
+                self.pc, self.addr, self.sp
+            ),
+            FaultKind::StackInVideo => write!(
+                f,
+                "{:04X}: stack access at {:04X} is in video RAM (SP={:04X})",
+                self.pc, self.addr, self.sp
+            ),
+            FaultKind::ExecuteVideo => write!(f, "{:04X}: executing video RAM", self.pc),
+            FaultKind::ExecuteUnmapped => write!(f, "{:04X}: executing unmapped memory", self.pc),
+            FaultKind::PcWrap => write!(f, "{:04X}: pc wraps past FFFF", self.pc),
+            FaultKind::SpWrap => write!(
+                f,
+                "{:04X}: stack wraps around the address space (SP={:04X})",
+                self.pc, self.sp
+            ),
+        }
+    }
+}
+
+impl Fault {
+    // The fault, the instructions leading up to it and a backtrace, one item a line.
+    pub fn report(&self, memory: &[u8], symbols: &SymbolTable) -> Vec<String> {
+        let mut lines = vec![self.to_string(), "Recent instructions:".to_string()];
+        for addr in &self.recent {
+            let instruction = disassemble(memory, *addr);
+            lines.push(format!(
+                "  {}",
+                format_symbolic_listing(&instruction, symbols)
+            ));
+        }
+        if !self.frames.is_empty() {
+            lines.push("Call stack:".to_string());
+            let mut addr = self.pc;
+            for (depth, frame) in self.frames.iter().rev().enumerate() {
+                lines.push(format!(
+                    "  #{:<2} {}",
+                    depth,
+                    location(addr, frame.target, symbols)
+                ));
+                addr = frame.site;
+            }
+            lines.push(format!("  #{:<2} {:04X}", self.frames.len(), addr));
+        }
+        lines
+    }
+}
+
+fn location(addr: u16, routine: u16, symbols: &SymbolTable) -> String {
+    let name = symbols
+        .name(routine)
+        .map_or_else(|| format!("{:04X}", routine), String::from);
+    if addr == routine {
+        format!("{:04X}  {}", addr, name)
+    } else {
+        format!("{:04X}  {}+{:X}", addr, name, addr.wrapping_sub(routine))
+    }
+}
+
+// Checks each instruction before it runs against the machine's memory map. Call check() ahead
+// of every instruction so the recent trace is complete.
+#[derive(Clone, Debug)]
+pub struct Strict {
+    map: MemoryMap,
+    ignored: BTreeSet<FaultKind>,
+    recent: VecDeque<u16>,
+}
+
+impl Strict {
+    // Every check is on to begin with. Machine::memory_map() gives the map.
+    pub fn new(map: MemoryMap) -> Self {
+        Strict {
+            map,
+            ignored: BTreeSet::new(),
+            recent: VecDeque::with_capacity(RECENT_LENGTH),
+        }
+    }
+
+    pub fn ignore(&mut self, kind: FaultKind) {
+        self.ignored.insert(kind);
+    }
+
+    pub fn enable(&mut self, kind: FaultKind) {
+        self.ignored.remove(&kind);
+    }
+
+    pub fn is_enabled(&self, kind: FaultKind) -> bool {
+        !self.ignored.contains(&kind)
+    }
+
+    pub fn check(&mut self, cpu: &Cpu) -> Vec<Fault> {
+        let pc = cpu.get_pc();
+        let sp = cpu.read_register(RegisterName::SP);
+        if self.recent.len() == RECENT_LENGTH {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(pc);
+
+        let mut found = Vec::new();
+        match self.map.region(pc) {
+            Region::Video => found.push((FaultKind::ExecuteVideo, pc)),
+            Region::Unmapped => found.push((FaultKind::ExecuteUnmapped, pc)),
+            Region::Rom | Region::Ram => {}
+        }
+        let code = cpu.read_memory(pc);
+        if u32::from(pc) + u32::from(opcode_length(code)) > 0xFFFF {
+            found.push((FaultKind::PcWrap, pc));
+        }
+        let accesses = cpu.memory_accesses();
+        if let Some(wraps) = stack_wraps(code, sp).filter(|_| !accesses.is_empty()) {
+            if wraps {
+                found.push((FaultKind::SpWrap, sp));
+            }
+            // Only the first offending byte is reported.
+            let stack_fault =
+                accesses
+                    .iter()
+                    .find_map(|access| match self.map.region(access.addr) {
+                        Region::Rom => Some((FaultKind::StackInRom, access.addr)),
+                        Region::Video => Some((FaultKind::StackInVideo, access.addr)),
+                        Region::Ram | Region::Unmapped => None,
+                    });
+            found.extend(stack_fault);
+        }
+
+        let frames = cpu
+            .call_stack()
+            .map(|stack| stack.frames().to_vec())
+            .unwrap_or_default();
+        found
+            .into_iter()
+            .filter(|(kind, _)| self.is_enabled(*kind))
+            .map(|(kind, addr)| Fault {
+                kind,
+                pc,
+                sp,
+                addr,
+                recent: self.recent.iter().copied().collect(),
+                frames: frames.clone(),
+            })
+            .collect()
+    }
+}
+
+// For instructions that use the stack, whether sp would go past either end of memory. None
+// for the rest.
+fn stack_wraps(code: u8, sp: u16) -> Option<bool> {
+    match code {
+        0xC5 | 0xD5 | 0xE5 | 0xF5 | 0xCD => Some(sp < 2),
+        _ if code & 0xC7 == 0xC7 || code & 0xC7 == 0xC4 => Some(sp < 2),
+        0xC1 | 0xD1 | 0xE1 | 0xF1 | 0xC9 => Some(sp > 0xFFFD),
+        _ if code & 0xC7 == 0xC0 => Some(sp > 0xFFFD),
+        0xE3 => Some(sp == 0xFFFF),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::invaders::Invaders;
+    use crate::machine::Machine;
+
+    fn cpu_at(pc: u16, sp: u16, code: &[u8]) -> Cpu {
+        let mut cpu = Cpu::default();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.write_memory(pc.wrapping_add(i as u16), *byte);
+        }
+        cpu.write_register(RegisterName::PC, pc);
+        cpu.write_register(RegisterName::SP, sp);
+        cpu
+    }
+
+    #[test]
+    fn test_invaders_faults() {
+        let mut strict = Strict::new(Invaders::new().memory_map());
+        // PUSH B with the stack just above ROM writes into it.
+        let faults = strict.check(&cpu_at(0x2000, 0x2001, &[0xC5]));
+        assert_eq!(faults.len(), 1);
+        assert_eq!(faults[0].kind, FaultKind::StackInRom);
+        assert_eq!(faults[0].addr, 0x1FFF);
+        // Invaders keeps its stack just below video RAM.
+        assert!(strict
+            .check(&cpu_at(0x0100, 0x2400, &[0xCD, 0x00, 0x02]))
+            .is_empty());
+        let faults = strict.check(&cpu_at(0x0100, 0x2402, &[0xCD, 0x00, 0x02]));
+        assert_eq!(faults[0].kind, FaultKind::StackInVideo);
+        assert_eq!(faults[0].addr, 0x2401);
+        // Conditional returns not taken don't touch the stack.
+        let mut cpu = cpu_at(0x0100, 0x3000, &[0xC8]);
+        cpu.write_register(RegisterName::F, 0);
+        assert!(strict.check(&cpu).is_empty());
+
+        let faults = strict.check(&cpu_at(0x3000, 0x2400, &[0x00]));
+        assert_eq!(faults[0].kind, FaultKind::ExecuteVideo);
+        let faults = strict.check(&cpu_at(0x4000, 0x2400, &[0x00]));
+        assert_eq!(faults[0].kind, FaultKind::ExecuteUnmapped);
+        assert_eq!(faults[0].recent.last(), Some(&0x4000));
+        strict.ignore(FaultKind::ExecuteUnmapped);
+        assert!(strict.check(&cpu_at(0x4000, 0x2400, &[0x00])).is_empty());
+    }
+
+    #[test]
+    fn test_wraps() {
+        let mut strict = Strict::new(MemoryMap::new());
+        let kinds = |strict: &mut Strict, cpu: &Cpu| -> Vec<FaultKind> {
+            strict.check(cpu).iter().map(|fault| fault.kind).collect()
+        };
+        assert_eq!(
+            kinds(&mut strict, &cpu_at(0xFFFE, 0, &[0xC3, 0, 0])),
+            [FaultKind::PcWrap]
+        );
+        assert!(kinds(&mut strict, &cpu_at(0xFFFE, 0x100, &[0x00])).is_empty());
+        assert_eq!(
+            kinds(&mut strict, &cpu_at(0x0100, 0x0001, &[0xD5])),
+            [FaultKind::SpWrap]
+        );
+        assert_eq!(
+            kinds(&mut strict, &cpu_at(0x0100, 0xFFFE, &[0xC9])),
+            [FaultKind::SpWrap]
+        );
+        assert!(kinds(&mut strict, &cpu_at(0x0100, 0xFFFC, &[0xC9])).is_empty());
+
+        // Wrapping used to panic in debug builds. Now it comes round like the real thing.
+        let mut cpu = cpu_at(0x0100, 0x0000, &[0xD5]);
+        cpu.execute_opcode();
+        assert_eq!(cpu.read_register(RegisterName::SP), 0xFFFE);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::debugger::parse_number;
+use crate::listing::Listing;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+// Names for addresses. Labels come from symbol files and listings, or are added by the user.
+// User labels and comments can be tied to a file (see user_file) so they're still there next
+// time the same ROM is loaded. Names are looked up without regard to case, as 8080 assemblers
+// treat them.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    names: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+    user_labels: BTreeMap<u16, String>,
+    comments: BTreeMap<u16, String>,
+    user_path: Option<PathBuf>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    // Listings (.lst and .prn) give their colon labels. Anything else is read as a symbol file.
+    // Returns how many labels were read.
+    pub fn load(&mut self, path: &str) -> io::Result<usize> {
+        let text = fs::read_to_string(path)?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let labels = match extension.as_str() {
+            "lst" | "prn" => Listing::parse(&text).labels().to_vec(),
+            _ => parse_symbols(&text),
+        };
+        for (addr, name) in &labels {
+            self.add(*addr, name);
+        }
+        Ok(labels.len())
+    }
+
+    // The first name given to an address is the one shown, unless the user picks another.
+    // Every name can still be used to look the address up.
+    pub fn add(&mut self, addr: u16, name: &str) {
+        self.addresses.insert(name.to_uppercase(), addr);
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn set_label(&mut self, addr: u16, name: &str) -> io::Result<()> {
+        if let Some(old) = self.user_labels.insert(addr, name.to_string()) {
+            self.addresses.remove(&old.to_uppercase());
+        }
+        self.addresses.insert(name.to_uppercase(), addr);
+        self.save()
+    }
+
+    pub fn remove_label(&mut self, addr: u16) -> io::Result<bool> {
+        match self.user_labels.remove(&addr) {
+            Some(name) => {
+                self.addresses.remove(&name.to_uppercase());
+                self.save()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn set_comment(&mut self, addr: u16, text: &str) -> io::Result<()> {
+        if text.is_empty() {
+            self.comments.remove(&addr);
+        } else {
+            self.comments.insert(addr, text.to_string());
+        }
+        self.save()
+    }
+
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.user_labels
+            .get(&addr)
+            .or_else(|| self.names.get(&addr))
+            .map(|name| name.as_str())
+    }
+
+    // The closest name at or before addr, for saying which routine an address is in.
+    pub fn nearest(&self, addr: u16) -> Option<(u16, &str)> {
+        let file = self.names.range(..=addr).next_back();
+        let user = self.user_labels.range(..=addr).next_back();
+        let (start, name) = match (file, user) {
+            (Some(file), Some(user)) if file.0 > user.0 => file,
+            (_, Some(user)) => user,
+            (file, None) => file?,
+        };
+        Some((*start, name.as_str()))
+    }
+
+    pub fn comment(&self, addr: u16) -> Option<&str> {
+        self.comments.get(&addr).map(|text| text.as_str())
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(&name.to_uppercase()).copied()
+    }
+
+    // Every address with a name, and the name shown for it.
+    pub fn labels(&self) -> impl Iterator<Item = (u16, &str)> {
+        let mut all: BTreeMap<u16, &str> = BTreeMap::new();
+        for (addr, name) in self.names.iter().chain(self.user_labels.iter()) {
+            all.insert(*addr, name);
+        }
+        all.into_iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    // An address typed by the user: a name, a name plus a hex offset, or a hex number.
+    pub fn resolve(&self, arg: &str) -> Option<u16> {
+        if let Some(addr) = self.address(arg) {
+            return Some(addr);
+        }
+        if let Some(i) = arg.find('+') {
+            if let Some(addr) = self.address(&arg[..i]) {
+                return Some(addr.wrapping_add(parse_number(&arg[i + 1..])?));
+            }
+        }
+        parse_number(arg)
+    }
+
+    // Reads user labels and comments from path, if it exists, and saves them back there from
+    // now on.
+    pub fn attach(&mut self, path: PathBuf) -> io::Result<()> {
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    let (addr, rest) = match split_address(line) {
+                        Some(split) => split,
+                        None => continue,
+                    };
+                    match rest.strip_prefix(';') {
+                        Some(comment) => {
+                            self.comments.insert(addr, comment.trim().to_string());
+                        }
+                        None if !rest.is_empty() => {
+                            self.user_labels.insert(addr, rest.to_string());
+                            self.addresses.insert(rest.to_uppercase(), addr);
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.user_path = Some(path);
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.user_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        for (addr, name) in &self.user_labels {
+            text.push_str(&format!("{:04X} {}\n", addr, name));
+        }
+        for (addr, comment) in &self.comments {
+            text.push_str(&format!("{:04X} ;{}\n", addr, comment));
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, text)
+    }
+}
+
+// Where user labels for a ROM with this CRC-32 live: $EMU8080_HOME/labels, falling back to
+// ~/.emu8080/labels.
+pub fn user_file(hash: u32) -> Option<PathBuf> {
+    let home = env::var_os("EMU8080_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".emu8080")))?;
+    Some(home.join("labels").join(format!("{:08x}.sym", hash)))
+}
+
+// Symbol files come in a few shapes, all handled here:
+//   1A5C CLEAR_SCREEN                     plain address and name
+//   0100 START   0103 LOOP   0110 DONE    .SYM files from M80/L80 and RMAC, several per line
+//   START EQU 0100H, START: 0100, START = $0100
+// Text after a ; is ignored.
+pub fn parse_symbols(text: &str) -> Vec<(u16, String)> {
+    let mut symbols = Vec::new();
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or_default();
+        let tokens: Vec<&str> = line
+            .split_whitespace()
+            .filter(|token| !["=", "EQU", "equ", "SET", "set"].contains(token))
+            .collect();
+        for pair in tokens.chunks(2) {
+            match *pair {
+                [first, second] => {
+                    let second = second.trim_end_matches(':');
+                    let first_name = first.trim_end_matches(':');
+                    if let (Some(addr), true) = (address(first), is_name(second)) {
+                        symbols.push((addr, second.to_string()));
+                    } else if let (true, Some(addr)) = (is_name(first_name), address(second)) {
+                        symbols.push((addr, first_name.to_string()));
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+    symbols
+}
+
+// Addresses in symbol files are hex, and written with a leading 0 and trailing H or $ as often
+// as not.
+fn address(token: &str) -> Option<u16> {
+    let digits = token
+        .trim_start_matches('$')
+        .trim_end_matches(&['h', 'H'][..]);
+    if digits.is_empty() || digits.len() > 5 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .filter(|addr| *addr <= 0xFFFF)
+        .map(|addr| addr as u16)
+}
+
+fn is_name(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || "_.?@".contains(c))
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.?@$".contains(c))
+}
+
+fn split_address(line: &str) -> Option<(u16, &str)> {
+    let line = line.trim();
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    Some((address(&line[..end])?, line[end..].trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_symbols() {
+        let text = "\
+            ; Invaders\n\
+            1A5C CLEAR_SCREEN\n\
+            0100 START\t0103 LOOP\t0110 DONE\n\
+            VRAM EQU 2400H\n\
+            ALIENS: 2100 ; rack\n";
+
+        assert_eq!(
+            parse_symbols(text),
+            vec![
+                (0x1A5C, String::from("CLEAR_SCREEN")),
+                (0x0100, String::from("START")),
+                (0x0103, String::from("LOOP")),
+                (0x0110, String::from("DONE")),
+                (0x2400, String::from("VRAM")),
+                (0x2100, String::from("ALIENS")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_names_and_resolve() {
+        let mut symbols = SymbolTable::new();
+        symbols.add(0x1A5C, "ClearScreen");
+        symbols.add(0x1A5C, "cls");
+
+        assert_eq!(symbols.name(0x1A5C), Some("ClearScreen"));
+        assert_eq!(symbols.resolve("CLS"), Some(0x1A5C));
+        assert_eq!(symbols.resolve("clearscreen+4"), Some(0x1A60));
+        assert_eq!(symbols.resolve("0x1A5C"), Some(0x1A5C));
+        assert_eq!(symbols.resolve("nowhere"), None);
+
+        symbols.set_label(0x1A5C, "wipe").unwrap();
+        assert_eq!(symbols.name(0x1A5C), Some("wipe"));
+    }
+
+    #[test]
+    fn test_user_labels_persist() {
+        let path = env::temp_dir().join(format!("emu8080-labels-{}.sym", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut symbols = SymbolTable::new();
+        symbols.attach(path.clone()).unwrap();
+        symbols.set_label(0x0A30, "score_loop").unwrap();
+        symbols
+            .set_comment(0x0A30, "adds the shot's points")
+            .unwrap();
+
+        let mut reloaded = SymbolTable::new();
+        reloaded.attach(path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.resolve("SCORE_LOOP"), Some(0x0A30));
+        assert_eq!(reloaded.comment(0x0A30), Some("adds the shot's points"));
+    }
+}
@@ -0,0 +1,196 @@
+pub mod diff;
+
+use crate::disasm;
+use crate::symbols::SymbolTable;
+use crate::{Cpu, RegisterName};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Range, RangeInclusive};
+
+// Writes one line per instruction, before it runs:
+//
+//   CYCLE      PC    BYTES     INSTRUCTION     REGISTERS
+//   0000001234 0A3C  C2 3C 0A  JNZ 0A3CH       A=00 F=56 BC=0000 DE=1B00 HL=2400 SP=2400
+//
+// CYCLE is decimal, the cycles run before this instruction. Everything else is hex. F is the
+// flags byte as PUSH PSW stores it. Columns are fixed width so traces line up under diff.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    addresses: RangeInclusive<u16>,
+    cycles: Range<u64>,
+    symbols: SymbolTable,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Tracer {
+            out,
+            addresses: 0..=0xFFFF,
+            cycles: 0..u64::MAX,
+            symbols: SymbolTable::new(),
+        }
+    }
+
+    pub fn to_file(path: &str) -> io::Result<Self> {
+        Ok(Tracer::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    // Only instructions at these addresses are written.
+    pub fn set_address_range(&mut self, addresses: RangeInclusive<u16>) {
+        self.addresses = addresses;
+    }
+
+    // Only instructions starting inside this window are written.
+    pub fn set_cycle_window(&mut self, cycles: Range<u64>) {
+        self.cycles = cycles;
+    }
+
+    // Operands with a name are written with it.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn record(&mut self, cycles: u64, cpu: &Cpu) -> io::Result<()> {
+        if !self.cycles.contains(&cycles) || !self.addresses.contains(&cpu.get_pc()) {
+            return Ok(());
+        }
+        write_symbolic_line(&mut self.out, cycles, cpu, &self.symbols)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+pub fn write_line<W: Write + ?Sized>(out: &mut W, cycles: u64, cpu: &Cpu) -> io::Result<()> {
+    write_symbolic_line(out, cycles, cpu, &SymbolTable::new())
+}
+
+pub fn write_symbolic_line<W: Write + ?Sized>(
+    out: &mut W,
+    cycles: u64,
+    cpu: &Cpu,
+    symbols: &SymbolTable,
+) -> io::Result<()> {
+    let instruction = disasm::disassemble(cpu.get_memory(), cpu.get_pc());
+    let bytes: Vec<String> = instruction
+        .bytes()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    writeln!(
+        out,
+        "{:010} {:04X}  {:<8}  {:<14}  A={:02X} F={:02X} BC={:04X} DE={:04X} HL={:04X} SP={:04X}",
+        cycles,
+        instruction.address,
+        bytes.join(" "),
+        disasm::symbolic_text(&instruction, symbols),
+        cpu.read_register(RegisterName::A),
+        cpu.read_register(RegisterName::F),
+        cpu.read_register(RegisterName::BC),
+        cpu.read_register(RegisterName::DE),
+        cpu.read_register(RegisterName::HL),
+        cpu.read_register(RegisterName::SP),
+    )
+}
+
+// Ranges on the command line are written start-end, both ends included and in hex for
+// addresses. Cycle windows are decimal.
+pub fn parse_address_range(arg: &str) -> Option<RangeInclusive<u16>> {
+    parse_symbolic_range(arg, &SymbolTable::new())
+}
+
+// The same, with names allowed for either end.
+pub fn parse_symbolic_range(arg: &str, symbols: &SymbolTable) -> Option<RangeInclusive<u16>> {
+    let (start, end) = split_range(arg)?;
+    Some(symbols.resolve(start)?..=symbols.resolve(end)?)
+}
+
+pub fn parse_cycle_window(arg: &str) -> Option<Range<u64>> {
+    let (start, end) = split_range(arg)?;
+    let end: u64 = if end.is_empty() {
+        u64::MAX
+    } else {
+        end.parse::<u64>().ok()?.saturating_add(1)
+    };
+    Some(start.parse().ok()?..end)
+}
+
+fn split_range(arg: &str) -> Option<(&str, &str)> {
+    let i = arg.find('-')?;
+    Some((&arg[..i], &arg[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Lets a test read back what a tracer wrote.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_line_format() {
+        let mut cpu = Cpu::new();
+        cpu.write_memory(0x0A3C, 0xC2);
+        cpu.write_memory(0x0A3D, 0x3C);
+        cpu.write_memory(0x0A3E, 0x0A);
+        cpu.set_pc(0x0A3C);
+        cpu.write_register(RegisterName::SP, 0x2400);
+        let mut out = Vec::new();
+        write_line(&mut out, 1234, &cpu).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0000001234 0A3C  C2 3C 0A  JNZ 0A3CH       \
+             A=00 F=02 BC=0000 DE=0000 HL=0000 SP=2400\n"
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let shared = Shared::default();
+        let mut tracer = Tracer::new(Box::new(shared.clone()));
+        tracer.set_address_range(0x10..=0x20);
+        tracer.set_cycle_window(100..200);
+        let mut cpu = Cpu::new();
+        for (pc, cycles) in &[
+            (0x00, 150),
+            (0x10, 50),
+            (0x10, 150),
+            (0x30, 150),
+            (0x20, 199),
+        ] {
+            cpu.set_pc(*pc);
+            tracer.record(*cycles, &cpu).unwrap();
+        }
+
+        assert_eq!(shared.0.borrow().split(|b| *b == b'\n').count() - 1, 2);
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_address_range("0A00-0AFF"), Some(0x0A00..=0x0AFF));
+        assert_eq!(parse_cycle_window("100-200"), Some(100..201));
+        assert_eq!(parse_cycle_window("100-"), Some(100..u64::MAX));
+        assert_eq!(parse_cycle_window("100"), None);
+    }
+}
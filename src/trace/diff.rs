@@ -0,0 +1,298 @@
+use crate::debugger::parse_number;
+use crate::Flags;
+use std::fmt;
+
+// The machine state before one instruction, as read from some emulator's trace. Fields a trace
+// format doesn't have are None and never compared.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    pub line: usize,
+    pub text: String,
+    pub cycle: Option<u64>,
+    pub pc: Option<u16>,
+    pub a: Option<u8>,
+    pub f: Option<u8>,
+    pub bc: Option<u16>,
+    pub de: Option<u16>,
+    pub hl: Option<u16>,
+    pub sp: Option<u16>,
+}
+
+// Reads one emulator's trace format. Implement this to compare against another emulator.
+pub trait TraceParser {
+    fn name(&self) -> &'static str;
+
+    // None for lines that don't describe an instruction, like headers and blank lines.
+    fn parse_line(&self, line: &str) -> Option<Record>;
+
+    fn parse(&self, text: &str) -> Vec<Record> {
+        text.lines()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                self.parse_line(line).map(|mut record| {
+                    record.line = i + 1;
+                    record.text = line.to_string();
+                    record
+                })
+            })
+            .collect()
+    }
+}
+
+// The format trace::Tracer writes.
+pub struct NativeParser;
+
+impl TraceParser for NativeParser {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn parse_line(&self, line: &str) -> Option<Record> {
+        let mut tokens = line.split_whitespace();
+        let cycle = tokens.next()?;
+        let pc = tokens.next()?;
+        if cycle.len() != 10 || pc.len() != 4 || !cycle.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let mut record = Record {
+            cycle: cycle.parse().ok(),
+            pc: Some(u16::from_str_radix(pc, 16).ok()?),
+            ..Record::default()
+        };
+        // The registers come last and are already key=value.
+        let registers = &line[line.find("A=")?..];
+        read_key_values(registers, &mut record);
+        Some(record)
+    }
+}
+
+// Traces made of KEY:VALUE or KEY=VALUE pairs in any order, which covers most emulators:
+//   PC: 0100, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: F000, CYC: 4
+//   A:00 F:02 B:00 C:00 D:00 E:00 H:00 L:00 SP:F000 PC:0100
+// Values are hex except cycles, which are decimal.
+pub struct KeyValueParser;
+
+impl TraceParser for KeyValueParser {
+    fn name(&self) -> &'static str {
+        "kv"
+    }
+
+    fn parse_line(&self, line: &str) -> Option<Record> {
+        let mut record = Record::default();
+        read_key_values(line, &mut record);
+        record.pc?;
+        Some(record)
+    }
+}
+
+pub fn parsers() -> Vec<Box<dyn TraceParser>> {
+    vec![Box::new(NativeParser), Box::new(KeyValueParser)]
+}
+
+pub fn parser_named(name: &str) -> Option<Box<dyn TraceParser>> {
+    parsers().into_iter().find(|parser| parser.name() == name)
+}
+
+// Picks the first parser that understands the first few lines with any content.
+pub fn detect_parser(text: &str) -> Box<dyn TraceParser> {
+    let sample: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .take(5)
+        .collect();
+    parsers()
+        .into_iter()
+        .find(|parser| sample.iter().any(|line| parser.parse_line(line).is_some()))
+        .unwrap_or_else(|| Box::new(KeyValueParser))
+}
+
+fn read_key_values(text: &str, record: &mut Record) {
+    let tokens: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| !token.is_empty())
+        .collect();
+    // b c d e h l, for formats that list them singly.
+    let mut singles = [None; 6];
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        i += 1;
+        let split = match token.find([':', '=']) {
+            Some(split) => split,
+            None => continue,
+        };
+        let key = token[..split].to_lowercase();
+        let mut value = &token[split + 1..];
+        if value.is_empty() {
+            value = tokens.get(i).copied().unwrap_or_default();
+            i += 1;
+        }
+        let hex = parse_number(value);
+        let byte = hex.filter(|v| *v <= 0xFF).map(|v| v as u8);
+        match key.as_str() {
+            "pc" => record.pc = hex,
+            "sp" => record.sp = hex,
+            "a" => record.a = byte,
+            "f" => record.f = byte,
+            "af" | "psw" => {
+                record.a = hex.map(|v| (v >> 8) as u8);
+                record.f = hex.map(|v| v as u8);
+            }
+            "bc" => record.bc = hex,
+            "de" => record.de = hex,
+            "hl" => record.hl = hex,
+            "cyc" | "cycle" | "cycles" => record.cycle = value.parse().ok(),
+            _ => {
+                if let Some(j) = ["b", "c", "d", "e", "h", "l"]
+                    .iter()
+                    .position(|r| *r == key)
+                {
+                    singles[j] = byte;
+                }
+            }
+        }
+    }
+    let pair = |high: Option<u8>, low: Option<u8>| Some(u16::from(high?) << 8 | u16::from(low?));
+    record.bc = record.bc.or_else(|| pair(singles[0], singles[1]));
+    record.de = record.de.or_else(|| pair(singles[2], singles[3]));
+    record.hl = record.hl.or_else(|| pair(singles[4], singles[5]));
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DiffOptions {
+    // Flag bits to compare. The default ignores the unused bits, which emulators fill in
+    // differently.
+    pub flags_mask: u8,
+    // Cycle counts are off by default, since emulators rarely agree on where they start.
+    pub compare_cycles: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            flags_mask: 0xD5,
+            compare_cycles: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+    pub field: String,
+    pub a: u64,
+    pub b: u64,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.field.as_str() {
+            "cycle" => write!(f, "cycle: {} vs {}", self.a, self.b),
+            "a" | "f" => write!(f, "{}: {:02X} vs {:02X}", self.field, self.a, self.b),
+            field if field.starts_with("flags.") => {
+                write!(f, "{}: {} vs {}", field, self.a, self.b)
+            }
+            field => write!(f, "{}: {:04X} vs {:04X}", field, self.a, self.b),
+        }
+    }
+}
+
+pub fn differences(a: &Record, b: &Record, options: DiffOptions) -> Vec<Difference> {
+    let mut found = Vec::new();
+    let mut compare = |field: &str, x: Option<u64>, y: Option<u64>| {
+        if let (Some(x), Some(y)) = (x, y) {
+            if x != y {
+                found.push(Difference {
+                    field: field.to_string(),
+                    a: x,
+                    b: y,
+                });
+            }
+        }
+    };
+    let wide = |value: Option<u16>| value.map(u64::from);
+    compare("pc", wide(a.pc), wide(b.pc));
+    compare("a", a.a.map(u64::from), b.a.map(u64::from));
+    compare("bc", wide(a.bc), wide(b.bc));
+    compare("de", wide(a.de), wide(b.de));
+    compare("hl", wide(a.hl), wide(b.hl));
+    compare("sp", wide(a.sp), wide(b.sp));
+    if options.compare_cycles {
+        compare("cycle", a.cycle, b.cycle);
+    }
+    if let (Some(x), Some(y)) = (a.f, b.f) {
+        let (x, y) = (
+            Flags::from(x & options.flags_mask),
+            Flags::from(y & options.flags_mask),
+        );
+        for name in &["s", "z", "ac", "p", "cy"] {
+            compare(
+                &format!("flags.{}", name),
+                x.get_by_name(name).map(u64::from),
+                y.get_by_name(name).map(u64::from),
+            );
+        }
+    }
+    found
+}
+
+// The index of the first pair of records that disagree. A trace that stops early doesn't
+// count as diverging.
+pub fn first_divergence(a: &[Record], b: &[Record], options: DiffOptions) -> Option<usize> {
+    a.iter()
+        .zip(b.iter())
+        .position(|(x, y)| !differences(x, y, options).is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_native_parser() {
+        let line = "0000001234 0A3C  C2 3C 0A  JNZ 0A3CH       \
+                    A=12 F=57 BC=0001 DE=1B00 HL=2400 SP=23FE";
+        let record = NativeParser.parse_line(line).unwrap();
+
+        assert_eq!(record.cycle, Some(1234));
+        assert_eq!(record.pc, Some(0x0A3C));
+        assert_eq!(record.a, Some(0x12));
+        assert_eq!(record.f, Some(0x57));
+        assert_eq!(record.sp, Some(0x23FE));
+        assert_eq!(NativeParser.parse_line("PC: 0100"), None);
+    }
+
+    #[test]
+    fn test_key_value_parser() {
+        let pairs = "PC: 0100, AF: 1202, BC: 0001, DE: 0000, HL: 2400, SP: F000, CYC: 4";
+        let singles = "A:12 F:02 B:00 C:01 D:00 E:00 H:24 L:00 SP:F000 PC:0100";
+        let x = KeyValueParser.parse_line(pairs).unwrap();
+        let y = KeyValueParser.parse_line(singles).unwrap();
+
+        assert_eq!(x.cycle, Some(4));
+        assert_eq!((x.pc, x.a, x.f, x.bc, x.hl), (y.pc, y.a, y.f, y.bc, y.hl));
+        assert_eq!(y.bc, Some(0x0001));
+    }
+
+    #[test]
+    fn test_flag_divergence() {
+        let text_a = "PC:0100 AF:0002\nPC:0101 AF:0003\n";
+        let text_b = "PC:0100 AF:0002\nPC:0101 AF:000A\n";
+        let a = KeyValueParser.parse(text_a);
+        let b = KeyValueParser.parse(text_b);
+        let options = DiffOptions::default();
+
+        assert_eq!(first_divergence(&a, &b, options), Some(1));
+        let found = differences(&a[1], &b[1], options);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].to_string(), "flags.cy: 1 vs 0");
+    }
+
+    #[test]
+    fn test_detect_parser() {
+        let native = "0000000000 0000  00        NOP             \
+                      A=00 F=02 BC=0000 DE=0000 HL=0000 SP=0000\n";
+
+        assert_eq!(detect_parser(native).name(), "native");
+        assert_eq!(detect_parser("PC:0000 A:00\n").name(), "kv");
+    }
+}